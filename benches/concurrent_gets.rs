@@ -0,0 +1,45 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+const RUN_FOR: Duration = Duration::from_secs(1);
+
+// Reads random keys from more and more threads at once, printing how many gets each
+// reaches per second. On a machine with that many cores, the total should grow with the
+// threads, as reads take no lock other reads contend for.
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for threads in [1, 2, 4, 8] {
+        let started = Instant::now();
+        let gets: u64 = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|t| {
+                    let store = store.clone();
+                    scope.spawn(move || {
+                        let mut gets = 0u64;
+                        let mut i = t * 7919;
+                        while started.elapsed() < RUN_FOR {
+                            i = (i * 31 + 17) % KEYS;
+                            store.get(format!("key{}", i)).unwrap();
+                            gets += 1;
+                        }
+                        gets
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        let per_sec = gets as f64 / started.elapsed().as_secs_f64();
+        println!("{} threads: {:.0} gets/s", threads, per_sec);
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{collections::Value, read_entry, KvStore, Result, Store};
+
+/// Number of prefixes listed in a `KeyspaceReport`.
+const TOP_PREFIXES: usize = 10;
+
+/// Summary statistics of a sampled quantity, in bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Distribution {
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+    pub mean: f64,
+}
+
+impl Distribution {
+    fn new(mut samples: Vec<u64>) -> Distribution {
+        if samples.is_empty() {
+            return Distribution::default();
+        }
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        Distribution {
+            min: samples[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+            mean: samples.iter().sum::<u64>() as f64 / samples.len() as f64,
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {}, p50 {}, p90 {}, p99 {}, max {}, mean {:.1}",
+            self.min, self.p50, self.p90, self.p99, self.max, self.mean
+        )
+    }
+}
+
+/// Statistics about the keys and values of a store, see `KvStore::analyze`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyspaceReport {
+    pub keys: u64,
+    pub sampled: u64,
+    pub key_lengths: Distribution,
+    /// Sizes of the values; for lists, sets and hashes the sizes of all their items.
+    pub value_sizes: Distribution,
+    /// The most common key prefixes, up to the first `:`, with the estimated number of
+    /// keys sharing them, most common first.
+    pub prefixes: Vec<(String, u64)>,
+    /// Estimated ratio of raw to compressed size of the sampled keys and values.
+    ///
+    /// Derived from their byte entropy, so it ignores repeated substrings; dictionary
+    /// based compressors usually do better.
+    pub compression_ratio: f64,
+}
+
+impl fmt::Display for KeyspaceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "keys: {} (sampled {})", self.keys, self.sampled)?;
+        writeln!(f, "key_length: {}", self.key_lengths)?;
+        writeln!(f, "value_size: {}", self.value_sizes)?;
+        writeln!(f, "compression_ratio: {:.2}", self.compression_ratio)?;
+        write!(f, "prefixes:")?;
+        for (prefix, keys) in &self.prefixes {
+            write!(f, "\n  {}: ~{}", prefix, keys)?;
+        }
+        Ok(())
+    }
+}
+
+impl Store {
+    pub(crate) fn analyze(&self, sample_size: usize) -> Result<KeyspaceReport> {
+        let keys = self.index().keys_in(..);
+        let step = keys.len().div_ceil(sample_size.max(1)).max(1);
+        let mut key_lengths = Vec::new();
+        let mut value_sizes = Vec::new();
+        let mut prefixes: HashMap<&str, u64> = HashMap::new();
+        let mut byte_counts = [0u64; 256];
+        for key in keys.iter().step_by(step) {
+            let entry = self.index().get(key).cloned();
+            let entry = entry.expect("keys come from the index");
+            let value = self.with_reader(|reader| read_entry(reader, &self.blobs(), &entry))?;
+            let items: Vec<&str> = match &value {
+                Value::String(value) => vec![value],
+                Value::List(items) => items.iter().map(String::as_str).collect(),
+                Value::Set(members) => members.iter().map(String::as_str).collect(),
+                Value::Hash(fields) => fields
+                    .iter()
+                    .flat_map(|(field, value)| [field.as_str(), value.as_str()])
+                    .collect(),
+            };
+            key_lengths.push(key.len() as u64);
+            value_sizes.push(items.iter().map(|item| item.len() as u64).sum());
+            if let Some((prefix, _)) = key.split_once(':') {
+                *prefixes.entry(prefix).or_default() += 1;
+            }
+            for byte in std::iter::once(key.as_str())
+                .chain(items)
+                .flat_map(str::bytes)
+            {
+                byte_counts[byte as usize] += 1;
+            }
+        }
+
+        let sampled = key_lengths.len() as u64;
+        let mut prefixes: Vec<(String, u64)> = prefixes
+            .into_iter()
+            .map(|(prefix, count)| (prefix.to_owned(), count * keys.len() as u64 / sampled))
+            .collect();
+        prefixes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        prefixes.truncate(TOP_PREFIXES);
+        Ok(KeyspaceReport {
+            keys: keys.len() as u64,
+            sampled,
+            key_lengths: Distribution::new(key_lengths),
+            value_sizes: Distribution::new(value_sizes),
+            prefixes,
+            compression_ratio: compression_ratio(&byte_counts),
+        })
+    }
+}
+
+impl KvStore {
+    /// Reads about `sample_size` keys spread evenly over the keyspace and reports
+    /// statistics about them, extrapolated to the whole store.
+    pub fn analyze(&self, sample_size: usize) -> Result<KeyspaceReport> {
+        self.read().analyze(sample_size)
+    }
+}
+
+// 8 bits per byte over the order-0 entropy of the bytes, in bits per byte
+fn compression_ratio(byte_counts: &[u64; 256]) -> f64 {
+    let total: u64 = byte_counts.iter().sum();
+    if total == 0 {
+        return 1.0;
+    }
+    let entropy: f64 = byte_counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum();
+    // a single repeated byte would need no bits at all
+    8.0 / entropy.max(1.0 / 8.0)
+}
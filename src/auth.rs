@@ -0,0 +1,68 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use failure::format_err;
+
+use crate::Result;
+
+/// Decides whether the clients of a `KvsServer` are who they claim to be, see
+/// `KvsServer::auth`.
+///
+/// Implement it to check secrets against an identity system, e.g. hashed password files,
+/// signed tokens or an external service.
+pub trait AuthProvider: Send + Sync {
+    /// Whether `secret`, a password or token sent by the client, proves it is `user`.
+    fn authenticate(&self, user: &str, secret: &str) -> Result<bool>;
+}
+
+/// Authenticates users against a fixed set of passwords.
+#[derive(Debug, Clone, Default)]
+pub struct Passwords {
+    passwords: HashMap<String, String>,
+}
+
+impl Passwords {
+    pub fn new() -> Passwords {
+        Passwords::default()
+    }
+
+    pub fn insert(
+        &mut self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> &mut Passwords {
+        self.passwords.insert(user.into(), password.into());
+        self
+    }
+
+    /// Reads the passwords from a file of `user:password` lines, skipping empty lines and
+    /// lines starting with `#`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Passwords> {
+        let path = path.as_ref();
+        let mut passwords = Passwords::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, password) = line.split_once(':').ok_or_else(|| {
+                format_err!("Line {} of {} is not user:password", i + 1, path.display())
+            })?;
+            passwords.insert(user, password);
+        }
+        Ok(passwords)
+    }
+}
+
+impl AuthProvider for Passwords {
+    fn authenticate(&self, user: &str, secret: &str) -> Result<bool> {
+        Ok(self
+            .passwords
+            .get(user)
+            .is_some_and(|password| constant_time_eq(password.as_bytes(), secret.as_bytes())))
+    }
+}
+
+// Compares without stopping at the first difference, so the time taken does not reveal
+// how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
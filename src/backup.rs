@@ -0,0 +1,314 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use failure::format_err;
+
+use crate::{
+    compaction::copy_entry,
+    copy_record,
+    hint::HINT_FILE_NAME,
+    meta::META_FILE_NAME,
+    reads::{entry_gens, Pins},
+    segment::{segment_gens, segment_path, LogReader, LEGACY_LOG_FILE_NAME},
+    segment_writer, CommandPos, CompressionStats, IndexEntry, KvStore, OpenOptions, Registration,
+    Result, Storage, StorageFile, Store, StoreInfo, COMPACT_FILE_NAME,
+};
+
+// The files of a store as they were when a backup started, pinned so a compaction
+// replacing them meanwhile does not matter.
+struct Source {
+    storage: Arc<dyn Storage>,
+    segments: Vec<(u64, Box<dyn StorageFile>)>,
+    _pins: Pins,
+    hint: Vec<u8>,
+    info: StoreInfo,
+}
+
+/// What `OpenOptions::restore` would do, see `OpenOptions::restore_plan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestorePlan {
+    /// Files of the store that would be removed.
+    pub removed: Vec<PathBuf>,
+    /// Files of the backup that would be copied into the store, with their sizes.
+    pub copied: Vec<(PathBuf, u64)>,
+}
+
+impl fmt::Display for RestorePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        for path in &self.removed {
+            lines.push(format!("remove {}", path.display()));
+        }
+        for (path, size) in &self.copied {
+            lines.push(format!("copy {} ({} bytes)", path.display(), size));
+        }
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+// The live records of a store as they were when a clone started, in segments pinned so a
+// compaction replacing them meanwhile does not matter.
+struct CloneSource {
+    storage: Arc<dyn Storage>,
+    reader: LogReader,
+    _pins: Pins,
+    blobs: Vec<(u64, CommandPos)>,
+    entries: Vec<(String, IndexEntry)>,
+    info: StoreInfo,
+}
+
+impl Store {
+    // Copies the index like a view, without the expired keys and the shared values only
+    // those refer to.
+    fn start_clone(&self, to: &Path) -> Result<CloneSource> {
+        check_empty(&*self.storage, to)?;
+        let reader = LogReader::new(
+            self.storage.clone(),
+            self.dir.clone(),
+            CompressionStats::default(),
+        );
+        let mut gens = Vec::new();
+        let mut entries = Vec::new();
+        let mut hashes = HashSet::new();
+        let keys = self.index().keys_in(..);
+        for key in keys {
+            let Some(entry) = self.entry(&key) else {
+                continue;
+            };
+            gens.extend(entry_gens(&entry));
+            hashes.extend(entry.blob);
+            entries.push((key, entry.clone()));
+        }
+        let mut blobs = Vec::new();
+        for (hash, cmd_pos) in self.blobs().iter() {
+            if hashes.contains(&hash) {
+                gens.push(cmd_pos.gen);
+                blobs.push((hash, cmd_pos.clone()));
+            }
+        }
+        let now = self.clock.now().as_secs();
+        Ok(CloneSource {
+            storage: self.storage.clone(),
+            reader,
+            _pins: self.reads.pin(gens),
+            blobs,
+            entries,
+            info: StoreInfo {
+                created_at: Some(now),
+                last_compaction: Some(now),
+                high_water_seq: Some(self.seq),
+                ..self.info.clone()
+            },
+        })
+    }
+
+    // Seals the current segment like a snapshot and opens the segments up to it.
+    fn start_backup(&mut self, to: &Path) -> Result<Source> {
+        check_empty(&*self.storage, to)?;
+        let gens = self.freeze()?;
+        let mut segments = Vec::with_capacity(gens.len());
+        for &gen in &gens {
+            let file = self.storage.open_read(&segment_path(&self.dir, gen))?;
+            segments.push((gen, file));
+        }
+        Ok(Source {
+            storage: self.storage.clone(),
+            segments,
+            _pins: self.reads.pin(gens),
+            hint: self.storage.read(&self.dir.join(HINT_FILE_NAME))?,
+            info: self.info.clone(),
+        })
+    }
+}
+
+impl Source {
+    // Copies the files into `to`, the metadata last, which marks the backup complete.
+    fn write_to(mut self, to: &Path) -> Result<()> {
+        self.storage.create_dir(to)?;
+        for (gen, file) in &mut self.segments {
+            copy(&*self.storage, file, &segment_path(to, *gen))?;
+        }
+        write_file(&*self.storage, &to.join(HINT_FILE_NAME), &self.hint)?;
+        self.storage.sync_dir(to)?;
+        self.info.save(&*self.storage, to, true)?;
+        self.storage.sync_dir(to)?;
+        Ok(())
+    }
+}
+
+impl CloneSource {
+    // Writes the records into the first segment of `to`, like a compaction, the metadata
+    // last.
+    fn write_to(mut self, to: &Path) -> Result<()> {
+        self.storage.create_dir(to)?;
+        let gen = 1;
+        let mut writer = segment_writer(self.storage.create(&segment_path(to, gen))?)?;
+        // shared values go first, so they are read before the records referring to them
+        for (_, cmd_pos) in &self.blobs {
+            copy_record(&mut self.reader, &mut writer, gen, cmd_pos)?;
+        }
+        for (key, entry) in &self.entries {
+            copy_entry(&mut self.reader, &mut writer, gen, key, entry)?;
+        }
+        writer.flush()?;
+        writer.writer.get_mut().sync()?;
+        drop(writer);
+        self.storage.sync_dir(to)?;
+        self.info.save(&*self.storage, to, true)?;
+        self.storage.sync_dir(to)?;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Writes a compacted copy of the live data of the store into the directory `to`, which
+    /// must not hold a store yet, and is independent of the store from then on.
+    ///
+    /// Unlike `KvStore::backup`, the copy holds a single record per key, none of the stale
+    /// history and no expired keys. The index is copied under a read lock like
+    /// `KvStore::view`, so writes go on meanwhile and those after it do not show up. The
+    /// values of a backing store that were never read into the store are not copied.
+    pub fn clone_to(&self, to: impl Into<PathBuf>) -> Result<()> {
+        let to = to.into();
+        let source = self.read().start_clone(&to)?;
+        source.write_to(&to)
+    }
+
+    /// Copies the store into the directory `to`, which must not hold a store yet, so it
+    /// can be restored with `KvStore::restore` or opened itself.
+    ///
+    /// Like `KvStore::snapshot`, the current segment is sealed first, so the backup holds
+    /// the store as it was at that point. Only sealing holds up writes. The segments are
+    /// copied afterwards, from files opened before, so writes and compactions go on
+    /// meanwhile. The metadata is copied last, a directory without it is an incomplete
+    /// backup.
+    pub fn backup(&self, to: impl Into<PathBuf>) -> Result<()> {
+        let to = to.into();
+        let source = self.write().start_backup(&to)?;
+        source.write_to(&to)
+    }
+
+    /// Replaces the store at `path` on the local disk with the backup in `from`, see
+    /// `OpenOptions::restore`.
+    pub fn restore(path: impl Into<PathBuf>, from: impl AsRef<Path>) -> Result<()> {
+        OpenOptions::new().restore(path, from)
+    }
+
+    /// Returns what `KvStore::restore` would do, see `OpenOptions::restore_plan`.
+    pub fn restore_plan(path: impl Into<PathBuf>, from: impl AsRef<Path>) -> Result<RestorePlan> {
+        OpenOptions::new().restore_plan(path, from)
+    }
+}
+
+impl OpenOptions {
+    /// Replaces the store at `path`, which must not be open, with a copy of the backup in
+    /// `from`, created by `KvStore::backup`.
+    ///
+    /// Everything in the store is lost. An interrupted restore can be run again.
+    pub fn restore(&self, path: impl Into<PathBuf>, from: impl AsRef<Path>) -> Result<()> {
+        let dir = path.into();
+        let from = from.as_ref();
+        let storage = self.resolved_storage();
+        let info = read_backup_info(&*storage, from)?;
+        storage.create_dir(&dir)?;
+        let _registration = Registration::acquire_exclusive(&*storage, &dir)?;
+
+        // the hint goes first, so it never describes segments of both
+        for file in [HINT_FILE_NAME, COMPACT_FILE_NAME, LEGACY_LOG_FILE_NAME] {
+            let path = dir.join(file);
+            if storage.exists(&path) {
+                storage.remove(&path)?;
+            }
+        }
+        for gen in segment_gens(&*storage, &dir)? {
+            storage.remove(&segment_path(&dir, gen))?;
+        }
+        for gen in segment_gens(&*storage, from)? {
+            let mut file = storage.open_read(&segment_path(from, gen))?;
+            copy(&*storage, &mut file, &segment_path(&dir, gen))?;
+        }
+        write_file(
+            &*storage,
+            &dir.join(HINT_FILE_NAME),
+            &storage.read(&from.join(HINT_FILE_NAME))?,
+        )?;
+        storage.sync_dir(&dir)?;
+        info.save(&*storage, &dir, true)?;
+        storage.sync_dir(&dir)?;
+        Ok(())
+    }
+
+    /// Returns the files `restore` would remove from the store at `path` and copy from the
+    /// backup in `from`, changing nothing.
+    ///
+    /// The backup is checked to be complete. No lock is taken, so the store may still
+    /// change until it is restored.
+    pub fn restore_plan(
+        &self,
+        path: impl Into<PathBuf>,
+        from: impl AsRef<Path>,
+    ) -> Result<RestorePlan> {
+        let dir = path.into();
+        let from = from.as_ref();
+        let storage = self.resolved_storage();
+        read_backup_info(&*storage, from)?;
+
+        let mut removed: Vec<PathBuf> = [HINT_FILE_NAME, COMPACT_FILE_NAME, LEGACY_LOG_FILE_NAME]
+            .into_iter()
+            .map(|file| dir.join(file))
+            .filter(|path| storage.exists(path))
+            .collect();
+        if storage.exists(&dir) {
+            for gen in segment_gens(&*storage, &dir)? {
+                removed.push(segment_path(&dir, gen));
+            }
+        }
+        let mut copied = Vec::new();
+        for gen in segment_gens(&*storage, from)? {
+            let path = segment_path(from, gen);
+            let size = storage.open_read(&path)?.size()?;
+            copied.push((path, size));
+        }
+        let hint = from.join(HINT_FILE_NAME);
+        let size = storage.read(&hint)?.len() as u64;
+        copied.push((hint, size));
+        Ok(RestorePlan { removed, copied })
+    }
+}
+
+fn read_backup_info(storage: &dyn Storage, from: &Path) -> Result<StoreInfo> {
+    match storage.read(&from.join(META_FILE_NAME)) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(format_err!("{} holds no complete backup", from.display()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn check_empty(storage: &dyn Storage, dir: &Path) -> Result<()> {
+    if storage.exists(&dir.join(META_FILE_NAME)) || !segment_gens(storage, dir)?.is_empty() {
+        return Err(format_err!("{} already holds a store", dir.display()));
+    }
+    Ok(())
+}
+
+// Copies the rest of `from` into a new file at `to` and syncs it.
+fn copy(storage: &dyn Storage, from: &mut Box<dyn StorageFile>, to: &Path) -> Result<()> {
+    let mut file = storage.create(to)?;
+    io::copy(from, &mut file)?;
+    file.sync()?;
+    Ok(())
+}
+
+fn write_file(storage: &dyn Storage, path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut file = storage.create(path)?;
+    file.write_all(bytes)?;
+    file.sync()?;
+    Ok(())
+}
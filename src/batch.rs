@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::{Commands, KvStore, Result, Store};
+
+/// Sets and removes to apply to a store at once, see `KvStore::write_batch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    // the values to set, or `None` to remove the key, in the order they were staged
+    ops: Vec<(String, Option<String>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.ops.push((key, Some(value)));
+        self
+    }
+
+    /// Removes `key`. Unlike `KvStore::remove`, the key does not have to exist.
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.ops.push((key, None));
+        self
+    }
+
+    /// Returns the number of sets and removes staged.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+impl Store {
+    pub(crate) fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if let Some(origin) = &self.backing_store {
+            for (key, value) in &batch.ops {
+                match value {
+                    Some(value) => origin.store(key, value)?,
+                    None => {
+                        origin.remove(key)?;
+                    }
+                }
+            }
+        }
+
+        // whether the keys exist after the operations before, removes of keys that do not
+        // write nothing
+        let mut exists: HashMap<String, bool> = HashMap::new();
+        let mut cmds = Vec::with_capacity(batch.ops.len());
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => {
+                    exists.insert(key.clone(), true);
+                    cmds.push(self.set_record(key, value, self.seq + cmds.len() as u64));
+                }
+                None => {
+                    let existed = match exists.insert(key.clone(), false) {
+                        Some(existed) => existed,
+                        None => self.entry(&key).is_some(),
+                    };
+                    if existed {
+                        cmds.push(Commands::Rm { key });
+                    }
+                }
+            }
+        }
+        if cmds.is_empty() {
+            return Ok(());
+        }
+
+        self.append_record(&Commands::Batch {
+            count: cmds.len() as u64,
+        })?;
+        let mut records = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let cmd_pos = self.append_record(&cmd)?;
+            records.push((cmd, cmd_pos));
+        }
+        self.flush_log()?;
+
+        self.apply_records(records);
+        self.after_write()
+    }
+}
+
+impl KvStore {
+    /// Applies all sets and removes of `batch` in order, so that either all or none of
+    /// them survive a crash.
+    ///
+    /// The records are written together with a single flush, behind a marker saying how
+    /// many there are. Opening a store whose log ends before the last of them discards
+    /// the others. Values are neither deduplicated nor split into chunks, and a backing
+    /// store is updated first, one key at a time, so it is not part of the batch.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write().write_batch(batch)
+    }
+}
@@ -0,0 +1,69 @@
+use std::{env::current_dir, path::PathBuf, process, time::Duration};
+
+use clap::Parser;
+use kvs::{parse_duration, parse_size, Config, ErrorCode, KvsServer, Passwords};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+#[derive(Parser)]
+#[command(name = "kvs-server")]
+#[command(version = env!("CARGO_PKG_VERSION"))]
+struct Cli {
+    /// Read the settings from this TOML file, the arguments below override them
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 127.0.0.1:4000]
+    #[arg(long)]
+    addr: Option<String>,
+    /// Only serve clients authenticating with a password from this file of
+    /// `user:password` lines
+    #[arg(long)]
+    auth_file: Option<PathBuf>,
+    /// Engine to serve the store with, `kvs` or `mem` [default: kvs]
+    #[arg(long)]
+    engine: Option<String>,
+    /// Size at which a new segment of the log is started, such as `64MiB`
+    #[arg(long, value_parser = |s: &str| parse_size(s).map_err(|e| e.to_string()))]
+    segment_size: Option<u64>,
+    /// Longest a write waits for compaction to catch up, such as `250ms`
+    #[arg(long, value_parser = |s: &str| parse_duration(s).map_err(|e| e.to_string()))]
+    max_write_stall: Option<Duration>,
+}
+
+fn main() {
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        // invalid arguments exit like those of `kvs`
+        process::exit(if e.use_stderr() { 4 } else { 0 })
+    });
+    if let Err(e) = run(cli) {
+        eprintln!("kvs-server: {}", e);
+        process::exit(ErrorCode::of(&e).exit_code());
+    }
+}
+
+fn run(cli: Cli) -> kvs::Result<()> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    config.addr = cli.addr.or(config.addr);
+    config.auth_file = cli.auth_file.or(config.auth_file);
+    config.engine = cli.engine.or(config.engine);
+    config.segment_size = cli.segment_size.or(config.segment_size);
+    config.max_write_stall = cli.max_write_stall.or(config.max_write_stall);
+
+    let store = config.open(current_dir()?)?;
+    let addr = config.addr.as_deref().unwrap_or(DEFAULT_ADDR);
+    eprintln!(
+        "kvs-server {} listening on {} with engine {}",
+        env!("CARGO_PKG_VERSION"),
+        addr,
+        config.engine.as_deref().unwrap_or("kvs")
+    );
+    let mut server = KvsServer::new(store);
+    if let Some(path) = &config.auth_file {
+        server = server.auth(Passwords::load(path)?);
+    }
+    server.run(addr)
+}
@@ -1,43 +1,623 @@
-use std::{env::current_dir, process};
+use std::{
+    env::current_dir,
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
 
-use clap::Parser;
+use failure::format_err;
+use kvs::{ErrorCode, KvsEngine, KvsError, Progress};
 
-use kvs::Commands;
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+// Exit codes other than those of `ErrorCode::exit_code`.
+const EXIT_USAGE: i32 = 4;
+
+// How often long commands redraw their progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
 #[command(version = env!("CARGO_PKG_VERSION"))]
+#[command(after_help = "\
+Values and other results go to stdout, errors and other diagnostics to stderr.
+
+Exit codes, except for fsck which has its own:
+  0  success
+  1  failure not listed below, e.g. of the file system
+  2  key not found
+  3  the store is corrupt
+  4  invalid arguments or request
+  5  the store is in use by another process")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
 
-fn main() -> kvs::Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum Commands {
+    Set {
+        key: Option<String>,
+        value: Option<String>,
+        #[command(flatten)]
+        input: Input,
+        /// Read the value from this file, `-` for stdin, instead of the argument
+        #[arg(long)]
+        value_file: Option<PathBuf>,
+    },
+    Get {
+        key: Option<String>,
+        #[command(flatten)]
+        input: Input,
+    },
+    Rm {
+        key: Option<String>,
+        #[command(flatten)]
+        input: Input,
+    },
+    /// Print the values of KEYS, one line each, empty for missing ones
+    ///
+    /// Missing keys are also named on stderr, and make the command exit with 2.
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Set each KEY to the VALUE after it, with a single flush
+    Mset {
+        #[arg(required = true, value_names = ["KEY", "VALUE"])]
+        pairs: Vec<String>,
+    },
+    /// Add DELTA to the integer value of KEY, 0 if missing, and print the result
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Subtract DELTA from the integer value of KEY, 0 if missing, and print the result
+    Decr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Compact the store now and print its space usage after
+    Compact,
+    /// Print the metadata and space usage of the store
+    Info,
+    /// Print the number of keys, the size of the log and how close it is to compaction
+    Stats {
+        /// Print the statistics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Sample the keys and print statistics about the keyspace
+    Analyze {
+        /// Number of keys to sample
+        #[arg(long, default_value_t = 1000)]
+        sample: usize,
+    },
+    /// Set the pairs read from stdin, one `key<TAB>value` per line
+    Load {
+        /// Buffer all pairs and write them at once, much faster for large inputs
+        #[arg(long)]
+        bulk: bool,
+    },
+    /// Write the string values of the store to stdout or a file
+    Export {
+        /// Write the pairs in key order, required by the sst-like format, the others always
+        /// are
+        #[arg(long)]
+        sorted: bool,
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Set the pairs read from FILE, `-` for stdin, as written by export, all at once
+    Import {
+        #[arg(long, value_enum)]
+        format: ImportFormat,
+        file: PathBuf,
+        /// Print how many keys would be added, overwritten or left unchanged, changing
+        /// nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Read commands from the terminal and print their results, opening the store once
+    ///
+    /// Type `help` for the commands and `exit` to leave.
+    Repl,
+    /// Answer commands read from stdin, one per line, on stdout, opening the store once
+    ///
+    /// A line is either a JSON request of the network protocol, answered in JSON, or a text
+    /// command such as `SET key value`, `GET key`, `GETRANGE key offset len`, `RM key` or
+    /// `SCAN [start [end]]`, with arguments holding spaces written as JSON strings. The bytes
+    /// of `GETRANGE` are answered in hex.
+    Pipe,
+    /// Check the store for damage, without opening it
+    ///
+    /// Exits with 0 when the store is clean, 1 when problems were repaired, 2 when
+    /// problems need --repair, 3 when they cannot be repaired and 4 when the check could
+    /// not run.
+    Fsck {
+        /// Repair the problems that can be repaired
+        #[arg(long)]
+        repair: bool,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+        /// With --repair, only report what would be repaired
+        #[arg(long, requires = "repair")]
+        dry_run: bool,
+    },
+    /// Create a writable copy of the store at PATH, sharing its data on disk
+    Branch { path: PathBuf },
+    /// Copy the store into the directory DIR, which must not hold a store yet
+    Backup { dir: PathBuf },
+    /// Replace the store with the backup in the directory DIR, losing everything in it
+    Restore {
+        dir: PathBuf,
+        /// Print the files that would be removed and copied, changing nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manage named snapshots of the store, to roll it back to later
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommands,
+    },
+}
+
+// How the key and value of `set`, `get` and `rm` are given, for those shell arguments
+// cannot express, such as with newlines. Keys and values are UTF-8 text all the way down
+// to the log, so files and hex only carry text, other bytes are rejected rather than
+// stored; with hex covering that, there is no base64 flag too.
+#[derive(Args)]
+struct Input {
+    /// Read the key from this file, `-` for stdin, instead of the argument
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+    /// Take keys and values given as arguments as the hex of their UTF-8 bytes, and print
+    /// values so
+    #[arg(long)]
+    hex: bool,
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommands {
+    /// Take a snapshot of the store as it is now
+    Create { name: String },
+    /// List the snapshots, oldest first
+    List,
+    /// Roll the store back to a snapshot, losing everything written since
+    Restore { name: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// Key-ordered binary entries followed by a sparse index block
+    SstLike,
+    /// One `{"key":...,"value":...}` object per line
+    Json,
+    /// A `key,value` header followed by one line per pair
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImportFormat {
+    /// One `{"key":...,"value":...}` object per line
+    Json,
+    /// A `key,value` header followed by one record per pair
+    Csv,
+}
+
+fn main() {
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        process::exit(if e.use_stderr() { EXIT_USAGE } else { 0 })
+    });
+    if let Err(e) = run_command(cli) {
+        if let Some(e) = e.downcast_ref::<clap::Error>() {
+            let _ = e.print();
+            process::exit(EXIT_USAGE);
+        }
+        eprintln!("kvs: {}", e);
+        process::exit(ErrorCode::of(&e).exit_code());
+    }
+}
 
-    let mut kvs = kvs::KvStore::open(current_dir()?).unwrap();
+fn run_command(cli: Cli) -> kvs::Result<()> {
+    if let Commands::Fsck {
+        repair,
+        json,
+        dry_run,
+    } = cli.command
+    {
+        if dry_run {
+            eprintln!("Dry run, nothing is repaired");
+        }
+        fsck(repair && !dry_run, json);
+    }
+    if let Commands::Snapshot {
+        command: SnapshotCommands::Restore { name },
+    } = &cli.command
+    {
+        return kvs::KvStore::restore_snapshot(current_dir()?, name);
+    }
+
+    if let Commands::Restore { dir, dry_run } = &cli.command {
+        if *dry_run {
+            println!("{}", kvs::KvStore::restore_plan(current_dir()?, dir)?);
+            return Ok(());
+        }
+        return kvs::KvStore::restore(current_dir()?, dir);
+    }
 
+    // read before opening the store, so invalid arguments leave no store behind
+    let engine_command = EngineCommand::read(&cli.command)?;
+
+    let mut kvs = kvs::KvStore::open(current_dir()?)?;
+
+    if let Some(command) = engine_command {
+        return run(&mut kvs, command);
+    }
     match cli.command {
-        Commands::Set { key, value } => {
-            kvs.set(key, value)?;
+        Commands::Set { .. } | Commands::Get { .. } | Commands::Rm { .. } => {
+            unreachable!("run as an engine command")
+        }
+        Commands::Mget { keys } => {
+            let mut missing = false;
+            for (key, value) in keys.iter().zip(kvs.get_many(&keys)?) {
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => {
+                        // an empty line keeps the values in line with the keys
+                        println!();
+                        eprintln!("kvs: {}: {}", KvsError::KeyNotFound, key);
+                        missing = true;
+                    }
+                }
+            }
+            if missing {
+                io::stdout().flush()?;
+                process::exit(ErrorCode::KeyNotFound.exit_code());
+            }
+            Ok(())
+        }
+        Commands::Mset { pairs } => {
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            kvs.set_many(pairs)
+        }
+        Commands::Incr { key, delta } => {
+            println!("{}", kvs.incr(key, delta)?);
+            Ok(())
+        }
+        Commands::Decr { key, delta } => {
+            println!("{}", kvs.decr(key, delta)?);
             Ok(())
         }
-        Commands::Get { key } => {
-            let value = kvs.get(key)?;
-            let value = value.unwrap_or_else(|| {
-                println!("Key not found");
-                process::exit(0);
+        Commands::Compact => {
+            let handle = kvs.compaction_handle();
+            with_progress(|| handle.progress(), true, || kvs.compact_now())?;
+            println!("{}", kvs.space_usage());
+            Ok(())
+        }
+        Commands::Info => {
+            println!("{}", kvs.info());
+            println!("{}", kvs.space_usage());
+            Ok(())
+        }
+        Commands::Stats { json } => {
+            let stats = kvs.stats()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                println!("{}", stats);
+            }
+            Ok(())
+        }
+        Commands::Analyze { sample } => {
+            println!("{}", kvs.analyze(sample)?);
+            Ok(())
+        }
+        Commands::Load { bulk } => {
+            let pairs = io::stdin().lock().lines().enumerate().map(|(i, line)| {
+                let line = line?;
+                let (key, value) = line
+                    .split_once('\t')
+                    .ok_or_else(|| format_err!("Line {} is not key<TAB>value", i + 1))?;
+                Ok((key.to_owned(), value.to_owned()))
             });
-            println!("{}", value);
+            if bulk {
+                kvs.bulk_load(pairs.collect::<kvs::Result<Vec<_>>>()?)?;
+            } else {
+                for pair in pairs {
+                    let (key, value) = pair?;
+                    kvs.set(key, value)?;
+                }
+            }
             Ok(())
         }
-        Commands::Rm { key } => {
-            let result = kvs.remove(key);
-            if result.is_err() {
-                println!("Key not found");
-                process::exit(1);
+        Commands::Export { format, output, .. } => {
+            // progress lines would garble values printed to the same terminal
+            let show = output.is_some() || !io::stdout().is_terminal();
+            let out: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout().lock()),
+            };
+            let progress = Progress::new();
+            with_progress(
+                || progress.report(),
+                show,
+                || match format {
+                    ExportFormat::SstLike => kvs.export_sorted_with_progress(out, &progress),
+                    ExportFormat::Json => kvs.export_json_with_progress(out, &progress),
+                    ExportFormat::Csv => kvs.export_csv_with_progress(out, &progress),
+                },
+            )?;
+            Ok(())
+        }
+        Commands::Import {
+            format,
+            file,
+            dry_run: true,
+        } => {
+            let input: Box<dyn Read> = if file == Path::new("-") {
+                Box::new(io::stdin().lock())
+            } else {
+                Box::new(File::open(file)?)
+            };
+            let plan = match format {
+                ImportFormat::Json => kvs.import_json_plan(input)?,
+                ImportFormat::Csv => kvs.import_csv_plan(input)?,
+            };
+            println!("{}", plan);
+            Ok(())
+        }
+        Commands::Import { format, file, .. } => {
+            let progress = Progress::new();
+            let input: Box<dyn Read> = if file == Path::new("-") {
+                Box::new(io::stdin().lock())
+            } else {
+                let file = File::open(file)?;
+                progress.expect_bytes(file.metadata()?.len());
+                Box::new(file)
+            };
+            with_progress(
+                || progress.report(),
+                true,
+                || match format {
+                    ImportFormat::Json => kvs.import_json_with_progress(input, &progress),
+                    ImportFormat::Csv => kvs.import_csv_with_progress(input, &progress),
+                },
+            )?;
+            Ok(())
+        }
+        Commands::Repl => {
+            let prompt = if io::stdin().is_terminal() {
+                "kvs> "
+            } else {
+                ""
+            };
+            kvs::serve_repl(&mut kvs, io::stdin().lock(), io::stdout().lock(), prompt)
+        }
+        Commands::Pipe => kvs::serve_pipe(&mut kvs, io::stdin().lock(), io::stdout().lock()),
+        Commands::Branch { path } => kvs.branch(path),
+        Commands::Backup { dir } => kvs.backup(dir),
+        Commands::Snapshot { command } => match command {
+            SnapshotCommands::Create { name } => {
+                println!("{}", kvs.snapshot(&name)?);
+                Ok(())
+            }
+            SnapshotCommands::List => {
+                for snapshot in kvs.snapshots()? {
+                    println!("{}", snapshot);
+                }
+                Ok(())
+            }
+            SnapshotCommands::Restore { .. } => unreachable!("handled before opening the store"),
+        },
+        Commands::Fsck { .. } | Commands::Restore { .. } => {
+            unreachable!("handled before opening the store")
+        }
+    }
+}
+
+// Set, get and rm with their key and value read, the commands every engine supports.
+enum EngineCommand {
+    Set { key: String, value: String },
+    Get { key: String, hex: bool },
+    Rm { key: String },
+}
+
+impl EngineCommand {
+    // Reads the key and value of `command` from its arguments or files, `None` for other
+    // commands after checking their arguments.
+    fn read(command: &Commands) -> kvs::Result<Option<EngineCommand>> {
+        let command = match command {
+            Commands::Set {
+                key,
+                value,
+                input,
+                value_file,
+            } => {
+                // with --key-file, the only argument is the value
+                let mut args = [key.clone(), value.clone()].into_iter().flatten();
+                let key_arg = input.key_file.is_none().then(|| args.next()).flatten();
+                let key = input.read("set", "key", input.key_file.as_deref(), key_arg)?;
+                let value_arg = value_file.is_none().then(|| args.next()).flatten();
+                let value = input.read("set", "value", value_file.as_deref(), value_arg)?;
+                if let Some(extra) = args.next() {
+                    return Err(usage("set", format!("Unexpected argument {:?}", extra)));
+                }
+                EngineCommand::Set { key, value }
+            }
+            Commands::Get { key, input } => EngineCommand::Get {
+                key: input.read("get", "key", input.key_file.as_deref(), key.clone())?,
+                hex: input.hex,
+            },
+            Commands::Rm { key, input } => EngineCommand::Rm {
+                key: input.read("rm", "key", input.key_file.as_deref(), key.clone())?,
+            },
+            Commands::Mset { pairs } if pairs.len() % 2 != 0 => {
+                return Err(usage(
+                    "mset",
+                    format!("The key {} has no value", pairs[pairs.len() - 1]),
+                ))
+            }
+            Commands::Export {
+                sorted: false,
+                format: ExportFormat::SstLike,
+                ..
+            } => {
+                return Err(usage(
+                    "export",
+                    "The sst-like format is always sorted, pass --sorted",
+                ))
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+}
+
+fn run(engine: &mut impl KvsEngine, command: EngineCommand) -> kvs::Result<()> {
+    match command {
+        EngineCommand::Set { key, value } => engine.set(key, value),
+        EngineCommand::Get { key, hex } => {
+            let value = engine.get(key)?.ok_or(KvsError::KeyNotFound)?;
+            if hex {
+                println!("{}", to_hex(value.as_bytes()));
+            } else {
+                println!("{}", value);
             }
             Ok(())
         }
+        EngineCommand::Rm { key } => match engine.remove_returning(key)? {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound.into()),
+        },
+    }
+}
+
+impl Input {
+    // Reads the key or value named `what` of `command` from `file` if given, else from
+    // `arg`.
+    fn read(
+        &self,
+        command: &str,
+        what: &str,
+        file: Option<&Path>,
+        arg: Option<String>,
+    ) -> kvs::Result<String> {
+        let bytes = match (file, arg) {
+            (Some(file), None) if file == Path::new("-") => {
+                let mut bytes = Vec::new();
+                io::stdin().read_to_end(&mut bytes)?;
+                bytes
+            }
+            (Some(file), None) => fs::read(file)?,
+            (Some(_), Some(_)) => {
+                return Err(usage(
+                    command,
+                    format!("Pass the {} as argument or file, not both", what),
+                ))
+            }
+            (None, Some(arg)) if self.hex => from_hex(&arg).ok_or_else(|| {
+                usage(command, format!("The {} {:?} is not valid hex", what, arg))
+            })?,
+            (None, Some(arg)) => return Ok(arg),
+            (None, None) => return Err(usage(command, format!("Missing the {}", what))),
+        };
+        String::from_utf8(bytes).map_err(|_| {
+            usage(
+                command,
+                format!(
+                    "The {} is not valid UTF-8, the store only holds UTF-8 text",
+                    what
+                ),
+            )
+        })
+    }
+}
+
+// Runs `op`, redrawing the `report` of its progress on stderr meanwhile if `show` and stderr
+// is a terminal, so nothing but the result ends up in logs and pipes.
+fn with_progress<T, R: fmt::Display>(
+    report: impl Fn() -> R + Send,
+    show: bool,
+    op: impl FnOnce() -> T,
+) -> T {
+    if !show || !io::stderr().is_terminal() {
+        return op();
+    }
+    let (done, finished) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        scope.spawn(move || loop {
+            let finished = finished.recv_timeout(PROGRESS_INTERVAL);
+            // `\r` and clearing the line overwrite the previous report
+            eprint!("\r\x1b[K{}", report());
+            if finished != Err(RecvTimeoutError::Timeout) {
+                eprintln!();
+                return;
+            }
+        });
+        let result = op();
+        drop(done);
+        result
+    })
+}
+
+// An error about the arguments, printed with the usage of the subcommand `name`.
+fn usage(name: &str, message: impl fmt::Display) -> failure::Error {
+    let mut cli = Cli::command();
+    // names the subcommand `kvs name` in its usage
+    cli.build();
+    let command = cli
+        .find_subcommand_mut(name)
+        .expect("usage errors are for existing subcommands");
+    command.error(ErrorKind::ValueValidation, message).into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn fsck(repair: bool, json: bool) -> ! {
+    let report = match current_dir() {
+        Ok(dir) => kvs::KvStore::fsck(dir, repair),
+        Err(e) => Err(e.into()),
+    };
+    match report {
+        Ok(report) => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                println!("{}", report);
+            }
+            process::exit(report.status.exit_code())
+        }
+        Err(e) => {
+            eprintln!("fsck failed: {}", e);
+            process::exit(4)
+        }
     }
 }
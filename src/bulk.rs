@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use crate::{codec, CommandPos, Commands, KvStore, Result, Store};
+
+impl Store {
+    pub(crate) fn bulk_load<I>(&mut self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let pairs: BTreeMap<String, String> = pairs.into_iter().collect();
+        if let Some(origin) = &self.backing_store {
+            for (key, value) in &pairs {
+                origin.store(key, value)?;
+            }
+        }
+
+        // at most what the marker of a batch takes
+        let marker_len = codec::encoded_len(&codec::to_vec(&Commands::Batch { count: u64::MAX })?);
+        let mut records = Vec::with_capacity(pairs.len());
+        // the records of the segment being filled, written as one batch once it is full
+        let mut batch = Vec::new();
+        let mut batch_size = marker_len;
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            let cmd = self.set_record(key, value, self.seq + i as u64);
+            let bytes = codec::to_vec(&cmd)?;
+            let len = codec::encoded_len(&bytes);
+            let started = !batch.is_empty() || self.writer.pos > codec::HEADER_LEN;
+            if started && self.writer.pos + batch_size + len > self.segment_size {
+                self.append_batch(std::mem::take(&mut batch), &mut records)?;
+                batch_size = marker_len;
+                self.seal_segment()?;
+            }
+            batch_size += len;
+            batch.push((cmd, bytes));
+        }
+        self.append_batch(batch, &mut records)?;
+        self.flush_log()?;
+
+        let loaded = records.len();
+        self.apply_records(records);
+        self.after_write()?;
+        Ok(loaded)
+    }
+
+    // Appends `batch` behind a marker, so it is replayed whole or not at all.
+    fn append_batch(
+        &mut self,
+        batch: Vec<(Commands, Vec<u8>)>,
+        records: &mut Vec<(Commands, CommandPos)>,
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.append_record(&Commands::Batch {
+            count: batch.len() as u64,
+        })?;
+        for (cmd, bytes) in batch {
+            let cmd_pos = self.append_encoded(&bytes)?;
+            records.push((cmd, cmd_pos));
+        }
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Sets all `pairs` at once, much faster than setting them one by one.
+    ///
+    /// The pairs are buffered in memory and written in key order, with a single flush at
+    /// the end. Later pairs win over earlier ones with the same key. Key limits and the
+    /// automatic compaction are only checked once everything is written.
+    ///
+    /// The pairs are split into segments of `OpenOptions::segment_size`, each share
+    /// written as one batch, see `KvStore::write_batch`. A load that fits the current
+    /// segment survives a crash whole or not at all; a larger one may keep the pairs of
+    /// the segments it filled, the first keys in order.
+    ///
+    /// Returns the number of distinct keys set.
+    pub fn bulk_load<I>(&self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.write().bulk_load(pairs)
+    }
+}
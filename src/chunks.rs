@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use failure::format_err;
+
+use crate::{
+    codec, reads::ReadState, segment::LogReader, CommandPos, Commands, IndexEntry, KvStore, Result,
+    Store, Value, ValueKind, Version,
+};
+
+impl Store {
+    // Sets `key` to a `value` larger than the chunk size, as `Chunk` records followed by
+    // the `Chunked` record listing them.
+    pub(crate) fn set_chunked(&mut self, key: String, value: String) -> Result<Version> {
+        let seq = self.seq;
+        let mut records = Vec::new();
+        let mut sizes = Vec::new();
+        for data in split(&value, self.chunk_size) {
+            let chunk = Commands::Chunk {
+                data: data.to_owned(),
+            };
+            let cmd_pos = self.append_record(&chunk)?;
+            records.push(cmd_pos.len);
+            sizes.push(data.len() as u64);
+        }
+        let manifest = Commands::Chunked {
+            key,
+            records,
+            sizes,
+            seq,
+        };
+        let cmd_pos = self.append_record(&manifest)?;
+        self.flush_log()?;
+        // the chunks are only indexed through the manifest
+        self.apply_record(manifest, cmd_pos);
+        self.after_write()?;
+        Ok(Version(seq))
+    }
+}
+
+impl ReadState {
+    pub(crate) fn get_range(
+        self: &Arc<Self>,
+        key: &str,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let (mut found, _pins) = self.look_up(&[key], ValueKind::String)?;
+        let Some(found) = found.pop().flatten() else {
+            return Ok(None);
+        };
+        let entry = &found.entry;
+        // only the chunks overlapping the range are read
+        if entry.blob.is_none() && !entry.deltas.is_empty() {
+            return self
+                .with_reader(|reader| read_range(reader, key, entry, offset, len))
+                .map(Some);
+        }
+        let Value::String(value) = self.with_reader(|reader| found.read(reader))? else {
+            unreachable!("looked up as a string");
+        };
+        Ok(Some(slice(value.as_bytes(), offset, len).to_vec()))
+    }
+}
+
+// Reads the part of the chunked value of `key` in the range, from the chunks overlapping it.
+fn read_range(
+    reader: &mut LogReader,
+    key: &str,
+    entry: &IndexEntry,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let Commands::Chunked { sizes, .. } = read_record(reader, &entry.base)? else {
+        return Err(format_err!(
+            "Key {} points at a record that is no manifest",
+            key
+        ));
+    };
+    let end = offset.saturating_add(len);
+    let mut range = Vec::new();
+    let mut start = 0;
+    for (size, cmd_pos) in sizes.into_iter().zip(&entry.deltas) {
+        if start < end && offset < start + size {
+            let Commands::Chunk { data } = read_record(reader, cmd_pos)? else {
+                return Err(format_err!(
+                    "Manifest of {} lists a record that is no chunk",
+                    key
+                ));
+            };
+            range.extend_from_slice(slice(
+                data.as_bytes(),
+                offset.saturating_sub(start),
+                end - offset.max(start),
+            ));
+        }
+        start += size;
+    }
+    Ok(range)
+}
+
+impl KvStore {
+    /// Returns up to `len` bytes of the value of `key`, starting at byte `offset`.
+    ///
+    /// Only the chunks overlapping the range are read for chunked values, see
+    /// `OpenOptions::chunk_values`. The range may split a character, which is why bytes
+    /// are returned. It is cut short at the end of the value.
+    pub fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        match self.reads.get_range(&key, offset, len)? {
+            // caching a value fetched from the origin writes to the store
+            None if self.reads.has_origin => Ok(self
+                .get(key)?
+                .map(|value| slice(value.as_bytes(), offset, len).to_vec())),
+            range => Ok(range),
+        }
+    }
+}
+
+fn read_record(reader: &mut LogReader, cmd_pos: &CommandPos) -> Result<Commands> {
+    codec::read_record(&mut reader.record(cmd_pos)?)
+}
+
+pub(crate) fn slice(bytes: &[u8], offset: u64, len: u64) -> &[u8] {
+    let start = (offset.min(bytes.len() as u64)) as usize;
+    let end = (offset.saturating_add(len).min(bytes.len() as u64)) as usize;
+    &bytes[start..end]
+}
+
+// Splits `value` into pieces of at most `chunk_size` bytes, or a single character if it
+// is larger.
+fn split(value: &str, chunk_size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let mut end = chunk_size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
@@ -0,0 +1,192 @@
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+    ops::Bound,
+    vec,
+};
+
+use failure::format_err;
+
+use crate::{
+    protocol::{read_response, Compression, ErrorCode, Request, Response},
+    CompactionProgress, CompressionStats, KvsEngine, KvsError, Pairs, Result,
+};
+
+/// A connection to a `KvsServer`.
+///
+/// It implements `KvsEngine`, so it can stand in for a local store.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    // how the server compresses its responses
+    compression: Option<Compression>,
+    stats: CompressionStats,
+}
+
+impl KvsClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            compression: None,
+            stats: CompressionStats::default(),
+        })
+    }
+
+    /// Proves to a server requiring authentication that this client is `user`, failing
+    /// with `KvsError::Unauthorized` if the server refuses `secret`.
+    pub fn authenticate(&mut self, user: &str, secret: &str) -> Result<()> {
+        let request = Request::Auth {
+            user: user.to_owned(),
+            secret: secret.to_owned(),
+        };
+        match self.request(request)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Asks the server to compress its large responses on this connection with the first
+    /// of `offered` it supports, and returns the compression agreed on.
+    ///
+    /// Offering nothing turns compression off again.
+    pub fn compress(&mut self, offered: &[Compression]) -> Result<Option<Compression>> {
+        let request = Request::Compress {
+            offered: offered.to_vec(),
+        };
+        match self.request(request)? {
+            Response::Compression(agreed) => {
+                self.compression = agreed;
+                Ok(agreed)
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Counts the responses the client decompressed.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.stats.clone()
+    }
+
+    fn request(&mut self, request: Request) -> Result<Response> {
+        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.flush()?;
+        match read_response(&mut self.reader, self.compression, &self.stats)? {
+            Response::Err { code, message } => Err(match code {
+                ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+                ErrorCode::Unauthorized => KvsError::Unauthorized,
+                code => KvsError::Server { code, message },
+            }
+            .into()),
+            response => Ok(response),
+        }
+    }
+}
+
+impl KvsEngine for KvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(Request::Set { key, value })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn get_range(&mut self, key: String, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        match self.request(Request::GetRange { key, offset, len })? {
+            Response::Bytes(bytes) => Ok(bytes),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(Request::Rm { key })? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Fetches the pairs from the server in batches as the iterator advances. Any other
+    /// request on the client ends the scan.
+    fn scan(&mut self, start: Bound<String>, end: Bound<String>) -> Result<Pairs<'_>> {
+        let mut scan = RemoteScan {
+            client: self,
+            batch: Vec::new().into_iter(),
+            more: true,
+        };
+        scan.fetch(Request::Scan { start, end })?;
+        Ok(Box::new(scan))
+    }
+
+    fn compaction_progress(&mut self) -> Result<CompactionProgress> {
+        match self.request(Request::CompactionProgress)? {
+            Response::CompactionProgress(progress) => Ok(progress),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn cancel_compaction(&mut self) -> Result<()> {
+        match self.request(Request::CancelCompaction)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn hot_keys(&mut self, n: usize) -> Result<Vec<(String, u64)>> {
+        match self.request(Request::HotKeys { n })? {
+            Response::HotKeys(keys) => Ok(keys),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+// The pairs of a scan on the server, the ones of the last batch that was fetched first.
+struct RemoteScan<'a> {
+    client: &'a mut KvsClient,
+    batch: vec::IntoIter<(String, String)>,
+    // whether the server has more batches
+    more: bool,
+}
+
+impl RemoteScan<'_> {
+    fn fetch(&mut self, request: Request) -> Result<()> {
+        self.more = false;
+        match self.client.request(request)? {
+            Response::Pairs { pairs, more } => {
+                self.batch = pairs.into_iter();
+                self.more = more;
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+impl Iterator for RemoteScan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.batch.next() {
+                return Some(Ok(pair));
+            }
+            if !self.more {
+                return None;
+            }
+            if let Err(e) = self.fetch(Request::ScanNext) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn unexpected(response: Response) -> failure::Error {
+    format_err!("Unexpected response from the server: {:?}", response)
+}
@@ -0,0 +1,56 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The source of time of a store, see `OpenOptions::clock`.
+///
+/// Drives compaction windows, write rate limits and the timestamps in the metadata.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the time elapsed since the Unix epoch.
+    fn now(&self) -> Duration;
+}
+
+/// The system clock, used unless another clock is configured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl SimClock {
+    /// Creates a clock showing `now`, counted from the Unix epoch.
+    pub fn new(now: Duration) -> SimClock {
+        SimClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: Duration) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{reads::ReadState, Commands, KvStore, KvsError, Result, Store};
+
+/// The kind of value stored under a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueKind {
+    String,
+    List,
+    Set,
+    Hash,
+}
+
+// A value rebuilt from the log. Lists, sets and hashes are written as deltas (pushes,
+// adds, removes and field updates) and only folded into a single record by compaction.
+pub(crate) enum Value {
+    String(String),
+    List(VecDeque<String>),
+    Set(BTreeSet<String>),
+    Hash(BTreeMap<String, String>),
+}
+
+impl Value {
+    pub(crate) fn empty(kind: ValueKind) -> Value {
+        match kind {
+            ValueKind::String => Value::String(String::new()),
+            ValueKind::List => Value::List(VecDeque::new()),
+            ValueKind::Set => Value::Set(BTreeSet::new()),
+            ValueKind::Hash => Value::Hash(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn apply(&mut self, cmd: Commands) {
+        match (self, cmd) {
+            (value, Commands::Set { value: s, .. } | Commands::Expiring { value: s, .. }) => {
+                *value = Value::String(s)
+            }
+            (value, Commands::List { items, .. }) => *value = Value::List(items.into()),
+            (value, Commands::SetMembers { members, .. }) => {
+                *value = Value::Set(members.into_iter().collect())
+            }
+            (value, Commands::Fields { fields, .. }) => {
+                *value = Value::Hash(fields.into_iter().collect())
+            }
+            (Value::List(list), Commands::Push { values, front, .. }) => {
+                if front {
+                    for value in values {
+                        list.push_front(value);
+                    }
+                } else {
+                    list.extend(values);
+                }
+            }
+            (Value::Set(set), Commands::SAdd { members, .. }) => set.extend(members),
+            (Value::Set(set), Commands::SRem { members, .. }) => {
+                for member in &members {
+                    set.remove(member);
+                }
+            }
+            (Value::Hash(hash), Commands::HSet { field, value, .. }) => {
+                hash.insert(field, value);
+            }
+            (Value::String(value), Commands::Chunk { data }) => value.push_str(&data),
+            (Value::Hash(hash), Commands::HDel { fields, .. }) => {
+                for field in &fields {
+                    hash.remove(field);
+                }
+            }
+            // the index never chains records of other kinds or removals to a value
+            _ => {}
+        }
+    }
+
+    pub(crate) fn into_record(self, key: String, seq: u64) -> Commands {
+        match self {
+            Value::String(value) => Commands::Set { key, value, seq },
+            Value::List(items) => Commands::List {
+                key,
+                items: items.into(),
+                seq,
+            },
+            Value::Set(members) => Commands::SetMembers {
+                key,
+                members: members.into_iter().collect(),
+                seq,
+            },
+            Value::Hash(fields) => Commands::Fields {
+                key,
+                fields: fields.into_iter().collect(),
+                seq,
+            },
+        }
+    }
+}
+
+impl Store {
+    pub(crate) fn lpush<I>(&mut self, key: String, values: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.push(key, values.into_iter().collect(), true)
+    }
+
+    pub(crate) fn rpush<I>(&mut self, key: String, values: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.push(key, values.into_iter().collect(), false)
+    }
+
+    fn push(&mut self, key: String, values: Vec<String>, front: bool) -> Result<()> {
+        self.check_kind(&key, ValueKind::List)?;
+        if values.is_empty() {
+            return Ok(());
+        }
+        let seq = self.seq;
+        self.write_record(Commands::Push {
+            key,
+            values,
+            front,
+            seq,
+        })
+    }
+
+    pub(crate) fn sadd<I>(&mut self, key: String, members: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.check_kind(&key, ValueKind::Set)?;
+        let members: Vec<String> = members.into_iter().collect();
+        if members.is_empty() {
+            return Ok(());
+        }
+        let seq = self.seq;
+        self.write_record(Commands::SAdd { key, members, seq })
+    }
+
+    pub(crate) fn srem<I>(&mut self, key: String, members: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.check_kind(&key, ValueKind::Set)?;
+        let members: Vec<String> = members.into_iter().collect();
+        if members.is_empty() || self.entry(&key).is_none() {
+            return Ok(());
+        }
+        let seq = self.seq;
+        self.write_record(Commands::SRem { key, members, seq })
+    }
+
+    pub(crate) fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.check_kind(&key, ValueKind::Hash)?;
+        let seq = self.seq;
+        self.write_record(Commands::HSet {
+            key,
+            field,
+            value,
+            seq,
+        })
+    }
+
+    pub(crate) fn hdel<I>(&mut self, key: String, fields: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.check_kind(&key, ValueKind::Hash)?;
+        let fields: Vec<String> = fields.into_iter().collect();
+        if fields.is_empty() || self.entry(&key).is_none() {
+            return Ok(());
+        }
+        let seq = self.seq;
+        self.write_record(Commands::HDel { key, fields, seq })
+    }
+
+    fn check_kind(&self, key: &str, kind: ValueKind) -> Result<()> {
+        match self.kind(key) {
+            Some(found) if found != kind => Err(KvsError::WrongType(key.to_owned()).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ReadState {
+    pub(crate) fn lrange(
+        self: &Arc<Self>,
+        key: String,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<String>> {
+        let list = match self.read_value(&key, ValueKind::List)? {
+            Some((Value::List(list), _)) => list,
+            _ => return Ok(Vec::new()),
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        Ok(list
+            .into_iter()
+            .skip(start as usize)
+            .take((stop - start + 1) as usize)
+            .collect())
+    }
+
+    pub(crate) fn llen(self: &Arc<Self>, key: String) -> Result<usize> {
+        match self.read_value(&key, ValueKind::List)? {
+            Some((Value::List(list), _)) => Ok(list.len()),
+            _ => Ok(0),
+        }
+    }
+
+    pub(crate) fn smembers(self: &Arc<Self>, key: String) -> Result<BTreeSet<String>> {
+        match self.read_value(&key, ValueKind::Set)? {
+            Some((Value::Set(set), _)) => Ok(set),
+            _ => Ok(BTreeSet::new()),
+        }
+    }
+
+    pub(crate) fn hget(self: &Arc<Self>, key: String, field: String) -> Result<Option<String>> {
+        match self.read_value(&key, ValueKind::Hash)? {
+            Some((Value::Hash(mut hash), _)) => Ok(hash.remove(&field)),
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn hgetall(self: &Arc<Self>, key: String) -> Result<BTreeMap<String, String>> {
+        match self.read_value(&key, ValueKind::Hash)? {
+            Some((Value::Hash(hash), _)) => Ok(hash),
+            _ => Ok(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvStore {
+    /// Pushes `values` to the front of the list at `key`, one after the other, creating it
+    /// if needed. Like Redis' `LPUSH`, the last value ends up first.
+    pub fn lpush<I>(&self, key: String, values: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.write().lpush(key, values)
+    }
+
+    /// Appends `values` to the list at `key`, creating it if needed.
+    pub fn rpush<I>(&self, key: String, values: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.write().rpush(key, values)
+    }
+
+    /// Returns the items of the list at `key` between `start` and `stop`, both inclusive.
+    ///
+    /// Negative indices count from the end of the list, so `lrange(key, 0, -1)` returns
+    /// the whole list. A missing key is an empty list.
+    pub fn lrange(&self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.reads.lrange(key, start, stop)
+    }
+
+    pub fn llen(&self, key: String) -> Result<usize> {
+        self.reads.llen(key)
+    }
+
+    /// Adds `members` to the set at `key`, creating it if needed.
+    pub fn sadd<I>(&self, key: String, members: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.write().sadd(key, members)
+    }
+
+    /// Removes `members` from the set at `key`.
+    ///
+    /// A set stays in the store, possibly empty, until the key itself is removed.
+    pub fn srem<I>(&self, key: String, members: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.write().srem(key, members)
+    }
+
+    /// Returns the members of the set at `key`. A missing key is an empty set.
+    pub fn smembers(&self, key: String) -> Result<BTreeSet<String>> {
+        self.reads.smembers(key)
+    }
+
+    /// Sets `field` of the hash at `key` to `value`, creating the hash if needed.
+    ///
+    /// Only the field is written to the log, not the whole hash.
+    pub fn hset(&self, key: String, field: String, value: String) -> Result<()> {
+        self.write().hset(key, field, value)
+    }
+
+    pub fn hget(&self, key: String, field: String) -> Result<Option<String>> {
+        self.reads.hget(key, field)
+    }
+
+    /// Removes `fields` from the hash at `key`.
+    ///
+    /// Like sets, a hash stays in the store, possibly empty, until the key is removed.
+    pub fn hdel<I>(&self, key: String, fields: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.write().hdel(key, fields)
+    }
+
+    /// Returns all fields of the hash at `key`. A missing key is an empty hash.
+    pub fn hgetall(&self, key: String) -> Result<BTreeMap<String, String>> {
+        self.reads.hgetall(key)
+    }
+}
@@ -0,0 +1,670 @@
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use failure::{format_err, Error};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec, copy_record,
+    progress::{format_bytes, format_secs},
+    replay_entry,
+    segment::{segment_gens, segment_path, LogReader},
+    segment_writer, unpoisoned, BufWriterWithPos, CommandPos, CompressionStats, IndexEntry,
+    KvStore, Result, Storage, StorageFile, Store, ValueKind, COMPACT_FILE_NAME,
+};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily time range, in UTC, during which automatic compaction may run.
+///
+/// The range may wrap around midnight, e.g. `22:00-04:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    // minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+impl CompactionWindow {
+    pub fn new(start_hour: u32, start_minute: u32, end_hour: u32, end_minute: u32) -> Result<Self> {
+        Ok(CompactionWindow {
+            start: minutes(start_hour, start_minute)?,
+            end: minutes(end_hour, end_minute)?,
+        })
+    }
+
+    /// Whether `minute`, counted from midnight, falls in the window.
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn minutes(hour: u32, minute: u32) -> Result<u32> {
+    if hour > 23 || minute > 59 {
+        return Err(format_err!("Invalid time of day {:02}:{:02}", hour, minute));
+    }
+    Ok(hour * 60 + minute)
+}
+
+impl FromStr for CompactionWindow {
+    type Err = Error;
+
+    /// Parses windows written as `HH:MM-HH:MM`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format_err!("Invalid compaction window {:?}, expected HH:MM-HH:MM", s);
+        let parse_time = |time: &str| -> Result<u32> {
+            let (hour, minute) = time.trim().split_once(':').ok_or_else(invalid)?;
+            minutes(
+                hour.parse().map_err(|_| invalid())?,
+                minute.parse().map_err(|_| invalid())?,
+            )
+        };
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(CompactionWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+impl fmt::Display for CompactionWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// A snapshot of the progress of a compaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactionProgress {
+    pub running: bool,
+    /// Live bytes copied so far by the running, or last, compaction.
+    pub bytes_processed: u64,
+    /// Live bytes the running, or last, compaction has to copy.
+    pub bytes_total: u64,
+    /// Estimated time left, extrapolated from the throughput so far.
+    pub eta: Option<Duration>,
+}
+
+impl fmt::Display for CompactionProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} live bytes compacted",
+            format_bytes(self.bytes_processed),
+            format_bytes(self.bytes_total)
+        )?;
+        if let Some(eta) = self.eta {
+            write!(f, ", ETA {}", format_secs(eta))?;
+        }
+        Ok(())
+    }
+}
+
+/// How much of the log of a store is live data, see `KvStore::space_usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceUsage {
+    /// Bytes of the records the index still refers to.
+    pub live_bytes: u64,
+    /// Bytes of the whole log.
+    pub disk_bytes: u64,
+}
+
+impl SpaceUsage {
+    /// The ratio of disk to live bytes: 1 right after a compaction, infinite for a log
+    /// that holds nothing but stale records.
+    pub fn amplification(&self) -> f64 {
+        if self.disk_bytes == 0 {
+            return 1.0;
+        }
+        self.disk_bytes as f64 / self.live_bytes as f64
+    }
+}
+
+impl fmt::Display for SpaceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "live_bytes: {}", self.live_bytes)?;
+        writeln!(f, "disk_bytes: {}", self.disk_bytes)?;
+        write!(f, "space_amplification: {:.2}", self.amplification())
+    }
+}
+
+/// How much writes waited for compactions to catch up, see `OpenOptions::write_stall`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStalls {
+    /// Writes that waited.
+    pub count: u64,
+    /// Time all of them waited.
+    pub total: Duration,
+    pub longest: Duration,
+}
+
+impl WriteStalls {
+    pub(crate) fn record(&mut self, stalled: Duration) {
+        self.count += 1;
+        self.total += stalled;
+        self.longest = self.longest.max(stalled);
+    }
+}
+
+impl fmt::Display for WriteStalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "write_stalls: {}", self.count)?;
+        writeln!(f, "write_stall_time: {:?}", self.total)?;
+        write!(f, "longest_write_stall: {:?}", self.longest)
+    }
+}
+
+/// Beyond which writes wait for the running compaction, see `OpenOptions::write_stall`.
+#[derive(Debug, Clone)]
+pub(crate) struct StallLimits {
+    pub(crate) amplification: f64,
+    pub(crate) stale_bytes: u64,
+    // the longest a single write waits
+    pub(crate) max_stall: Duration,
+}
+
+impl StallLimits {
+    pub(crate) fn exceeded(&self, usage: SpaceUsage) -> bool {
+        usage.amplification() > self.amplification
+            || usage.disk_bytes - usage.live_bytes > self.stale_bytes
+    }
+}
+
+/// Follows and cancels the compactions of a store, see `KvStore::compaction_handle`.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionHandle {
+    shared: Arc<CompactionState>,
+}
+
+#[derive(Debug, Default)]
+struct CompactionState {
+    running: AtomicBool,
+    cancelled: AtomicBool,
+    processed: AtomicU64,
+    total: AtomicU64,
+    started: Mutex<Option<Instant>>,
+    // notified, with `started` locked, once a compaction finished
+    finished: Condvar,
+    // why the running, or last, compaction did not finish
+    failure: Mutex<Option<String>>,
+}
+
+impl CompactionHandle {
+    pub fn progress(&self) -> CompactionProgress {
+        let state = &self.shared;
+        let running = state.running.load(Ordering::SeqCst);
+        let processed = state.processed.load(Ordering::SeqCst);
+        let total = state.total.load(Ordering::SeqCst);
+        let started = *state.started.lock().unwrap();
+        let eta = match started {
+            Some(started) if running && processed > 0 => {
+                let elapsed = started.elapsed().as_secs_f64();
+                let left = total.saturating_sub(processed) as f64 / processed as f64;
+                Some(Duration::from_secs_f64(elapsed * left))
+            }
+            _ => None,
+        };
+        CompactionProgress {
+            running,
+            bytes_processed: processed,
+            bytes_total: total,
+            eta,
+        }
+    }
+
+    /// Blocks until the running compaction, if any, finished and the store uses its
+    /// output.
+    pub fn wait(&self) {
+        let state = &self.shared;
+        let mut started = state.started.lock().unwrap();
+        while state.running.load(Ordering::SeqCst) {
+            started = state.finished.wait(started).unwrap();
+        }
+    }
+
+    // Like `wait`, giving up after `timeout`.
+    pub(crate) fn wait_timeout(&self, timeout: Duration) {
+        let state = &self.shared;
+        let started = state.started.lock().unwrap();
+        let _ = state
+            .finished
+            .wait_timeout_while(started, timeout, |_| state.running.load(Ordering::SeqCst))
+            .unwrap();
+    }
+
+    /// Cancels the running compaction, if any.
+    ///
+    /// The partial output is discarded and the store keeps using its current log. The
+    /// next automatic compaction waits until the stale data has doubled.
+    pub fn cancel(&self) {
+        if self.shared.running.load(Ordering::SeqCst) {
+            self.shared.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn start(&self, total: u64) -> CompactionRun {
+        let state = &self.shared;
+        *state.started.lock().unwrap() = Some(Instant::now());
+        state.processed.store(0, Ordering::SeqCst);
+        state.total.store(total, Ordering::SeqCst);
+        state.cancelled.store(false, Ordering::SeqCst);
+        *state.failure.lock().unwrap() = None;
+        state.running.store(true, Ordering::SeqCst);
+        CompactionRun {
+            handle: self.clone(),
+        }
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn advance(&self, bytes: u64) {
+        self.shared.processed.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn fail(&self, message: String) {
+        *self.shared.failure.lock().unwrap() = Some(message);
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.shared.failure.lock().unwrap().clone()
+    }
+}
+
+/// Marks the compaction as finished when dropped, however it ended.
+pub(crate) struct CompactionRun {
+    handle: CompactionHandle,
+}
+
+impl CompactionRun {
+    pub(crate) fn handle(&self) -> &CompactionHandle {
+        &self.handle
+    }
+}
+
+impl Drop for CompactionRun {
+    fn drop(&mut self) {
+        let state = &self.handle.shared;
+        state.running.store(false, Ordering::SeqCst);
+        state.cancelled.store(false, Ordering::SeqCst);
+        let _started = state.started.lock().unwrap();
+        state.finished.notify_all();
+    }
+}
+
+/// When automatic compaction is allowed to run once enough stale data accumulated.
+///
+/// With neither a window nor a write rate limit, it runs as soon as the target amplification
+/// is exceeded.
+/// Otherwise it runs when any of the configured conditions holds.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompactionSchedule {
+    pub(crate) window: Option<CompactionWindow>,
+    pub(crate) max_write_rate: Option<f64>,
+}
+
+impl CompactionSchedule {
+    /// Whether a compaction may run at `now`, counted from the Unix epoch.
+    pub(crate) fn allows(&self, rate: &WriteRate, now: Duration) -> bool {
+        if self.window.is_none() && self.max_write_rate.is_none() {
+            return true;
+        }
+        let in_window = self.window.is_some_and(|window| {
+            window.contains((now.as_secs() / 60 % MINUTES_PER_DAY as u64) as u32)
+        });
+        let quiet = self
+            .max_write_rate
+            .is_some_and(|max_rate| rate.per_second(now) <= max_rate);
+        in_window || quiet
+    }
+}
+
+/// Estimates the number of writes per second over a sliding one-second window.
+#[derive(Default)]
+pub(crate) struct WriteRate {
+    // the current one-second bucket, counted from the Unix epoch, and the counts for it
+    // and the one before it
+    bucket: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl WriteRate {
+    pub(crate) fn record(&mut self, now: Duration) {
+        let bucket = now.as_secs();
+        if bucket != self.bucket {
+            self.previous = if bucket == self.bucket + 1 {
+                self.current
+            } else {
+                0
+            };
+            self.current = 0;
+            self.bucket = bucket;
+        }
+        self.current += 1;
+    }
+
+    pub(crate) fn per_second(&self, now: Duration) -> f64 {
+        let (current, previous) = match now.as_secs().checked_sub(self.bucket) {
+            Some(0) => (self.current, self.previous),
+            Some(1) => (0, self.current),
+            _ => (0, 0),
+        };
+        // weigh the previous second by how much of it is still inside the window
+        let fract = now.subsec_nanos() as f64 / 1e9;
+        previous as f64 * (1.0 - fract) + current as f64
+    }
+}
+
+impl Store {
+    // Seals the current segment and compacts the sealed segments on a background thread.
+    // The compacted records take the generation after the current one, so writes go on in
+    // the generation after that and replay after them.
+    pub(crate) fn start_compaction(&mut self) -> Result<()> {
+        let Some(store) = self.this.upgrade() else {
+            return Ok(());
+        };
+        // expired values are not copied
+        self.purge_expired();
+        let total = self.index().iter().map(|(_, entry)| entry.len()).sum();
+        let run = self.compaction.start(total);
+        let gen = self.gen + 1;
+        self.writer.flush()?;
+        let writer = self.storage.create(&segment_path(&self.dir, gen + 1))?;
+        self.sealed_size += self.writer.pos;
+        self.writer = segment_writer(writer)?;
+        self.gen = gen + 1;
+
+        let job = CompactionJob {
+            storage: self.storage.clone(),
+            dir: self.dir.clone(),
+            gen,
+            sealed_size: self.sealed_size,
+            blobs: self
+                .blobs()
+                .iter()
+                .map(|(hash, cmd_pos)| (hash, cmd_pos.clone()))
+                .collect(),
+            entries: self
+                .index()
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+        };
+        let compactor = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                let result = job.run(run.handle());
+                unpoisoned(store.write()).finish_compaction(result);
+                // the store is released before the compaction counts as finished
+                drop(store);
+                drop(run);
+            })?;
+        self.compactor = Some(compactor);
+        Ok(())
+    }
+
+    fn finish_compaction(&mut self, result: CompactionResult) {
+        let compacted = match result {
+            CompactionResult::Done(compacted) => compacted,
+            CompactionResult::Cancelled => {
+                // wait for twice as much stale data before trying again
+                self.compact_after = self.stale_size * 2;
+                self.compaction
+                    .fail("The compaction was cancelled".to_owned());
+                return;
+            }
+            CompactionResult::Failed(e) => {
+                error!("Compaction of {} failed: {}", self.dir.display(), e);
+                self.compact_after = self.stale_size * 2;
+                self.compaction
+                    .fail(format!("The compaction failed: {}", e));
+                return;
+            }
+        };
+        if let Err(e) = self.swap_in(compacted) {
+            error!(
+                "Failed to replace the compacted segments of {}: {}",
+                self.dir.display(),
+                e
+            );
+            self.compaction
+                .fail(format!("Failed to replace the compacted segments: {}", e));
+        }
+    }
+
+    // Points the index at the compacted records and retires the segments they replace,
+    // which are deleted once the reads and views still using them are done.
+    fn swap_in(&mut self, compacted: Compacted) -> Result<()> {
+        {
+            let mut index = self.index_mut();
+            for moved in compacted.entries {
+                let Some(entry) = index.get_mut(&moved.key) else {
+                    continue;
+                };
+                // keys written since keep their newer records, deltas added since apply on
+                // top of the compacted value
+                if entry.base != moved.old_base {
+                    continue;
+                }
+                let newer = entry.deltas.split_off(moved.old_deltas);
+                entry.base = moved.base;
+                entry.deltas = moved.deltas;
+                entry.deltas.extend(newer);
+            }
+            self.blobs_mut().move_blobs(compacted.blobs);
+        }
+
+        self.sealed_size = self.sealed_size - compacted.sealed_size + compacted.size;
+        let gens = segment_gens(&*self.storage, &self.dir)?;
+        let kept = gens.iter().filter(|&&gen| gen >= compacted.gen).count() as u64;
+        let live_size = self
+            .index()
+            .iter()
+            .map(|(_, entry)| entry.len())
+            .sum::<u64>()
+            + self.blobs().size()
+            + kept * codec::HEADER_LEN;
+        self.stale_size = self.sealed_size + self.writer.pos - live_size;
+        self.compact_after = 0;
+
+        // saved before the records of older versions are deleted
+        self.info.last_compaction = Some(self.clock.now().as_secs());
+        self.info.high_water_seq = Some(self.seq);
+        self.info.save(&*self.storage, &self.dir, false)?;
+        self.reads
+            .retire(gens.into_iter().filter(|&gen| gen < compacted.gen));
+        self.save_hint()
+    }
+}
+
+impl KvStore {
+    /// Compacts the store now, whatever its amplification, and returns once the compacted
+    /// segment replaced the ones it compacted, such as before a backup or after removing
+    /// many keys.
+    ///
+    /// A compaction that is already running is waited for first, since it may not cover
+    /// the latest writes. Reads and writes go on meanwhile, as for automatic compactions,
+    /// which ignore the compaction window and write rate limit likewise. Fails if the
+    /// compaction fails or is cancelled through `KvStore::compaction_handle`.
+    pub fn compact_now(&self) -> Result<()> {
+        let handle = self.compaction_handle();
+        loop {
+            handle.wait();
+            let mut store = self.write();
+            store.check_writable()?;
+            // an automatic compaction may have started meanwhile
+            if !handle.is_running() {
+                store.start_compaction()?;
+                break;
+            }
+        }
+        handle.wait();
+        match handle.failure() {
+            Some(message) => Err(format_err!("{}", message)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Copies the records of the value of `key` to `writer`, which writes the segment of
+// generation `gen`, returning the new positions of its base record and deltas.
+pub(crate) fn copy_entry(
+    reader: &mut LogReader,
+    writer: &mut BufWriterWithPos<Box<dyn StorageFile>>,
+    gen: u64,
+    key: &str,
+    entry: &IndexEntry,
+) -> Result<(CommandPos, Vec<CommandPos>)> {
+    let mut deltas = Vec::new();
+    let base = if entry.deltas.is_empty() {
+        copy_record(reader, writer, gen, &entry.base)?
+    } else if entry.kind == ValueKind::String {
+        // the chunks of a large value stay separate records, right before the manifest
+        for cmd_pos in &entry.deltas {
+            deltas.push(copy_record(reader, writer, gen, cmd_pos)?);
+        }
+        copy_record(reader, writer, gen, &entry.base)?
+    } else {
+        // fold the deltas into a single record holding the whole value
+        let value = replay_entry(reader, entry)?;
+        let cmd = value.into_record(key.to_owned(), entry.seq);
+        let pos = writer.pos;
+        let len = codec::write_record(writer, &cmd)?;
+        CommandPos { gen, pos, len }
+    };
+    Ok((base, deltas))
+}
+
+/// How a background compaction ended.
+enum CompactionResult {
+    Done(Compacted),
+    Cancelled,
+    Failed(Error),
+}
+
+/// The records a compaction rewrote into the segment of generation `gen`.
+struct Compacted {
+    gen: u64,
+    // bytes of the compacted segment, and of the segments it replaces
+    size: u64,
+    sealed_size: u64,
+    // each shared value with its old and new position
+    blobs: Vec<(u64, CommandPos, CommandPos)>,
+    entries: Vec<MovedEntry>,
+}
+
+struct MovedEntry {
+    key: String,
+    // the base record and number of deltas the compaction read
+    old_base: CommandPos,
+    old_deltas: usize,
+    base: CommandPos,
+    deltas: Vec<CommandPos>,
+}
+
+// A copy of what the index held when the compaction started, all in sealed segments which
+// no write touches.
+struct CompactionJob {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    gen: u64,
+    sealed_size: u64,
+    blobs: Vec<(u64, CommandPos)>,
+    entries: Vec<(String, IndexEntry)>,
+}
+
+impl CompactionJob {
+    fn run(self, handle: &CompactionHandle) -> CompactionResult {
+        let file = self.dir.join(COMPACT_FILE_NAME);
+        match self.compact(&file, handle) {
+            Ok(Some(compacted)) => CompactionResult::Done(compacted),
+            Ok(None) => match self.storage.remove(&file) {
+                Ok(()) => CompactionResult::Cancelled,
+                Err(e) => CompactionResult::Failed(e.into()),
+            },
+            Err(e) => {
+                // the file is discarded on the next open if this fails too
+                let _ = self.storage.remove(&file);
+                CompactionResult::Failed(e)
+            }
+        }
+    }
+
+    // Writes the live records to `file` and renames it to the compacted segment, unless
+    // the compaction is cancelled first.
+    fn compact(&self, file: &Path, handle: &CompactionHandle) -> Result<Option<Compacted>> {
+        let gen = self.gen;
+        // records are copied as they are, compressed values stay compressed
+        let mut reader = LogReader::new(
+            self.storage.clone(),
+            self.dir.clone(),
+            CompressionStats::default(),
+        );
+        let mut writer = segment_writer(self.storage.create(file)?)?;
+        // shared values go first, so they are read before the records referring to them
+        let mut blobs = Vec::new();
+        for (hash, cmd_pos) in &self.blobs {
+            let new_pos = copy_record(&mut reader, &mut writer, gen, cmd_pos)?;
+            blobs.push((*hash, cmd_pos.clone(), new_pos));
+        }
+        let mut entries = Vec::new();
+        for (key, entry) in &self.entries {
+            if handle.is_cancelled() {
+                return Ok(None);
+            }
+            let (base, deltas) = copy_entry(&mut reader, &mut writer, gen, key, entry)?;
+            entries.push(MovedEntry {
+                key: key.clone(),
+                old_base: entry.base.clone(),
+                old_deltas: entry.deltas.len(),
+                base,
+                deltas,
+            });
+            writer.flush()?;
+            handle.advance(entry.len());
+        }
+        writer.flush()?;
+        // the compacted records have to be durable before the rename publishes them, and
+        // the rename before the segments they replace are deleted
+        writer.writer.get_mut().sync()?;
+        let size = writer.pos;
+        drop(writer);
+        self.storage.rename(file, &segment_path(&self.dir, gen))?;
+        self.storage.sync_dir(&self.dir)?;
+        Ok(Some(Compacted {
+            gen,
+            size,
+            sealed_size: self.sealed_size,
+            blobs,
+            entries,
+        }))
+    }
+}
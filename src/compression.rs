@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use failure::format_err;
+use miniz_oxide::{deflate, inflate};
+
+use crate::{unpoisoned, Commands, Compression, KvStore, Result, Store};
+
+impl Compression {
+    fn compress(self, raw: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Deflate => deflate::compress_to_vec(raw, 6),
+        }
+    }
+
+    // Fails if the input is invalid or inflates beyond `limit` bytes.
+    fn decompress(self, compressed: &[u8], limit: usize) -> Result<Vec<u8>> {
+        match self {
+            Compression::Deflate => inflate::decompress_to_vec_with_limit(compressed, limit)
+                .map_err(|e| format_err!("{}", e)),
+        }
+    }
+}
+
+/// What compressing with one codec did so far, see `CompressionStats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodecStats {
+    /// Payloads compressed.
+    pub compressed: u64,
+    /// Payloads decompressed.
+    pub decompressed: u64,
+    /// Size of the payloads before compression.
+    pub raw_bytes: u64,
+    /// Size of the payloads after compression.
+    pub compressed_bytes: u64,
+    pub compress_time: Duration,
+    pub decompress_time: Duration,
+}
+
+impl CodecStats {
+    /// How many times smaller the payloads got, 1 before there were any.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.compressed_bytes as f64
+    }
+}
+
+/// Counts the payloads compressed and decompressed by each codec and the time spent on
+/// them, so its cost can be weighed against the bytes it saves. Clones share the counts.
+///
+/// Payloads too small to be compressed are not counted.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionStats {
+    codecs: Arc<Mutex<BTreeMap<Compression, CodecStats>>>,
+}
+
+impl CompressionStats {
+    /// The counts of `codec`, all zero if it was never used.
+    pub fn get(&self, codec: Compression) -> CodecStats {
+        let codecs = unpoisoned(self.codecs.lock());
+        codecs.get(&codec).copied().unwrap_or_default()
+    }
+
+    /// The counts of every codec used so far.
+    pub fn codecs(&self) -> Vec<(Compression, CodecStats)> {
+        let codecs = unpoisoned(self.codecs.lock());
+        codecs
+            .iter()
+            .map(|(&codec, &stats)| (codec, stats))
+            .collect()
+    }
+
+    pub(crate) fn compress(&self, codec: Compression, raw: &[u8]) -> Vec<u8> {
+        let started = Instant::now();
+        let compressed = codec.compress(raw);
+        let elapsed = started.elapsed();
+        let mut codecs = unpoisoned(self.codecs.lock());
+        let stats = codecs.entry(codec).or_default();
+        stats.compressed += 1;
+        stats.raw_bytes += raw.len() as u64;
+        stats.compressed_bytes += compressed.len() as u64;
+        stats.compress_time += elapsed;
+        compressed
+    }
+
+    // Fails if `compressed` is invalid or inflates beyond `limit` bytes.
+    pub(crate) fn decompress(
+        &self,
+        codec: Compression,
+        compressed: &[u8],
+        limit: usize,
+    ) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let raw = codec.decompress(compressed, limit)?;
+        let elapsed = started.elapsed();
+        let mut codecs = unpoisoned(self.codecs.lock());
+        let stats = codecs.entry(codec).or_default();
+        stats.decompressed += 1;
+        stats.raw_bytes += raw.len() as u64;
+        stats.compressed_bytes += compressed.len() as u64;
+        stats.decompress_time += elapsed;
+        Ok(raw)
+    }
+
+    pub(crate) fn decompress_value(&self, codec: Compression, data: &[u8]) -> Result<String> {
+        let raw = self
+            .decompress(codec, data, usize::MAX)
+            .map_err(|e| format_err!("Invalid compressed value: {}", e))?;
+        Ok(String::from_utf8(raw)?)
+    }
+}
+
+impl Store {
+    // The record setting `key` to `value`, compressed if it is large enough and gets
+    // smaller.
+    pub(crate) fn set_record(&self, key: String, value: String, seq: u64) -> Commands {
+        if let Some((codec, min_size)) = self.compression {
+            if value.len() >= min_size {
+                let data = self.compression_stats.compress(codec, value.as_bytes());
+                if data.len() < value.len() {
+                    return Commands::Compressed {
+                        key,
+                        codec,
+                        data,
+                        seq,
+                    };
+                }
+            }
+        }
+        Commands::Set { key, value, seq }
+    }
+}
+
+impl KvStore {
+    /// Counts the values compressed and decompressed since the store was opened, see
+    /// `OpenOptions::compress_values`.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.reads.compression_stats.clone()
+    }
+}
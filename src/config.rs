@@ -0,0 +1,205 @@
+use std::{fmt, fs, path::Path, path::PathBuf, time::Duration};
+
+use failure::format_err;
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{json, Value};
+
+use crate::{
+    toml,
+    units::{deserialize_duration, deserialize_size},
+    CompactionWindow, Engine, KvsEngine, OpenOptions, Result,
+};
+
+/// The settings of a `kvs-server`, usually read from a TOML file with `Config::load`.
+///
+/// Every field is optional, missing ones keep the defaults of the server and of
+/// `OpenOptions`. Sizes are numbers of bytes or strings for `parse_size` such as
+/// `"512MiB"`, durations strings for `parse_duration` such as `"250ms"`:
+///
+/// ```toml
+/// engine = "kvs"
+/// segment_size = "64MiB"
+/// max_write_stall = "250ms"
+/// sync_writes = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address to listen on.
+    pub addr: Option<String>,
+    /// The name of the engine, see `Engine`.
+    pub engine: Option<String>,
+    /// A file of `user:password` lines, see `Passwords::load`.
+    pub auth_file: Option<PathBuf>,
+    /// See `OpenOptions::segment_size`.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub segment_size: Option<u64>,
+    /// See `OpenOptions::max_write_stall`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_write_stall: Option<Duration>,
+    /// See `OpenOptions::persist_heat`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub persist_heat: Option<Duration>,
+    /// See `OpenOptions::sync_writes`.
+    pub sync_writes: Option<bool>,
+    /// See `OpenOptions::read_only`.
+    pub read_only: Option<bool>,
+    /// See `OpenOptions::compaction_window`, written like `"22:00-04:00"`.
+    #[serde(default, deserialize_with = "deserialize_window")]
+    pub compaction_window: Option<CompactionWindow>,
+    /// Writes per second, see `OpenOptions::compaction_max_write_rate`.
+    pub compaction_max_write_rate: Option<f64>,
+    /// See `OpenOptions::target_amplification`.
+    pub target_amplification: Option<f64>,
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file and validates it.
+    ///
+    /// Each line sets one field, like `segment_size = "64MiB"`, tables and arrays are not
+    /// supported. Fields the configuration does not know, values of the wrong type and
+    /// settings that contradict each other are all rejected, with the line they are on.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let invalid = |line: usize, message: &dyn fmt::Display| {
+            format_err!(
+                "Invalid configuration in {} at line {}: {}{}",
+                path.display(),
+                line,
+                message,
+                quoted_line(&text, line)
+            )
+        };
+        let entries = toml::parse(&text).map_err(|e| invalid(e.line, &e.message))?;
+        // each field on its own first, for the error to point at its line
+        for entry in &entries {
+            let field = json!({ entry.key.as_str(): entry.value });
+            serde_json::from_value::<Config>(field).map_err(|e| invalid(entry.line, &e))?;
+        }
+        let fields = entries
+            .iter()
+            .map(|entry| (entry.key.clone(), entry.value.clone()))
+            .collect();
+        let config: Config = serde_json::from_value(Value::Object(fields))?;
+        config.check().map_err(|(field, message)| {
+            match entries.iter().find(|entry| entry.key == field) {
+                Some(entry) => invalid(entry.line, &message),
+                None => format_err!("Invalid configuration in {}: {}", path.display(), message),
+            }
+        })?;
+        Ok(config)
+    }
+
+    /// Checks that the settings make sense together, such as after overriding some of
+    /// those loaded with command line arguments.
+    pub fn validate(&self) -> Result<()> {
+        self.check()
+            .map_err(|(_, message)| format_err!("Invalid configuration: {}", message))
+    }
+
+    // Returns the field that is wrong and what is wrong with it, if any.
+    fn check(&self) -> std::result::Result<(), (&'static str, String)> {
+        let engine = match &self.engine {
+            Some(name) => name
+                .parse::<Engine>()
+                .map_err(|e| ("engine", e.to_string()))?,
+            None => Engine::Kvs,
+        };
+        if self.segment_size == Some(0) {
+            return Err(("segment_size", "segment_size must be above 0".to_owned()));
+        }
+        if let Some(rate) = self.compaction_max_write_rate {
+            if rate <= 0.0 {
+                return Err((
+                    "compaction_max_write_rate",
+                    format!("compaction_max_write_rate must be above 0, not {}", rate),
+                ));
+            }
+        }
+        if let Some(target) = self.target_amplification {
+            if target < 1.0 {
+                return Err((
+                    "target_amplification",
+                    format!(
+                        "target_amplification must be at least 1, the log is never smaller \
+                         than the live data, not {}",
+                        target
+                    ),
+                ));
+            }
+        }
+        if self.read_only == Some(true) {
+            if engine == Engine::Mem {
+                return Err((
+                    "read_only",
+                    "read_only cannot be used with the mem engine, it would always be empty"
+                        .to_owned(),
+                ));
+            }
+            if self.sync_writes == Some(true) {
+                return Err((
+                    "read_only",
+                    "read_only and sync_writes contradict each other, a read-only store is \
+                     never written"
+                        .to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the options to open the store with.
+    pub fn open_options(&self) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        if let Some(segment_size) = self.segment_size {
+            options.segment_size(segment_size);
+        }
+        if let Some(max) = self.max_write_stall {
+            options.max_write_stall(max);
+        }
+        if let Some(interval) = self.persist_heat {
+            options.persist_heat(interval);
+        }
+        if let Some(window) = self.compaction_window {
+            options.compaction_window(window);
+        }
+        if let Some(rate) = self.compaction_max_write_rate {
+            options.compaction_max_write_rate(rate);
+        }
+        if let Some(target) = self.target_amplification {
+            options.target_amplification(target);
+        }
+        options.sync_writes(self.sync_writes.unwrap_or(false));
+        options.read_only(self.read_only.unwrap_or(false));
+        options
+    }
+
+    /// Opens the engine configured at `path`, after validating the configuration.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<Box<dyn KvsEngine + Send>> {
+        self.validate()?;
+        let engine = match &self.engine {
+            Some(name) => name.parse()?,
+            None => Engine::Kvs,
+        };
+        engine.open_with(path, &self.open_options())
+    }
+}
+
+// Deserializes a compaction window given as a string for `CompactionWindow::from_str`.
+fn deserialize_window<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<CompactionWindow>, D::Error> {
+    match Option::<String>::deserialize(d)? {
+        None => Ok(None),
+        Some(text) => text.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+// Returns line `line`, counted from 1, indented on a line of its own to show in an error.
+fn quoted_line(text: &str, line: usize) -> String {
+    match line.checked_sub(1).and_then(|i| text.lines().nth(i)) {
+        Some(line) if !line.trim().is_empty() => format!("\n    {}", line.trim()),
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::{codec, segment::LogReader, CommandPos, Commands, Result, Store, Version};
+
+/// Values shared by several keys, written once as `Commands::Blob` records and referred
+/// to by `Commands::SetRef` records, see `OpenOptions::dedup_values`.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Blobs {
+    blobs: HashMap<u64, Blob>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Blob {
+    pos: CommandPos,
+    // number of keys currently referring to the blob
+    refs: u64,
+}
+
+impl Blobs {
+    /// Records the blob written at `cmd_pos`, returning how many bytes became stale.
+    ///
+    /// A blob already known keeps its references and only moves, as when replaying a
+    /// compacted segment next to the segments it replaces.
+    pub(crate) fn insert(&mut self, hash: u64, cmd_pos: CommandPos) -> u64 {
+        match self.blobs.get_mut(&hash) {
+            Some(blob) => std::mem::replace(&mut blob.pos, cmd_pos).len,
+            None => {
+                self.blobs.insert(
+                    hash,
+                    Blob {
+                        pos: cmd_pos,
+                        refs: 0,
+                    },
+                );
+                0
+            }
+        }
+    }
+
+    pub(crate) fn contains(&self, hash: u64) -> bool {
+        self.blobs.contains_key(&hash)
+    }
+
+    pub(crate) fn acquire(&mut self, hash: u64) {
+        if let Some(blob) = self.blobs.get_mut(&hash) {
+            blob.refs += 1;
+        }
+    }
+
+    /// Drops a reference to the blob, returning how many bytes became stale.
+    pub(crate) fn release(&mut self, hash: u64) -> u64 {
+        match self.blobs.get_mut(&hash) {
+            Some(blob) if blob.refs <= 1 => self.blobs.remove(&hash).map_or(0, |blob| blob.pos.len),
+            Some(blob) => {
+                blob.refs -= 1;
+                0
+            }
+            None => 0,
+        }
+    }
+
+    /// Forgets the blobs nothing refers to, returning how many bytes became stale.
+    pub(crate) fn remove_unreferenced(&mut self) -> u64 {
+        let mut stale = 0;
+        self.blobs.retain(|_, blob| {
+            if blob.refs == 0 {
+                stale += blob.pos.len;
+            }
+            blob.refs > 0
+        });
+        stale
+    }
+
+    pub(crate) fn read(&self, reader: &mut LogReader, hash: u64) -> Result<String> {
+        read_blob(reader, hash, &self.pos(hash)?)
+    }
+
+    // Returns where the blob with `hash` was written.
+    pub(crate) fn pos(&self, hash: u64) -> Result<CommandPos> {
+        match self.blobs.get(&hash) {
+            Some(blob) => Ok(blob.pos.clone()),
+            None => Err(format_err!("Missing blob {:016x}", hash)),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, &CommandPos)> {
+        self.blobs.iter().map(|(hash, blob)| (*hash, &blob.pos))
+    }
+
+    /// Moves each blob from its old to its new position, unless it was written again
+    /// since.
+    pub(crate) fn move_blobs(&mut self, moves: Vec<(u64, CommandPos, CommandPos)>) {
+        for (hash, old_pos, new_pos) in moves {
+            if let Some(blob) = self.blobs.get_mut(&hash) {
+                if blob.pos == old_pos {
+                    blob.pos = new_pos;
+                }
+            }
+        }
+    }
+
+    /// Returns the bytes of the blob records.
+    pub(crate) fn size(&self) -> u64 {
+        self.blobs.values().map(|blob| blob.pos.len).sum()
+    }
+}
+
+/// A stable 64-bit FNV-1a hash, since the hashes are persisted in the log.
+pub(crate) fn content_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Store {
+    // Sets `key` to a large `value`, referring to an identical blob if there is one.
+    pub(crate) fn set_deduplicated(&mut self, key: String, value: String) -> Result<Version> {
+        let seq = self.seq;
+        let hash = content_hash(&value);
+        let mut records = Vec::new();
+        if self.blobs().contains(hash) {
+            if self.with_reader(|reader| self.blobs().read(reader, hash))? != value {
+                // a hash collision, keep the value out of the blobs
+                self.write_record(Commands::Set { key, value, seq })?;
+                return Ok(Version(seq));
+            }
+        } else {
+            let blob = Commands::Blob { hash, value };
+            let cmd_pos = self.append_record(&blob)?;
+            records.push((blob, cmd_pos));
+        }
+        let set_ref = Commands::SetRef { key, hash, seq };
+        let cmd_pos = self.append_record(&set_ref)?;
+        records.push((set_ref, cmd_pos));
+        self.flush_log()?;
+        // the blob and the reference are applied together, so a compaction never sees a
+        // blob nothing refers to yet
+        self.apply_records(records);
+        self.after_write()?;
+        Ok(Version(seq))
+    }
+}
+
+// Reads the value of the blob with `hash` written at `cmd_pos`.
+pub(crate) fn read_blob(reader: &mut LogReader, hash: u64, cmd_pos: &CommandPos) -> Result<String> {
+    match codec::read_record(&mut reader.record(cmd_pos)?)? {
+        Commands::Blob { value, .. } => Ok(value),
+        _ => Err(format_err!("Blob {:016x} points at another record", hash)),
+    }
+}
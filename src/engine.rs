@@ -0,0 +1,261 @@
+use std::{fmt, ops::Bound, path::PathBuf, str::FromStr};
+
+use failure::format_err;
+
+use crate::{
+    chunks::slice, CompactionProgress, KvStore, KvsError, MemStorage, OpenOptions, Result,
+};
+
+/// Key-value pairs returned by `KvsEngine::scan`.
+pub type Pairs<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// The basic operations every storage engine supports, so the command line tool and
+/// future servers can work with any of them.
+///
+/// The trait is object safe, `Box<dyn KvsEngine>` is an engine too, see `Engine::open`.
+pub trait KvsEngine {
+    /// Sets `key` to `value`, overwriting any previous value.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Returns the value of `key`, or `None` if it is not set.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Removes `key`, failing with `KvsError::KeyNotFound` if it is not set.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Returns up to `len` bytes of the value of `key` from byte `offset` on, or `None` if
+    /// it is not set, see `KvStore::get_range`. The default cuts them out of `get`.
+    fn get_range(&mut self, key: String, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .get(key)?
+            .map(|value| slice(value.as_bytes(), offset, len).to_vec()))
+    }
+
+    /// Sets `key` to `value` and returns the value it replaced, if any. The default reads
+    /// it with `get` first, engines that can should do both at once.
+    fn set_returning(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(old_value)
+    }
+
+    /// Removes `key` and returns the value it held, or `None` if it was not set, like
+    /// `set_returning`.
+    fn remove_returning(&mut self, key: String) -> Result<Option<String>> {
+        let old_value = self.get(key.clone())?;
+        if old_value.is_some() {
+            self.remove(key)?;
+        }
+        Ok(old_value)
+    }
+
+    /// Returns the string key-value pairs whose keys fall between `start` and `end`, in
+    /// ascending key order, reading them as the iterator advances.
+    fn scan(&mut self, start: Bound<String>, end: Bound<String>) -> Result<Pairs<'_>>;
+
+    /// Returns the string key-value pairs whose keys start with `prefix`, in ascending key
+    /// order, like `scan`.
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Pairs<'_>> {
+        self.scan(Bound::Included(prefix.to_owned()), prefix_end(prefix))
+    }
+
+    /// Returns another handle to the same engine, to be used from another thread, or
+    /// `None` if the engine cannot be shared, which is the default.
+    fn share(&self) -> Option<Box<dyn KvsEngine + Send>> {
+        None
+    }
+
+    /// Returns the progress of the running, or last, compaction, see
+    /// `CompactionHandle::progress`. Engines that don't compact fail with
+    /// `KvsError::Unsupported`, which is the default.
+    fn compaction_progress(&mut self) -> Result<CompactionProgress> {
+        Err(KvsError::Unsupported("compaction progress").into())
+    }
+
+    /// Cancels the running compaction, if any, see `CompactionHandle::cancel`, failing like
+    /// `compaction_progress` by default.
+    fn cancel_compaction(&mut self) -> Result<()> {
+        Err(KvsError::Unsupported("cancelling compactions").into())
+    }
+
+    /// Returns the `n` most accessed keys with their estimated access counts, see
+    /// `KvStore::hot_keys`, failing like `compaction_progress` by default.
+    fn hot_keys(&mut self, _n: usize) -> Result<Vec<(String, u64)>> {
+        Err(KvsError::Unsupported("hot keys").into())
+    }
+}
+
+/// The bound right after the keys starting with `prefix`: the prefix with its last
+/// character incremented, after dropping the ones that cannot be.
+pub(crate) fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        // skips the surrogates, which are no characters
+        let next = (u32::from(last) + 1..=u32::from(char::MAX)).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Bound::Excluded(end.into_iter().collect());
+        }
+    }
+    Bound::Unbounded
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn get_range(&mut self, key: String, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        (**self).get_range(key, offset, len)
+    }
+
+    fn set_returning(&mut self, key: String, value: String) -> Result<Option<String>> {
+        (**self).set_returning(key, value)
+    }
+
+    fn remove_returning(&mut self, key: String) -> Result<Option<String>> {
+        (**self).remove_returning(key)
+    }
+
+    fn scan(&mut self, start: Bound<String>, end: Bound<String>) -> Result<Pairs<'_>> {
+        (**self).scan(start, end)
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Pairs<'_>> {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn share(&self) -> Option<Box<dyn KvsEngine + Send>> {
+        (**self).share()
+    }
+
+    fn compaction_progress(&mut self) -> Result<CompactionProgress> {
+        (**self).compaction_progress()
+    }
+
+    fn cancel_compaction(&mut self) -> Result<()> {
+        (**self).cancel_compaction()
+    }
+
+    fn hot_keys(&mut self, n: usize) -> Result<Vec<(String, u64)>> {
+        (**self).hot_keys(n)
+    }
+}
+
+/// The engines that can be chosen at runtime, by the names they parse from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// `kvs`: a `KvStore` on the local disk.
+    Kvs,
+    /// `mem`: a `KvStore` in memory, gone once it is dropped.
+    Mem,
+}
+
+impl Engine {
+    /// Opens the engine named `name` at `path`, such as from a configuration file.
+    ///
+    /// `sled` is recognized but not built in, opening it fails like any unknown name.
+    pub fn open(name: &str, path: impl Into<PathBuf>) -> Result<Box<dyn KvsEngine + Send>> {
+        name.parse::<Engine>()?.open_at(path)
+    }
+
+    pub fn open_at(self, path: impl Into<PathBuf>) -> Result<Box<dyn KvsEngine + Send>> {
+        self.open_with(path, &OpenOptions::new())
+    }
+
+    /// Opens the engine at `path` with `options`. The mem engine replaces their storage.
+    pub fn open_with(
+        self,
+        path: impl Into<PathBuf>,
+        options: &OpenOptions,
+    ) -> Result<Box<dyn KvsEngine + Send>> {
+        let store = match self {
+            Engine::Kvs => options.open(path)?,
+            Engine::Mem => options.clone().storage(MemStorage::new()).open(path)?,
+        };
+        Ok(Box::new(store))
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Engine::Kvs => "kvs",
+            Engine::Mem => "mem",
+        })
+    }
+}
+
+impl FromStr for Engine {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Engine> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "mem" => Ok(Engine::Mem),
+            "sled" => Err(format_err!(
+                "The sled engine is not built into this version"
+            )),
+            _ => Err(format_err!("Unknown engine {:?}, expected kvs or mem", s)),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn get_range(&mut self, key: String, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        KvStore::get_range(self, key, offset, len)
+    }
+
+    fn set_returning(&mut self, key: String, value: String) -> Result<Option<String>> {
+        KvStore::set_returning(self, key, value)
+    }
+
+    fn remove_returning(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::remove_returning(self, key)
+    }
+
+    fn scan(&mut self, start: Bound<String>, end: Bound<String>) -> Result<Pairs<'_>> {
+        Ok(Box::new(KvStore::scan(self, (start, end))?))
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Pairs<'_>> {
+        Ok(Box::new(KvStore::scan_prefix(self, prefix)?))
+    }
+
+    fn share(&self) -> Option<Box<dyn KvsEngine + Send>> {
+        Some(Box::new(self.clone()))
+    }
+
+    fn compaction_progress(&mut self) -> Result<CompactionProgress> {
+        Ok(self.compaction_handle().progress())
+    }
+
+    fn cancel_compaction(&mut self) -> Result<()> {
+        self.compaction_handle().cancel();
+        Ok(())
+    }
+
+    fn hot_keys(&mut self, n: usize) -> Result<Vec<(String, u64)>> {
+        Ok(KvStore::hot_keys(self, n))
+    }
+}
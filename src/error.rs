@@ -0,0 +1,92 @@
+use std::{fmt, path::PathBuf};
+
+use failure::Fail;
+
+use crate::ErrorCode;
+
+/// Errors with a meaning callers may want to act on.
+///
+/// They are returned wrapped in a `failure::Error`; use `downcast_ref` to inspect them.
+/// Errors of a `KvsClient` that are no `KvsError` come from the connection itself.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KvsError {
+    KeyNotFound,
+    VersionConflict(String),
+    TypeMismatch {
+        key: String,
+        expected: &'static str,
+    },
+    WrongType(String),
+    AlreadyOpen(PathBuf),
+    /// Another process holds a lock on the store that conflicts, see `OpenOptions::read_only`.
+    Locked(PathBuf),
+    /// The store is a follower, see `OpenOptions::follow`.
+    ReadOnly,
+    /// The server refused the credentials, or a request sent without them.
+    Unauthorized,
+    /// The engine does not support the operation, such as a compaction request to an
+    /// engine that never compacts.
+    Unsupported(&'static str),
+    /// A server failed a request for a reason with no variant of its own.
+    Server {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::VersionConflict(key) => write!(f, "Version conflict on key {}", key),
+            KvsError::TypeMismatch { key, expected } => {
+                write!(f, "Value of key {} is not a valid {}", key, expected)
+            }
+            KvsError::WrongType(key) => {
+                write!(f, "Key {} holds the wrong kind of value", key)
+            }
+            KvsError::AlreadyOpen(dir) => {
+                write!(
+                    f,
+                    "Store in {} is already open in this process",
+                    dir.display()
+                )
+            }
+            KvsError::Locked(dir) => {
+                write!(f, "Store in {} is locked by another process", dir.display())
+            }
+            KvsError::ReadOnly => write!(f, "The store is read-only"),
+            KvsError::Unauthorized => write!(f, "Not authorized"),
+            KvsError::Unsupported(operation) => {
+                write!(f, "The engine does not support {}", operation)
+            }
+            KvsError::Server { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl KvsError {
+    /// The code a server answers with when a request fails with this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::VersionConflict(_) => ErrorCode::Conflict,
+            KvsError::TypeMismatch { .. } | KvsError::WrongType(_) => ErrorCode::WrongType,
+            KvsError::AlreadyOpen(_) | KvsError::Locked(_) => ErrorCode::Busy,
+            KvsError::ReadOnly | KvsError::Unsupported(_) => ErrorCode::BadRequest,
+            KvsError::Unauthorized => ErrorCode::Unauthorized,
+            KvsError::Server { code, .. } => *code,
+        }
+    }
+
+    /// See `ErrorCode::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_retryable()
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.code() == ErrorCode::KeyNotFound
+    }
+}
+
+impl Fail for KvsError {}
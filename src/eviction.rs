@@ -0,0 +1,159 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::Arc,
+};
+
+/// Decides which key to evict once a store holds more keys than allowed, see
+/// `OpenOptions::eviction`.
+///
+/// A policy only learns about keys through these calls. On open it is told about the
+/// existing keys in the order they were last written.
+pub trait EvictionPolicy: fmt::Debug + Send {
+    /// `key` was written for the first time.
+    fn on_insert(&mut self, key: &str);
+
+    /// `key` was read or overwritten.
+    fn on_access(&mut self, key: &str);
+
+    /// `key` was removed, by the user or by an eviction.
+    fn on_remove(&mut self, key: &str);
+
+    /// Returns the key to evict next.
+    fn victim(&mut self) -> Option<String>;
+}
+
+/// Evicts the least recently read or written key.
+#[derive(Debug, Clone, Default)]
+pub struct Lru {
+    ranking: Ranking<u64>,
+    tick: u64,
+}
+
+impl EvictionPolicy for Lru {
+    fn on_insert(&mut self, key: &str) {
+        self.on_access(key);
+    }
+
+    fn on_access(&mut self, key: &str) {
+        self.tick += 1;
+        self.ranking.set(key, self.tick);
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.ranking.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.ranking.first()
+    }
+}
+
+/// Evicts the least frequently read or written key, the oldest one among equals.
+#[derive(Debug, Clone, Default)]
+pub struct Lfu {
+    // access count and the tick of the first access
+    ranking: Ranking<(u64, u64)>,
+    tick: u64,
+}
+
+impl EvictionPolicy for Lfu {
+    fn on_insert(&mut self, key: &str) {
+        self.tick += 1;
+        self.ranking.set(key, (1, self.tick));
+    }
+
+    fn on_access(&mut self, key: &str) {
+        match self.ranking.get(key) {
+            Some(&(count, first)) => self.ranking.set(key, (count + 1, first)),
+            None => self.on_insert(key),
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        self.ranking.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.ranking.first()
+    }
+}
+
+/// Evicts the key that was inserted first, regardless of how it is used.
+#[derive(Debug, Clone, Default)]
+pub struct Fifo {
+    ranking: Ranking<u64>,
+    tick: u64,
+}
+
+impl EvictionPolicy for Fifo {
+    fn on_insert(&mut self, key: &str) {
+        self.tick += 1;
+        self.ranking.set(key, self.tick);
+    }
+
+    fn on_access(&mut self, _key: &str) {}
+
+    fn on_remove(&mut self, key: &str) {
+        self.ranking.remove(key);
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.ranking.first()
+    }
+}
+
+// Keys ordered by a rank, lowest first.
+#[derive(Debug, Clone)]
+struct Ranking<R> {
+    ranks: HashMap<String, R>,
+    order: BTreeSet<(R, String)>,
+}
+
+impl<R> Default for Ranking<R> {
+    fn default() -> Self {
+        Ranking {
+            ranks: HashMap::new(),
+            order: BTreeSet::new(),
+        }
+    }
+}
+
+impl<R: Ord + Clone> Ranking<R> {
+    fn get(&self, key: &str) -> Option<&R> {
+        self.ranks.get(key)
+    }
+
+    fn set(&mut self, key: &str, rank: R) {
+        if let Some(old) = self.ranks.insert(key.to_owned(), rank.clone()) {
+            self.order.remove(&(old, key.to_owned()));
+        }
+        self.order.insert((rank, key.to_owned()));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.ranks.remove(key) {
+            self.order.remove(&(old, key.to_owned()));
+        }
+    }
+
+    fn first(&self) -> Option<String> {
+        self.order.first().map(|(_, key)| key.clone())
+    }
+}
+
+/// A key limit and the policy enforcing it, one instance per opened store.
+#[derive(Clone)]
+pub(crate) struct Eviction {
+    pub(crate) max_keys: usize,
+    pub(crate) policy: Arc<dyn Fn() -> Box<dyn EvictionPolicy> + Send + Sync>,
+}
+
+impl fmt::Debug for Eviction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Eviction")
+            .field("max_keys", &self.max_keys)
+            .field("policy", &(self.policy)())
+            .finish()
+    }
+}
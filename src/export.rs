@@ -0,0 +1,153 @@
+use std::io::Write;
+
+use failure::format_err;
+use serde::Serialize;
+
+use crate::{
+    progress::{Counted, Progress},
+    KvStore, Result, Store,
+};
+
+/// Magic bytes ending every sorted export.
+pub const SORTED_EXPORT_MAGIC: &[u8; 8] = b"KVSSST01";
+/// Number of entries between two keys of the index block of a sorted export.
+pub const SORTED_EXPORT_INDEX_INTERVAL: u64 = 16;
+
+impl Store {
+    pub(crate) fn export_sorted(&self, out: impl Write, progress: &Progress) -> Result<u64> {
+        let keys = self.string_keys_in(..);
+        progress.start(Some(keys.len() as u64));
+        let mut out = Counted {
+            inner: out,
+            progress,
+        };
+        let mut offset = 0u64;
+        let mut entries = 0u64;
+        let mut index = Vec::new();
+        for key in keys {
+            let Some((value, _)) = self.get_local(&key)? else {
+                return Err(format_err!("Key {} vanished during export", key));
+            };
+            if entries.is_multiple_of(SORTED_EXPORT_INDEX_INTERVAL) {
+                write_bytes(&mut index, key.as_bytes())?;
+                index.extend_from_slice(&offset.to_le_bytes());
+            }
+            write_bytes(&mut out, key.as_bytes())?;
+            write_bytes(&mut out, value.as_bytes())?;
+            offset += 8 + key.len() as u64 + value.len() as u64;
+            entries += 1;
+            progress.add_key();
+        }
+        out.write_all(&index)?;
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&entries.to_le_bytes())?;
+        out.write_all(SORTED_EXPORT_MAGIC)?;
+        out.flush()?;
+        Ok(entries)
+    }
+}
+
+impl KvStore {
+    /// Writes the string values of the store to `out` in key order, followed by a sparse
+    /// index, and returns the number of entries written.
+    ///
+    /// All integers are little-endian. The data block holds one entry per key, a `u32`
+    /// key length, the key, a `u32` value length and the value. The index block holds
+    /// every `SORTED_EXPORT_INDEX_INTERVAL`th key, starting with the first, as a `u32`
+    /// key length, the key and the `u64` offset of its entry. The footer holds the `u64`
+    /// offset of the index block, the `u64` number of entries and `SORTED_EXPORT_MAGIC`.
+    pub fn export_sorted(&self, out: impl Write) -> Result<u64> {
+        self.export_sorted_with_progress(out, &Progress::new())
+    }
+
+    /// Like `export_sorted`, counting the entries and bytes written into `progress`.
+    pub fn export_sorted_with_progress(&self, out: impl Write, progress: &Progress) -> Result<u64> {
+        self.read().export_sorted(out, progress)
+    }
+}
+
+impl KvStore {
+    /// Writes every string key-value pair to `out` in key order as JSON lines, one
+    /// `{"key":...,"value":...}` object per line, and returns the number written.
+    ///
+    /// The pairs come from a `View` taken at the start, reading one value at a time, so
+    /// writes meanwhile neither show up nor wait for the export.
+    pub fn export_json(&self, out: impl Write) -> Result<u64> {
+        self.export_json_with_progress(out, &Progress::new())
+    }
+
+    /// Like `export_json`, counting the pairs and bytes written into `progress`.
+    pub fn export_json_with_progress(&self, out: impl Write, progress: &Progress) -> Result<u64> {
+        #[derive(Serialize)]
+        struct Pair<'a> {
+            key: &'a str,
+            value: &'a str,
+        }
+
+        let view = self.view()?;
+        progress.start(Some(view.len() as u64));
+        let mut out = Counted {
+            inner: out,
+            progress,
+        };
+        let mut entries = 0;
+        for pair in view.iter() {
+            let (key, value) = pair?;
+            serde_json::to_writer(
+                &mut out,
+                &Pair {
+                    key: &key,
+                    value: &value,
+                },
+            )?;
+            out.write_all(b"\n")?;
+            entries += 1;
+            progress.add_key();
+        }
+        out.flush()?;
+        Ok(entries)
+    }
+
+    /// Writes every string key-value pair to `out` in key order as CSV with a `key,value`
+    /// header, and returns the number written, like `export_json`.
+    ///
+    /// Fields holding commas, quotes or line breaks are quoted, doubling their quotes.
+    pub fn export_csv(&self, out: impl Write) -> Result<u64> {
+        self.export_csv_with_progress(out, &Progress::new())
+    }
+
+    /// Like `export_csv`, counting the pairs and bytes written into `progress`.
+    pub fn export_csv_with_progress(&self, out: impl Write, progress: &Progress) -> Result<u64> {
+        let view = self.view()?;
+        progress.start(Some(view.len() as u64));
+        let mut out = Counted {
+            inner: out,
+            progress,
+        };
+        out.write_all(b"key,value\n")?;
+        let mut entries = 0;
+        for pair in view.iter() {
+            let (key, value) = pair?;
+            writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?;
+            entries += 1;
+            progress.add_key();
+        }
+        out.flush()?;
+        Ok(entries)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(bytes)?;
+    Ok(())
+}
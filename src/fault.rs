@@ -0,0 +1,336 @@
+//! A storage layer injecting delays, failures and crashes, for crash-recovery tests.
+//!
+//! ```no_run
+//! # use kvs::{DiskStorage, Fault, FaultInjector, IoOp, OpenOptions};
+//! let faults = FaultInjector::new(DiskStorage);
+//! // crash the process while the third record of a compaction is written
+//! faults.inject(Fault::crash(IoOp::Write).on_file("kvs.compact.log").after(2));
+//! let mut store = OpenOptions::new().storage(faults.clone()).open("db")?;
+//! # kvs::Result::Ok(())
+//! ```
+
+use std::{
+    ffi::OsStr,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{Storage, StorageFile};
+
+/// The storage operations faults can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoOp {
+    Open,
+    Create,
+    Read,
+    Write,
+    Flush,
+    Seek,
+    SetLen,
+    Sync,
+    Rename,
+    Remove,
+    Link,
+    CreateDir,
+    SyncDir,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Delay(Duration),
+    Fail,
+    Crash,
+    TornWrite,
+}
+
+/// A fault to inject into the matching storage operations, see `FaultInjector::inject`.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    op: IoOp,
+    action: Action,
+    file: Option<String>,
+    skip: u64,
+    times: Option<u64>,
+}
+
+impl Fault {
+    /// Delays every matching operation by `delay`.
+    pub fn delay(op: IoOp, delay: Duration) -> Fault {
+        Fault::new(op, Action::Delay(delay), None)
+    }
+
+    /// Fails the next matching operation with an I/O error, without side effects.
+    pub fn fail(op: IoOp) -> Fault {
+        Fault::new(op, Action::Fail, Some(1))
+    }
+
+    /// Crashes at the next matching operation: it and every later operation fail
+    /// without side effects, as if the process died right before it.
+    pub fn crash(op: IoOp) -> Fault {
+        Fault::new(op, Action::Crash, Some(1))
+    }
+
+    /// Crashes in the middle of the next write, after only half of it reached the file.
+    pub fn torn_write() -> Fault {
+        Fault::new(IoOp::Write, Action::TornWrite, Some(1))
+    }
+
+    fn new(op: IoOp, action: Action, times: Option<u64>) -> Fault {
+        Fault {
+            op,
+            action,
+            file: None,
+            skip: 0,
+            times,
+        }
+    }
+
+    /// Only matches operations on files named `name`, or renames and links to or from them.
+    pub fn on_file(mut self, name: &str) -> Fault {
+        self.file = Some(name.to_owned());
+        self
+    }
+
+    /// Lets the first `n` matching operations through.
+    pub fn after(mut self, n: u64) -> Fault {
+        self.skip = n;
+        self
+    }
+
+    /// Injects the fault into at most `n` matching operations.
+    pub fn times(mut self, n: u64) -> Fault {
+        self.times = Some(n);
+        self
+    }
+
+    fn matches(&self, op: IoOp, paths: &[&Path]) -> bool {
+        self.op == op
+            && self.file.as_ref().is_none_or(|name| {
+                paths
+                    .iter()
+                    .any(|path| path.file_name() == Some(OsStr::new(name)))
+            })
+    }
+}
+
+/// Wraps a storage and injects faults into its operations.
+///
+/// Clones share the injected faults, so faults can be added while a store uses it.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    inner: Arc<dyn Storage>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    faults: Vec<Fault>,
+    crashed: bool,
+}
+
+impl FaultInjector {
+    pub fn new(inner: impl Storage + 'static) -> FaultInjector {
+        FaultInjector {
+            inner: Arc::new(inner),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push(fault);
+    }
+
+    /// Whether a crash was injected; every operation fails from then on.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// Removes all faults and recovers from a crash.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.faults.clear();
+        state.crashed = false;
+    }
+
+    // Runs the faults matching `op`, returning the action the operation has to carry out
+    // itself, if any.
+    fn check(&self, op: IoOp, paths: &[&Path]) -> io::Result<Option<Action>> {
+        let mut delay = Duration::ZERO;
+        let mut result = Ok(None);
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.crashed {
+                return Err(crashed());
+            }
+            for fault in state.faults.iter_mut().filter(|f| f.matches(op, paths)) {
+                if fault.skip > 0 {
+                    fault.skip -= 1;
+                    continue;
+                }
+                if fault.times == Some(0) {
+                    continue;
+                }
+                if let Some(times) = &mut fault.times {
+                    *times -= 1;
+                }
+                match fault.action {
+                    Action::Delay(d) => delay += d,
+                    Action::Fail => {
+                        result = Err(io::Error::other(format!("injected {:?} failure", op)))
+                    }
+                    Action::Crash | Action::TornWrite => {
+                        result = Ok(Some(fault.action));
+                        break;
+                    }
+                }
+            }
+            if result.as_ref().is_ok_and(|action| action.is_some()) {
+                state.crashed = true;
+            }
+        }
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        match result {
+            Ok(Some(Action::Crash)) => Err(crashed()),
+            result => result,
+        }
+    }
+
+    fn file(&self, path: &Path, file: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+        Box::new(FaultyFile {
+            injector: self.clone(),
+            path: path.to_owned(),
+            inner: file,
+        })
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("injected crash")
+}
+
+impl Storage for FaultInjector {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.check(IoOp::Open, &[path])?;
+        Ok(self.file(path, self.inner.open(path)?))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.check(IoOp::Open, &[path])?;
+        Ok(self.file(path, self.inner.open_read(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        self.check(IoOp::Create, &[path])?;
+        Ok(self.file(path, self.inner.create(path)?))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.check(IoOp::Read, &[path])?;
+        self.inner.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(IoOp::Rename, &[from, to])?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.check(IoOp::Remove, &[path])?;
+        self.inner.remove(path)
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check(IoOp::Link, &[from, to])?;
+        self.inner.link(from, to)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        self.check(IoOp::CreateDir, &[dir])?;
+        self.inner.create_dir(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.check(IoOp::SyncDir, &[dir])?;
+        self.inner.sync_dir(dir)
+    }
+
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(dir)
+    }
+
+    fn try_lock(&self, path: &Path, exclusive: bool) -> io::Result<Box<dyn Send + Sync>> {
+        self.inner.try_lock(path, exclusive)
+    }
+}
+
+struct FaultyFile {
+    injector: FaultInjector,
+    // the path the file was opened at, faults keep matching it after a rename
+    path: PathBuf,
+    inner: Box<dyn StorageFile>,
+}
+
+impl FaultyFile {
+    fn check(&self, op: IoOp) -> io::Result<Option<Action>> {
+        self.injector.check(op, &[&self.path])
+    }
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check(IoOp::Read)?;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.check(IoOp::Write)? == Some(Action::TornWrite) {
+            self.inner.write_all(&buf[..buf.len() / 2])?;
+            self.inner.flush()?;
+            return Err(crashed());
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check(IoOp::Flush)?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.check(IoOp::Seek)?;
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn size(&mut self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.check(IoOp::SetLen)?;
+        self.inner.set_len(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.check(IoOp::Sync)?;
+        self.inner.sync()
+    }
+}
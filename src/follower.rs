@@ -0,0 +1,165 @@
+use std::{
+    mem,
+    sync::{RwLock, Weak},
+    thread,
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::{
+    segment::{segment_gens, segment_path},
+    unpoisoned, BufWriterWithPos, KvStore, OpenOptions, Replay, Result, Store,
+};
+
+/// What a store opened with `OpenOptions::follow` knows about the store it follows.
+pub(crate) struct Follower {
+    // the segments indexed, the last one up to the position of the writer
+    gens: Vec<u64>,
+    // to open the store again once the one followed compacted
+    options: OpenOptions,
+    // keeps other processes from replacing the files while they are read
+    _lock: Option<Box<dyn Send + Sync>>,
+}
+
+impl Follower {
+    pub(crate) fn new(
+        gens: Vec<u64>,
+        options: &OpenOptions,
+        lock: Option<Box<dyn Send + Sync>>,
+    ) -> Follower {
+        Follower {
+            gens,
+            options: options.clone(),
+            _lock: lock,
+        }
+    }
+}
+
+/// Looks for changes to the files of the store every `interval` until it is closed, and
+/// refreshes it when there are. Looking only takes the lock shared with reads.
+pub(crate) fn spawn_refresher(store: Weak<RwLock<Store>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let Some(store) = store.upgrade() else {
+            break;
+        };
+        let changed = {
+            let store = unpoisoned(store.read());
+            store.changed_on_disk().unwrap_or_else(|e| {
+                warn!(
+                    "Failed to look for changes in {}: {}",
+                    store.dir.display(),
+                    e
+                );
+                false
+            })
+        };
+        if !changed {
+            continue;
+        }
+        let mut store = unpoisoned(store.write());
+        if let Err(e) = store.refresh() {
+            warn!("Failed to refresh {}: {}", store.dir.display(), e);
+        }
+    });
+}
+
+impl Store {
+    // Whether the store followed added or replaced segments, or appended to the current
+    // one, since the last refresh.
+    fn changed_on_disk(&self) -> Result<bool> {
+        let Some(follower) = &self.follower else {
+            return Ok(false);
+        };
+        if segment_gens(&*self.storage, &self.dir)? != follower.gens {
+            return Ok(true);
+        }
+        let size = self
+            .storage
+            .open_read(&segment_path(&self.dir, self.gen))?
+            .size()?;
+        Ok(size != self.writer.pos)
+    }
+
+    pub(crate) fn refresh(&mut self) -> Result<()> {
+        let Some(follower) = &mut self.follower else {
+            return Ok(());
+        };
+        let gens = segment_gens(&*self.storage, &self.dir)?;
+        let (known, new): (Vec<u64>, Vec<u64>) = gens.iter().partition(|&&gen| gen <= self.gen);
+        // a compaction replaced segments, the records indexed may have moved
+        if known != follower.gens {
+            return self.reload();
+        }
+        let last = gens.last().copied();
+        for gen in Some(self.gen).into_iter().chain(new) {
+            let mut file = self.storage.open_read(&segment_path(&self.dir, gen))?;
+            let start = if gen == self.gen { self.writer.pos } else { 0 };
+            // reads wait for the records of the segment to be indexed
+            let mut index = unpoisoned(self.reads.index.write());
+            let mut blobs = unpoisoned(self.reads.blobs.write());
+            let mut replay = Replay {
+                index: &mut index,
+                blobs: &mut blobs,
+                seq: &mut self.seq,
+                stale_size: &mut self.stale_size,
+            };
+            let replayed = replay.segment(&mut file, gen, start)?;
+            drop((index, blobs));
+            if gen != self.gen {
+                self.sealed_size += self.writer.pos;
+                self.gen = gen;
+                follower.gens.push(gen);
+            }
+            self.writer = BufWriterWithPos::new(file)?;
+            self.writer.pos = replayed.end;
+            if let Some(e) = replayed.damage {
+                // the segment being written may end in a record written halfway so far,
+                // the next refresh reads it once it is complete
+                if Some(gen) == last {
+                    break;
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the index with the one of the store opened again from scratch.
+    fn reload(&mut self) -> Result<()> {
+        let Some(follower) = &self.follower else {
+            return Ok(());
+        };
+        let mut fresh = Store::open_with(self.dir.clone(), &follower.options)?;
+        mem::swap(
+            &mut *unpoisoned(self.reads.index.write()),
+            &mut *unpoisoned(fresh.reads.index.write()),
+        );
+        mem::swap(
+            &mut *unpoisoned(self.reads.blobs.write()),
+            &mut *unpoisoned(fresh.reads.blobs.write()),
+        );
+        mem::swap(&mut self.writer, &mut fresh.writer);
+        mem::swap(&mut self.follower, &mut fresh.follower);
+        self.gen = fresh.gen;
+        self.sealed_size = fresh.sealed_size;
+        self.stale_size = fresh.stale_size;
+        self.seq = fresh.seq;
+        self.info = fresh.info.clone();
+        // they may still hold on to the segments replaced
+        self.reads.close_readers();
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Reads the records the store followed wrote since the last refresh, see
+    /// `OpenOptions::follow`. Does nothing for a store opened for writing.
+    ///
+    /// Once the store followed compacted, the follower reads its log again from scratch.
+    /// Until then, reads of keys whose records the compaction moved may fail.
+    pub fn refresh(&self) -> Result<()> {
+        self.write().refresh()
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_json::Deserializer;
+
+use crate::{
+    codec,
+    meta::META_FILE_NAME,
+    recover_compaction,
+    segment::{segment_gens, segment_path, LEGACY_LOG_FILE_NAME},
+    Commands, KvStore, OpenOptions, Registration, Result, StoreInfo, COMPACT_FILE_NAME,
+    FORMAT_VERSION,
+};
+
+/// The overall result of `OpenOptions::fsck`.
+///
+/// `kvs fsck` exits with the code of the status, see `FsckStatus::exit_code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsckStatus {
+    /// No problems were found.
+    Clean,
+    /// Problems were found and all of them were repaired.
+    Repaired,
+    /// Problems were found that a repair can fix, but no repair was asked for.
+    NeedsRepair,
+    /// Problems were found that cannot be repaired without losing data written after them.
+    Unrecoverable,
+}
+
+impl FsckStatus {
+    /// The exit code of `kvs fsck`: 0 when clean, 1 when repaired, 2 when a repair is
+    /// needed and 3 when unrecoverable. Failing to run the check at all exits with 4.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FsckStatus::Clean => 0,
+            FsckStatus::Repaired => 1,
+            FsckStatus::NeedsRepair => 2,
+            FsckStatus::Unrecoverable => 3,
+        }
+    }
+}
+
+impl fmt::Display for FsckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsckStatus::Clean => "clean",
+            FsckStatus::Repaired => "repaired",
+            FsckStatus::NeedsRepair => "needs_repair",
+            FsckStatus::Unrecoverable => "unrecoverable",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The output of an interrupted compaction is still around.
+    LeftoverCompaction,
+    /// The metadata file cannot be parsed.
+    CorruptMetadata,
+    /// The store was written by a newer version of kvs.
+    UnsupportedFormat,
+    /// The log ends in an unreadable record, typically a torn last write.
+    TornTail,
+    /// The log holds an unreadable record followed by readable ones.
+    CorruptRecord,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+/// What `OpenOptions::fsck` found, serializable for automation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    pub status: FsckStatus,
+    /// Readable records in the log.
+    pub records: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "status: {}", self.status)?;
+        write!(f, "records: {}", self.records)?;
+        for problem in &self.problems {
+            write!(f, "\nproblem: {}", problem.detail)?;
+            if problem.repaired {
+                write!(f, " (repaired)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OpenOptions {
+    /// Checks the store at `path`, which must not be open, repairing what can be repaired
+    /// if `repair` is set.
+    pub fn fsck(&self, path: impl Into<PathBuf>, repair: bool) -> Result<FsckReport> {
+        let dir = path.into();
+        let storage = self.resolved_storage();
+        let _registration = if repair {
+            Registration::acquire_exclusive(&*storage, &dir)?
+        } else {
+            Registration::acquire(&*storage, &dir)?
+        };
+        let mut problems = Vec::new();
+        let mut unrecoverable = false;
+
+        if storage.exists(&dir.join(COMPACT_FILE_NAME)) {
+            if repair {
+                recover_compaction(&*storage, &dir)?;
+            }
+            problems.push(Problem {
+                kind: ProblemKind::LeftoverCompaction,
+                detail: format!("{} left behind by a compaction", COMPACT_FILE_NAME),
+                repaired: repair,
+            });
+        }
+
+        let meta_path = dir.join(META_FILE_NAME);
+        if storage.exists(&meta_path) {
+            match serde_json::from_slice::<StoreInfo>(&storage.read(&meta_path)?) {
+                Ok(info) if info.format_version > FORMAT_VERSION => {
+                    unrecoverable = true;
+                    problems.push(Problem {
+                        kind: ProblemKind::UnsupportedFormat,
+                        detail: format!(
+                            "format version {} is newer than the supported version {}",
+                            info.format_version, FORMAT_VERSION
+                        ),
+                        repaired: false,
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if repair {
+                        // loading without the file treats the store as predating metadata
+                        storage.remove(&meta_path)?;
+                        StoreInfo::load(&*storage, &*self.resolved_clock(), &dir)?
+                            .save(&*storage, &dir, false)?;
+                    }
+                    problems.push(Problem {
+                        kind: ProblemKind::CorruptMetadata,
+                        detail: format!("{} cannot be parsed: {}", META_FILE_NAME, e),
+                        repaired: repair,
+                    });
+                }
+            }
+        }
+
+        // stores that were not opened since segments exist still have a single log
+        let mut log_paths: Vec<PathBuf> = segment_gens(&*storage, &dir)?
+            .into_iter()
+            .map(|gen| segment_path(&dir, gen))
+            .collect();
+        let legacy_path = dir.join(LEGACY_LOG_FILE_NAME);
+        if storage.exists(&legacy_path) {
+            log_paths.insert(0, legacy_path);
+        }
+        let mut records = 0;
+        for (i, log_path) in log_paths.iter().enumerate() {
+            let log = storage.read(log_path)?;
+            let last = i + 1 == log_paths.len();
+            // segments written before the binary format are converted on the next open
+            let scan = if codec::is_json(&log) {
+                scan_json(&log)
+            } else {
+                scan_binary(&log)
+            };
+            records += scan.records;
+            if let Some(damage) = scan.damage {
+                let pos = damage.pos;
+                let detail = format!(
+                    "unreadable record at offset {} of {}: {}",
+                    pos,
+                    file_name(log_path),
+                    damage.error
+                );
+                // cutting off anything but the end of the last segment loses the segments
+                // after it
+                if !last || damage.readable_after {
+                    unrecoverable = true;
+                    problems.push(Problem {
+                        kind: ProblemKind::CorruptRecord,
+                        detail,
+                        repaired: false,
+                    });
+                } else {
+                    if repair {
+                        storage.open(log_path)?.set_len(pos as u64)?;
+                    }
+                    problems.push(Problem {
+                        kind: ProblemKind::TornTail,
+                        detail: format!("{}, {} bytes to discard", detail, log.len() - pos),
+                        repaired: repair,
+                    });
+                }
+            }
+        }
+
+        let status = if unrecoverable {
+            FsckStatus::Unrecoverable
+        } else if problems.is_empty() {
+            FsckStatus::Clean
+        } else if repair {
+            FsckStatus::Repaired
+        } else {
+            FsckStatus::NeedsRepair
+        };
+        Ok(FsckReport {
+            status,
+            records,
+            problems,
+        })
+    }
+}
+
+// What reading the records of a segment found.
+struct Scan {
+    records: u64,
+    damage: Option<Damage>,
+}
+
+// The first unreadable record of a segment.
+struct Damage {
+    pos: usize,
+    error: String,
+    // whether a record can be read after it, i.e. cutting the segment there would lose
+    // more than the damaged record
+    readable_after: bool,
+}
+
+fn scan_binary(log: &[u8]) -> Scan {
+    let mut records = 0;
+    if !log.is_empty() {
+        if let Err(e) = codec::read_header(&mut &log[..]) {
+            let damage = Damage {
+                pos: 0,
+                error: e.to_string(),
+                readable_after: codec::frames(log).any(|(_, frame)| readable(frame)),
+            };
+            return Scan {
+                records,
+                damage: Some(damage),
+            };
+        }
+    }
+    let mut frames = codec::frames(log);
+    while let Some((pos, frame)) = frames.next() {
+        let error = match frame.map(codec::from_slice::<Commands>) {
+            Some(Ok(_)) => {
+                records += 1;
+                continue;
+            }
+            Some(Err(e)) => e.to_string(),
+            None => "record cut short".to_owned(),
+        };
+        let damage = Damage {
+            pos,
+            error,
+            readable_after: frames.any(|(_, frame)| readable(frame)),
+        };
+        return Scan {
+            records,
+            damage: Some(damage),
+        };
+    }
+    Scan {
+        records,
+        damage: None,
+    }
+}
+
+fn readable(frame: Option<&[u8]>) -> bool {
+    frame.is_some_and(|bytes| codec::from_slice::<Commands>(bytes).is_ok())
+}
+
+fn scan_json(log: &[u8]) -> Scan {
+    let mut records = 0;
+    let mut pos = 0;
+    let mut stream = Deserializer::from_slice(log).into_iter::<Commands>();
+    while let Some(cmd) = stream.next() {
+        if let Err(e) = cmd {
+            let damage = Damage {
+                pos,
+                error: e.to_string(),
+                readable_after: readable_record_after(log, pos),
+            };
+            return Scan {
+                records,
+                damage: Some(damage),
+            };
+        }
+        records += 1;
+        pos = stream.byte_offset();
+    }
+    Scan {
+        records,
+        damage: None,
+    }
+}
+
+// Whether a record can be read from any offset after `pos`, i.e. cutting the log at `pos`
+// would lose more than the damaged record.
+fn readable_record_after(log: &[u8], pos: usize) -> bool {
+    (pos + 1..log.len())
+        .filter(|&start| log[start] == b'{')
+        .any(|start| {
+            Deserializer::from_slice(&log[start..])
+                .into_iter::<Commands>()
+                .next()
+                .is_some_and(|cmd| cmd.is_ok())
+        })
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+impl KvStore {
+    /// Checks the store at `path` on the local disk, see `OpenOptions::fsck`.
+    pub fn fsck(path: impl Into<PathBuf>, repair: bool) -> Result<FsckReport> {
+        OpenOptions::new().fsck(path, repair)
+    }
+}
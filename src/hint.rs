@@ -0,0 +1,121 @@
+use std::{io::Write, path::Path};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dedup::Blobs, index::Index, segment::segment_path, IndexEntry, IndexKind, Result, Storage,
+    Store,
+};
+
+pub(crate) const HINT_FILE_NAME: &str = "kvs.hint";
+const HINT_TMP_FILE_NAME: &str = "kvs.hint.tmp";
+
+/// The index of a store as it was when the hint was written, so opening it only has to
+/// replay the records written since.
+///
+/// A hint covers the segments it lists, the last one up to the size it had. It is only
+/// used if they are all still there with those sizes, except for the last one, which may
+/// have grown since.
+#[derive(Deserialize)]
+pub(crate) struct Hint {
+    // the generation and size of each segment covered, oldest first
+    segments: Vec<(u64, u64)>,
+    pub(crate) seq: u64,
+    pub(crate) stale_size: u64,
+    entries: Vec<(String, IndexEntry)>,
+    blobs: Blobs,
+}
+
+#[derive(Serialize)]
+struct HintRef<'a> {
+    segments: &'a [(u64, u64)],
+    seq: u64,
+    stale_size: u64,
+    entries: Vec<(&'a String, &'a IndexEntry)>,
+    blobs: &'a Blobs,
+}
+
+impl Hint {
+    /// Loads the hint of the store in `dir` with segments `gens`, or nothing if there is
+    /// none or it does not match the segments.
+    pub(crate) fn load(storage: &dyn Storage, dir: &Path, gens: &[u64]) -> Option<Hint> {
+        let bytes = storage.read(&dir.join(HINT_FILE_NAME)).ok()?;
+        let hint: Hint = match serde_json::from_slice(&bytes) {
+            Ok(hint) => hint,
+            Err(e) => {
+                warn!("Ignoring the unreadable hint of {}: {}", dir.display(), e);
+                return None;
+            }
+        };
+        let covered = gens.get(..hint.segments.len())?;
+        if !covered.iter().eq(hint.segments.iter().map(|(gen, _)| gen)) {
+            return None;
+        }
+        for (i, &(gen, size)) in hint.segments.iter().enumerate() {
+            let actual = storage
+                .open_read(&segment_path(dir, gen))
+                .ok()?
+                .size()
+                .ok()?;
+            let last = i + 1 == hint.segments.len();
+            if actual < size || (actual > size && !last) {
+                return None;
+            }
+        }
+        Some(hint)
+    }
+
+    /// Where replaying has to start: the generation of the last segment covered and its
+    /// size at the time.
+    pub(crate) fn end(&self) -> (u64, u64) {
+        self.segments.last().copied().unwrap_or((0, 0))
+    }
+
+    /// The size of the segments before the last one covered.
+    pub(crate) fn sealed_size(&self) -> u64 {
+        let sealed = &self.segments[..self.segments.len().saturating_sub(1)];
+        sealed.iter().map(|(_, size)| size).sum()
+    }
+
+    pub(crate) fn restore(self, kind: IndexKind) -> (Index, Blobs) {
+        let mut index = Index::new(kind);
+        for (key, entry) in self.entries {
+            index.insert(key, entry);
+        }
+        (index, self.blobs)
+    }
+}
+
+impl Store {
+    /// Writes a hint of the current index, synced and replaced atomically like the
+    /// metadata.
+    pub(crate) fn save_hint(&mut self) -> Result<()> {
+        let mut segments = Vec::new();
+        for gen in self.reads.live_gens()? {
+            let size = if gen == self.gen {
+                self.writer.pos
+            } else {
+                self.storage.open(&segment_path(&self.dir, gen))?.size()?
+            };
+            segments.push((gen, size));
+        }
+        let index = self.index();
+        let blobs = self.blobs();
+        let hint = HintRef {
+            segments: &segments,
+            seq: self.seq,
+            stale_size: self.stale_size,
+            entries: index.iter().collect(),
+            blobs: &blobs,
+        };
+        let tmp_path = self.dir.join(HINT_TMP_FILE_NAME);
+        let mut tmp = self.storage.create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(&hint)?)?;
+        tmp.sync()?;
+        drop(tmp);
+        self.storage
+            .rename(&tmp_path, &self.dir.join(HINT_FILE_NAME))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    path::Path,
+    sync::{RwLock, Weak},
+    thread,
+    time::Duration,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{reads::Sharded, unpoisoned, KvStore, Result, Storage, Store};
+
+pub(crate) const HEAT_FILE_NAME: &str = "kvs.heat";
+const HEAT_TMP_FILE_NAME: &str = "kvs.heat.tmp";
+
+const DEPTH: usize = 4;
+const WIDTH: usize = 1024;
+/// Number of keys whose counts are tracked exactly enough to be reported.
+const CANDIDATES: usize = 64;
+/// Accesses after which all counts are halved, so keys that cooled down drop out.
+const DECAY_INTERVAL: u64 = 100_000;
+
+/// Approximate access counts per key, kept in a count-min sketch.
+///
+/// The sketch only ever overestimates, by at most a small fraction of all accesses. The
+/// keys with the highest estimates are kept aside to be reported. A store keeps one per
+/// shard, the counts of a key being the sum of those, see `Sharded<HotKeys>::top`.
+pub(crate) struct HotKeys {
+    counters: Box<[[u32; WIDTH]; DEPTH]>,
+    candidates: HashMap<String, u32>,
+    accesses: u64,
+}
+
+impl Default for HotKeys {
+    fn default() -> HotKeys {
+        HotKeys {
+            counters: Box::new([[0; WIDTH]; DEPTH]),
+            candidates: HashMap::new(),
+            accesses: 0,
+        }
+    }
+}
+
+impl HotKeys {
+    pub(crate) fn record(&mut self, key: &str) {
+        self.accesses += 1;
+        if self.accesses.is_multiple_of(DECAY_INTERVAL) {
+            self.decay();
+        }
+        let mut estimate = u32::MAX;
+        for (row, counters) in self.counters.iter_mut().enumerate() {
+            let counter = &mut counters[slot(row, key)];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        if let Some(count) = self.candidates.get_mut(key) {
+            *count = estimate;
+        } else if self.candidates.len() < CANDIDATES {
+            self.candidates.insert(key.to_owned(), estimate);
+        } else {
+            let (coldest, count) = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count))
+                .expect("candidates are full");
+            if estimate > count {
+                self.candidates.remove(&coldest);
+                self.candidates.insert(key.to_owned(), estimate);
+            }
+        }
+    }
+
+    // Counts the keys as accessed as often as they were, as if the accesses happened
+    // since the store was opened.
+    fn restore(&mut self, keys: Vec<(String, u64)>) {
+        for (key, count) in keys {
+            let count = u32::try_from(count).unwrap_or(u32::MAX);
+            let mut estimate = u32::MAX;
+            for (row, counters) in self.counters.iter_mut().enumerate() {
+                let counter = &mut counters[slot(row, &key)];
+                *counter = counter.saturating_add(count);
+                estimate = estimate.min(*counter);
+            }
+            if self.candidates.len() < CANDIDATES {
+                self.candidates.insert(key, estimate);
+            }
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u32 {
+        (0..DEPTH)
+            .map(|row| self.counters[row][slot(row, key)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn decay(&mut self) {
+        for counter in self.counters.iter_mut().flatten() {
+            *counter /= 2;
+        }
+        self.candidates.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+    }
+}
+
+impl Sharded<HotKeys> {
+    pub(crate) fn record(&self, key: &str) {
+        self.local().record(key);
+    }
+
+    // Returns up to `n` of the keys kept aside by any shard, most accessed first, counted
+    // in all of them.
+    pub(crate) fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let shards: Vec<_> = self.each().collect();
+        let keys: HashSet<&String> = shards
+            .iter()
+            .flat_map(|shard| shard.candidates.keys())
+            .collect();
+        let mut top: Vec<(String, u64)> = keys
+            .into_iter()
+            .map(|key| {
+                let count = shards
+                    .iter()
+                    .map(|shard| match shard.candidates.get(key) {
+                        Some(count) => u64::from(*count),
+                        None => u64::from(shard.estimate(key)),
+                    })
+                    .sum();
+                (key.clone(), count)
+            })
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+fn slot(row: usize, key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish() as usize % WIDTH
+}
+
+/// The hot keys of a store as they were when it was last saved, so they stay hot across
+/// restarts, see `OpenOptions::persist_heat`.
+#[derive(Serialize, Deserialize)]
+struct HeatMap {
+    // most accessed first
+    keys: Vec<(String, u64)>,
+}
+
+/// Loads what the store in `dir` saved of its hot keys, or nothing if it saved none.
+pub(crate) fn load_heat(storage: &dyn Storage, dir: &Path) -> Sharded<HotKeys> {
+    let hot_keys = Sharded::<HotKeys>::default();
+    let bytes = match storage.read(&dir.join(HEAT_FILE_NAME)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return hot_keys,
+        Err(e) => {
+            warn!(
+                "Ignoring the unreadable heat map of {}: {}",
+                dir.display(),
+                e
+            );
+            return hot_keys;
+        }
+    };
+    match serde_json::from_slice::<HeatMap>(&bytes) {
+        Ok(heat) => hot_keys.local().restore(heat.keys),
+        Err(e) => warn!(
+            "Ignoring the unreadable heat map of {}: {}",
+            dir.display(),
+            e
+        ),
+    }
+    hot_keys
+}
+
+/// Saves the hot keys of the store every `interval` until it is closed.
+pub(crate) fn spawn_heat_saver(store: Weak<RwLock<Store>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let Some(store) = store.upgrade() else {
+            break;
+        };
+        let store = unpoisoned(store.read());
+        if store.closed {
+            break;
+        }
+        if let Err(e) = store.save_heat() {
+            warn!(
+                "Failed to save the heat map of {}: {}",
+                store.dir.display(),
+                e
+            );
+        }
+    });
+}
+
+impl Store {
+    pub(crate) fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.reads.hot_keys.top(n)
+    }
+
+    /// Writes the hot keys, synced and replaced atomically like the metadata.
+    pub(crate) fn save_heat(&self) -> Result<()> {
+        let heat = HeatMap {
+            keys: self.hot_keys(CANDIDATES),
+        };
+        let tmp_path = self.dir.join(HEAT_TMP_FILE_NAME);
+        let mut tmp = self.storage.create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(&heat)?)?;
+        tmp.sync()?;
+        drop(tmp);
+        self.storage
+            .rename(&tmp_path, &self.dir.join(HEAT_FILE_NAME))?;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Returns up to `n` of the most accessed keys with their approximate access counts,
+    /// most accessed first.
+    ///
+    /// Reads, including of missing keys, and writes count as accesses. Counts are halved
+    /// every hundred thousand accesses of the same thread, so they favor recent traffic.
+    pub fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.read().hot_keys(n)
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, BufReader, Read},
+};
+
+use failure::format_err;
+use serde::Deserialize;
+
+use crate::{
+    progress::{Counted, Progress},
+    KvStore, KvsError, Result,
+};
+
+/// What an import would change, see `KvStore::import_json_plan`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportPlan {
+    /// Keys missing from the store.
+    pub new_keys: usize,
+    /// Keys whose value would change, or whose value is not a string.
+    pub overwritten_keys: usize,
+    /// Keys that already have the value imported.
+    pub unchanged_keys: usize,
+}
+
+impl fmt::Display for ImportPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "new_keys: {}", self.new_keys)?;
+        writeln!(f, "overwritten_keys: {}", self.overwritten_keys)?;
+        write!(f, "unchanged_keys: {}", self.unchanged_keys)
+    }
+}
+
+impl KvStore {
+    /// Sets the pairs of JSON lines as written by `export_json`, one
+    /// `{"key":...,"value":...}` object per line, with `bulk_load`, and returns the number
+    /// of distinct keys set.
+    ///
+    /// Empty lines are skipped. Nothing is set if any line is invalid.
+    pub fn import_json(&self, input: impl BufRead) -> Result<usize> {
+        self.import_json_with_progress(input, &Progress::new())
+    }
+
+    /// Like `import_json`, counting the pairs and bytes read into `progress`.
+    pub fn import_json_with_progress(
+        &self,
+        input: impl Read,
+        progress: &Progress,
+    ) -> Result<usize> {
+        self.bulk_load(read_json(input, progress)?)
+    }
+
+    /// Reads the pairs like `import_json` and returns what importing them would change,
+    /// leaving the store alone.
+    pub fn import_json_plan(&self, input: impl Read) -> Result<ImportPlan> {
+        self.import_plan(read_json(input, &Progress::new())?)
+    }
+
+    /// Sets the pairs of CSV as written by `export_csv`, starting with a `key,value` header,
+    /// with `bulk_load`, and returns the number of distinct keys set.
+    ///
+    /// Fields may be quoted, holding commas, doubled quotes and line breaks, and lines may
+    /// end with CRLF. Nothing is set if any record is invalid.
+    pub fn import_csv(&self, input: impl Read) -> Result<usize> {
+        self.import_csv_with_progress(input, &Progress::new())
+    }
+
+    /// Like `import_csv`, counting the pairs and bytes read into `progress`.
+    pub fn import_csv_with_progress(&self, input: impl Read, progress: &Progress) -> Result<usize> {
+        self.bulk_load(read_csv(input, progress)?)
+    }
+
+    /// Reads the pairs like `import_csv` and returns what importing them would change,
+    /// like `import_json_plan`.
+    pub fn import_csv_plan(&self, input: impl Read) -> Result<ImportPlan> {
+        self.import_plan(read_csv(input, &Progress::new())?)
+    }
+
+    fn import_plan(&self, pairs: Vec<(String, String)>) -> Result<ImportPlan> {
+        let mut plan = ImportPlan::default();
+        // the last value of a key wins, as in `bulk_load`
+        let pairs: BTreeMap<String, String> = pairs.into_iter().collect();
+        for (key, value) in pairs {
+            if !self.contains_key(&key) {
+                plan.new_keys += 1;
+                continue;
+            }
+            match self.get(key) {
+                Ok(current) if current == Some(value) => plan.unchanged_keys += 1,
+                Ok(_) => plan.overwritten_keys += 1,
+                Err(e) if matches!(e.downcast_ref(), Some(KvsError::WrongType(_))) => {
+                    plan.overwritten_keys += 1
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(plan)
+    }
+}
+
+fn read_json(input: impl Read, progress: &Progress) -> Result<Vec<(String, String)>> {
+    #[derive(Deserialize)]
+    struct Pair {
+        key: String,
+        value: String,
+    }
+
+    progress.start(None);
+    let input = BufReader::new(Counted {
+        inner: input,
+        progress,
+    });
+    let mut pairs = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let pair: Pair = serde_json::from_str(&line)
+            .map_err(|e| format_err!("Invalid pair at line {}: {}", i + 1, e))?;
+        pairs.push((pair.key, pair.value));
+        progress.add_key();
+    }
+    Ok(pairs)
+}
+
+fn read_csv(input: impl Read, progress: &Progress) -> Result<Vec<(String, String)>> {
+    progress.start(None);
+    let mut text = String::new();
+    Counted {
+        inner: input,
+        progress,
+    }
+    .read_to_string(&mut text)?;
+    let mut records = parse_csv(&text)?.into_iter();
+    match records.next() {
+        Some((_, header)) if header == ["key", "value"] => {}
+        _ => return Err(format_err!("Expected a key,value header on the first line")),
+    }
+    let mut pairs = Vec::new();
+    for (line, record) in records {
+        let Ok([key, value]) = <[String; 2]>::try_from(record) else {
+            return Err(format_err!(
+                "Invalid record at line {}: expected a key and a value",
+                line
+            ));
+        };
+        pairs.push((key, value));
+        progress.add_key();
+    }
+    Ok(pairs)
+}
+
+// Splits CSV into its records, with the line each starts on, skipping empty lines.
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut record = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            match chars.next() {
+                Some('"') if quoted => {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        field.push('"');
+                    } else {
+                        quoted = false;
+                    }
+                }
+                Some('"') if field.is_empty() => quoted = true,
+                Some(c) if quoted => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+                Some(',') => record.push(std::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') => {
+                    line += 1;
+                    break;
+                }
+                Some(c) => field.push(c),
+                None if quoted => {
+                    return Err(format_err!(
+                        "Invalid record at line {}: unterminated quoted field",
+                        start
+                    ))
+                }
+                None => break,
+            }
+        }
+        if record.is_empty() && field.is_empty() {
+            continue;
+        }
+        record.push(field);
+        records.push((start, record));
+    }
+    Ok(records)
+}
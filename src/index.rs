@@ -0,0 +1,114 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::{Bound, RangeBounds},
+};
+
+use crate::IndexEntry;
+
+/// The data structure backing the in-memory index of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// A `HashMap`: fastest point lookups, scans have to sort the matching keys.
+    #[default]
+    Hash,
+    /// A `BTreeMap`: slightly slower point lookups, scans walk the keys in order.
+    Ordered,
+}
+
+pub(crate) enum Index {
+    Hash(HashMap<String, IndexEntry>),
+    Ordered(BTreeMap<String, IndexEntry>),
+}
+
+impl Index {
+    pub(crate) fn new(kind: IndexKind) -> Index {
+        match kind {
+            IndexKind::Hash => Index::Hash(HashMap::new()),
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&IndexEntry> {
+        match self {
+            Index::Hash(map) => map.get(key),
+            Index::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Index::Hash(map) => map.len(),
+            Index::Ordered(map) => map.len(),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        match self {
+            Index::Hash(map) => map.contains_key(key),
+            Index::Ordered(map) => map.contains_key(key),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: String, entry: IndexEntry) -> Option<IndexEntry> {
+        match self {
+            Index::Hash(map) => map.insert(key, entry),
+            Index::Ordered(map) => map.insert(key, entry),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<IndexEntry> {
+        match self {
+            Index::Hash(map) => map.remove(key),
+            Index::Ordered(map) => map.remove(key),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &str) -> Option<&mut IndexEntry> {
+        match self {
+            Index::Hash(map) => map.get_mut(key),
+            Index::Ordered(map) => map.get_mut(key),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = (&String, &IndexEntry)> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter()),
+            Index::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (&String, &mut IndexEntry)> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter_mut()),
+            Index::Ordered(map) => Box::new(map.iter_mut()),
+        }
+    }
+
+    /// Returns the keys in `range`, sorted in ascending order.
+    pub(crate) fn keys_in<R: RangeBounds<String>>(&self, range: R) -> Vec<String> {
+        match self {
+            Index::Hash(map) => {
+                let mut keys: Vec<String> = map
+                    .keys()
+                    .filter(|key| range.contains(key))
+                    .cloned()
+                    .collect();
+                keys.sort_unstable();
+                keys
+            }
+            // `BTreeMap::range` panics on inverted bounds, treat them as empty instead
+            Index::Ordered(_) if is_inverted(&range) => Vec::new(),
+            Index::Ordered(map) => map.range(range).map(|(key, _)| key.clone()).collect(),
+        }
+    }
+}
+
+fn is_inverted<R: RangeBounds<String>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Included(start), Bound::Included(end)) => start > end,
+        _ => false,
+    }
+}
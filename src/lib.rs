@@ -1,176 +1,2013 @@
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{BTreeSet, HashSet},
+    fmt,
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::{Bound, RangeBounds, RangeFull},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LockResult, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+    vec,
 };
 
-use clap::Subcommand;
-
 use failure::{format_err, Error};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+
+pub use analyze::{Distribution, KeyspaceReport};
+pub use auth::{AuthProvider, Passwords};
+pub use backup::RestorePlan;
+pub use batch::WriteBatch;
+pub use client::KvsClient;
+pub use clock::{Clock, SimClock, SystemClock};
+pub use collections::ValueKind;
+pub use compaction::{
+    CompactionHandle, CompactionProgress, CompactionWindow, SpaceUsage, WriteStalls,
+};
+pub use compression::{CodecStats, CompressionStats};
+pub use config::Config;
+pub use engine::{Engine, KvsEngine, Pairs};
+pub use error::KvsError;
+pub use eviction::{EvictionPolicy, Fifo, Lfu, Lru};
+pub use export::{SORTED_EXPORT_INDEX_INTERVAL, SORTED_EXPORT_MAGIC};
+#[cfg(feature = "fault-injection")]
+pub use fault::{Fault, FaultInjector, IoOp};
+pub use fsck::{FsckReport, FsckStatus, Problem, ProblemKind};
+pub use import::ImportPlan;
+pub use index::IndexKind;
+pub use meta::{StoreInfo, FORMAT_VERSION};
+pub use model::{check_against_model, Divergence, ModelOp, ModelStore, Outcome};
+pub use pipe::{serve_pipe, serve_repl};
+pub use progress::{Progress, ProgressReport};
+pub use protocol::{Compression, ErrorCode};
+pub use server::KvsServer;
+pub use snapshot::Snapshot;
+pub use stats::Stats;
+pub use storage::{DiskStorage, MemStorage, Storage, StorageFile};
+pub use tiering::BackingStore;
+pub use units::{parse_duration, parse_size};
+pub use view::View;
+pub use warmup::{Prefetch, WarmUp};
+pub use watch::{BufferPolicy, EventKind, KeyEvent, Subscription};
+
+use collections::Value;
+use compaction::{CompactionSchedule, StallLimits, WriteRate};
+use dedup::Blobs;
+use engine::prefix_end;
+use eviction::Eviction;
+use follower::Follower;
+use hint::Hint;
+use index::Index;
+use meta::CODEC;
+use reads::{ReadState, Readers, Sharded};
+use segment::{
+    segment_gens, segment_path, upgrade_json_segments, upgrade_legacy_log, LogReader,
+    LEGACY_LOG_FILE_NAME,
+};
+use watch::Watchers;
+
+mod analyze;
+mod auth;
+mod backup;
+mod batch;
+mod bulk;
+mod chunks;
+mod client;
+mod clock;
+mod codec;
+mod collections;
+mod compaction;
+mod compression;
+mod config;
+mod dedup;
+mod engine;
+mod error;
+mod eviction;
+mod export;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod follower;
+mod fsck;
+mod hint;
+mod hotkeys;
+mod import;
+mod index;
+mod meta;
+mod model;
+mod multi;
+mod pipe;
+mod platform;
+mod progress;
+mod protocol;
+mod reads;
+mod relocate;
+mod segment;
+mod server;
+mod snapshot;
+mod stats;
+mod storage;
+mod tiering;
+mod toml;
+mod ttl;
+mod units;
+mod view;
+mod warmup;
+mod watch;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Subcommand, Serialize, Deserialize)]
-pub enum Commands {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+#[derive(Debug, Serialize, Deserialize)]
+enum Commands {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    Rm {
+        key: String,
+    },
+    RmMany {
+        keys: Vec<String>,
+    },
+    Push {
+        key: String,
+        values: Vec<String>,
+        front: bool,
+        seq: u64,
+    },
+    List {
+        key: String,
+        items: Vec<String>,
+        seq: u64,
+    },
+    SAdd {
+        key: String,
+        members: Vec<String>,
+        seq: u64,
+    },
+    SRem {
+        key: String,
+        members: Vec<String>,
+        seq: u64,
+    },
+    SetMembers {
+        key: String,
+        members: Vec<String>,
+        seq: u64,
+    },
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+        seq: u64,
+    },
+    HDel {
+        key: String,
+        fields: Vec<String>,
+        seq: u64,
+    },
+    Fields {
+        key: String,
+        fields: Vec<(String, String)>,
+        seq: u64,
+    },
+    // a value shared by the keys whose `SetRef` records carry its hash
+    Blob {
+        hash: u64,
+        value: String,
+    },
+    SetRef {
+        key: String,
+        hash: u64,
+        seq: u64,
+    },
+    // a piece of a large value, followed by the other pieces and the `Chunked` record
+    // listing them
+    Chunk {
+        data: String,
+    },
+    // the record lengths and value sizes of the chunks right before this record
+    Chunked {
+        key: String,
+        records: Vec<u64>,
+        sizes: Vec<u64>,
+        seq: u64,
+    },
+    // the value of a `Set`, compressed with `codec`
+    Compressed {
+        key: String,
+        codec: Compression,
+        data: Vec<u8>,
+        seq: u64,
+    },
+    // starts a batch of the `count` records right after it, which are only applied once
+    // all of them were read
+    Batch {
+        count: u64,
+    },
+    // a `Set` of a value that is gone once the clock passes `expires_at`, in milliseconds
+    // since the Unix epoch
+    Expiring {
+        key: String,
+        value: String,
+        expires_at: u64,
+        seq: u64,
+    },
+}
+
+impl Commands {
+    fn seq(&self) -> Option<u64> {
+        match self {
+            Commands::Set { seq, .. }
+            | Commands::Push { seq, .. }
+            | Commands::List { seq, .. }
+            | Commands::SAdd { seq, .. }
+            | Commands::SRem { seq, .. }
+            | Commands::SetMembers { seq, .. }
+            | Commands::HSet { seq, .. }
+            | Commands::HDel { seq, .. }
+            | Commands::Fields { seq, .. }
+            | Commands::SetRef { seq, .. }
+            | Commands::Chunked { seq, .. }
+            | Commands::Compressed { seq, .. }
+            | Commands::Expiring { seq, .. } => Some(*seq),
+            Commands::Rm { .. }
+            | Commands::RmMany { .. }
+            | Commands::Blob { .. }
+            | Commands::Chunk { .. }
+            | Commands::Batch { .. } => None,
+        }
+    }
+
+    fn events(&self) -> Vec<(String, EventKind)> {
+        match self {
+            Commands::Rm { key } => vec![(key.clone(), EventKind::Removed)],
+            Commands::RmMany { keys } => keys
+                .iter()
+                .map(|key| (key.clone(), EventKind::Removed))
+                .collect(),
+            Commands::Set { key, .. }
+            | Commands::Push { key, .. }
+            | Commands::List { key, .. }
+            | Commands::SAdd { key, .. }
+            | Commands::SRem { key, .. }
+            | Commands::SetMembers { key, .. }
+            | Commands::HSet { key, .. }
+            | Commands::HDel { key, .. }
+            | Commands::Fields { key, .. }
+            | Commands::SetRef { key, .. }
+            | Commands::Chunked { key, .. }
+            | Commands::Compressed { key, .. }
+            | Commands::Expiring { key, .. } => vec![(key.clone(), EventKind::Written)],
+            Commands::Blob { .. } | Commands::Chunk { .. } | Commands::Batch { .. } => Vec::new(),
+        }
+    }
+}
+
+/// An opaque token identifying one write of a key, see `KvStore::set_if_version`.
+///
+/// It round-trips through its `Display` and `FromStr` implementations, so it can be
+/// handed to clients as a string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Version(u64);
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Version> {
+        u64::from_str_radix(s, 16)
+            .map(Version)
+            .map_err(|_| format_err!("Invalid version token {:?}", s))
+    }
+}
+
+/// Information about a stored value, returned by `KvStore::get_with_metadata`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub version: Version,
+}
+
+/// Which keys passed to `KvStore::remove_many` existed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoveSummary {
+    pub removed: Vec<String>,
+    pub missing: Vec<String>,
 }
 
+/// How a single write is made durable, overriding `OpenOptions::sync_writes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Whether the write syncs the log to disk before returning.
+    pub sync: bool,
+}
+
+/// What was read back from the log when opening a store that was not closed cleanly, or
+/// whose log ended in a torn write.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Records replayed from the log.
+    pub records: u64,
+    /// Bytes of unreadable data cut off the end of the log, e.g. a torn last write.
+    pub bytes_discarded: u64,
+}
+
+/// A handle to an open store.
+///
+/// Handles are cheap to clone and can be shared between threads. Anything writing to the
+/// store holds it exclusively, while reads share it and run in parallel, each with its
+/// own reader of the log. Point reads and scans do without the store: they only wait
+/// while a write or a compaction changes the index in memory, never for a write to reach
+/// the disk, a write stall or a subscriber holding up a write. The store is closed once
+/// the last handle is dropped, after waiting for a running compaction.
+#[derive(Clone)]
 pub struct KvStore {
+    store: Arc<RwLock<Store>>,
+    handles: Arc<Handles>,
+    reads: Arc<ReadState>,
+}
+
+// Shared by the handles of a store, to wait for its compaction once the last one is
+// dropped. Otherwise the compaction thread would close the store whenever it is done.
+struct Handles {
+    store: Arc<RwLock<Store>>,
+}
+
+impl Drop for Handles {
+    fn drop(&mut self) {
+        let compactor = unpoisoned(self.store.write()).compactor.take();
+        if let Some(compactor) = compactor {
+            if compactor.join().is_err() {
+                warn!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+pub(crate) struct Store {
     dir: PathBuf,
-    index: HashMap<String, CommandPos>,
-    reader: BufReaderWithPos<File>,
-    writer: BufWriterWithPos<File>,
+    storage: Arc<dyn Storage>,
+    clock: Arc<dyn Clock>,
+    backing_store: Option<Arc<dyn BackingStore>>,
+    // keys beyond this are evicted by the policy
+    max_keys: usize,
+    // the index, the blobs and what else reads need, which they reach without the store
+    reads: Arc<ReadState>,
+    // when the values with a TTL expire, soonest first, including ones overwritten since
+    expiries: BTreeSet<(u64, String)>,
+    // values at least this long are deduplicated
+    dedup_min_size: usize,
+    // values longer than this are split into chunks
+    chunk_size: usize,
+    // whether writes sync the log before returning
+    sync_writes: bool,
+    // string values of at least this size are compressed with the codec
+    compression: Option<(Compression, usize)>,
+    compression_stats: CompressionStats,
+    // appends to the segment of generation `gen`, which is rolled over once it grows
+    // beyond `segment_size`
+    writer: BufWriterWithPos<Box<dyn StorageFile>>,
+    gen: u64,
+    segment_size: u64,
+    // bytes in the segments before the current one
+    sealed_size: u64,
+    // bytes of the log no longer referenced by the index
     stale_size: u64,
+    // sequence number given to the next set
+    seq: u64,
+    watchers: Watchers,
+    // whether closing the store saves the hot keys
+    persist_heat: bool,
+    // how stale a segment has to be for hot keys to be moved out of it
+    relocation: Option<f64>,
+    writes_since_relocation: u64,
+    relocated: u64,
+    schedule: CompactionSchedule,
+    write_rate: WriteRate,
+    compaction: CompactionHandle,
+    // beyond which writes wait for the running compaction
+    stall_limits: Option<StallLimits>,
+    stalls: Arc<Mutex<WriteStalls>>,
+    // the thread running the last compaction, which holds on to the store until done
+    compactor: Option<JoinHandle<()>>,
+    this: Weak<RwLock<Store>>,
+    // amplification above which automatic compaction runs
+    target_amplification: f64,
+    // stale size the next automatic compaction waits for, raised after a cancellation
+    compact_after: u64,
+    info: StoreInfo,
+    recovery: Option<RecoveryReport>,
+    warm_up: Option<WarmUp>,
+    // set by `shutdown`, so dropping the store does not close it a second time
+    closed: bool,
+    // set if the store was opened read-only to follow another one writing to the files
+    follower: Option<Follower>,
+    // declared last so the directory is only released once everything else is closed,
+    // unset for followers, which do not claim it
+    _registration: Option<Registration>,
 }
 
-const THRESHOLD: u64 = 100;
+const DEFAULT_TARGET_AMPLIFICATION: f64 = 2.0;
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_WRITE_STALL: Duration = Duration::from_secs(1);
 const COMPACT_FILE_NAME: &str = "kvs.compact.log";
 
-impl KvStore {
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
+/// Options controlling how a `KvStore` is opened.
+///
+/// Sizes and durations given by users, such as `512MiB` or `250ms`, can be turned into
+/// the arguments of its methods with `parse_size` and `parse_duration`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    index: IndexKind,
+    schedule: CompactionSchedule,
+    target_amplification: Option<f64>,
+    storage: Option<Arc<dyn Storage>>,
+    clock: Option<Arc<dyn Clock>>,
+    backing_store: Option<Arc<dyn BackingStore>>,
+    eviction: Option<Eviction>,
+    dedup_min_size: Option<usize>,
+    chunk_size: Option<usize>,
+    segment_size: Option<u64>,
+    follow: Option<Duration>,
+    read_only: bool,
+    compression: Option<(Compression, usize)>,
+    sync_writes: bool,
+    prefetch: Vec<Prefetch>,
+    persist_heat: Option<Duration>,
+    relocation: Option<f64>,
+    stall_limits: Option<(f64, u64)>,
+    max_write_stall: Option<Duration>,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    /// Sets the data structure used for the in-memory index.
+    ///
+    /// Use `IndexKind::Ordered` when range scans dominate the workload.
+    pub fn index(&mut self, kind: IndexKind) -> &mut OpenOptions {
+        self.index = kind;
+        self
+    }
+
+    /// Only compacts automatically during `window`, unless another condition allows it.
+    pub fn compaction_window(&mut self, window: CompactionWindow) -> &mut OpenOptions {
+        self.schedule.window = Some(window);
+        self
+    }
+
+    /// Only compacts automatically while the store sees at most `writes_per_second`,
+    /// unless another condition allows it.
+    pub fn compaction_max_write_rate(&mut self, writes_per_second: f64) -> &mut OpenOptions {
+        self.schedule.max_write_rate = Some(writes_per_second);
+        self
+    }
+
+    /// Compacts automatically once the log is more than `target` times the size of the
+    /// live data, 2 by default. See `KvStore::space_usage`.
+    ///
+    /// Compactions run on a background thread, over the segments that were full when
+    /// they started, while reads and writes go on.
+    ///
+    /// Lower targets use less disk at the cost of compacting more often.
+    pub fn target_amplification(&mut self, target: f64) -> &mut OpenOptions {
+        self.target_amplification = Some(target);
+        self
+    }
+
+    /// Makes writes wait for the running compaction while the log is more than
+    /// `amplification` times the size of the live data or holds more than `stale_bytes`
+    /// of stale data, instead of letting it grow without bound when compactions cannot keep
+    /// up. Beyond either limit, automatic compactions also run outside their window and
+    /// write rate limit.
+    ///
+    /// Each write waits for at most a second, see `OpenOptions::max_write_stall`. Stalls
+    /// are counted in `KvStore::write_stalls`. Writes never stall by default.
+    pub fn write_stall(&mut self, amplification: f64, stale_bytes: u64) -> &mut OpenOptions {
+        self.stall_limits = Some((amplification, stale_bytes));
+        self
+    }
+
+    /// Sets the longest a single write waits, see `OpenOptions::write_stall`.
+    pub fn max_write_stall(&mut self, max: Duration) -> &mut OpenOptions {
+        self.max_write_stall = Some(max);
+        self
+    }
 
-        let mut reader = BufReaderWithPos::new(get_log_file(&path)?)?;
-        let mut writer = BufWriterWithPos::new(get_log_file(&path)?)?;
+    /// Syncs the log to disk before every write returns, so the writes acknowledged
+    /// survive a crash of the system and not only of the process. Off by default, writes
+    /// are only handed to the operating system. `WriteOptions` override it per write.
+    pub fn sync_writes(&mut self, sync: bool) -> &mut OpenOptions {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Reads what `prefetch` asks for right after opening the store, so the operating
+    /// system caches it before the first reads need it. Can be given more than once.
+    ///
+    /// Opening takes longer in exchange. Failing to read ahead is logged and does not fail
+    /// the open, see `KvStore::warm_up` for what was read.
+    pub fn prefetch(&mut self, prefetch: Prefetch) -> &mut OpenOptions {
+        self.prefetch.push(prefetch);
+        self
+    }
+
+    /// Saves the keys `KvStore::hot_keys` reports to `kvs.heat` every `interval` and when
+    /// the store is closed.
+    ///
+    /// A store opened later starts out with those keys hot, with or without this option.
+    /// Its eviction policy treats them as the most recently accessed keys, so they are the
+    /// last to be evicted, and `Prefetch::Hot` reads their values ahead.
+    pub fn persist_heat(&mut self, interval: Duration) -> &mut OpenOptions {
+        self.persist_heat = Some(interval);
+        self
+    }
+
+    /// Every thousand writes, copies the values of the hottest keys to the current
+    /// segment if the segment holding them is at least `min_stale_fraction` stale, so
+    /// their reads hit recently written data instead of waiting for a compaction.
+    ///
+    /// The copies make the old records stale, which the next compaction reclaims.
+    /// Shared values stay where they are. See `KvStore::relocated_keys`.
+    pub fn relocate_hot_keys(&mut self, min_stale_fraction: f64) -> &mut OpenOptions {
+        self.relocation = Some(min_stale_fraction);
+        self
+    }
+
+    /// Keeps the files of the store in `storage` instead of on the local disk.
+    pub fn storage(&mut self, storage: impl Storage + 'static) -> &mut OpenOptions {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    /// Takes the time from `clock` instead of the system clock.
+    pub fn clock(&mut self, clock: impl Clock + 'static) -> &mut OpenOptions {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Puts the store in front of `origin`: missing keys are fetched from it and cached,
+    /// and sets and removals are written through to it before they are applied locally.
+    pub fn backing_store(&mut self, origin: impl BackingStore + 'static) -> &mut OpenOptions {
+        self.backing_store = Some(Arc::new(origin));
+        self
+    }
+
+    /// Keeps at most `max_keys` keys, evicting the keys chosen by `policy` beyond that.
+    ///
+    /// Evicted keys are only removed locally: with a backing store they are fetched again
+    /// on the next read, without one they are gone. Every opened store gets its own
+    /// copy of `policy`.
+    pub fn eviction<P>(&mut self, max_keys: usize, policy: P) -> &mut OpenOptions
+    where
+        P: EvictionPolicy + Clone + Sync + 'static,
+    {
+        self.eviction = Some(Eviction {
+            max_keys,
+            policy: Arc::new(move || Box::new(policy.clone())),
+        });
+        self
+    }
+
+    /// Stores string values of at least `min_size` bytes once per distinct content,
+    /// however many keys they are set under.
+    ///
+    /// The shared copy is only reclaimed by a compaction after the last key referring to
+    /// it is overwritten or removed. Deduplication only applies to `set`, other writes
+    /// keep their values inline.
+    pub fn dedup_values(&mut self, min_size: usize) -> &mut OpenOptions {
+        self.dedup_min_size = Some(min_size);
+        self
+    }
+
+    /// Splits string values longer than `chunk_size` bytes into records of at most that
+    /// size, so no single record grows with the values.
+    ///
+    /// Chunking only applies to `set`, and values that are deduplicated are kept whole.
+    pub fn chunk_values(&mut self, chunk_size: usize) -> &mut OpenOptions {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Compresses string values of at least `min_size` bytes with `codec` before writing
+    /// them to the log, and decompresses them when they are read.
+    ///
+    /// Each record says whether it is compressed, so a store can be opened with other
+    /// settings later and values that do not get smaller are written as they are.
+    /// Compression only applies to `set`, and values that are deduplicated or split into
+    /// chunks are kept uncompressed. See `KvStore::compression_stats` for what it saves.
+    pub fn compress_values(&mut self, codec: Compression, min_size: usize) -> &mut OpenOptions {
+        self.compression = Some((codec, min_size));
+        self
+    }
+
+    /// Starts a new segment of the log, `1.log`, `2.log` and so on, once the current one
+    /// reached `segment_size` bytes. Defaults to 64 MiB.
+    ///
+    /// Segments only roll over between writes, so the records of one write always end up
+    /// in the same segment, which may grow a little beyond the size.
+    pub fn segment_size(&mut self, segment_size: u64) -> &mut OpenOptions {
+        self.segment_size = Some(segment_size);
+        self
+    }
+
+    /// Opens the store read-only, to serve reads next to another process writing to it,
+    /// such as from a copy of its directory that is synced from time to time or from a
+    /// shared file system. Every `interval`, a background thread looks at the segments of
+    /// the log and refreshes the index once records were added to them or a compaction
+    /// replaced them, see `KvStore::refresh`. Looking only compares the list of segments
+    /// and the size of the last one and does not block reads, so short intervals bound
+    /// the delay before new writes show up at little cost.
+    ///
+    /// A follower never writes to the directory, writes to it fail with
+    /// `KvsError::ReadOnly`. It can be opened in the same process as the store it follows.
+    pub fn follow(&mut self, interval: Duration) -> &mut OpenOptions {
+        self.follow = Some(interval);
+        self
+    }
+
+    /// Opens the store read-only like `follow`, but only reads the records added since
+    /// when `KvStore::refresh` is called, such as from an analytics process running next
+    /// to the one writing.
+    ///
+    /// Any number of processes may read a store while at most one writes to it. The writer
+    /// holds an exclusive lock on `kvs.lock`, a second one fails to open with
+    /// `KvsError::Locked`. Readers hold a shared lock on `kvs.readers.lock`, which they
+    /// never create, and never take the writer's. A reader cannot become the writer, it
+    /// has to be opened again without this option once the writer is closed. Restoring a
+    /// snapshot and repairing the store replace its files, so they also fail while any
+    /// process reads it.
+    pub fn read_only(&mut self, read_only: bool) -> &mut OpenOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        let mut store = Store::open_with(path.into(), self)?;
+        let reads = store.reads.clone();
+        let store = Arc::new_cyclic(|this| {
+            store.this = this.clone();
+            RwLock::new(store)
+        });
+        let store = KvStore {
+            handles: Arc::new(Handles {
+                store: store.clone(),
+            }),
+            store,
+            reads,
+        };
+        if !self.prefetch.is_empty() {
+            let mut store = store.write();
+            match store.warm_up(&self.prefetch) {
+                Ok(warm_up) => store.warm_up = Some(warm_up),
+                Err(e) => warn!("Failed to read {} ahead: {}", store.dir.display(), e),
+            }
+        }
+        if let Some(interval) = self.follow {
+            follower::spawn_refresher(Arc::downgrade(&store.store), interval);
+        } else if !self.read_only {
+            if let Some(interval) = self.persist_heat {
+                hotkeys::spawn_heat_saver(Arc::downgrade(&store.store), interval);
+            }
+            // the limit may have been lowered since the store was last open
+            store.write().evict()?;
+        }
+        Ok(store)
+    }
+
+    fn resolved_storage(&self) -> Arc<dyn Storage> {
+        self.storage
+            .clone()
+            .unwrap_or_else(|| Arc::new(DiskStorage))
+    }
+
+    fn resolved_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+}
+
+impl Store {
+    fn open_with(path: PathBuf, options: &OpenOptions) -> Result<Store> {
+        let storage = options.resolved_storage();
+        let clock = options.resolved_clock();
+        // a follower leaves everything on disk to the store it follows
+        let follow = options.follow.is_some() || options.read_only;
+        let mut registration = None;
+        let mut reader_lock = None;
+        if follow {
+            reader_lock = lock_as_reader(&*storage, &path)?;
+        } else {
+            registration = Some(Registration::acquire(&*storage, &path)?);
+            recover_compaction(&*storage, &path)?;
+            upgrade_legacy_log(&*storage, &path)?;
+        }
+        let mut info = StoreInfo::load(&*storage, &*clock, &path)?;
+        // a store that was not closed cleanly may end in a torn or garbled record, which
+        // is cut off instead of failing the open
+        let dirty = info.clean_shutdown != Some(true) && !follow;
+        let mut repaired = false;
+        if !follow {
+            upgrade_json_segments(&*storage, &path, dirty)?;
+            info.format_version = FORMAT_VERSION;
+            info.codec = CODEC.to_owned();
+        }
+        let mut gens = segment_gens(&*storage, &path)?;
+        if follow && gens.is_empty() {
+            return Err(format_err!("No store to follow in {}", path.display()));
+        }
+        let gen = gens.last().copied().unwrap_or(1);
+        if gens.is_empty() {
+            gens.push(gen);
+        }
         let mut stale_size = 0;
-        let mut index = HashMap::new();
-        let mut pos = reader.seek(SeekFrom::Start(0))?;
-        writer.seek(SeekFrom::End(0))?;
-        // load the data from file
-        let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Commands>();
-        while let Some(cmd) = stream.next() {
-            let new_pos = stream.byte_offset() as u64;
-            match cmd? {
-                Commands::Set { key, .. } => {
-                    if let Some(old_cmd) = index.insert(
-                        key,
-                        CommandPos {
-                            pos,
-                            len: new_pos - pos,
-                        },
-                    ) {
-                        stale_size += old_cmd.len;
-                    }
+        let mut sealed_size = 0;
+        let mut seq = 0;
+        let mut index = Index::new(options.index);
+        let mut blobs = Blobs::default();
+        // the hint holds the index as of its end, so only the records after it are replayed
+        let (mut start_gen, mut start_pos) = (0, 0);
+        if let Some(hint) = Hint::load(&*storage, &path, &gens) {
+            (start_gen, start_pos) = hint.end();
+            sealed_size = hint.sealed_size();
+            seq = hint.seq;
+            stale_size = hint.stale_size;
+            (index, blobs) = hint.restore(options.index);
+        }
+        let mut records = 0;
+        let mut bytes_discarded = 0;
+        let mut end = 0;
+        // load the data from the segments, oldest first
+        for &segment_gen in gens.iter().filter(|&&segment_gen| segment_gen >= start_gen) {
+            let segment = segment_path(&path, segment_gen);
+            let mut file = if follow {
+                storage.open_read(&segment)?
+            } else {
+                storage.open(&segment)?
+            };
+            let start = if segment_gen == start_gen {
+                start_pos
+            } else {
+                0
+            };
+            let mut replay = Replay {
+                index: &mut index,
+                blobs: &mut blobs,
+                seq: &mut seq,
+                stale_size: &mut stale_size,
+            };
+            let replayed = replay.segment(&mut file, segment_gen, start)?;
+            records += replayed.records;
+            end = replayed.end;
+            if let Some(e) = replayed.damage {
+                // only the current segment was written to when the store went down, and a
+                // follower may see the record being written to it. Closing does not sync
+                // the log, so even a clean store may lose the end of its last write to a
+                // crash of the system.
+                let torn = codec::Error::is_cut_short(&e);
+                if segment_gen != gen || !(dirty || follow || torn) {
+                    return Err(e);
                 }
-                Commands::Rm { key } => {
-                    if let Some(old_cmd) = index.remove(&key) {
-                        stale_size += old_cmd.len;
-                    }
+                if !follow {
+                    repaired = true;
+                    warn!(
+                        "Unreadable record at offset {} of segment {}: {}",
+                        end, segment_gen, e
+                    );
+                    bytes_discarded = file.size()? - end;
+                    file.set_len(end)?;
+                }
+            }
+            if segment_gen != gen {
+                sealed_size += end;
+            }
+        }
+        // compactions may have dropped the records of the latest versions
+        seq = seq.max(info.high_water_seq.unwrap_or(0));
+        let follower = if follow {
+            Some(Follower::new(gens, options, reader_lock))
+        } else {
+            // a blob whose reference was cut off by a crash
+            stale_size += blobs.remove_unreferenced();
+            None
+        };
+        let writer = match &follower {
+            // never written to, it only tracks how far the current segment was read
+            Some(_) => BufWriterWithPos {
+                writer: BufWriter::new(storage.open_read(&segment_path(&path, gen))?),
+                pos: end,
+            },
+            None => segment_writer(storage.open(&segment_path(&path, gen))?)?,
+        };
+
+        let recovery = if dirty || repaired {
+            warn!(
+                "{} {}, recovered {} records and discarded {} bytes",
+                path.display(),
+                if dirty {
+                    "was not closed cleanly"
+                } else {
+                    "ends in a torn write"
+                },
+                records,
+                bytes_discarded
+            );
+            Some(RecoveryReport {
+                records,
+                bytes_discarded,
+            })
+        } else {
+            None
+        };
+
+        let hot_keys = hotkeys::load_heat(&*storage, &path);
+        let mut eviction_policy = options
+            .eviction
+            .as_ref()
+            .map(|eviction| (eviction.policy)());
+        if let Some(policy) = &mut eviction_policy {
+            let mut keys: Vec<(u64, &String)> = index
+                .iter_mut()
+                .map(|(key, entry)| (entry.seq, key))
+                .collect();
+            keys.sort();
+            for (_, key) in keys {
+                policy.on_insert(key);
+            }
+            // the hottest keys last, so they are evicted last
+            for (key, _) in hot_keys.top(usize::MAX).iter().rev() {
+                if index.contains_key(key) {
+                    policy.on_access(key);
                 }
-                _ => {}
             }
-            pos = new_pos;
         }
 
-        Ok(KvStore {
+        // the flag stays unset on disk until the store is dropped
+        if follower.is_none() {
+            info.save(&*storage, &path, false)?;
+        }
+
+        let compression_stats = CompressionStats::default();
+        let reads = Arc::new(ReadState {
+            dir: path.clone(),
+            storage: storage.clone(),
+            clock: clock.clone(),
+            compression_stats: compression_stats.clone(),
+            index: RwLock::new(index),
+            blobs: RwLock::new(blobs),
+            hot_keys,
+            eviction_policy: eviction_policy.map(Mutex::new),
+            accessed: Sharded::default(),
+            has_origin: options.backing_store.is_some(),
+            readers: Readers::default(),
+        });
+        let mut store = Store {
+            storage,
+            clock,
+            backing_store: options.backing_store.clone(),
+            max_keys: options
+                .eviction
+                .as_ref()
+                .map_or(usize::MAX, |eviction| eviction.max_keys),
+            reads,
+            dedup_min_size: options.dedup_min_size.unwrap_or(usize::MAX),
+            chunk_size: options.chunk_size.unwrap_or(usize::MAX),
+            compression: options.compression,
+            sync_writes: options.sync_writes,
+            compression_stats,
+            closed: false,
+            follower,
+            _registration: registration,
+            info,
+            recovery,
+            warm_up: None,
             dir: path,
-            index,
-            reader,
+            expiries: BTreeSet::new(),
             writer,
+            gen,
+            segment_size: options.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE),
+            sealed_size,
             stale_size,
-        })
+            seq,
+            watchers: Watchers::default(),
+            persist_heat: options.persist_heat.is_some() && !follow,
+            relocation: options.relocation,
+            writes_since_relocation: 0,
+            relocated: 0,
+            schedule: options.schedule.clone(),
+            write_rate: WriteRate::default(),
+            compaction: CompactionHandle::default(),
+            stall_limits: options
+                .stall_limits
+                .map(|(amplification, stale_bytes)| StallLimits {
+                    amplification,
+                    stale_bytes,
+                    max_stall: options.max_write_stall.unwrap_or(DEFAULT_MAX_WRITE_STALL),
+                }),
+            stalls: Arc::default(),
+            compactor: None,
+            this: Weak::new(),
+            target_amplification: options
+                .target_amplification
+                .unwrap_or(DEFAULT_TARGET_AMPLIFICATION),
+            compact_after: 0,
+        };
+        store.track_expiries();
+        store.purge_expired();
+        Ok(store)
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Commands::Set {
-            key: key.clone(),
-            value,
-        };
+    // Appends `cmd` to the log and applies it to the index.
+    fn write_record(&mut self, cmd: Commands) -> Result<()> {
+        let cmd_pos = self.append_record(&cmd)?;
+        self.flush_log()?;
+        self.apply_record(cmd, cmd_pos);
+        self.after_write()
+    }
+
+    // Flushes the records appended so far, syncing them to disk if writes are synced.
+    fn flush_log(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.sync_writes {
+            self.writer.writer.get_mut().sync()?;
+        }
+        Ok(())
+    }
+
+    // Runs the write `op` synced or not as `options` say, whatever the store does otherwise.
+    fn with_write_options<T>(
+        &mut self,
+        options: WriteOptions,
+        op: impl FnOnce(&mut Store) -> Result<T>,
+    ) -> Result<T> {
+        let sync_writes = mem::replace(&mut self.sync_writes, options.sync);
+        let result = op(self);
+        self.sync_writes = sync_writes;
+        result
+    }
+
+    // Appends `cmd` to the log buffer, without flushing it.
+    fn append_record(&mut self, cmd: &Commands) -> Result<CommandPos> {
+        self.append_encoded(&codec::to_vec(cmd)?)
+    }
+
+    // Appends a record encoded with `codec::to_vec`.
+    fn append_encoded(&mut self, bytes: &[u8]) -> Result<CommandPos> {
+        self.check_writable()?;
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+        let len = codec::write_encoded(&mut self.writer, bytes)?;
+        Ok(CommandPos {
+            gen: self.gen,
+            pos,
+            len,
+        })
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.follower.is_some() {
+            return Err(KvsError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    // Starts the segment after the current one, once the current one is full.
+    fn roll_over(&mut self) -> Result<()> {
+        if self.writer.pos < self.segment_size {
+            return Ok(());
+        }
+        self.seal_segment()
+    }
+
+    // Moves the writer to a new segment, so the current one is never written again.
+    fn seal_segment(&mut self) -> Result<()> {
         self.writer.flush()?;
-        let len = self.writer.pos - pos;
-        if let Some(old_cmd) = self.index.insert(key, CommandPos { pos, len }) {
-            self.stale_size += old_cmd.len;
+        let writer = self
+            .storage
+            .create(&segment_path(&self.dir, self.gen + 1))?;
+        self.sealed_size += self.writer.pos;
+        self.writer = segment_writer(writer)?;
+        self.gen += 1;
+        Ok(())
+    }
+
+    // Applies `cmd`, already written at `cmd_pos`, to the index and tells everyone
+    // following the keys about it.
+    fn apply_record(&mut self, cmd: Commands, cmd_pos: CommandPos) {
+        self.apply_records(vec![(cmd, cmd_pos)]);
+    }
+
+    // Applies records already written to the index at once, so reads see either none or all
+    // of them, and then tells everyone following the keys about them.
+    fn apply_records(&mut self, records: Vec<(Commands, CommandPos)>) {
+        let mut events = Vec::with_capacity(records.len());
+        for (cmd, _) in &records {
+            if let Some(cmd_seq) = cmd.seq() {
+                self.seq = self.seq.max(cmd_seq + 1);
+            }
+            self.track_expiry(cmd);
+            events.push(cmd.events());
         }
+        {
+            let mut index = unpoisoned(self.reads.index.write());
+            let mut blobs = unpoisoned(self.reads.blobs.write());
+            // the reads came before this write
+            if let Some(policy) = &self.reads.eviction_policy {
+                self.reads
+                    .flush_accesses(&index, &mut **unpoisoned(policy.lock()));
+            }
+            for ((cmd, cmd_pos), events) in records.into_iter().zip(&events) {
+                for (key, kind) in events {
+                    self.reads.hot_keys.record(key);
+                    if let Some(policy) = &self.reads.eviction_policy {
+                        let mut policy = unpoisoned(policy.lock());
+                        match kind {
+                            EventKind::Removed => policy.on_remove(key),
+                            EventKind::Written if index.contains_key(key) => policy.on_access(key),
+                            EventKind::Written => policy.on_insert(key),
+                        }
+                    }
+                }
+                self.stale_size += index_record(&mut index, &mut blobs, cmd, cmd_pos);
+            }
+        }
+        // a subscriber may hold up the write here, but no read
+        for (key, kind) in events.into_iter().flatten() {
+            self.watchers.notify(&key, kind);
+        }
+    }
+
+    // Enforces the key limit and compacts if needed once a write was applied.
+    fn after_write(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        self.purge_expired();
+        self.evict()?;
+        self.relocate_hot_keys()?;
+        self.roll_over()?;
 
-        if self.stale_size > THRESHOLD {
-            self.compact()?;
+        self.write_rate.record(now);
+        if !self.compaction.is_running()
+            && self.space_usage().amplification() > self.target_amplification
+            && self.stale_size > self.compact_after
+            && (self.schedule.allows(&self.write_rate, now) || self.stall_for().is_some())
+        {
+            self.start_compaction()?;
         }
+        Ok(())
+    }
 
+    fn evict(&mut self) -> Result<()> {
+        while self.index().len() > self.max_keys {
+            let victim = self
+                .reads
+                .eviction_policy
+                .as_ref()
+                .and_then(|policy| unpoisoned(policy.lock()).victim());
+            let Some(victim) = victim else {
+                break;
+            };
+            if !self.index().contains_key(&victim) {
+                warn!("Eviction policy chose missing key {}", victim);
+                break;
+            }
+            self.write_record(Commands::Rm { key: victim })?;
+        }
         Ok(())
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd) = self.index.get(&key) {
-            self.reader.seek(SeekFrom::Start(cmd.pos))?;
-            let cmd_reader = self.reader.by_ref().take(cmd.len);
-            if let Commands::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
+    // Runs `read` with a reader of the log no other read is using.
+    fn with_reader<T>(&self, read: impl FnOnce(&mut LogReader) -> Result<T>) -> Result<T> {
+        self.reads.with_reader(read)
+    }
+
+    // Locks the index for a read, which writes to the index wait for.
+    fn index(&self) -> RwLockReadGuard<'_, Index> {
+        unpoisoned(self.reads.index.read())
+    }
+
+    // Locks the index for a change, which reads wait for, so it is held for nothing else.
+    fn index_mut(&self) -> RwLockWriteGuard<'_, Index> {
+        unpoisoned(self.reads.index.write())
+    }
+
+    fn blobs(&self) -> RwLockReadGuard<'_, Blobs> {
+        unpoisoned(self.reads.blobs.read())
+    }
+
+    fn blobs_mut(&self) -> RwLockWriteGuard<'_, Blobs> {
+        unpoisoned(self.reads.blobs.write())
+    }
+
+    pub(crate) fn kind(&self, key: &str) -> Option<ValueKind> {
+        self.reads.kind(key)
+    }
+
+    pub(crate) fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_versioned(key, value).map(|_| ())
+    }
+
+    fn set_versioned(&mut self, key: String, value: String) -> Result<Version> {
+        if let Some(origin) = &self.backing_store {
+            origin.store(&key, &value)?;
+        }
+        if value.len() >= self.dedup_min_size {
+            return self.set_deduplicated(key, value);
+        }
+        if value.len() > self.chunk_size {
+            return self.set_chunked(key, value);
+        }
+        let seq = self.seq;
+        self.write_record(self.set_record(key, value, seq))?;
+        Ok(Version(seq))
+    }
+
+    pub(crate) fn set_if_version(
+        &mut self,
+        key: String,
+        version: Version,
+        value: String,
+    ) -> Result<Version> {
+        match self.entry(&key) {
+            Some(entry) if entry.seq == version.0 => self.set_versioned(key, value),
+            _ => Err(KvsError::VersionConflict(key).into()),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_metadata(key)?.map(|(value, _)| value))
+    }
+
+    pub(crate) fn get_with_metadata(&mut self, key: String) -> Result<Option<(String, Metadata)>> {
+        if let Some(found) = self.get_local(&key)? {
+            return Ok(Some(found));
+        }
+        let Some(origin) = &self.backing_store else {
+            return Ok(None);
+        };
+        let Some(value) = origin.fetch(&key)? else {
+            return Ok(None);
+        };
+        // cache the value without writing it back to the origin
+        let seq = self.seq;
+        self.write_record(self.set_record(key, value.clone(), seq))?;
+        Ok(Some((
+            value,
+            Metadata {
+                version: Version(seq),
+            },
+        )))
+    }
+
+    // Reads the string value of `key` if the store holds it, without asking the origin.
+    pub(crate) fn get_local(&self, key: &str) -> Result<Option<(String, Metadata)>> {
+        self.reads.get(key)
+    }
+
+    // Reads the value `key` holds before a write replaces it. A value only the origin holds
+    // is not cached, it would be overwritten right away.
+    fn previous_value(&self, key: &str) -> Result<Option<String>> {
+        if let Some((value, _)) = self.get_local(key)? {
+            return Ok(Some(value));
+        }
+        match &self.backing_store {
+            Some(origin) => origin.fetch(key),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn set_returning(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old_value = self.previous_value(&key)?;
+        self.set(key, value)?;
+        Ok(old_value)
+    }
+
+    pub(crate) fn remove_returning(&mut self, key: String) -> Result<Option<String>> {
+        let old_value = self.previous_value(&key)?;
+        if old_value.is_some() {
+            self.remove(key)?;
+        }
+        Ok(old_value)
+    }
+
+    pub(crate) fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if current.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    // Replaces the integer value of `key`, 0 if missing, with what `op` makes of it.
+    pub(crate) fn update_i64(
+        &mut self,
+        key: String,
+        op: impl FnOnce(i64) -> Option<i64>,
+    ) -> Result<i64> {
+        let current = match self.get(key.clone())? {
+            Some(value) => value.parse().map_err(|_| KvsError::TypeMismatch {
+                key: key.clone(),
+                expected: "i64",
+            })?,
+            None => 0,
+        };
+        let value = op(current)
+            .ok_or_else(|| format_err!("The value of key {} would overflow an i64", key))?;
+        self.set_i64(key, value)?;
+        Ok(value)
+    }
+
+    pub(crate) fn set_i64(&mut self, key: String, value: i64) -> Result<()> {
+        self.set(key, value.to_string())
+    }
+
+    pub(crate) fn set_u64(&mut self, key: String, value: u64) -> Result<()> {
+        self.set(key, value.to_string())
+    }
+
+    pub(crate) fn set_f64(&mut self, key: String, value: f64) -> Result<()> {
+        self.set(key, value.to_string())
+    }
+
+    pub(crate) fn set_bool(&mut self, key: String, value: bool) -> Result<()> {
+        self.set(key, value.to_string())
+    }
+
+    // Returns the keys of the string values in `range`, in ascending order.
+    pub(crate) fn string_keys_in<R: RangeBounds<String>>(&self, range: R) -> Vec<String> {
+        self.reads.string_keys_in(range)
+    }
+
+    pub(crate) fn remove(&mut self, key: String) -> Result<()> {
+        let in_origin = match &self.backing_store {
+            Some(origin) => origin.remove(&key)?,
+            None => false,
+        };
+        if self.entry(&key).is_none() {
+            return if in_origin {
+                Ok(())
             } else {
-                Ok(None)
+                Err(KvsError::KeyNotFound.into())
+            };
+        }
+        self.write_record(Commands::Rm { key })
+    }
+
+    pub(crate) fn remove_many<I>(&mut self, keys: I) -> Result<RemoveSummary>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut summary = RemoveSummary::default();
+        let mut seen = HashSet::new();
+        for key in keys {
+            if !seen.insert(key.clone()) {
+                continue;
             }
-        } else {
-            Ok(None)
+            let in_origin = match &self.backing_store {
+                Some(origin) => origin.remove(&key)?,
+                None => false,
+            };
+            if in_origin || self.entry(&key).is_some() {
+                summary.removed.push(key);
+            } else {
+                summary.missing.push(key);
+            }
+        }
+        // keys only the origin had need no record
+        let local: Vec<String> = summary
+            .removed
+            .iter()
+            .filter(|key| self.entry(key).is_some())
+            .cloned()
+            .collect();
+        if local.is_empty() {
+            return Ok(summary);
+        }
+
+        self.write_record(Commands::RmMany { keys: local })?;
+        Ok(summary)
+    }
+
+    pub(crate) fn compaction_handle(&self) -> CompactionHandle {
+        self.compaction.clone()
+    }
+
+    // How long writes may wait for the compaction, if the log is beyond the stall limits.
+    fn stall_for(&self) -> Option<Duration> {
+        let limits = self.stall_limits.as_ref()?;
+        limits
+            .exceeded(self.space_usage())
+            .then_some(limits.max_stall)
+    }
+
+    pub(crate) fn space_usage(&self) -> SpaceUsage {
+        SpaceUsage {
+            live_bytes: self.sealed_size + self.writer.pos - self.stale_size,
+            disk_bytes: self.sealed_size + self.writer.pos,
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.get(&key).is_none() {
-            return Err(format_err!("Key not found"));
+    pub(crate) fn shutdown(mut self) -> Result<()> {
+        self.closed = true;
+        if self.follower.is_some() {
+            return Ok(());
         }
-        let cmd = Commands::Rm { key: key.clone() };
-        serde_json::to_writer(&mut self.writer, &cmd)?;
         self.writer.flush()?;
-        if let Some(old_cmd) = self.index.remove(&key) {
-            self.stale_size += old_cmd.len;
+        self.writer.writer.get_mut().sync()?;
+        self.save_hint()?;
+        if self.persist_heat {
+            self.save_heat()?;
         }
-        if self.stale_size > THRESHOLD {
-            self.compact()?;
+        self.info.save(&*self.storage, &self.dir, true)
+    }
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        OpenOptions::new().open(path)
+    }
+
+    // Locks the store for a read, shared with other reads.
+    fn read(&self) -> RwLockReadGuard<'_, Store> {
+        unpoisoned(self.store.read())
+    }
+
+    // Locks the store for anything that changes it, once the running compaction got the
+    // log back within the stall limits or the stall timed out.
+    fn write(&self) -> RwLockWriteGuard<'_, Store> {
+        let store = unpoisoned(self.store.write());
+        let Some(max_stall) = store.stall_for().filter(|_| store.compaction.is_running()) else {
+            return store;
+        };
+        let (compaction, stalls) = (store.compaction.clone(), store.stalls.clone());
+        // the compaction needs the store to finish
+        drop(store);
+        let started = Instant::now();
+        compaction.wait_timeout(max_stall);
+        unpoisoned(stalls.lock()).record(started.elapsed());
+        unpoisoned(self.store.write())
+    }
+
+    /// Returns the kind of value stored under `key`, without reading it.
+    pub fn kind(&self, key: &str) -> Option<ValueKind> {
+        self.reads.kind(key)
+    }
+
+    /// Whether the store holds `key`, of any kind, answered from the index without
+    /// reading the log. Unlike `get`, it does not ask the backing store.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.reads.contains_key(key)
+    }
+
+    /// Returns the number of keys in the store, of any kind.
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.write().set(key, value)
+    }
+
+    /// Sets `key` to `value` only if its current version is still `version`.
+    ///
+    /// Returns the version of the new value, or `KvsError::VersionConflict` if the key
+    /// was written or removed since `version` was obtained.
+    pub fn set_if_version(&self, key: String, version: Version, value: String) -> Result<Version> {
+        self.write().set_if_version(key, version, value)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_metadata(key)?.map(|(value, _)| value))
+    }
+
+    /// Like `get`, but also returns the version of the value for use with
+    /// `set_if_version`.
+    pub fn get_with_metadata(&self, key: String) -> Result<Option<(String, Metadata)>> {
+        match self.reads.get(&key)? {
+            // caching a value fetched from the origin writes to the store
+            None if self.reads.has_origin => self.write().get_with_metadata(key),
+            found => Ok(found),
         }
+    }
 
-        Ok(())
+    /// Sets `key` to `value` and returns the value it replaced, if any.
+    ///
+    /// The old value is read under the same lock as the write, so no other write gets in
+    /// between. A value only the backing store holds is returned without caching it.
+    pub fn set_returning(&self, key: String, value: String) -> Result<Option<String>> {
+        self.write().set_returning(key, value)
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let file = self.dir.join(COMPACT_FILE_NAME);
-        let mut compact_writer = BufWriterWithPos::new(open_file(&file)?)?;
-        for cmd_pos in &mut self.index.values_mut() {
-            self.reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let mut cmd_reader = self.reader.by_ref().take(cmd_pos.len);
-            let pos = compact_writer.pos;
-            let len = io::copy(&mut cmd_reader, &mut compact_writer)?;
-            compact_writer.flush()?;
-            *cmd_pos = CommandPos { pos, len };
-        }
-        compact_writer.flush()?;
-
-        // Delete old file
-        std::fs::remove_file(self.dir.join("kvs.log"))?;
-        // Rename compact file
-        std::fs::rename(file, self.dir.join("kvs.log"))?;
-
-        // reconfigure the reader and writer
-        self.reader = BufReaderWithPos::new(get_log_file(&self.dir)?)?;
-        self.writer = BufWriterWithPos::new(get_log_file(&self.dir)?)?;
-        self.reader.seek(SeekFrom::Start(0))?;
-        self.writer.seek(SeekFrom::End(0))?;
-        self.stale_size = 0;
-        Ok(())
+    /// Removes `key` and returns the value it held, or `None` if it did not exist, which
+    /// unlike `remove` is no error.
+    pub fn remove_returning(&self, key: String) -> Result<Option<String>> {
+        self.write().remove_returning(key)
+    }
+
+    /// Same as `set_returning`, named after the Redis command.
+    pub fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.set_returning(key, value)
+    }
+
+    /// Same as `remove_returning`, named after the Redis command.
+    pub fn get_del(&self, key: String) -> Result<Option<String>> {
+        self.remove_returning(key)
+    }
+
+    /// Replaces the value of `key` with `new` if it is still `expected`, returning whether
+    /// it was. `None` stands for a missing key on both sides, so `expected` set to `None`
+    /// only creates the key and `new` set to `None` removes it.
+    ///
+    /// The check and the write happen under the same lock, no other write gets in between.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.write().compare_and_swap(key, expected, new)
+    }
+
+    pub fn get_i64(&self, key: String) -> Result<Option<i64>> {
+        self.get_parsed(key, "i64")
+    }
+
+    pub fn set_i64(&self, key: String, value: i64) -> Result<()> {
+        self.write().set_i64(key, value)
+    }
+
+    /// Adds `delta` to the integer value of `key`, starting from 0 if it is missing, and
+    /// returns the result.
+    ///
+    /// Fails with `KvsError::TypeMismatch` if the value is no `i64`, leaving it as it is.
+    pub fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.write()
+            .update_i64(key, |value| value.checked_add(delta))
+    }
+
+    /// Subtracts `delta` from the integer value of `key`, see `incr`.
+    pub fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.write()
+            .update_i64(key, |value| value.checked_sub(delta))
+    }
+
+    pub fn get_u64(&self, key: String) -> Result<Option<u64>> {
+        self.get_parsed(key, "u64")
+    }
+
+    pub fn set_u64(&self, key: String, value: u64) -> Result<()> {
+        self.write().set_u64(key, value)
+    }
+
+    pub fn get_f64(&self, key: String) -> Result<Option<f64>> {
+        self.get_parsed(key, "f64")
+    }
+
+    pub fn set_f64(&self, key: String, value: f64) -> Result<()> {
+        self.write().set_f64(key, value)
+    }
+
+    /// Reads a value stored as `true` or `false`.
+    pub fn get_bool(&self, key: String) -> Result<Option<bool>> {
+        self.get_parsed(key, "bool")
+    }
+
+    pub fn set_bool(&self, key: String, value: bool) -> Result<()> {
+        self.write().set_bool(key, value)
+    }
+
+    // numbers and booleans are stored in their `Display` form, so the CLI shows them as is
+    fn get_parsed<T: FromStr>(&self, key: String, expected: &'static str) -> Result<Option<T>> {
+        match self.get(key.clone())? {
+            Some(value) => match value.parse() {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(_) => Err(KvsError::TypeMismatch { key, expected }.into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.write().remove(key)
+    }
+
+    /// Like `set`, synced or not as `options` say.
+    pub fn set_opts(&self, key: String, value: String, options: WriteOptions) -> Result<()> {
+        self.write()
+            .with_write_options(options, |store| store.set(key, value))
+    }
+
+    /// Like `remove`, synced or not as `options` say.
+    pub fn remove_opts(&self, key: String, options: WriteOptions) -> Result<()> {
+        self.write()
+            .with_write_options(options, |store| store.remove(key))
+    }
+
+    /// Removes all existing `keys` with a single record and a single flush.
+    ///
+    /// Unlike `remove`, missing keys are not an error; they are reported in the summary.
+    pub fn remove_many<I>(&self, keys: I) -> Result<RemoveSummary>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.write().remove_many(keys)
+    }
+
+    /// Returns a handle to follow and cancel the compactions of this store from another
+    /// thread.
+    pub fn compaction_handle(&self) -> CompactionHandle {
+        self.read().compaction_handle()
+    }
+
+    /// Returns how long writes waited for compactions, see `OpenOptions::write_stall`.
+    pub fn write_stalls(&self) -> WriteStalls {
+        *unpoisoned(self.read().stalls.lock())
+    }
+
+    /// Returns how much of the log is live data, see `OpenOptions::target_amplification`.
+    pub fn space_usage(&self) -> SpaceUsage {
+        self.read().space_usage()
+    }
+
+    /// Returns the key-value pairs whose keys fall in `range`, in ascending key order.
+    ///
+    /// Only string values are returned; lists, sets and hashes are skipped. The keys are
+    /// collected up front and their values read as the iterator advances, so keys removed
+    /// or expired in the meantime are skipped, while values written since are returned as
+    /// they are then.
+    ///
+    /// Both ends accept any `Bound`, and the returned iterator can be reversed with
+    /// `.rev()` to walk the range in descending order.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Scan<'_>> {
+        let keys = self.reads.string_keys_in(range);
+        Ok(Scan {
+            store: self,
+            keys: keys.into_iter(),
+        })
+    }
+
+    /// Returns every live key, of any kind, in ascending order, as of the call.
+    pub fn keys(&self) -> vec::IntoIter<String> {
+        let mut keys = unpoisoned(self.reads.index.read()).keys_in::<RangeFull>(..);
+        keys.retain(|key| self.reads.contains_key(key));
+        keys.into_iter()
+    }
+
+    /// Returns every string key-value pair in ascending key order, reading the values as
+    /// the iterator advances, like `scan`. Keys of other kinds are left out.
+    pub fn iter(&self) -> Scan<'_> {
+        let keys = self.reads.string_keys_in::<RangeFull>(..);
+        Scan {
+            store: self,
+            keys: keys.into_iter(),
+        }
+    }
+
+    /// Returns the string key-value pairs whose keys start with `prefix`, in ascending key
+    /// order, like `scan`.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Scan<'_>> {
+        self.scan((Bound::Included(prefix.to_owned()), prefix_end(prefix)))
+    }
+
+    /// Returns the metadata of the store, as found when it was opened and updated since.
+    pub fn info(&self) -> StoreInfo {
+        self.read().info.clone()
+    }
+
+    /// Returns what was recovered from the log, if the store was not closed cleanly before
+    /// this open or its last write was cut short anyway, as a crash of the system can do
+    /// after a clean close. Anything else unreadable in a cleanly closed store fails the
+    /// open.
+    ///
+    /// Stores created before shutdowns were recorded are treated as not closed cleanly.
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        self.read().recovery.clone()
+    }
+
+    /// Closes the store, syncing the log to disk and recording a clean shutdown.
+    ///
+    /// Dropping the last handle also records a clean shutdown, but does not sync the log
+    /// and can only log failures. If this fails, the shutdown is left recorded as dirty.
+    /// Fails without closing anything while other handles to the store are alive.
+    pub fn shutdown(self) -> Result<()> {
+        let KvStore { store, handles, .. } = self;
+        if Arc::try_unwrap(handles).is_err() {
+            return Err(format_err!(
+                "Cannot shut the store down while other handles are alive"
+            ));
+        }
+        match Arc::try_unwrap(store) {
+            Ok(store) => unpoisoned(store.into_inner()).shutdown(),
+            Err(_) => Err(format_err!("The store is still in use")),
+        }
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if self.closed || self.follower.is_some() {
+            return;
+        }
+        let result = self
+            .writer
+            .flush()
+            .map_err(Error::from)
+            .and_then(|_| self.save_hint())
+            .and_then(|_| {
+                if self.persist_heat {
+                    self.save_heat()
+                } else {
+                    Ok(())
+                }
+            })
+            .and_then(|_| self.info.save(&*self.storage, &self.dir, true));
+        if let Err(e) = result {
+            warn!("Failed to close the store in {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Iterator over the key-value pairs returned by `KvStore::scan`.
+pub struct Scan<'a> {
+    store: &'a KvStore,
+    keys: vec::IntoIter<String>,
+}
+
+impl Scan<'_> {
+    // Reads the value of `key`, or nothing if it is gone since the keys were collected.
+    fn read(&mut self, key: String) -> Option<Result<(String, String)>> {
+        match self.store.get(key.clone()) {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next()?;
+            if let Some(pair) = self.read(key) {
+                return Some(pair);
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // keys may be gone by the time they are read
+        (0, self.keys.size_hint().1)
+    }
+}
+
+impl DoubleEndedIterator for Scan<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next_back()?;
+            if let Some(pair) = self.read(key) {
+                return Some(pair);
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a KvStore {
+    type Item = Result<(String, String)>;
+    type IntoIter = Scan<'a>;
+
+    fn into_iter(self) -> Scan<'a> {
+        self.iter()
+    }
+}
+
+// Directories of the stores currently open in this process.
+static OPEN_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+// Held exclusively by the one process writing to a store.
+const WRITER_LOCK_FILE_NAME: &str = "kvs.lock";
+// Held shared by the processes reading a store without writing to it, and exclusively by
+// the ones replacing its files wholesale.
+const READERS_LOCK_FILE_NAME: &str = "kvs.readers.lock";
+
+/// Marks a directory as open in this process until dropped, and locks it against writers in
+/// other processes.
+struct Registration {
+    dir: PathBuf,
+    _locks: Vec<Box<dyn Send + Sync>>,
+}
+
+impl Registration {
+    fn acquire(storage: &dyn Storage, dir: &Path) -> Result<Registration> {
+        let canonical = storage.canonicalize(dir)?;
+        let mut open_dirs = OPEN_DIRS.lock().unwrap_or_else(PoisonError::into_inner);
+        if !open_dirs.insert(canonical.clone()) {
+            return Err(KvsError::AlreadyOpen(canonical).into());
+        }
+        // dropping the registration if locking fails takes the lock again
+        drop(open_dirs);
+        let mut registration = Registration {
+            dir: canonical,
+            _locks: Vec::new(),
+        };
+        registration.lock(storage, &dir.join(WRITER_LOCK_FILE_NAME), true)?;
+        // readers only look for their lock, it is up to the writer to create it
+        let readers = dir.join(READERS_LOCK_FILE_NAME);
+        if !storage.exists(&readers) {
+            storage.create(&readers)?;
+        }
+        Ok(registration)
+    }
+
+    // Like `acquire`, but also fails while other processes read the store, to replace its
+    // files.
+    fn acquire_exclusive(storage: &dyn Storage, dir: &Path) -> Result<Registration> {
+        let mut registration = Registration::acquire(storage, dir)?;
+        registration.lock(storage, &dir.join(READERS_LOCK_FILE_NAME), true)?;
+        Ok(registration)
+    }
+
+    fn lock(&mut self, storage: &dyn Storage, path: &Path, exclusive: bool) -> Result<()> {
+        match storage.try_lock(path, exclusive) {
+            Ok(lock) => {
+                self._locks.push(lock);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(KvsError::Locked(self.dir.clone()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Locks the store in `dir` as read by this process, so nothing replaces its files
+// meanwhile. Stores written by versions without locks have no lock to take.
+fn lock_as_reader(storage: &dyn Storage, dir: &Path) -> Result<Option<Box<dyn Send + Sync>>> {
+    match storage.try_lock(&dir.join(READERS_LOCK_FILE_NAME), false) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            Err(KvsError::Locked(dir.to_owned()).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        OPEN_DIRS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.dir);
+    }
+}
+
+// A compaction writes and syncs the whole compact file, renames it to a new segment,
+// syncs the directory and only then deletes the segments it replaces. If the compact file
+// is still there the compaction did not get to the rename, so its output may be
+// incomplete while the segments it replaces are all there. Older versions kept a single
+// log and deleted it before the rename; if there is no log at all the compact file was
+// complete and only the rename is left to do.
+fn recover_compaction(storage: &dyn Storage, dir: &Path) -> Result<()> {
+    let compact_path = dir.join(COMPACT_FILE_NAME);
+    if !storage.exists(&compact_path) {
+        return Ok(());
+    }
+    let log_path = dir.join(LEGACY_LOG_FILE_NAME);
+    if storage.exists(&log_path) || !segment_gens(storage, dir)?.is_empty() {
+        storage.remove(&compact_path)?;
+        warn!(
+            "Discarded the output of an unfinished compaction in {}",
+            dir.display()
+        );
+    } else {
+        storage.rename(&compact_path, &log_path)?;
+        warn!("Finished an interrupted compaction in {}", dir.display());
     }
+    Ok(())
 }
 
-fn open_file(path: &Path) -> Result<File> {
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)?;
-    Ok(file)
+// Returns what a lock held, even if a thread panicked while holding it.
+fn unpoisoned<T>(result: LockResult<T>) -> T {
+    result.unwrap_or_else(PoisonError::into_inner)
 }
 
-fn get_log_file(path: &Path) -> Result<File> {
-    let log_path = path.join("kvs.log");
-    open_file(&log_path)
+/// Updates `index` for `cmd` written at `cmd_pos`, returning how many bytes became stale.
+fn index_record(index: &mut Index, blobs: &mut Blobs, cmd: Commands, cmd_pos: CommandPos) -> u64 {
+    let (key, kind, seq, is_delta) = match cmd {
+        // removals and batch markers are stale as soon as they are applied
+        Commands::Batch { .. } => return cmd_pos.len,
+        Commands::Rm { key } => {
+            return cmd_pos.len + index.remove(&key).map_or(0, |entry| dropped(blobs, entry));
+        }
+        Commands::RmMany { keys } => {
+            return cmd_pos.len
+                + keys
+                    .iter()
+                    .filter_map(|key| index.remove(key))
+                    .map(|entry| dropped(blobs, entry))
+                    .sum::<u64>();
+        }
+        Commands::Blob { hash, .. } => return blobs.insert(hash, cmd_pos),
+        // chunks are only indexed once their manifest is read
+        Commands::Chunk { .. } => return 0,
+        Commands::Chunked {
+            key, records, seq, ..
+        } => {
+            let mut end = cmd_pos.pos;
+            let mut deltas: Vec<CommandPos> = records
+                .iter()
+                .rev()
+                .map(|&len| {
+                    end -= len;
+                    CommandPos {
+                        gen: cmd_pos.gen,
+                        pos: end,
+                        len,
+                    }
+                })
+                .collect();
+            deltas.reverse();
+            let entry = IndexEntry {
+                kind: ValueKind::String,
+                seq,
+                base: cmd_pos,
+                deltas,
+                blob: None,
+                expires_at: None,
+            };
+            return index
+                .insert(key, entry)
+                .map_or(0, |old| dropped(blobs, old));
+        }
+        Commands::Expiring {
+            key,
+            expires_at,
+            seq,
+            ..
+        } => {
+            let entry = IndexEntry {
+                kind: ValueKind::String,
+                seq,
+                base: cmd_pos,
+                deltas: Vec::new(),
+                blob: None,
+                expires_at: Some(expires_at),
+            };
+            return index
+                .insert(key, entry)
+                .map_or(0, |old| dropped(blobs, old));
+        }
+        Commands::SetRef { key, hash, seq } => {
+            blobs.acquire(hash);
+            let entry = IndexEntry {
+                kind: ValueKind::String,
+                seq,
+                base: cmd_pos,
+                deltas: Vec::new(),
+                blob: Some(hash),
+                expires_at: None,
+            };
+            return index
+                .insert(key, entry)
+                .map_or(0, |old| dropped(blobs, old));
+        }
+        Commands::Set { key, seq, .. } | Commands::Compressed { key, seq, .. } => {
+            (key, ValueKind::String, seq, false)
+        }
+        Commands::List { key, seq, .. } => (key, ValueKind::List, seq, false),
+        Commands::SetMembers { key, seq, .. } => (key, ValueKind::Set, seq, false),
+        Commands::Fields { key, seq, .. } => (key, ValueKind::Hash, seq, false),
+        Commands::Push { key, seq, .. } => (key, ValueKind::List, seq, true),
+        Commands::SAdd { key, seq, .. } | Commands::SRem { key, seq, .. } => {
+            (key, ValueKind::Set, seq, true)
+        }
+        Commands::HSet { key, seq, .. } | Commands::HDel { key, seq, .. } => {
+            (key, ValueKind::Hash, seq, true)
+        }
+    };
+    if is_delta {
+        if let Some(entry) = index.get_mut(&key).filter(|entry| entry.kind == kind) {
+            entry.deltas.push(cmd_pos);
+            entry.seq = seq;
+            return 0;
+        }
+    }
+    let entry = IndexEntry {
+        kind,
+        seq,
+        base: cmd_pos,
+        deltas: Vec::new(),
+        blob: None,
+        expires_at: None,
+    };
+    index
+        .insert(key, entry)
+        .map_or(0, |old| dropped(blobs, old))
+}
+
+// Copies the record at `cmd_pos` to `writer`, which writes the segment of generation `gen`,
+// returning its new position.
+fn copy_record(
+    reader: &mut LogReader,
+    writer: &mut BufWriterWithPos<Box<dyn StorageFile>>,
+    gen: u64,
+    cmd_pos: &CommandPos,
+) -> Result<CommandPos> {
+    let pos = writer.pos;
+    io::copy(&mut reader.record(cmd_pos)?, writer)?;
+    Ok(CommandPos {
+        gen,
+        pos,
+        len: writer.pos - pos,
+    })
+}
+
+// Returns the bytes of a value that is no longer referred to.
+fn dropped(blobs: &mut Blobs, entry: IndexEntry) -> u64 {
+    entry.len() + entry.blob.map_or(0, |hash| blobs.release(hash))
+}
+
+// Rebuilds a value from its base record and the deltas written on top of it.
+fn read_entry(reader: &mut LogReader, blobs: &Blobs, entry: &IndexEntry) -> Result<Value> {
+    match entry.blob {
+        Some(hash) => Ok(Value::String(blobs.read(reader, hash)?)),
+        None => replay_entry(reader, entry),
+    }
+}
+
+// Rebuilds a value that is not shared from its records.
+fn replay_entry(reader: &mut LogReader, entry: &IndexEntry) -> Result<Value> {
+    let mut value = Value::empty(entry.kind);
+    for cmd_pos in std::iter::once(&entry.base).chain(&entry.deltas) {
+        let cmd = match codec::read_record(&mut reader.record(cmd_pos)?)? {
+            Commands::Compressed {
+                key,
+                codec,
+                data,
+                seq,
+            } => Commands::Set {
+                key,
+                value: reader.stats.decompress_value(codec, &data)?,
+                seq,
+            },
+            cmd => cmd,
+        };
+        value.apply(cmd);
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    kind: ValueKind,
+    seq: u64,
+    // the record holding the value, followed by the deltas to apply on top of it
+    base: CommandPos,
+    deltas: Vec<CommandPos>,
+    // the hash of the shared value the base record refers to
+    blob: Option<u64>,
+    // when the value expires, in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
 }
 
-#[derive(Debug)]
+impl IndexEntry {
+    fn len(&self) -> u64 {
+        self.base.len + self.deltas.iter().map(|cmd_pos| cmd_pos.len).sum::<u64>()
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct CommandPos {
+    // the generation of the segment holding the record
+    gen: u64,
     pos: u64,
     len: u64,
 }
@@ -182,7 +2019,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -205,6 +2042,122 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     }
 }
 
+// Where replaying a segment stopped.
+struct Replayed {
+    // the offset after the last record read
+    end: u64,
+    records: u64,
+    // why it stopped before the end of the segment
+    damage: Option<Error>,
+}
+
+// What replaying records changes.
+struct Replay<'a> {
+    index: &'a mut Index,
+    blobs: &'a mut Blobs,
+    seq: &'a mut u64,
+    stale_size: &'a mut u64,
+}
+
+impl Replay<'_> {
+    // Indexes the records of segment `gen` from offset `start` on, up to its end or up to
+    // the first one that cannot be read.
+    fn segment(
+        &mut self,
+        file: &mut Box<dyn StorageFile>,
+        gen: u64,
+        start: u64,
+    ) -> Result<Replayed> {
+        let size = file.size()?;
+        let mut reader = BufReaderWithPos::new(file)?;
+        let mut pos = reader.seek(SeekFrom::Start(start))?;
+        let mut replayed = Replayed {
+            end: pos,
+            records: 0,
+            damage: None,
+        };
+        if start == 0 && size > 0 {
+            if let Err(e) = codec::read_header(&mut reader) {
+                replayed.damage = Some(e);
+                return Ok(replayed);
+            }
+            pos = codec::HEADER_LEN;
+        }
+        // the records of the batch being read, held back until it is complete
+        let mut batch: Option<Batch> = None;
+        loop {
+            let (cmd, len) = match codec::next_record::<Commands>(&mut reader) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    replayed.damage = Some(e);
+                    break;
+                }
+            };
+            let cmd_pos = CommandPos { gen, pos, len };
+            pos += len;
+            if let Some(pending) = &mut batch {
+                pending.records.push((cmd, cmd_pos));
+                if pending.records.len() as u64 == pending.count {
+                    let pending = batch.take().expect("a batch is pending");
+                    for (cmd, cmd_pos) in pending.records {
+                        self.record(cmd, cmd_pos, &mut replayed);
+                    }
+                }
+                continue;
+            }
+            match cmd {
+                Commands::Batch { count } if count > 0 => {
+                    batch = Some(Batch {
+                        start: cmd_pos.pos,
+                        count: count + 1,
+                        records: vec![(cmd, cmd_pos)],
+                    });
+                }
+                cmd => self.record(cmd, cmd_pos, &mut replayed),
+            }
+        }
+        replayed.end = pos;
+        // a batch the segment ends in is discarded like a torn record
+        if let Some(pending) = batch {
+            replayed.end = pending.start;
+            replayed.damage.get_or_insert_with(|| {
+                codec::cut_short("The log ends in the middle of a batch").into()
+            });
+        }
+        Ok(replayed)
+    }
+
+    fn record(&mut self, cmd: Commands, cmd_pos: CommandPos, replayed: &mut Replayed) {
+        if let Some(cmd_seq) = cmd.seq() {
+            *self.seq = (*self.seq).max(cmd_seq + 1);
+        }
+        *self.stale_size += index_record(self.index, self.blobs, cmd, cmd_pos);
+        replayed.records += 1;
+    }
+}
+
+// A batch whose records are being read.
+struct Batch {
+    // the offset of its marker
+    start: u64,
+    // of records, including the marker
+    count: u64,
+    records: Vec<(Commands, CommandPos)>,
+}
+
+// Opens a writer appending to a segment, starting it with the header if it is empty.
+fn segment_writer(
+    mut file: Box<dyn StorageFile>,
+) -> Result<BufWriterWithPos<Box<dyn StorageFile>>> {
+    file.seek(SeekFrom::End(0))?;
+    let mut writer = BufWriterWithPos::new(file)?;
+    if writer.pos == 0 {
+        codec::write_header(&mut writer)?;
+    }
+    Ok(writer)
+}
+
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
     pos: u64,
@@ -212,7 +2165,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
@@ -0,0 +1,132 @@
+use std::{fmt, io::Write, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{segment::segment_gens, Clock, Result, Storage};
+
+pub(crate) const META_FILE_NAME: &str = "kvs.meta";
+const META_TMP_FILE_NAME: &str = "kvs.meta.tmp";
+
+/// Version of the on-disk log format written by this build.
+pub const FORMAT_VERSION: u32 = 2;
+/// Encoding of the records written by this build.
+pub(crate) const CODEC: &str = "binary";
+
+/// Store-level metadata persisted next to the log, see `KvStore::info`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreInfo {
+    /// Seconds since the Unix epoch; unknown for stores created before metadata existed.
+    pub created_at: Option<u64>,
+    pub format_version: u32,
+    /// Encoding of the records in the log.
+    pub codec: String,
+    pub encrypted: bool,
+    /// Seconds since the Unix epoch of the last finished compaction, if any.
+    pub last_compaction: Option<u64>,
+    /// Whether the store was closed cleanly before this open; unknown for stores created
+    /// before metadata existed.
+    pub clean_shutdown: Option<bool>,
+    /// The sequence number of the next write as of the last compaction, which drops the
+    /// records versions were handed out for, so they are never handed out again.
+    pub high_water_seq: Option<u64>,
+}
+
+impl StoreInfo {
+    /// Loads the metadata of the store in `dir`, creating it when missing.
+    ///
+    /// A store that already has a log but no metadata predates it, so its creation time and
+    /// shutdown state are unknown.
+    pub(crate) fn load(storage: &dyn Storage, clock: &dyn Clock, dir: &Path) -> Result<StoreInfo> {
+        match storage.read(&dir.join(META_FILE_NAME)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let fresh = segment_gens(storage, dir)?.is_empty();
+                Ok(StoreInfo {
+                    created_at: if fresh {
+                        Some(clock.now().as_secs())
+                    } else {
+                        None
+                    },
+                    format_version: FORMAT_VERSION,
+                    codec: CODEC.to_owned(),
+                    encrypted: false,
+                    last_compaction: None,
+                    clean_shutdown: if fresh { Some(true) } else { None },
+                    high_water_seq: None,
+                })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the metadata to `dir`, recording whether the store is currently closed.
+    ///
+    /// The file is synced and replaced atomically, so a crash leaves either the old or the
+    /// new copy.
+    pub(crate) fn save(&self, storage: &dyn Storage, dir: &Path, closed: bool) -> Result<()> {
+        let on_disk = StoreInfo {
+            clean_shutdown: Some(closed),
+            ..self.clone()
+        };
+        let tmp_path = dir.join(META_TMP_FILE_NAME);
+        let mut tmp = storage.create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(&on_disk)?)?;
+        tmp.sync()?;
+        drop(tmp);
+        storage.rename(&tmp_path, &dir.join(META_FILE_NAME))?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for StoreInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |secs: Option<u64>, missing: &str| match secs {
+            Some(secs) => format_timestamp(secs),
+            None => missing.to_owned(),
+        };
+        writeln!(f, "created_at: {}", time(self.created_at, "unknown"))?;
+        writeln!(f, "format_version: {}", self.format_version)?;
+        writeln!(f, "codec: {}", self.codec)?;
+        writeln!(
+            f,
+            "encryption: {}",
+            if self.encrypted { "enabled" } else { "none" }
+        )?;
+        writeln!(
+            f,
+            "last_compaction: {}",
+            time(self.last_compaction, "never")
+        )?;
+        let shutdown = match self.clean_shutdown {
+            Some(true) => "clean",
+            Some(false) => "dirty",
+            None => "unknown",
+        };
+        write!(f, "last_shutdown: {}", shutdown)
+    }
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+pub(crate) fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
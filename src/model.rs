@@ -0,0 +1,139 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+use failure::Fail;
+
+use crate::{KvStore, KvsError, OpenOptions, Result};
+
+/// An operation run by `check_against_model`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelOp {
+    Set(String, String),
+    Get(String),
+    Remove(String),
+    /// Scans the keys in `start..end`.
+    Scan(String, String),
+    /// Closes the store and opens it again.
+    Reopen,
+}
+
+/// What an operation returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    Value(Option<String>),
+    Pairs(Vec<(String, String)>),
+    KeyNotFound,
+}
+
+/// A reference implementation of the string operations of `KvStore`, kept in a map.
+#[derive(Debug, Clone, Default)]
+pub struct ModelStore {
+    map: BTreeMap<String, String>,
+}
+
+impl ModelStore {
+    pub fn new() -> ModelStore {
+        ModelStore::default()
+    }
+
+    /// Applies `op` and returns what `KvStore` is expected to return for it.
+    pub fn apply(&mut self, op: &ModelOp) -> Outcome {
+        match op {
+            ModelOp::Set(key, value) => {
+                self.map.insert(key.clone(), value.clone());
+                Outcome::Done
+            }
+            ModelOp::Get(key) => Outcome::Value(self.map.get(key).cloned()),
+            ModelOp::Remove(key) => match self.map.remove(key) {
+                Some(_) => Outcome::Done,
+                None => Outcome::KeyNotFound,
+            },
+            ModelOp::Scan(start, end) if start > end => Outcome::Pairs(Vec::new()),
+            ModelOp::Scan(start, end) => Outcome::Pairs(
+                self.map
+                    .range(start.clone()..end.clone())
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect(),
+            ),
+            ModelOp::Reopen => Outcome::Done,
+        }
+    }
+}
+
+/// A step at which a `KvStore` returned something else than the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the operation in the sequence.
+    pub step: usize,
+    pub op: ModelOp,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Step {} ({:?}) returned {:?}, expected {:?}",
+            self.step, self.op, self.actual, self.expected
+        )
+    }
+}
+
+impl Fail for Divergence {}
+
+/// Runs `ops` against both a `ModelStore` and a `KvStore` opened at `path`, which must
+/// not hold a store yet, and compares what every operation returns.
+///
+/// Returns a `Divergence` error at the first difference, or the error of the store if an
+/// operation failed in another way than the model allows.
+pub fn check_against_model(
+    options: &OpenOptions,
+    path: impl Into<PathBuf>,
+    ops: &[ModelOp],
+) -> Result<()> {
+    let path = path.into();
+    let mut model = ModelStore::new();
+    let mut store = options.open(&path)?;
+    for (step, op) in ops.iter().enumerate() {
+        let expected = model.apply(op);
+        if *op == ModelOp::Reopen {
+            drop(store);
+            store = options.open(&path)?;
+        }
+        let actual = run(&store, op)?;
+        if actual != expected {
+            return Err(Divergence {
+                step,
+                op: op.clone(),
+                expected,
+                actual,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+fn run(store: &KvStore, op: &ModelOp) -> Result<Outcome> {
+    Ok(match op {
+        ModelOp::Set(key, value) => {
+            store.set(key.clone(), value.clone())?;
+            Outcome::Done
+        }
+        ModelOp::Get(key) => Outcome::Value(store.get(key.clone())?),
+        ModelOp::Remove(key) => match store.remove(key.clone()) {
+            Ok(()) => Outcome::Done,
+            Err(e) => match e.downcast_ref::<KvsError>() {
+                Some(KvsError::KeyNotFound) => Outcome::KeyNotFound,
+                _ => return Err(e),
+            },
+        },
+        ModelOp::Scan(start, end) => Outcome::Pairs(
+            store
+                .scan(start.clone()..end.clone())?
+                .collect::<Result<_>>()?,
+        ),
+        ModelOp::Reopen => Outcome::Done,
+    })
+}
@@ -0,0 +1,30 @@
+use crate::{KvStore, Result, WriteBatch};
+
+impl KvStore {
+    /// Returns the string values of `keys`, in the same order, `None` for missing keys.
+    ///
+    /// All values are looked up at once and read with one reader, in the order they were
+    /// written rather than the order of `keys`. Keys missing from the store are asked
+    /// of the backing store one at a time.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut values = self.reads.get_many(keys)?;
+        if !self.reads.has_origin {
+            return Ok(values);
+        }
+        for (key, value) in keys.iter().zip(&mut values) {
+            if value.is_none() {
+                *value = self.get(key.clone())?;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Sets all `pairs` in order with a single flush, as one `WriteBatch`.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            batch.set(key, value);
+        }
+        self.write_batch(batch)
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    io::{BufRead, Write},
+    ops::Bound,
+};
+
+use serde_json::Deserializer;
+
+use crate::{
+    protocol::{ErrorCode, Request, Response, SCAN_BATCH_BYTES, SCAN_BATCH_PAIRS},
+    KvsEngine, Result,
+};
+
+const REPL_HELP: &str = "\
+SET key value       set key to value
+GET key             print the value of key
+GETRANGE key o n    print up to n bytes of the value of key from byte o on, in hex
+RM key              remove key
+SCAN [start [end]]  print the pairs from start up to, not including, end
+EXIT                end the session
+Arguments holding spaces or line breaks are written as JSON strings, such as \"a b\".";
+
+// How a request was written, which the response is written the same way.
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Text,
+}
+
+/// Answers the commands read from `input`, one per line, on `output` until `input` ends,
+/// such as for `kvs pipe`.
+///
+/// A line starting with `{` is a JSON request as sent by `KvsClient`, such as
+/// `{"Get":{"key":"a"}}`, and is answered with a JSON response on one line. Any other line
+/// is a text command, `SET key value`, `GET key`, `GETRANGE key offset len`, `RM key` or
+/// `SCAN [start [end]]`, with arguments that hold spaces or line breaks written as JSON
+/// strings. It is answered with `OK`, the value as a JSON string, the bytes of a range in
+/// hex, `(nil)` for a missing key, or `ERR` followed by the `ErrorCode` and the message.
+///
+/// Scans are answered with all their batches in a row, JSON ones as `Pairs` responses the
+/// last of which says there are no more, text ones with a line of two JSON strings per pair
+/// and `END`. An invalid line is answered with a `BadRequest` error and does not end the
+/// pipe. Every response is flushed once written.
+pub fn serve_pipe(
+    engine: &mut impl KvsEngine,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<()> {
+    serve(engine, input, output, None)
+}
+
+/// Answers commands like `serve_pipe`, for a person at a terminal, such as for `kvs repl`.
+///
+/// `prompt` is written before reading each command, unless empty. Besides the commands of a
+/// pipe, `HELP` lists them and `EXIT` or `QUIT` ends the session, like the end of `input`.
+pub fn serve_repl(
+    engine: &mut impl KvsEngine,
+    input: impl BufRead,
+    output: impl Write,
+    prompt: &str,
+) -> Result<()> {
+    serve(engine, input, output, Some(prompt))
+}
+
+fn serve(
+    engine: &mut impl KvsEngine,
+    mut input: impl BufRead,
+    mut output: impl Write,
+    prompt: Option<&str>,
+) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        if let Some(prompt) = prompt.filter(|prompt| !prompt.is_empty()) {
+            write!(output, "{}", prompt)?;
+            output.flush()?;
+        }
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if prompt.is_some() {
+            match line.to_ascii_uppercase().as_str() {
+                "EXIT" | "QUIT" => return Ok(()),
+                "HELP" => {
+                    writeln!(output, "{}", REPL_HELP)?;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        let format = if line.starts_with('{') {
+            Format::Json
+        } else {
+            Format::Text
+        };
+        let request = match format {
+            Format::Json => serde_json::from_str(line).map_err(|e| e.to_string()),
+            Format::Text => parse_text(line),
+        };
+        let response = match request {
+            Ok(Request::Set { key, value }) => respond(engine.set(key, value), |_| Response::Done),
+            Ok(Request::Get { key }) => respond(engine.get(key), Response::Value),
+            Ok(Request::GetRange { key, offset, len }) => {
+                respond(engine.get_range(key, offset, len), Response::Bytes)
+            }
+            Ok(Request::Rm { key }) => respond(engine.remove(key), |_| Response::Done),
+            Ok(Request::Scan { start, end }) => {
+                scan(engine, start, end, format, &mut output)?;
+                continue;
+            }
+            Ok(Request::CompactionProgress) => {
+                respond(engine.compaction_progress(), Response::CompactionProgress)
+            }
+            Ok(Request::CancelCompaction) => {
+                respond(engine.cancel_compaction(), |_| Response::Done)
+            }
+            Ok(Request::HotKeys { n }) => respond(engine.hot_keys(n), Response::HotKeys),
+            Ok(Request::Auth { .. } | Request::Compress { .. } | Request::ScanNext) => {
+                bad_request("Not supported by a pipe")
+            }
+            Err(message) => bad_request(message),
+        };
+        write(&mut output, &response, format)?;
+    }
+}
+
+fn scan(
+    engine: &mut impl KvsEngine,
+    start: Bound<String>,
+    end: Bound<String>,
+    format: Format,
+    output: &mut impl Write,
+) -> Result<()> {
+    let mut pairs = match engine.scan(start, end) {
+        Ok(pairs) => pairs.peekable(),
+        Err(e) => return write(output, &Response::error(&e), format),
+    };
+    loop {
+        let mut batch = Vec::new();
+        let mut size = 0;
+        while batch.len() < SCAN_BATCH_PAIRS && size < SCAN_BATCH_BYTES {
+            match pairs.next() {
+                Some(Ok((key, value))) => {
+                    size += key.len() + value.len();
+                    batch.push((key, value));
+                }
+                Some(Err(e)) => return write(output, &Response::error(&e), format),
+                None => break,
+            }
+        }
+        let more = pairs.peek().is_some();
+        write(output, &Response::Pairs { pairs: batch, more }, format)?;
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+// Parses a text command into the request it stands for.
+fn parse_text(line: &str) -> std::result::Result<Request, String> {
+    let mut args = split_args(line)?.into_iter();
+    let name = args.next().unwrap_or_default().to_ascii_uppercase();
+    let args: Vec<String> = args.collect();
+    let request = match (name.as_str(), args.as_slice()) {
+        ("SET", [key, value]) => Request::Set {
+            key: key.clone(),
+            value: value.clone(),
+        },
+        ("GET", [key]) => Request::Get { key: key.clone() },
+        ("GETRANGE", [key, offset, len]) => Request::GetRange {
+            key: key.clone(),
+            offset: number(offset)?,
+            len: number(len)?,
+        },
+        ("RM", [key]) => Request::Rm { key: key.clone() },
+        ("SCAN", rest) if rest.len() <= 2 => Request::Scan {
+            start: rest
+                .first()
+                .map_or(Bound::Unbounded, |start| Bound::Included(start.clone())),
+            end: rest
+                .get(1)
+                .map_or(Bound::Unbounded, |end| Bound::Excluded(end.clone())),
+        },
+        ("SET" | "GET" | "GETRANGE" | "RM" | "SCAN", _) => {
+            return Err(format!("Wrong number of arguments for {}", name))
+        }
+        _ => return Err(format!("Unknown command {:?}", name)),
+    };
+    Ok(request)
+}
+
+fn number(arg: &str) -> std::result::Result<u64, String> {
+    arg.parse()
+        .map_err(|_| format!("Expected a number, found {:?}", arg))
+}
+
+// Splits a text command into words, reading those starting with `"` as JSON strings.
+fn split_args(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        if rest.starts_with('"') {
+            let mut strings = Deserializer::from_str(rest).into_iter::<String>();
+            let arg = match strings.next() {
+                Some(Ok(arg)) => arg,
+                _ => return Err(format!("Invalid quoted argument {}", rest)),
+            };
+            rest = &rest[strings.byte_offset()..];
+            if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+                return Err("Expected a space after a quoted argument".to_owned());
+            }
+            args.push(arg);
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            args.push(rest[..end].to_owned());
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(args)
+}
+
+fn write(output: &mut impl Write, response: &Response, format: Format) -> Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer(&mut *output, response)?;
+            writeln!(output)?;
+        }
+        Format::Text => match response {
+            Response::Done => writeln!(output, "OK")?,
+            Response::Value(Some(value)) => writeln!(output, "{}", serde_json::to_string(value)?)?,
+            Response::Value(None) | Response::Bytes(None) => writeln!(output, "(nil)")?,
+            Response::Bytes(Some(bytes)) => {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                writeln!(output, "{}", hex)?
+            }
+            Response::Pairs { pairs, more } => {
+                for (key, value) in pairs {
+                    writeln!(
+                        output,
+                        "{} {}",
+                        serde_json::to_string(key)?,
+                        serde_json::to_string(value)?
+                    )?;
+                }
+                if !more {
+                    writeln!(output, "END")?;
+                }
+            }
+            Response::Err { code, message } => {
+                writeln!(output, "ERR {:?} {}", code, message.replace('\n', " "))?
+            }
+            Response::Compression(_) => unreachable!("pipes do not compress"),
+            Response::CompactionProgress(_) | Response::HotKeys(_) => {
+                unreachable!("no text command asks for these")
+            }
+        },
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn respond<T>(result: Result<T>, ok: impl FnOnce(T) -> Response) -> Response {
+    match result {
+        Ok(value) => ok(value),
+        Err(e) => Response::error(&e),
+    }
+}
+
+fn bad_request(message: impl Into<String>) -> Response {
+    Response::Err {
+        code: ErrorCode::BadRequest,
+        message: message.into(),
+    }
+}
@@ -0,0 +1,141 @@
+//! File system operations whose semantics differ between platforms.
+//!
+//! On Unix a file can be renamed over or deleted while it is open. On Windows an open
+//! file can only be renamed or deleted if every handle to it allows it, and even then
+//! other processes (indexers, virus scanners) briefly open files and make such calls
+//! fail with a sharing violation. The helpers here retry those transient failures on
+//! Windows, so callers only need to make sure they hold no handle to the files
+//! themselves.
+
+use std::{fs, io, path::Path, thread, time::Duration};
+
+/// How often an operation failing with a transient error is retried.
+const RETRIES: u32 = if cfg!(windows) { 10 } else { 0 };
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Atomically replaces `to` with `from`.
+///
+/// Handles opened on `from` stay valid and refer to `to` afterwards, on both platforms,
+/// since the standard library opens files with `FILE_SHARE_DELETE` on Windows.
+pub(crate) fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    retry(RETRIES, RETRY_DELAY, || fs::rename(from, to))
+}
+
+/// Deletes the file at `path`, which must not be open in this process on Windows.
+pub(crate) fn remove_file(path: &Path) -> io::Result<()> {
+    retry(RETRIES, RETRY_DELAY, || fs::remove_file(path))
+}
+
+/// Makes the files created, renamed and removed in `dir` durable.
+///
+/// Windows cannot open a directory as a file, and NTFS journals these changes anyway.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(windows) {
+        return Ok(());
+    }
+    fs::File::open(dir)?.sync_all()
+}
+
+fn retry<T>(retries: u32, delay: Duration, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                thread::sleep(delay * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+// ERROR_ACCESS_DENIED and ERROR_SHARING_VIOLATION both surface as `PermissionDenied`
+fn is_transient(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::PermissionDenied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        fs::File,
+        io::{Read, Seek, SeekFrom, Write},
+    };
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn replace_keeps_handles_on_the_source() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        fs::write(&to, "old")?;
+        let mut handle = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&from)?;
+        handle.write_all(b"new")?;
+
+        replace_file(&from, &to)?;
+
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to)?, "new");
+        // writes through the old handle land in the renamed file
+        handle.write_all(b"er")?;
+        let mut contents = String::new();
+        handle.seek(SeekFrom::Start(0))?;
+        handle.read_to_string(&mut contents)?;
+        assert_eq!(contents, "newer");
+        assert_eq!(fs::read_to_string(&to)?, "newer");
+        Ok(())
+    }
+
+    #[test]
+    fn remove_closed_file() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("file");
+        drop(File::create(&path)?);
+        remove_file(&path)?;
+        assert!(!path.exists());
+        assert_eq!(
+            remove_file(&path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let mut calls = 0;
+        let result = retry(3, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        calls = 0;
+        let result: io::Result<()> = retry(2, Duration::ZERO, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn do_not_retry_other_errors() {
+        let mut calls = 0;
+        let result: io::Result<()> = retry(5, Duration::ZERO, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::unpoisoned;
+
+/// Follows a long operation of a store, such as an export or an import, from another
+/// thread, see `KvStore::export_json_with_progress`.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    shared: Arc<ProgressState>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    keys: AtomicU64,
+    // 0 while unknown
+    keys_total: AtomicU64,
+    bytes: AtomicU64,
+    bytes_total: AtomicU64,
+    started: Mutex<Option<Instant>>,
+}
+
+/// A snapshot of a `Progress`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressReport {
+    /// Keys written or read so far.
+    pub keys: u64,
+    /// Keys the operation has to go through, if known.
+    pub keys_total: Option<u64>,
+    /// Bytes written or read so far.
+    pub bytes: u64,
+    /// Bytes the operation has to go through, if known.
+    pub bytes_total: Option<u64>,
+    /// Time since the operation started.
+    pub elapsed: Duration,
+    /// Estimated time left, extrapolated from the keys or else the bytes so far.
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Sets the number of bytes the operation goes through when the store cannot know it,
+    /// such as the size of the file an import reads.
+    pub fn expect_bytes(&self, bytes: u64) {
+        self.shared.bytes_total.store(bytes, Ordering::SeqCst);
+    }
+
+    pub fn report(&self) -> ProgressReport {
+        let state = &self.shared;
+        let keys = state.keys.load(Ordering::SeqCst);
+        let keys_total = state.keys_total.load(Ordering::SeqCst);
+        let bytes = state.bytes.load(Ordering::SeqCst);
+        let bytes_total = state.bytes_total.load(Ordering::SeqCst);
+        let started = *unpoisoned(state.started.lock());
+        let elapsed = started.map_or(Duration::ZERO, |started| started.elapsed());
+        let (done, total) = if keys_total > 0 {
+            (keys, keys_total)
+        } else {
+            (bytes, bytes_total)
+        };
+        let eta = (done > 0 && total > 0).then(|| {
+            let left = total.saturating_sub(done) as f64 / done as f64;
+            Duration::from_secs_f64(elapsed.as_secs_f64() * left)
+        });
+        ProgressReport {
+            keys,
+            keys_total: (keys_total > 0).then_some(keys_total),
+            bytes,
+            bytes_total: (bytes_total > 0).then_some(bytes_total),
+            elapsed,
+            eta,
+        }
+    }
+
+    // Starts the clock, with the number of keys to go through if known.
+    pub(crate) fn start(&self, keys_total: Option<u64>) {
+        let state = &self.shared;
+        state.keys.store(0, Ordering::SeqCst);
+        state.bytes.store(0, Ordering::SeqCst);
+        state
+            .keys_total
+            .store(keys_total.unwrap_or(0), Ordering::SeqCst);
+        *unpoisoned(state.started.lock()) = Some(Instant::now());
+    }
+
+    pub(crate) fn add_key(&self) {
+        self.shared.keys.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn add_bytes(&self, bytes: usize) {
+        self.shared.bytes.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Display for ProgressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.keys_total {
+            Some(total) => write!(f, "{}/{} keys", self.keys, total)?,
+            None => write!(f, "{} keys", self.keys)?,
+        }
+        match self.bytes_total {
+            Some(total) => write!(f, ", {}/{}", format_bytes(self.bytes), format_bytes(total))?,
+            None => write!(f, ", {}", format_bytes(self.bytes))?,
+        }
+        write!(f, ", {} elapsed", format_secs(self.elapsed))?;
+        if let Some(eta) = self.eta {
+            write!(f, ", ETA {}", format_secs(eta))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1 << 10 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for name in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = name;
+    }
+    format!("{:.1}{}", size, unit)
+}
+
+pub(crate) fn format_secs(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
+
+// Counts the bytes going through a reader or writer into a `Progress`.
+pub(crate) struct Counted<'a, T> {
+    pub(crate) inner: T,
+    pub(crate) progress: &'a Progress,
+}
+
+impl<T: Read> Read for Counted<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.add_bytes(read);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Counted<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.progress.add_bytes(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,245 @@
+//! The messages exchanged by `KvsClient` and `KvsServer`.
+//!
+//! Each message is a JSON value, sent back to back on a TCP connection the same way
+//! records are stored in the log. The server answers every request with one response,
+//! in order.
+//!
+//! Once a client and the server agreed on a compression with `Request::Compress`, every
+//! later response is sent as a frame instead: a byte telling how the payload is
+//! compressed, 0 for not at all, the `u32` little-endian length of the payload, and the
+//! payload, the JSON response. Only responses of at least `COMPRESSION_MIN_SIZE` bytes
+//! are compressed.
+//!
+//! A `Request::Scan` is answered with a first batch of pairs. While a batch says there are
+//! more, the client asks for the next one with `Request::ScanNext`; any other request ends
+//! the scan. The server never holds more than one batch.
+//!
+//! Besides reading and writing, requests let operators follow the store, such as
+//! `Request::CompactionProgress` and `Request::HotKeys`, and cancel its compactions with
+//! `Request::CancelCompaction`, answered with `Response::Done`.
+//!
+//! A failed request is answered with `Response::Err`, holding an `ErrorCode` besides the
+//! message, so clients can tell failures apart without parsing the message.
+
+use std::{
+    io::{Read, Write},
+    ops::Bound,
+};
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{codec, CompactionProgress, CompressionStats, KvsError, Result};
+
+/// A compression codec, for the responses of a connection, see `KvsClient::compress`,
+/// and for values in the log, see `OpenOptions::compress_values`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    /// Raw DEFLATE streams, as in RFC 1951.
+    Deflate,
+}
+
+impl Compression {
+    /// The byte marking frames compressed this way.
+    fn tag(self) -> u8 {
+        match self {
+            Compression::Deflate => 1,
+        }
+    }
+}
+
+/// What made a request fail, sent with every error response.
+///
+/// Codes are sent as their names, such as `"KeyNotFound"`, which never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// `KvsError::KeyNotFound`.
+    KeyNotFound,
+    /// `KvsError::VersionConflict`.
+    Conflict,
+    /// `KvsError::TypeMismatch` or `KvsError::WrongType`.
+    WrongType,
+    /// `KvsError::Unauthorized`.
+    Unauthorized,
+    /// `KvsError::AlreadyOpen`, the store is in use.
+    Busy,
+    /// A record of the store could not be decoded.
+    Corruption,
+    /// Reading or writing the files of the store failed.
+    Io,
+    /// The request is not valid at this point, such as `ScanNext` outside a scan or a write
+    /// to a follower, `KvsError::ReadOnly`, or not supported by the engine,
+    /// `KvsError::Unsupported`.
+    BadRequest,
+    /// Any other failure.
+    Internal,
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed if sent again later, on a new connection if
+    /// the failure broke this one.
+    ///
+    /// Conflicts only go away once the caller read the current version, so they are not.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, ErrorCode::Busy | ErrorCode::Io)
+    }
+
+    /// The exit code of the command line tools failing with this code: 2 for
+    /// `KeyNotFound`, 3 for `Corruption`, 4 for `BadRequest`, as for invalid arguments, 5
+    /// for `Busy` and 1 for anything else.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCode::KeyNotFound => 2,
+            ErrorCode::Corruption => 3,
+            ErrorCode::BadRequest => 4,
+            ErrorCode::Busy => 5,
+            _ => 1,
+        }
+    }
+
+    /// Returns the code of the error `e`, failing a request or a command.
+    pub fn of(e: &failure::Error) -> ErrorCode {
+        if let Some(e) = e.downcast_ref::<KvsError>() {
+            e.code()
+        } else if e.downcast_ref::<serde_json::Error>().is_some()
+            || e.downcast_ref::<codec::Error>().is_some()
+        {
+            ErrorCode::Corruption
+        } else if e.downcast_ref::<std::io::Error>().is_some() {
+            ErrorCode::Io
+        } else {
+            ErrorCode::Internal
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Request {
+    Auth {
+        user: String,
+        secret: String,
+    },
+    /// Asks for the first of `offered` the server supports to be used from the response
+    /// to this request on, or no compression if there is none.
+    Compress {
+        offered: Vec<Compression>,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    /// Asks for up to `len` bytes of the value of `key` from byte `offset` on, so large
+    /// values can be fetched piece by piece.
+    GetRange {
+        key: String,
+        offset: u64,
+        len: u64,
+    },
+    Rm {
+        key: String,
+    },
+    Scan {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    ScanNext,
+    CompactionProgress,
+    CancelCompaction,
+    /// Asks for the `n` most accessed keys, most accessed first.
+    HotKeys {
+        n: usize,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Response {
+    Done,
+    Value(Option<String>),
+    /// The bytes of a `Request::GetRange`, `None` if the key is not set.
+    Bytes(Option<Vec<u8>>),
+    Compression(Option<Compression>),
+    Pairs {
+        pairs: Vec<(String, String)>,
+        more: bool,
+    },
+    CompactionProgress(CompactionProgress),
+    HotKeys(Vec<(String, u64)>),
+    Err {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl Response {
+    pub(crate) fn error(e: &failure::Error) -> Response {
+        Response::Err {
+            code: ErrorCode::of(e),
+            message: e.to_string(),
+        }
+    }
+}
+
+// A batch of a scan ends after this many pairs, or once it holds this many bytes.
+pub(crate) const SCAN_BATCH_PAIRS: usize = 256;
+pub(crate) const SCAN_BATCH_BYTES: usize = 64 * 1024;
+
+// Smaller responses are not worth compressing.
+const COMPRESSION_MIN_SIZE: usize = 1024;
+// Compressed payloads may not inflate beyond this, so a bad frame can't exhaust memory.
+const MAX_RESPONSE_SIZE: usize = 1 << 30;
+const PLAIN_TAG: u8 = 0;
+
+pub(crate) fn write_response(
+    writer: &mut impl Write,
+    response: &Response,
+    compression: Option<Compression>,
+    stats: &CompressionStats,
+) -> Result<()> {
+    let Some(compression) = compression else {
+        serde_json::to_writer(&mut *writer, response)?;
+        writer.flush()?;
+        return Ok(());
+    };
+    let json = serde_json::to_vec(response)?;
+    let (tag, payload) = if json.len() < COMPRESSION_MIN_SIZE {
+        (PLAIN_TAG, json)
+    } else {
+        let payload = stats.compress(compression, &json);
+        (compression.tag(), payload)
+    };
+    writer.write_all(&[tag])?;
+    writer.write_all(&u32::try_from(payload.len())?.to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub(crate) fn read_response(
+    reader: &mut impl Read,
+    compression: Option<Compression>,
+    stats: &CompressionStats,
+) -> Result<Response> {
+    if compression.is_none() {
+        // a response ends with its last byte, so nothing after it is read
+        return Ok(Response::deserialize(&mut Deserializer::from_reader(
+            reader,
+        ))?);
+    }
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    let [tag, len @ ..] = header;
+    let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut payload)?;
+    let json = match tag {
+        PLAIN_TAG => payload,
+        tag if tag == Compression::Deflate.tag() => stats
+            .decompress(Compression::Deflate, &payload, MAX_RESPONSE_SIZE)
+            .map_err(|e| format_err!("Invalid compressed response: {}", e))?,
+        tag => return Err(format_err!("Unknown compression {} of a response", tag)),
+    };
+    Ok(serde_json::from_slice(&json)?)
+}
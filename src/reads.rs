@@ -0,0 +1,464 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    ops::RangeBounds,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+};
+
+use log::warn;
+
+use crate::{
+    collections::Value,
+    dedup::{read_blob, Blobs},
+    eviction::EvictionPolicy,
+    hotkeys::HotKeys,
+    index::Index,
+    replay_entry,
+    segment::{segment_gens, segment_path, LogReader},
+    unpoisoned, Clock, CommandPos, CompressionStats, IndexEntry, KvsError, Metadata, Result,
+    Storage, ValueKind, Version,
+};
+
+// Number of parts what every read updates is split into, see `Sharded`.
+const SHARDS: usize = 16;
+
+// Keys a thread reads before the eviction policy is told about them, if no write tells it
+// first.
+const MAX_BUFFERED_ACCESSES: usize = 1024;
+
+/// What reads of single keys need of a store, shared by its handles outside the lock
+/// writes take.
+///
+/// Writes change the index and the blobs only once their records reached the log, each
+/// for as long as the change in memory takes, so reads never wait for a write to reach the
+/// disk, for a write stall or for a subscriber holding up a write. What reads update
+/// themselves, the hot keys, the accesses for the eviction policy, the readers of the log
+/// and the pins of segments, is `Sharded`, so reads take no lock other reads contend for
+/// but the read lock of the index.
+pub(crate) struct ReadState {
+    pub(crate) dir: PathBuf,
+    pub(crate) storage: Arc<dyn Storage>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) compression_stats: CompressionStats,
+    pub(crate) index: RwLock<Index>,
+    // locked after the index where both are
+    pub(crate) blobs: RwLock<Blobs>,
+    pub(crate) hot_keys: Sharded<HotKeys>,
+    // told about the keys reads access, locked after the index
+    pub(crate) eviction_policy: Option<Mutex<Box<dyn EvictionPolicy>>>,
+    // keys read since the eviction policy was last told about them
+    pub(crate) accessed: Sharded<Vec<String>>,
+    // whether keys missing from the store may be fetched from a backing store
+    pub(crate) has_origin: bool,
+    pub(crate) readers: Readers,
+}
+
+/// The readers of the log lent to reads and the segments they use, see `ReadState`.
+#[derive(Default)]
+pub(crate) struct Readers {
+    idle: Sharded<Pool>,
+    // reads and views using each segment outside the store lock
+    pins: Sharded<HashMap<u64, usize>>,
+    // segments a compaction replaced, deleted oldest first once nothing uses them
+    retired: Mutex<BTreeSet<u64>>,
+    // whether `retired` holds any, for dropping pins to check without locking it
+    has_retired: AtomicBool,
+    // held while retired segments are deleted, so they go oldest first
+    removing: Mutex<()>,
+}
+
+/// A value per shard, each thread using the same one, so threads updating the same state
+/// rarely wait for each other.
+pub(crate) struct Sharded<T> {
+    shards: Box<[Mutex<T>]>,
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Sharded<T> {
+        Sharded {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl<T> Sharded<T> {
+    // Locks the shard of the calling thread.
+    pub(crate) fn local(&self) -> MutexGuard<'_, T> {
+        self.get(thread_shard())
+    }
+
+    pub(crate) fn get(&self, shard: usize) -> MutexGuard<'_, T> {
+        unpoisoned(self.shards[shard].lock())
+    }
+
+    // Locks each shard in turn, for what has to see all of them.
+    pub(crate) fn each(&self) -> impl Iterator<Item = MutexGuard<'_, T>> {
+        self.shards.iter().map(|shard| unpoisoned(shard.lock()))
+    }
+}
+
+// Returns the shard of the calling thread, given out in turn as threads first ask.
+fn thread_shard() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+    SHARD.with(|shard| *shard)
+}
+
+// Readers opened as more reads run at once.
+#[derive(Default)]
+struct Pool {
+    readers: Vec<LogReader>,
+    // bumped when segments are retired, readers lent out before are closed once returned
+    epoch: u64,
+}
+
+/// Keeps segments on disk until dropped, see `ReadState::pin`.
+pub(crate) struct Pins {
+    state: Arc<ReadState>,
+    gens: Vec<u64>,
+    // the shard of pins counting them, pins may be dropped on another thread
+    shard: usize,
+}
+
+impl ReadState {
+    // Milliseconds since the Unix epoch, which expiry times are counted in.
+    pub(crate) fn now_millis(&self) -> u64 {
+        u64::try_from(self.clock.now().as_millis()).unwrap_or(u64::MAX)
+    }
+
+    // Returns the index entry of `key`, unless its value expired.
+    pub(crate) fn entry(&self, key: &str) -> Option<IndexEntry> {
+        let now = self.now_millis();
+        let index = unpoisoned(self.index.read());
+        index.get(key).filter(|entry| !entry.expired(now)).cloned()
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        let now = self.now_millis();
+        let index = unpoisoned(self.index.read());
+        index.get(key).is_some_and(|entry| !entry.expired(now))
+    }
+
+    pub(crate) fn kind(&self, key: &str) -> Option<ValueKind> {
+        let now = self.now_millis();
+        let index = unpoisoned(self.index.read());
+        index
+            .get(key)
+            .filter(|entry| !entry.expired(now))
+            .map(|entry| entry.kind)
+    }
+
+    // Returns the keys of the string values in `range`, in ascending order.
+    pub(crate) fn string_keys_in<R: RangeBounds<String>>(&self, range: R) -> Vec<String> {
+        let now = self.now_millis();
+        let index = unpoisoned(self.index.read());
+        let mut keys = index.keys_in(range);
+        keys.retain(|key| {
+            index
+                .get(key)
+                .is_some_and(|entry| entry.kind == ValueKind::String && !entry.expired(now))
+        });
+        keys
+    }
+
+    // Reads the string value of `key`, without asking the origin.
+    pub(crate) fn get(self: &Arc<Self>, key: &str) -> Result<Option<(String, Metadata)>> {
+        match self.read_value(key, ValueKind::String)? {
+            Some((Value::String(value), seq)) => Ok(Some((
+                value,
+                Metadata {
+                    version: Version(seq),
+                },
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    // Reads the value of `key` by replaying its records, checking it has the expected kind.
+    pub(crate) fn read_value(
+        self: &Arc<Self>,
+        key: &str,
+        kind: ValueKind,
+    ) -> Result<Option<(Value, u64)>> {
+        let (mut found, _pins) = self.look_up(&[key], kind)?;
+        let Some(found) = found.pop().flatten() else {
+            return Ok(None);
+        };
+        let value = self.with_reader(|reader| found.read(reader))?;
+        Ok(Some((value, found.entry.seq)))
+    }
+
+    // Reads the string values of `keys` with a single reader, in the order they lie in the
+    // log rather than the order asked for, so the reads move forward through each segment.
+    pub(crate) fn get_many(self: &Arc<Self>, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let (found, _pins) = self.look_up(&keys, ValueKind::String)?;
+        let mut order: Vec<usize> = (0..keys.len()).filter(|&i| found[i].is_some()).collect();
+        order.sort_by_key(|&i| found[i].as_ref().map(Found::position));
+        let mut values = vec![None; keys.len()];
+        self.with_reader(|reader| {
+            for i in order {
+                let found = found[i].as_ref().expect("filtered above");
+                if let Value::String(value) = found.read(reader)? {
+                    values[i] = Some(value);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    // Looks the values of `keys` up at once, checking they have the expected kind, and
+    // pins the segments holding them before the index is let go, so a compaction swapped
+    // in meanwhile keeps them until they are read.
+    pub(crate) fn look_up(
+        self: &Arc<Self>,
+        keys: &[&str],
+        kind: ValueKind,
+    ) -> Result<(Vec<Option<Found>>, Pins)> {
+        let now = self.now_millis();
+        let mut found = Vec::with_capacity(keys.len());
+        let mut gens = Vec::new();
+        let pins = {
+            let index = unpoisoned(self.index.read());
+            for &key in keys {
+                self.hot_keys.record(key);
+                let Some(entry) = index.get(key).filter(|entry| !entry.expired(now)) else {
+                    found.push(None);
+                    continue;
+                };
+                if entry.kind != kind {
+                    return Err(KvsError::WrongType(key.to_owned()).into());
+                }
+                let blob = match entry.blob {
+                    Some(hash) => Some((hash, unpoisoned(self.blobs.read()).pos(hash)?)),
+                    None => None,
+                };
+                match &blob {
+                    Some((_, cmd_pos)) => gens.push(cmd_pos.gen),
+                    None => gens.extend(entry_gens(entry)),
+                }
+                found.push(Some(Found {
+                    entry: entry.clone(),
+                    blob,
+                }));
+            }
+            self.pin(gens)
+        };
+        for (key, found) in keys.iter().zip(&found) {
+            if found.is_some() {
+                self.record_access(key);
+            }
+        }
+        Ok((found, pins))
+    }
+
+    // Notes `key` was read for the eviction policy, told once a write applies or enough
+    // keys were read.
+    fn record_access(&self, key: &str) {
+        let Some(policy) = &self.eviction_policy else {
+            return;
+        };
+        let mut accessed = self.accessed.local();
+        accessed.push(key.to_owned());
+        if accessed.len() < MAX_BUFFERED_ACCESSES {
+            return;
+        }
+        drop(accessed);
+        let index = unpoisoned(self.index.read());
+        self.flush_accesses(&index, &mut **unpoisoned(policy.lock()));
+    }
+
+    // Tells `policy` about the keys read since it was last told, those still in `index`.
+    pub(crate) fn flush_accesses(&self, index: &Index, policy: &mut dyn EvictionPolicy) {
+        for mut accessed in self.accessed.each() {
+            for key in accessed.drain(..) {
+                if index.contains_key(&key) {
+                    policy.on_access(&key);
+                }
+            }
+        }
+    }
+
+    // Runs `read` with a reader of the log no other read is using.
+    pub(crate) fn with_reader<T>(
+        &self,
+        read: impl FnOnce(&mut LogReader) -> Result<T>,
+    ) -> Result<T> {
+        let shard = thread_shard();
+        let (reader, epoch) = {
+            let mut pool = self.readers.idle.get(shard);
+            (pool.readers.pop(), pool.epoch)
+        };
+        let mut reader = reader.unwrap_or_else(|| {
+            LogReader::new(
+                self.storage.clone(),
+                self.dir.clone(),
+                self.compression_stats.clone(),
+            )
+        });
+        let result = read(&mut reader);
+        let mut pool = self.readers.idle.get(shard);
+        if pool.epoch == epoch {
+            pool.readers.push(reader);
+        } else {
+            // it may hold on to retired segments, which Windows refuses to delete
+            drop(pool);
+            drop(reader);
+            self.remove_retired_or_warn();
+        }
+        result
+    }
+
+    /// Keeps the segments `gens` on disk until the returned pins are dropped, even once a
+    /// compaction replaced them.
+    ///
+    /// Segments are only pinned while they hold records of the index, so reads pin them
+    /// before letting go of the index, or hold the store lock.
+    pub(crate) fn pin(self: &Arc<Self>, mut gens: Vec<u64>) -> Pins {
+        gens.sort_unstable();
+        gens.dedup();
+        let shard = thread_shard();
+        let mut pins = self.readers.pins.get(shard);
+        for &gen in &gens {
+            *pins.entry(gen).or_default() += 1;
+        }
+        Pins {
+            state: self.clone(),
+            gens,
+            shard,
+        }
+    }
+
+    // Deletes the segments `gens` once nothing uses them, oldest first.
+    pub(crate) fn retire(&self, gens: impl IntoIterator<Item = u64>) {
+        let mut retired = unpoisoned(self.readers.retired.lock());
+        retired.extend(gens);
+        self.readers
+            .has_retired
+            .store(!retired.is_empty(), Ordering::SeqCst);
+        drop(retired);
+        self.close_readers();
+        self.remove_retired_or_warn();
+    }
+
+    // Closes the readers of the log, idle ones right away and those lent out once returned,
+    // as they may hold on to segments replaced.
+    pub(crate) fn close_readers(&self) {
+        for mut pool in self.readers.idle.each() {
+            pool.readers.clear();
+            pool.epoch += 1;
+        }
+    }
+
+    // Returns the generations of the segments on disk, without the retired ones.
+    pub(crate) fn live_gens(&self) -> Result<Vec<u64>> {
+        let mut gens = segment_gens(&*self.storage, &self.dir)?;
+        let retired = unpoisoned(self.readers.retired.lock());
+        gens.retain(|gen| !retired.contains(gen));
+        Ok(gens)
+    }
+
+    // Whether a read or view uses the segment `gen`. Retired segments are no longer in the
+    // index, so none is pinned again while the shards are gone through.
+    fn pinned(&self, gen: u64) -> bool {
+        self.readers.pins.each().any(|pins| pins.contains_key(&gen))
+    }
+
+    // Replaying older segments left behind by a crash before the compacted one gives the
+    // same state, they only take up space, as long as they are deleted oldest first so no
+    // value outlives the segment holding its removal.
+    fn remove_retired(&self) -> Result<()> {
+        let _removing = unpoisoned(self.readers.removing.lock());
+        loop {
+            let gen = {
+                let retired = unpoisoned(self.readers.retired.lock());
+                match retired.first() {
+                    Some(&gen) if !self.pinned(gen) => gen,
+                    _ => return Ok(()),
+                }
+            };
+            match self.storage.remove(&segment_path(&self.dir, gen)) {
+                Ok(()) => {}
+                // removed by a store opened again in the meantime
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            let mut retired = unpoisoned(self.readers.retired.lock());
+            retired.remove(&gen);
+            self.readers
+                .has_retired
+                .store(!retired.is_empty(), Ordering::SeqCst);
+        }
+    }
+
+    // Deleting is tried again once the next pin is dropped or a compaction retires more.
+    fn remove_retired_or_warn(&self) {
+        if let Err(e) = self.remove_retired() {
+            warn!(
+                "Failed to delete the compacted segments of {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for Pins {
+    fn drop(&mut self) {
+        let mut pins = self.state.readers.pins.get(self.shard);
+        for gen in &self.gens {
+            if let Some(count) = pins.get_mut(gen) {
+                *count -= 1;
+                if *count == 0 {
+                    pins.remove(gen);
+                }
+            }
+        }
+        drop(pins);
+        // set before a retired segment is checked for pins, so either that check or this
+        // one sees the pin is gone
+        if self.state.readers.has_retired.load(Ordering::SeqCst) {
+            self.state.remove_retired_or_warn();
+        }
+    }
+}
+
+/// A value looked up in the index, to be read once the index is let go.
+pub(crate) struct Found {
+    pub(crate) entry: IndexEntry,
+    // the hash and position of the blob the value refers to
+    blob: Option<(u64, CommandPos)>,
+}
+
+impl Found {
+    pub(crate) fn read(&self, reader: &mut LogReader) -> Result<Value> {
+        match &self.blob {
+            Some((hash, cmd_pos)) => read_blob(reader, *hash, cmd_pos).map(Value::String),
+            None => replay_entry(reader, &self.entry),
+        }
+    }
+
+    // Where the value starts in the log.
+    fn position(&self) -> (u64, u64) {
+        let cmd_pos = self
+            .blob
+            .as_ref()
+            .map_or(&self.entry.base, |(_, cmd_pos)| cmd_pos);
+        (cmd_pos.gen, cmd_pos.pos)
+    }
+}
+
+// Returns the generations of the segments holding the records of `entry`.
+pub(crate) fn entry_gens(entry: &IndexEntry) -> Vec<u64> {
+    std::iter::once(&entry.base)
+        .chain(&entry.deltas)
+        .map(|cmd_pos: &CommandPos| cmd_pos.gen)
+        .collect()
+}
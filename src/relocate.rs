@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use crate::{compaction::copy_entry, segment::segment_path, unpoisoned, KvStore, Result, Store};
+
+/// Writes between two looks for hot keys to relocate.
+const RELOCATION_INTERVAL: u64 = 1_000;
+/// Number of the hottest keys looked at each time.
+const RELOCATION_CANDIDATES: usize = 16;
+
+impl Store {
+    // Every `RELOCATION_INTERVAL` writes, copies the values of the hottest keys living in
+    // mostly stale segments to the current one, so reads of them hit recently written
+    // data until the next compaction rewrites the rest.
+    pub(crate) fn relocate_hot_keys(&mut self) -> Result<()> {
+        let Some(min_stale_fraction) = self.relocation else {
+            return Ok(());
+        };
+        self.writes_since_relocation += 1;
+        if self.writes_since_relocation < RELOCATION_INTERVAL || self.compaction.is_running() {
+            return Ok(());
+        }
+        self.writes_since_relocation = 0;
+
+        let candidates: Vec<String> = self
+            .hot_keys(RELOCATION_CANDIDATES)
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| {
+                self.entry(key)
+                    .is_some_and(|entry| entry.blob.is_none() && entry.base.gen < self.gen)
+            })
+            .collect();
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        let stale_fractions = self.stale_fractions()?;
+        let mut moved = Vec::new();
+        let reads = self.reads.clone();
+        reads.with_reader(|reader| {
+            for key in candidates {
+                let Some(entry) = self.entry(&key) else {
+                    continue;
+                };
+                let stale = stale_fractions.get(&entry.base.gen).copied().unwrap_or(0.0);
+                if stale < min_stale_fraction {
+                    continue;
+                }
+                self.check_writable()?;
+                let (base, deltas) = copy_entry(reader, &mut self.writer, self.gen, &key, &entry)?;
+                moved.push((key, base, deltas));
+            }
+            Ok(())
+        })?;
+        // reads go by the index without the store lock, so it only points at records
+        // once they reached the log
+        self.flush_log()?;
+        let mut index = unpoisoned(self.reads.index.write());
+        for (key, base, deltas) in moved {
+            let entry = index.get_mut(&key).expect("copied above");
+            self.stale_size += entry.len();
+            entry.base = base;
+            entry.deltas = deltas;
+            self.stale_size -= entry.len();
+            self.relocated += 1;
+        }
+        Ok(())
+    }
+
+    // Returns the fraction of each sealed segment no longer referenced by the index.
+    fn stale_fractions(&self) -> Result<HashMap<u64, f64>> {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for (_, entry) in self.index().iter() {
+            for cmd_pos in std::iter::once(&entry.base).chain(&entry.deltas) {
+                *live.entry(cmd_pos.gen).or_default() += cmd_pos.len;
+            }
+        }
+        for (_, cmd_pos) in self.blobs().iter() {
+            *live.entry(cmd_pos.gen).or_default() += cmd_pos.len;
+        }
+        let mut fractions = HashMap::new();
+        for (gen, live) in live {
+            if gen == self.gen {
+                continue;
+            }
+            let size = self
+                .storage
+                .open_read(&segment_path(&self.dir, gen))?
+                .size()?;
+            if size > 0 {
+                fractions.insert(gen, 1.0 - live.min(size) as f64 / size as f64);
+            }
+        }
+        Ok(fractions)
+    }
+}
+
+impl KvStore {
+    /// Returns how many times the value of a hot key was moved out of a mostly stale
+    /// segment, see `OpenOptions::relocate_hot_keys`.
+    pub fn relocated_keys(&self) -> u64 {
+        self.read().relocated
+    }
+}
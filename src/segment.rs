@@ -0,0 +1,150 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{self, BufWriter, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use failure::format_err;
+use log::warn;
+use serde_json::Deserializer;
+
+use crate::{
+    codec, hint::HINT_FILE_NAME, BufReaderWithPos, CommandPos, Commands, CompressionStats, Result,
+    Storage, StorageFile,
+};
+
+/// File name of the log of stores written before the log was split into segments.
+pub(crate) const LEGACY_LOG_FILE_NAME: &str = "kvs.log";
+
+/// Returns the path of the segment of generation `gen` in `dir`.
+pub(crate) fn segment_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Returns the generations of the segments in `dir`, in ascending order.
+pub(crate) fn segment_gens(storage: &dyn Storage, dir: &Path) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = storage
+        .list(dir)?
+        .iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+// Turns the single log of an older store into its first segment.
+pub(crate) fn upgrade_legacy_log(storage: &dyn Storage, dir: &Path) -> Result<()> {
+    let legacy_path = dir.join(LEGACY_LOG_FILE_NAME);
+    if !storage.exists(&legacy_path) {
+        return Ok(());
+    }
+    if !segment_gens(storage, dir)?.is_empty() {
+        return Err(format_err!(
+            "{} holds both {} and log segments",
+            dir.display(),
+            LEGACY_LOG_FILE_NAME
+        ));
+    }
+    storage.rename(&legacy_path, &segment_path(dir, 1))?;
+    warn!("Moved the log of {} to its first segment", dir.display());
+    Ok(())
+}
+
+/// Rewrites the segments of JSON records written before the binary format existed.
+///
+/// The records move, so the hint is dropped first. A store that was not closed cleanly
+/// may end in a torn record, which is dropped like a replay would.
+pub(crate) fn upgrade_json_segments(storage: &dyn Storage, dir: &Path, dirty: bool) -> Result<()> {
+    let gens = segment_gens(storage, dir)?;
+    let mut upgraded = false;
+    for (i, &gen) in gens.iter().enumerate() {
+        let path = segment_path(dir, gen);
+        let log = storage.read(&path)?;
+        if !codec::is_json(&log) {
+            continue;
+        }
+        let hint_path = dir.join(HINT_FILE_NAME);
+        if !upgraded && storage.exists(&hint_path) {
+            storage.remove(&hint_path)?;
+        }
+        upgraded = true;
+
+        let tmp_path = dir.join(format!("{}.log.tmp", gen));
+        let mut out = BufWriter::new(storage.create(&tmp_path)?);
+        codec::write_header(&mut out)?;
+        // the lengths of the records written so far, which chunked values are found by
+        let mut lens = Vec::new();
+        for cmd in Deserializer::from_slice(&log).into_iter::<Commands>() {
+            let mut cmd = match cmd {
+                Err(e) if dirty && i + 1 == gens.len() => {
+                    warn!("Dropped the unreadable end of segment {}: {}", gen, e);
+                    break;
+                }
+                cmd => cmd?,
+            };
+            if let Commands::Chunked { records, .. } = &mut cmd {
+                let start = lens.len().checked_sub(records.len()).ok_or_else(|| {
+                    format_err!("Chunked value in segment {} without its chunks", gen)
+                })?;
+                *records = lens[start..].to_vec();
+            }
+            lens.push(codec::write_record(&mut out, &cmd)?);
+        }
+        let mut file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync()?;
+        drop(file);
+        storage.rename(&tmp_path, &path)?;
+    }
+    if upgraded {
+        storage.sync_dir(dir)?;
+        warn!(
+            "Converted the log of {} to the binary format",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Reads records from any segment of a log, opening the segments as they are needed.
+pub(crate) struct LogReader {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    segments: HashMap<u64, BufReaderWithPos<Box<dyn StorageFile>>>,
+    // counts the values decompressed
+    pub(crate) stats: CompressionStats,
+}
+
+impl LogReader {
+    pub(crate) fn new(
+        storage: Arc<dyn Storage>,
+        dir: PathBuf,
+        stats: CompressionStats,
+    ) -> LogReader {
+        LogReader {
+            storage,
+            dir,
+            segments: HashMap::new(),
+            stats,
+        }
+    }
+
+    /// Returns the bytes of the record at `cmd_pos`.
+    pub(crate) fn record(
+        &mut self,
+        cmd_pos: &CommandPos,
+    ) -> Result<io::Take<&mut BufReaderWithPos<Box<dyn StorageFile>>>> {
+        let reader = match self.segments.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = self
+                    .storage
+                    .open_read(&segment_path(&self.dir, cmd_pos.gen))?;
+                entry.insert(BufReaderWithPos::new(file)?)
+            }
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        Ok(reader.take(cmd_pos.len))
+    }
+}
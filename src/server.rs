@@ -0,0 +1,219 @@
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    ops::Bound,
+    sync::Arc,
+    thread,
+};
+
+use log::error;
+use serde_json::Deserializer;
+
+use crate::{
+    protocol::{
+        write_response, Compression, ErrorCode, Request, Response, SCAN_BATCH_BYTES,
+        SCAN_BATCH_PAIRS,
+    },
+    AuthProvider, CompressionStats, KvsEngine, KvsError, Result,
+};
+
+/// Serves the requests of `KvsClient`s from a single engine.
+///
+/// Each connection is served on its own thread, with its own handle to the engine, see
+/// `KvsEngine::share`. Connections to an engine that cannot be shared are served one after
+/// the other.
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+    auth: Option<Arc<dyn AuthProvider>>,
+    stats: CompressionStats,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer {
+            engine,
+            auth: None,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    /// Only serves connections that authenticated with `provider` first, see
+    /// `KvsClient::authenticate`.
+    pub fn auth(mut self, provider: impl AuthProvider + 'static) -> KvsServer<E> {
+        self.auth = Some(Arc::new(provider));
+        self
+    }
+
+    /// Counts the responses the server compressed, over all connections, which keeps
+    /// counting once the server is running.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.stats.clone()
+    }
+
+    /// Listens on `addr` and serves connections until accepting one fails.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves the connections of `listener` until accepting one fails.
+    pub fn serve(mut self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            let Some(mut engine) = self.engine.share() else {
+                Connection {
+                    engine: &mut self.engine,
+                    auth: self.auth.as_deref(),
+                    stats: &self.stats,
+                }
+                .serve(stream, peer);
+                continue;
+            };
+            let (auth, stats) = (self.auth.clone(), self.stats.clone());
+            thread::spawn(move || {
+                Connection {
+                    engine: &mut *engine,
+                    auth: auth.as_deref(),
+                    stats: &stats,
+                }
+                .serve(stream, peer)
+            });
+        }
+        Ok(())
+    }
+}
+
+// A connection being served, with what it needs of the server.
+struct Connection<'a, E: KvsEngine + ?Sized> {
+    engine: &'a mut E,
+    auth: Option<&'a dyn AuthProvider>,
+    stats: &'a CompressionStats,
+}
+
+impl<E: KvsEngine + ?Sized> Connection<'_, E> {
+    fn serve(mut self, stream: TcpStream, peer: SocketAddr) {
+        // a broken connection only ends that connection
+        if let Err(e) = self.handle(stream) {
+            error!("Connection with {} failed: {}", peer, e);
+        }
+    }
+
+    fn handle(&mut self, stream: TcpStream) -> Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut requests = Deserializer::from_reader(reader).into_iter::<Request>();
+        let mut authenticated = self.auth.is_none();
+        let mut compression = None;
+        // a request that ended a scan early, still to be answered
+        let mut pending = None;
+        while let Some(request) = pending.take().map(Ok).or_else(|| requests.next()) {
+            let mut switch_to = None;
+            let response = match request? {
+                Request::Auth { user, secret } => {
+                    authenticated = match self.auth {
+                        Some(auth) => auth.authenticate(&user, &secret)?,
+                        None => true,
+                    };
+                    if authenticated {
+                        Response::Done
+                    } else {
+                        unauthorized()
+                    }
+                }
+                Request::Compress { offered } => {
+                    // every compression is supported so far
+                    let agreed = offered.into_iter().next();
+                    switch_to = Some(agreed);
+                    Response::Compression(agreed)
+                }
+                _ if !authenticated => unauthorized(),
+                Request::Set { key, value } => {
+                    respond(self.engine.set(key, value), |_| Response::Done)
+                }
+                Request::Get { key } => respond(self.engine.get(key), Response::Value),
+                Request::GetRange { key, offset, len } => {
+                    respond(self.engine.get_range(key, offset, len), Response::Bytes)
+                }
+                Request::Rm { key } => respond(self.engine.remove(key), |_| Response::Done),
+                Request::Scan { start, end } => {
+                    pending = self.scan(start, end, &mut requests, &mut writer, compression)?;
+                    continue;
+                }
+                Request::CompactionProgress => respond(
+                    self.engine.compaction_progress(),
+                    Response::CompactionProgress,
+                ),
+                Request::CancelCompaction => {
+                    respond(self.engine.cancel_compaction(), |_| Response::Done)
+                }
+                Request::HotKeys { n } => respond(self.engine.hot_keys(n), Response::HotKeys),
+                Request::ScanNext => Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: "No scan to continue".to_owned(),
+                },
+            };
+            write_response(&mut writer, &response, compression, self.stats)?;
+            if let Some(agreed) = switch_to {
+                compression = agreed;
+            }
+        }
+        Ok(())
+    }
+
+    // Sends the pairs of a scan in batches, each once the client asked for it, and returns
+    // the request that ended the scan early, if any.
+    fn scan(
+        &mut self,
+        start: Bound<String>,
+        end: Bound<String>,
+        requests: &mut impl Iterator<Item = serde_json::Result<Request>>,
+        writer: &mut impl Write,
+        compression: Option<Compression>,
+    ) -> Result<Option<Request>> {
+        let mut pairs = match self.engine.scan(start, end) {
+            Ok(pairs) => pairs.peekable(),
+            Err(e) => {
+                write_response(writer, &Response::error(&e), compression, self.stats)?;
+                return Ok(None);
+            }
+        };
+        loop {
+            let mut batch = Vec::new();
+            let mut size = 0;
+            while batch.len() < SCAN_BATCH_PAIRS && size < SCAN_BATCH_BYTES {
+                match pairs.next() {
+                    Some(Ok((key, value))) => {
+                        size += key.len() + value.len();
+                        batch.push((key, value));
+                    }
+                    Some(Err(e)) => {
+                        write_response(writer, &Response::error(&e), compression, self.stats)?;
+                        return Ok(None);
+                    }
+                    None => break,
+                }
+            }
+            let more = pairs.peek().is_some();
+            let response = Response::Pairs { pairs: batch, more };
+            write_response(writer, &response, compression, self.stats)?;
+            if !more {
+                return Ok(None);
+            }
+            match requests.next().transpose()? {
+                Some(Request::ScanNext) => {}
+                request => return Ok(request),
+            }
+        }
+    }
+}
+
+fn respond<T>(result: Result<T>, ok: impl FnOnce(T) -> Response) -> Response {
+    match result {
+        Ok(value) => ok(value),
+        Err(e) => Response::error(&e),
+    }
+}
+
+fn unauthorized() -> Response {
+    Response::error(&KvsError::Unauthorized.into())
+}
@@ -0,0 +1,262 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hint::HINT_FILE_NAME,
+    meta::{format_timestamp, META_FILE_NAME},
+    segment::{segment_gens, segment_path, LEGACY_LOG_FILE_NAME},
+    KvStore, OpenOptions, Registration, Result, Storage, Store, StoreInfo, COMPACT_FILE_NAME,
+};
+
+/// The directory inside the store holding its snapshots.
+const SNAPSHOT_DIR_NAME: &str = "snapshots";
+
+/// A named copy of a store as it was at some point, see `KvStore::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Size of the log segments it holds, which are shared with the store until
+    /// compactions replace them.
+    pub size: u64,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{} bytes",
+            self.name,
+            format_timestamp(self.created_at),
+            self.size
+        )
+    }
+}
+
+// Written once all files of a snapshot are in place, a snapshot without one is incomplete.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    snapshot: Snapshot,
+    gens: Vec<u64>,
+    info: StoreInfo,
+}
+
+impl Store {
+    // Seals the current segment, so the segments returned are never written again and
+    // can be linked elsewhere, together with a hint covering them.
+    pub(crate) fn freeze(&mut self) -> Result<Vec<u64>> {
+        self.check_writable()?;
+        self.writer.flush()?;
+        self.writer.writer.get_mut().sync()?;
+        self.save_hint()?;
+        let gens = segment_gens(&*self.storage, &self.dir)?;
+        self.seal_segment()?;
+        Ok(gens)
+    }
+
+    // Links the segments into the snapshot directory, after moving the writer to a new
+    // segment so they are never written again.
+    pub(crate) fn snapshot(&mut self, name: &str) -> Result<Snapshot> {
+        check_name(name)?;
+        let dir = self.dir.join(SNAPSHOT_DIR_NAME);
+        if self.storage.exists(&manifest_path(&dir, name)) {
+            return Err(format_err!("Snapshot {} already exists", name));
+        }
+        self.storage.create_dir(&dir)?;
+        // the files of an attempt that did not finish
+        for path in snapshot_files(&*self.storage, &dir, name)? {
+            self.storage.remove(&path)?;
+        }
+
+        let gens = self.freeze()?;
+        let mut size = 0;
+        for &gen in &gens {
+            let path = segment_path(&self.dir, gen);
+            size += self.storage.open(&path)?.size()?;
+            self.storage.link(&path, &segment_file(&dir, name, gen))?;
+        }
+        self.storage
+            .link(&self.dir.join(HINT_FILE_NAME), &hint_file(&dir, name))?;
+        self.storage.sync_dir(&dir)?;
+
+        let manifest = Manifest {
+            snapshot: Snapshot {
+                name: name.to_owned(),
+                created_at: self.clock.now().as_secs(),
+                size,
+            },
+            gens,
+            info: self.info.clone(),
+        };
+        let tmp_path = dir.join(format!("{}.snapshot.tmp", name));
+        let mut tmp = self.storage.create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+        tmp.sync()?;
+        drop(tmp);
+        self.storage.rename(&tmp_path, &manifest_path(&dir, name))?;
+        self.storage.sync_dir(&dir)?;
+        Ok(manifest.snapshot)
+    }
+
+    pub(crate) fn branch(&mut self, to: &Path) -> Result<()> {
+        if self.storage.exists(&to.join(META_FILE_NAME))
+            || !segment_gens(&*self.storage, to)?.is_empty()
+        {
+            return Err(format_err!("{} already holds a store", to.display()));
+        }
+        self.storage.create_dir(to)?;
+        let gens = self.freeze()?;
+        for &gen in &gens {
+            self.storage
+                .link(&segment_path(&self.dir, gen), &segment_path(to, gen))?;
+        }
+        self.storage
+            .link(&self.dir.join(HINT_FILE_NAME), &to.join(HINT_FILE_NAME))?;
+        // the branch appends to its own segments from the start
+        let next = gens.last().map_or(1, |gen| gen + 1);
+        self.storage.create(&segment_path(to, next))?;
+        let info = StoreInfo {
+            created_at: Some(self.clock.now().as_secs()),
+            ..self.info.clone()
+        };
+        info.save(&*self.storage, to, true)?;
+        self.storage.sync_dir(to)?;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Takes a snapshot of the store named `name`, to roll it back to with
+    /// `KvStore::restore_snapshot` later.
+    ///
+    /// Snapshots live in the `snapshots` directory of the store and hard-link its log
+    /// segments, so taking one is cheap. They only take up space of their own once
+    /// compactions replace the segments. Names may only contain ASCII letters, digits,
+    /// `-` and `_`.
+    pub fn snapshot(&self, name: &str) -> Result<Snapshot> {
+        self.write().snapshot(name)
+    }
+
+    /// Creates a new store at `path` starting out with the same contents, to be opened
+    /// with the same storage.
+    ///
+    /// The branch hard-links the segments of the store, like a snapshot, and only writes
+    /// its own changes to new ones. The two are independent from then on, writes to one
+    /// never show up in the other.
+    pub fn branch(&self, path: impl Into<PathBuf>) -> Result<()> {
+        self.write().branch(&path.into())
+    }
+
+    /// Returns the snapshots of the store, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let store = self.read();
+        list(&*store.storage, &store.dir)
+    }
+
+    /// Rolls the store at `path` on the local disk back to a snapshot, see
+    /// `OpenOptions::restore_snapshot`.
+    pub fn restore_snapshot(path: impl Into<PathBuf>, name: &str) -> Result<()> {
+        OpenOptions::new().restore_snapshot(path, name)
+    }
+}
+
+impl OpenOptions {
+    /// Rolls the store at `path`, which must not be open, back to its snapshot `name`.
+    ///
+    /// Everything written since the snapshot was taken is lost. The snapshot is kept, so
+    /// it can be restored again, which also finishes an interrupted restore.
+    pub fn restore_snapshot(&self, path: impl Into<PathBuf>, name: &str) -> Result<()> {
+        let dir = path.into();
+        let storage = self.resolved_storage();
+        let _registration = Registration::acquire_exclusive(&*storage, &dir)?;
+        check_name(name)?;
+        let snapshots = dir.join(SNAPSHOT_DIR_NAME);
+        let manifest: Manifest = match storage.read(&manifest_path(&snapshots, name)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(format_err!("No snapshot named {}", name));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // the hint goes first, so it never describes segments of both
+        for file in [HINT_FILE_NAME, COMPACT_FILE_NAME, LEGACY_LOG_FILE_NAME] {
+            let path = dir.join(file);
+            if storage.exists(&path) {
+                storage.remove(&path)?;
+            }
+        }
+        for gen in segment_gens(&*storage, &dir)? {
+            storage.remove(&segment_path(&dir, gen))?;
+        }
+        for &gen in &manifest.gens {
+            storage.link(
+                &segment_file(&snapshots, name, gen),
+                &segment_path(&dir, gen),
+            )?;
+        }
+        storage.link(&hint_file(&snapshots, name), &dir.join(HINT_FILE_NAME))?;
+        // the store appends to its last segment, which has to stay the snapshot's
+        let next = manifest.gens.last().map_or(1, |gen| gen + 1);
+        storage.create(&segment_path(&dir, next))?;
+        manifest.info.save(&*storage, &dir, true)?;
+        storage.sync_dir(&dir)?;
+        Ok(())
+    }
+}
+
+fn list(storage: &dyn Storage, dir: &Path) -> Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for path in storage.list(&dir.join(SNAPSHOT_DIR_NAME))? {
+        if path.extension().is_some_and(|ext| ext == "snapshot") {
+            let manifest: Manifest = serde_json::from_slice(&storage.read(&path)?)?;
+            snapshots.push(manifest.snapshot);
+        }
+    }
+    snapshots.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+    Ok(snapshots)
+}
+
+// Names end at the first dot of a file name, so they cannot contain one.
+fn check_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format_err!(
+            "Snapshot names may only contain ASCII letters, digits, '-' and '_', not {:?}",
+            name
+        ));
+    }
+    Ok(())
+}
+
+fn snapshot_files(storage: &dyn Storage, dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let prefix = format!("{}.", name);
+    Ok(storage
+        .list(dir)?
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .and_then(|file| file.to_str())
+                .is_some_and(|file| file.starts_with(&prefix))
+        })
+        .collect())
+}
+
+fn manifest_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.snapshot", name))
+}
+
+fn segment_file(dir: &Path, name: &str, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}.log", name, gen))
+}
+
+fn hint_file(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.hint", name))
+}
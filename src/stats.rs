@@ -0,0 +1,99 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{
+    hint::HINT_FILE_NAME,
+    meta::META_FILE_NAME,
+    segment::{segment_gens, segment_path},
+    KvStore, Result, Store,
+};
+
+/// How big the store is and how close it is to its next compaction, see `KvStore::stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Live keys of every kind.
+    pub keys: usize,
+    /// Bytes of the records the index still refers to.
+    pub live_bytes: u64,
+    /// Bytes of the records a compaction would drop.
+    pub stale_bytes: u64,
+    /// Segments of the log, including the one written to.
+    pub segments: usize,
+    /// Bytes of the log, the hint and the metadata on disk.
+    pub disk_bytes: u64,
+    /// See `SpaceUsage::amplification`, `null` in JSON for a log of nothing but stale
+    /// records.
+    pub amplification: f64,
+    /// The amplification beyond which a compaction starts, see
+    /// `OpenOptions::target_amplification`.
+    pub target_amplification: f64,
+    /// Stale bytes the log has to exceed as well, above 0 after a compaction was cancelled
+    /// or failed.
+    pub compact_after: u64,
+    pub compaction_running: bool,
+    /// Whether the next write starts a compaction, unless the compaction window or the
+    /// write rate holds it off.
+    pub compaction_due: bool,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "live_bytes: {}", self.live_bytes)?;
+        writeln!(f, "stale_bytes: {}", self.stale_bytes)?;
+        writeln!(f, "segments: {}", self.segments)?;
+        writeln!(f, "disk_bytes: {}", self.disk_bytes)?;
+        writeln!(f, "space_amplification: {:.2}", self.amplification)?;
+        writeln!(f, "target_amplification: {:.2}", self.target_amplification)?;
+        writeln!(f, "compact_after: {}", self.compact_after)?;
+        writeln!(f, "compaction_running: {}", self.compaction_running)?;
+        write!(f, "compaction_due: {}", self.compaction_due)
+    }
+}
+
+impl Store {
+    fn stats(&self) -> Result<Stats> {
+        let usage = self.space_usage();
+        let segments = segment_gens(&*self.storage, &self.dir)?;
+        let mut disk_bytes = 0;
+        for gen in &segments {
+            disk_bytes += if *gen == self.gen {
+                self.writer.pos
+            } else {
+                self.storage
+                    .open_read(&segment_path(&self.dir, *gen))?
+                    .size()?
+            };
+        }
+        for file in [HINT_FILE_NAME, META_FILE_NAME] {
+            let path = self.dir.join(file);
+            if self.storage.exists(&path) {
+                disk_bytes += self.storage.open_read(&path)?.size()?;
+            }
+        }
+        let compaction_running = self.compaction.is_running();
+        Ok(Stats {
+            keys: self.len(),
+            live_bytes: usage.live_bytes,
+            stale_bytes: self.stale_size,
+            segments: segments.len(),
+            disk_bytes,
+            amplification: usage.amplification(),
+            target_amplification: self.target_amplification,
+            compact_after: self.compact_after,
+            compaction_running,
+            compaction_due: !compaction_running
+                && usage.amplification() > self.target_amplification
+                && self.stale_size > self.compact_after,
+        })
+    }
+}
+
+impl KvStore {
+    /// Returns the number of keys, the size of the log and how close it is to being
+    /// compacted.
+    pub fn stats(&self) -> Result<Stats> {
+        self.read().stats()
+    }
+}
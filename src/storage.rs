@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, TryLockError},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::platform;
+
+/// The file system a store keeps its files in, see `OpenOptions::storage`.
+///
+/// Stores only use flat file names inside their directory, and rely on `rename`
+/// atomically replacing its target while handles opened on the source keep working.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Opens the file at `path` for reading and writing, creating it when missing.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Opens the existing file at `path` for reading only, so it works on read-only file
+    /// systems. Writing to it fails.
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Creates an empty file at `path`, truncating any existing one.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Returns the paths of the files directly in the directory `dir`, in no particular
+    /// order, or nothing if it does not exist.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Atomically replaces `to` with `from`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Makes `to` another name of the file at `from`. It keeps the contents when `from` is
+    /// later replaced or removed, so it must only be used for files that are no longer
+    /// written to.
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Creates the directory `dir` inside an existing one, if it is missing.
+    fn create_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Makes the files created, renamed and removed in the directory `dir` so far durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Returns a name that is the same for every path to the directory `dir`, used to
+    /// refuse opening a store twice.
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf>;
+
+    /// Locks the file at `path` until the returned guard is dropped, exclusively or shared
+    /// with other shared locks. Fails with `WouldBlock` if a conflicting lock is held by
+    /// any process, or with `NotFound` if a shared lock is asked for a missing file. An
+    /// exclusive lock creates the file.
+    ///
+    /// Storages only used by a single process need not lock anything, the default does
+    /// nothing.
+    fn try_lock(&self, _path: &Path, _exclusive: bool) -> io::Result<Box<dyn Send + Sync>> {
+        Ok(Box::new(()))
+    }
+}
+
+/// A file opened through a `Storage`.
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    /// Returns the size of the file in bytes.
+    fn size(&mut self) -> io::Result<u64>;
+
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Makes the contents of the file durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// The local file system, used unless another storage is configured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskStorage;
+
+impl Storage for DiskStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        platform::replace_file(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        platform::remove_file(path)
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        match fs::create_dir(dir) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        }
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        platform::sync_dir(dir)
+    }
+
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+        dir.canonicalize()
+    }
+
+    fn try_lock(&self, path: &Path, exclusive: bool) -> io::Result<Box<dyn Send + Sync>> {
+        let file = if exclusive {
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?
+        } else {
+            // readers may not be allowed to write to the directory
+            File::open(path)?
+        };
+        let locked = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match locked {
+            Ok(()) => Ok(Box::new(file)),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+impl StorageFile for File {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// An in-memory file system, for deterministic tests.
+///
+/// Clones share the same files, so a store can be reopened on them. Paths are used as
+/// given, without resolving `.` or `..`, and syncing does nothing since files live as
+/// long as the storage does.
+#[derive(Debug, Clone)]
+pub struct MemStorage {
+    // distinguishes the directories of different storages in the double-open check
+    id: u64,
+    files: Arc<Mutex<HashMap<PathBuf, MemData>>>,
+}
+
+// the contents of a file, shared by its handles
+type MemData = Arc<Mutex<Vec<u8>>>;
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        MemStorage {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            files: Arc::default(),
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<MemData> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+impl Default for MemStorage {
+    fn default() -> MemStorage {
+        MemStorage::new()
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let data = self
+            .files
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemFile {
+            data,
+            pos: 0,
+            read_only: false,
+        }))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(MemFile {
+            data: self.file(path)?,
+            pos: 0,
+            read_only: true,
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), data.clone());
+        Ok(Box::new(MemFile {
+            data,
+            pos: 0,
+            read_only: false,
+        }))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Ok(self.file(path)?.lock().unwrap().clone())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let data = files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(drop)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let data = self.file(from)?;
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    // directories only exist as the parents of files
+    fn create_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+        Ok(PathBuf::from(format!(
+            "memory-{}:{}",
+            self.id,
+            dir.display()
+        )))
+    }
+}
+
+// A handle on a file of a `MemStorage`, which keeps referring to it across renames.
+struct MemFile {
+    data: MemData,
+    pos: u64,
+    read_only: bool,
+}
+
+impl MemFile {
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file opened for reading only",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        let mut data = self.data.lock().unwrap();
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.data.lock().unwrap().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for MemFile {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.data.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,17 @@
+use std::fmt;
+
+use crate::Result;
+
+/// A slower origin behind a store, which then acts as a persistent cache in front of it,
+/// see `OpenOptions::backing_store`.
+///
+/// Only string values are tiered; lists, sets and hashes stay local.
+pub trait BackingStore: fmt::Debug + Send + Sync {
+    /// Returns the value of `key`, or `None` if the origin does not have it.
+    fn fetch(&self, key: &str) -> Result<Option<String>>;
+
+    fn store(&self, key: &str, value: &str) -> Result<()>;
+
+    /// Removes `key` from the origin, returning whether it was there.
+    fn remove(&self, key: &str) -> Result<bool>;
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use serde_json::{Number, Value};
+
+// A `key = value` line of a TOML document.
+pub(crate) struct Entry {
+    pub(crate) key: String,
+    pub(crate) value: Value,
+    // counted from 1
+    pub(crate) line: usize,
+}
+
+// What is wrong with a TOML document, and on which line, counted from 1.
+#[derive(Debug)]
+pub(crate) struct ParseError {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+// Parses the part of TOML a configuration needs: `key = value` lines, with the values
+// strings, integers, floats or booleans, and `#` comments. Tables, arrays, dates and
+// multi-line strings are rejected rather than misread.
+pub(crate) fn parse(text: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut entries = Vec::new();
+    let mut lines = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |message: String| ParseError {
+            line: line_number,
+            message,
+        };
+        let mut cursor = Cursor { rest: line.trim() };
+        if cursor.at_end() {
+            continue;
+        }
+        if cursor.rest.starts_with('[') {
+            return Err(error(
+                "tables are not supported, set every key at the top of the file".to_owned(),
+            ));
+        }
+        let key = cursor.key().map_err(error)?;
+        cursor.skip_whitespace();
+        if !cursor.eat('=') {
+            return Err(error(format!("expected `=` after the key `{}`", key)));
+        }
+        cursor.skip_whitespace();
+        let value = cursor.value().map_err(error)?;
+        cursor.skip_whitespace();
+        if !cursor.at_end() {
+            return Err(error(format!(
+                "unexpected `{}` after the value of `{}`",
+                cursor.rest, key
+            )));
+        }
+        if let Some(first) = lines.insert(key.clone(), line_number) {
+            return Err(error(format!(
+                "`{}` is set twice, first at line {}",
+                key, first
+            )));
+        }
+        entries.push(Entry {
+            key,
+            value,
+            line: line_number,
+        });
+    }
+    Ok(entries)
+}
+
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    // Whether only whitespace or a comment is left.
+    fn at_end(&self) -> bool {
+        self.rest.is_empty() || self.rest.starts_with('#')
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t']);
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Takes the longest prefix of characters matching `pred`.
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest.find(|c| !pred(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        match self.rest.chars().next() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let key = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if key.is_empty() {
+                    return Err(format!("expected a key, found `{}`", self.rest));
+                }
+                if self.rest.starts_with('.') {
+                    return Err("dotted keys are not supported".to_owned());
+                }
+                Ok(key.to_owned())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.rest.chars().next() {
+            None | Some('#') => Err("expected a value after `=`".to_owned()),
+            Some('"') if self.rest.starts_with("\"\"\"") => {
+                Err("multi-line strings are not supported".to_owned())
+            }
+            Some('\'') if self.rest.starts_with("'''") => {
+                Err("multi-line strings are not supported".to_owned())
+            }
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => Err("arrays are not supported".to_owned()),
+            Some('{') => Err("inline tables are not supported".to_owned()),
+            Some(_) => {
+                let token = self.take_while(|c| !matches!(c, ' ' | '\t' | '#'));
+                match token {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => number(token).ok_or_else(|| {
+                        format!(
+                            "expected a string, a number or a boolean, found `{}`",
+                            token
+                        )
+                    }),
+                }
+            }
+        }
+    }
+
+    // Takes a `"..."` string, with its escapes.
+    fn basic_string(&mut self) -> Result<String, String> {
+        let mut chars = self.rest[1..].char_indices();
+        let mut string = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 2..];
+                    return Ok(string);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('b') => '\u{8}',
+                        Some('t') => '\t',
+                        Some('n') => '\n',
+                        Some('f') => '\u{c}',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some(u @ ('u' | 'U')) => {
+                            let digits = if u == 'u' { 4 } else { 8 };
+                            let hex: String = chars.by_ref().take(digits).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .filter(|_| hex.len() == digits)
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| format!("invalid escape `\\{}{}`", u, hex))?
+                        }
+                        Some(c) => return Err(format!("invalid escape `\\{}`", c)),
+                        None => break,
+                    };
+                    string.push(escaped);
+                }
+                c if c.is_control() && c != '\t' => {
+                    return Err("control characters must be escaped in strings".to_owned())
+                }
+                c => string.push(c),
+            }
+        }
+        Err("unterminated string".to_owned())
+    }
+
+    // Takes a `'...'` string, which has no escapes.
+    fn literal_string(&mut self) -> Result<String, String> {
+        match self.rest[1..].find('\'') {
+            Some(end) => {
+                let string = self.rest[1..end + 1].to_owned();
+                self.rest = &self.rest[end + 2..];
+                Ok(string)
+            }
+            None => Err("unterminated string".to_owned()),
+        }
+    }
+}
+
+// Parses a decimal integer or float, with `_` allowed between digits.
+fn number(token: &str) -> Option<Value> {
+    let unsigned = token.strip_prefix(['+', '-']).unwrap_or(token);
+    let bytes = unsigned.as_bytes();
+    let valid = !unsigned.is_empty()
+        && bytes.iter().enumerate().all(|(i, &b)| match b {
+            b'0'..=b'9' => true,
+            b'_' => {
+                i > 0
+                    && bytes[i - 1].is_ascii_digit()
+                    && bytes.get(i + 1).is_some_and(u8::is_ascii_digit)
+            }
+            b'.' => {
+                i > 0
+                    && bytes[i - 1].is_ascii_digit()
+                    && bytes.get(i + 1).is_some_and(u8::is_ascii_digit)
+            }
+            b'e' | b'E' | b'+' | b'-' => true,
+            _ => false,
+        });
+    if !valid || !bytes[0].is_ascii_digit() {
+        return None;
+    }
+    let digits = token.replace('_', "");
+    if unsigned.contains(['.', 'e', 'E']) {
+        let float = digits.parse::<f64>().ok()?;
+        return Number::from_f64(float).map(Value::Number);
+    }
+    // no leading zeros, as in TOML
+    if unsigned.len() > 1 && unsigned.starts_with('0') {
+        return None;
+    }
+    digits.parse::<i64>().ok().map(Value::from)
+}
@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use crate::{
+    dropped, reads::ReadState, unpoisoned, Commands, EventKind, IndexEntry, KvStore, Result, Store,
+};
+
+impl Store {
+    // Milliseconds since the Unix epoch, which expiry times are counted in.
+    fn now_millis(&self) -> u64 {
+        self.reads.now_millis()
+    }
+
+    // Returns the index entry of `key`, unless its value expired.
+    pub(crate) fn entry(&self, key: &str) -> Option<IndexEntry> {
+        self.reads.entry(key)
+    }
+
+    pub(crate) fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        if let Some(origin) = &self.backing_store {
+            origin.store(&key, &value)?;
+        }
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = self.now_millis().saturating_add(ttl);
+        let seq = self.seq;
+        self.write_record(Commands::Expiring {
+            key,
+            value,
+            expires_at,
+            seq,
+        })
+    }
+
+    pub(crate) fn ttl(&self, key: &str) -> Option<Duration> {
+        self.reads.ttl(key)
+    }
+
+    pub(crate) fn persist(&mut self, key: String) -> Result<bool> {
+        if self.ttl(&key).is_none() {
+            return Ok(false);
+        }
+        let Some((value, _)) = self.get_local(&key)? else {
+            return Ok(false);
+        };
+        let seq = self.seq;
+        self.write_record(self.set_record(key, value, seq))?;
+        Ok(true)
+    }
+
+    // Drops the keys whose values expired from the index. Their records are stale from then
+    // on and left behind by the next compaction, nothing is written for them.
+    pub(crate) fn purge_expired(&mut self) {
+        let now = self.now_millis();
+        let mut purged = Vec::new();
+        {
+            let mut index = unpoisoned(self.reads.index.write());
+            let mut blobs = unpoisoned(self.reads.blobs.write());
+            while let Some((expires_at, key)) = self.expiries.first().cloned() {
+                if expires_at > now {
+                    break;
+                }
+                self.expiries.pop_first();
+                // the key may have been written again since
+                let current = index.get(&key).and_then(|entry| entry.expires_at);
+                if current != Some(expires_at) {
+                    continue;
+                }
+                let entry = index.remove(&key).expect("checked above");
+                self.stale_size += dropped(&mut blobs, entry);
+                if let Some(policy) = &self.reads.eviction_policy {
+                    unpoisoned(policy.lock()).on_remove(&key);
+                }
+                purged.push(key);
+            }
+        }
+        for key in purged {
+            self.watchers.notify(&key, EventKind::Removed);
+        }
+    }
+
+    // Returns the number of keys indexed whose values did not expire.
+    pub(crate) fn len(&self) -> usize {
+        let now = self.now_millis();
+        let index = self.index();
+        let expired = self
+            .expiries
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .filter(|(expires_at, key)| {
+                index.get(key).and_then(|entry| entry.expires_at) == Some(*expires_at)
+            })
+            .count();
+        index.len() - expired
+    }
+
+    // Notes when the value written by `cmd` expires, to purge it then.
+    pub(crate) fn track_expiry(&mut self, cmd: &Commands) {
+        if let Commands::Expiring {
+            key, expires_at, ..
+        } = cmd
+        {
+            self.expiries.insert((*expires_at, key.clone()));
+        }
+    }
+
+    // Notes when the values indexed expire, after replaying the log.
+    pub(crate) fn track_expiries(&mut self) {
+        for (key, entry) in unpoisoned(self.reads.index.read()).iter() {
+            if let Some(expires_at) = entry.expires_at {
+                self.expiries.insert((expires_at, key.clone()));
+            }
+        }
+    }
+}
+
+impl ReadState {
+    pub(crate) fn ttl(&self, key: &str) -> Option<Duration> {
+        // one reading of the clock, for the value not to expire in between
+        let now = self.now_millis();
+        let index = unpoisoned(self.index.read());
+        let expires_at = index
+            .get(key)
+            .filter(|entry| !entry.expired(now))?
+            .expires_at?;
+        Some(Duration::from_millis(expires_at - now))
+    }
+}
+
+impl KvStore {
+    /// Sets the string value of `key` for `ttl`, after which it is gone.
+    ///
+    /// The expiry time is written to the log with the value, counted by the clock of the
+    /// store, so it holds across restarts. Expired keys read as missing right away and
+    /// are dropped once the store is written to next, their records by the next
+    /// compaction. Setting the key again without a TTL keeps it. Values with a TTL are
+    /// neither deduplicated, split into chunks nor compressed.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.write().set_with_ttl(key, value, ttl)
+    }
+
+    /// Returns how long the value of `key` has left, or `None` if there is no such key or
+    /// it does not expire.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        self.reads.ttl(key)
+    }
+
+    /// Keeps the value of `key` from expiring, returning whether it was going to.
+    pub fn persist(&self, key: String) -> Result<bool> {
+        self.write().persist(key)
+    }
+}
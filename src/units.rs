@@ -0,0 +1,154 @@
+use std::{fmt, time::Duration};
+
+use failure::format_err;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::Result;
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("B", 1),
+    ("KB", 1_000),
+    ("MB", 1_000_000),
+    ("GB", 1_000_000_000),
+    ("TB", 1_000_000_000_000),
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("TiB", 1 << 40),
+];
+
+const DURATION_UNITS: &[(&str, Duration)] = &[
+    ("us", Duration::from_micros(1)),
+    ("ms", Duration::from_millis(1)),
+    ("s", Duration::from_secs(1)),
+    ("m", Duration::from_secs(60)),
+    ("h", Duration::from_secs(60 * 60)),
+    ("d", Duration::from_secs(24 * 60 * 60)),
+];
+
+/// Parses a number of bytes such as `4096`, `512MiB` or `1.5GB`.
+///
+/// The units are `B`, the decimal `KB`, `MB`, `GB` and `TB` and the binary `KiB`, `MiB`,
+/// `GiB` and `TiB`, with optional space before them. A number without unit counts bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (number, unit) = split_number(s)?;
+    let factor = match SIZE_UNITS.iter().find(|(name, _)| *name == unit) {
+        Some((_, factor)) => *factor,
+        None if unit.is_empty() => 1,
+        None if unit.ends_with('b') => {
+            return Err(format_err!(
+                "Invalid size {:?}: sizes are in bytes, write B instead of b",
+                s
+            ))
+        }
+        None => {
+            return Err(format_err!(
+                "Invalid size {:?}: unknown unit {:?}, expected one of B, KB, MB, GB, TB, \
+                 KiB, MiB, GiB or TiB",
+                s,
+                unit
+            ))
+        }
+    };
+    let bytes = number * factor as f64;
+    if bytes.fract() != 0.0 || bytes >= u64::MAX as f64 {
+        return Err(format_err!(
+            "Invalid size {:?}: not a whole number of bytes",
+            s
+        ));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parses a duration such as `250ms`, `2h` or `1h30m`.
+///
+/// The units are `us`, `ms`, `s`, `m`, `h` and `d`. A duration may add up several numbers
+/// with their units, but every number needs one.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(format_err!("Invalid duration {:?}: it is empty", s));
+    }
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..digits]
+            .parse()
+            .map_err(|_| format_err!("Invalid duration {:?}: expected a number", s))?;
+        rest = rest[digits..].trim_start();
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        let Some((_, factor)) = DURATION_UNITS.iter().find(|(name, _)| *name == unit) else {
+            return Err(format_err!(
+                "Invalid duration {:?}: {}, expected one of us, ms, s, m, h or d",
+                s,
+                match unit {
+                    "" => "missing unit".to_owned(),
+                    unit => format!("unknown unit {:?}", unit),
+                }
+            ));
+        };
+        total += Duration::try_from_secs_f64(factor.as_secs_f64() * number)
+            .map_err(|_| format_err!("Invalid duration {:?}: too long", s))?;
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+// Splits `s` into its leading number and the unit after it.
+fn split_number(s: &str) -> Result<(f64, &str)> {
+    let s = s.trim();
+    let digits = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let number = s[..digits]
+        .parse()
+        .map_err(|_| format_err!("Invalid size {:?}: expected a number", s))?;
+    Ok((number, s[digits..].trim_start()))
+}
+
+// Deserializes a size given either as a number of bytes or as a string for `parse_size`.
+pub(crate) fn deserialize_size<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    struct SizeVisitor;
+
+    impl<'de> Visitor<'de> for SizeVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number of bytes or a size such as \"512MiB\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, bytes: u64) -> std::result::Result<Option<u64>, E> {
+            Ok(Some(bytes))
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> std::result::Result<Option<u64>, E> {
+            parse_size(s).map(Some).map_err(E::custom)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> std::result::Result<Option<u64>, E> {
+            Ok(None)
+        }
+    }
+
+    d.deserialize_any(SizeVisitor)
+}
+
+// Deserializes a duration given as a string for `parse_duration`.
+pub(crate) fn deserialize_duration<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    match Option::<String>::deserialize(d)? {
+        None => Ok(None),
+        Some(text) => parse_duration(&text).map(Some).map_err(de::Error::custom),
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::Mutex,
+};
+
+use crate::{
+    dedup::read_blob,
+    engine::prefix_end,
+    reads::{entry_gens, Pins},
+    replay_entry,
+    segment::LogReader,
+    unpoisoned, CommandPos, IndexEntry, KvStore, Result, Store, Value,
+};
+
+/// The string values of a store as they were at one point, see `KvStore::view`.
+///
+/// Writes to the store after the view was taken do not show up in it. The view keeps a copy
+/// of the index and pins the segments it points into, so reading it never blocks the store
+/// and still works once a compaction replaced those segments, which stay on disk until the
+/// view is dropped.
+pub struct View {
+    values: BTreeMap<String, Frozen>,
+    reader: Mutex<LogReader>,
+    _pins: Pins,
+}
+
+// Where a value of the view was written.
+enum Frozen {
+    Entry(IndexEntry),
+    // a value shared with other keys, with its hash
+    Blob(u64, CommandPos),
+}
+
+impl View {
+    /// Returns the value `key` had when the view was taken.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.values.get(key) {
+            Some(frozen) => self.read(frozen).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// Returns the number of string keys in the view.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the keys of the view in ascending order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> + '_ {
+        self.values.keys().map(String::as_str)
+    }
+
+    /// Returns the key-value pairs whose keys fall in `range`, in ascending key order,
+    /// reading the values as the iterator advances.
+    pub fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        // an inverted range is empty rather than a panic of `BTreeMap::range`
+        let inverted = match (&range.0, &range.1) {
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end)) => start > end,
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        let pairs = if inverted {
+            None
+        } else {
+            Some(self.values.range(range))
+        };
+        pairs
+            .into_iter()
+            .flatten()
+            .map(|(key, frozen)| Ok((key.clone(), self.read(frozen)?)))
+    }
+
+    /// Returns the key-value pairs whose keys start with `prefix`, like `scan`.
+    pub fn scan_prefix(&self, prefix: &str) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.scan((Bound::Included(prefix.to_owned()), prefix_end(prefix)))
+    }
+
+    /// Returns every key-value pair of the view in ascending key order, like `scan`.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.scan(..)
+    }
+
+    fn read(&self, frozen: &Frozen) -> Result<String> {
+        let mut reader = unpoisoned(self.reader.lock());
+        match frozen {
+            Frozen::Entry(entry) => match replay_entry(&mut reader, entry)? {
+                Value::String(value) => Ok(value),
+                _ => unreachable!("views only hold strings"),
+            },
+            Frozen::Blob(hash, cmd_pos) => read_blob(&mut reader, *hash, cmd_pos),
+        }
+    }
+}
+
+impl Store {
+    pub(crate) fn view(&self) -> Result<View> {
+        let mut values = BTreeMap::new();
+        let mut gens = Vec::new();
+        let reader = LogReader::new(
+            self.storage.clone(),
+            self.dir.clone(),
+            self.compression_stats.clone(),
+        );
+        for key in self.string_keys_in(..) {
+            let Some(entry) = self.entry(&key) else {
+                continue;
+            };
+            let frozen = match entry.blob {
+                Some(hash) => Frozen::Blob(hash, self.blobs().pos(hash)?),
+                None => Frozen::Entry(entry.clone()),
+            };
+            match &frozen {
+                Frozen::Entry(entry) => gens.extend(entry_gens(entry)),
+                Frozen::Blob(_, cmd_pos) => gens.push(cmd_pos.gen),
+            }
+            values.insert(key, frozen);
+        }
+        Ok(View {
+            values,
+            reader: Mutex::new(reader),
+            _pins: self.reads.pin(gens),
+        })
+    }
+}
+
+impl KvStore {
+    /// Takes a view of the string values of the store as they are now, which later writes
+    /// leave alone, such as to export or back up a consistent state while writes go on.
+    ///
+    /// Taking it copies the part of the index for string keys and pins the segments those
+    /// point into, it reads no values. Values that expire after the view was taken are read
+    /// from it all the same.
+    pub fn view(&self) -> Result<View> {
+        self.read().view()
+    }
+}
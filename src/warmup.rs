@@ -0,0 +1,99 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    read_entry,
+    segment::{segment_gens, segment_path},
+    KvStore, Result, Store,
+};
+
+/// What to read ahead when a store is opened, see `OpenOptions::prefetch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Prefetch {
+    /// The last `n` bytes of the log, which hold the records written most recently.
+    Recent(u64),
+    /// The whole log.
+    All,
+    /// The values of these keys, such as the ones `KvStore::hot_keys` returned before the
+    /// store was closed. They also start out as hot keys.
+    Keys(Vec<String>),
+    /// The values of the `n` hottest keys saved when the store was last closed, see
+    /// `OpenOptions::persist_heat`.
+    Hot(usize),
+}
+
+/// What opening a store read ahead, see `KvStore::warm_up`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarmUp {
+    /// Bytes of the log read.
+    pub bytes: u64,
+    /// Keys whose values were read.
+    pub keys: usize,
+    pub elapsed: Duration,
+}
+
+impl Store {
+    // Reads what `prefetch` asks for, so the page cache holds it before the first reads.
+    pub(crate) fn warm_up(&mut self, prefetch: &[Prefetch]) -> Result<WarmUp> {
+        let started = Instant::now();
+        let mut warm_up = WarmUp::default();
+        for prefetch in prefetch {
+            match prefetch {
+                Prefetch::Recent(n) => warm_up.bytes += self.read_log_tail(*n)?,
+                Prefetch::All => warm_up.bytes += self.read_log_tail(u64::MAX)?,
+                Prefetch::Keys(keys) => {
+                    for key in keys {
+                        if self.read_ahead(key, &mut warm_up)? {
+                            self.reads.hot_keys.record(key);
+                        }
+                    }
+                }
+                Prefetch::Hot(n) => {
+                    for (key, _) in self.hot_keys(*n) {
+                        self.read_ahead(&key, &mut warm_up)?;
+                    }
+                }
+            }
+        }
+        warm_up.elapsed = started.elapsed();
+        Ok(warm_up)
+    }
+
+    // Reads the value of `key`, returning whether there is one.
+    fn read_ahead(&self, key: &str, warm_up: &mut WarmUp) -> Result<bool> {
+        let Some(entry) = self.entry(key) else {
+            return Ok(false);
+        };
+        self.with_reader(|reader| read_entry(reader, &self.blobs(), &entry))?;
+        warm_up.bytes += entry.len();
+        warm_up.keys += 1;
+        Ok(true)
+    }
+
+    // Reads the last `n` bytes of the log, newest segment first, returning how many there
+    // were.
+    fn read_log_tail(&self, n: u64) -> Result<u64> {
+        let mut left = n;
+        for gen in segment_gens(&*self.storage, &self.dir)?.into_iter().rev() {
+            if left == 0 {
+                break;
+            }
+            let mut file = self.storage.open_read(&segment_path(&self.dir, gen))?;
+            let size = file.size()?;
+            let len = size.min(left);
+            file.seek(SeekFrom::Start(size - len))?;
+            io::copy(&mut file.take(len), &mut io::sink())?;
+            left -= len;
+        }
+        Ok(n - left)
+    }
+}
+
+impl KvStore {
+    /// Returns what was read ahead when the store was opened, if anything was asked for.
+    pub fn warm_up(&self) -> Option<WarmUp> {
+        self.read().warm_up
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use failure::format_err;
+
+use crate::{KvStore, Result, Store};
+
+/// What happened to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The key was set, or its list, set or hash was modified.
+    Written,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: String,
+    pub kind: EventKind,
+}
+
+/// How a subscription buffers events its consumer has not received yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferPolicy {
+    /// Keep at most this many events, discarding the oldest ones when full.
+    DropOldest(usize),
+    /// Keep at most this many events, blocking writes to the store when full.
+    Block(usize),
+    /// Keep one pending event per key, replaced by later events on the same key.
+    Coalesce,
+}
+
+/// A stream of events for the keys matching a pattern, see `KvStore::subscribe`.
+pub struct Subscription {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    pattern: Pattern,
+    policy: BufferPolicy,
+    state: Mutex<State>,
+    // signalled when an event is queued or the store is dropped
+    readable: Condvar,
+    // signalled when an event is taken or the subscription is dropped
+    writable: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<KeyEvent>,
+    // pending event kinds for `BufferPolicy::Coalesce`, keyed by the keys in `events`
+    coalesced: HashMap<String, EventKind>,
+    dropped: u64,
+    unsubscribed: bool,
+    store_closed: bool,
+}
+
+impl Subscription {
+    /// Waits for the next event, returning `None` once the store is dropped and all
+    /// buffered events were received.
+    pub fn recv(&self) -> Option<KeyEvent> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(event) = self.shared.pop(&mut state) {
+                return Some(event);
+            }
+            if state.store_closed {
+                return None;
+            }
+            state = self.shared.readable.wait(state).unwrap();
+        }
+    }
+
+    /// Like `recv`, but gives up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<KeyEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(event) = self.shared.pop(&mut state) {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if state.store_closed || now >= deadline {
+                return None;
+            }
+            state = self
+                .shared
+                .readable
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Returns the next buffered event without waiting.
+    pub fn try_recv(&self) -> Option<KeyEvent> {
+        let mut state = self.shared.lock();
+        self.shared.pop(&mut state)
+    }
+
+    /// Number of events discarded so far by `BufferPolicy::DropOldest`.
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = KeyEvent;
+
+    fn next(&mut self) -> Option<KeyEvent> {
+        self.recv()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.shared.lock().unsubscribed = true;
+        // a writer may be blocked on this subscription
+        self.shared.writable.notify_all();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn pop(&self, state: &mut State) -> Option<KeyEvent> {
+        let mut event = state.events.pop_front()?;
+        if let Some(kind) = state.coalesced.remove(&event.key) {
+            event.kind = kind;
+        }
+        self.writable.notify_all();
+        Some(event)
+    }
+
+    fn push(&self, event: KeyEvent) {
+        let mut state = self.lock();
+        match self.policy {
+            BufferPolicy::DropOldest(capacity) => {
+                while state.events.len() >= capacity.max(1) {
+                    state.events.pop_front();
+                    state.dropped += 1;
+                }
+                state.events.push_back(event);
+            }
+            BufferPolicy::Block(capacity) => {
+                while state.events.len() >= capacity.max(1) && !state.unsubscribed {
+                    state = self.writable.wait(state).unwrap();
+                }
+                state.events.push_back(event);
+            }
+            BufferPolicy::Coalesce => {
+                if let Some(kind) = state.coalesced.get_mut(&event.key) {
+                    *kind = event.kind;
+                } else {
+                    state.coalesced.insert(event.key.clone(), event.kind);
+                    state.events.push_back(event);
+                }
+            }
+        }
+        self.readable.notify_all();
+    }
+}
+
+/// The subscriptions registered on a store.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    subscriptions: Vec<Arc<Shared>>,
+}
+
+impl Watchers {
+    pub(crate) fn notify(&mut self, key: &str, kind: EventKind) {
+        self.subscriptions
+            .retain(|shared| !shared.lock().unsubscribed);
+        for shared in &self.subscriptions {
+            if shared.pattern.matches(key) {
+                shared.push(KeyEvent {
+                    key: key.to_owned(),
+                    kind,
+                });
+            }
+        }
+    }
+}
+
+impl Drop for Watchers {
+    fn drop(&mut self) {
+        for shared in &self.subscriptions {
+            shared.lock().store_closed = true;
+            shared.readable.notify_all();
+        }
+    }
+}
+
+impl Store {
+    pub(crate) fn subscribe(
+        &mut self,
+        pattern: &str,
+        policy: BufferPolicy,
+    ) -> Result<Subscription> {
+        let shared = Arc::new(Shared {
+            pattern: Pattern::parse(pattern)?,
+            policy,
+            state: Mutex::new(State::default()),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+        });
+        self.watchers.subscriptions.push(shared.clone());
+        Ok(Subscription { shared })
+    }
+}
+
+impl KvStore {
+    /// Subscribes to writes and removals of the keys matching the glob `pattern`.
+    ///
+    /// Patterns support `*` (any sequence), `?` (any character), `[abc]`, `[a-z]` and
+    /// `[!abc]` classes, and `\` to escape the next character. Events are delivered
+    /// after the write reached the log; with `BufferPolicy::Block` a slow consumer holds
+    /// up writes to the store, so it must not be read from the writing thread.
+    pub fn subscribe(&self, pattern: &str, policy: BufferPolicy) -> Result<Subscription> {
+        self.write().subscribe(pattern, policy)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    Any,
+    AnySequence,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+#[derive(Debug)]
+struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Pattern> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let escaped = |i: usize| {
+            chars
+                .get(i)
+                .copied()
+                .ok_or_else(|| format_err!("Dangling escape in pattern {:?}", pattern))
+        };
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = match chars[i] {
+                '*' => Token::AnySequence,
+                '?' => Token::Any,
+                '\\' => {
+                    i += 1;
+                    Token::Char(escaped(i)?)
+                }
+                '[' => {
+                    i += 1;
+                    let negated = chars.get(i) == Some(&'!');
+                    if negated {
+                        i += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    // a `]` right after the opening bracket is a literal
+                    while ranges.is_empty() || chars.get(i) != Some(&']') {
+                        let start = match chars.get(i) {
+                            Some('\\') => {
+                                i += 1;
+                                escaped(i)?
+                            }
+                            Some(c) => *c,
+                            None => {
+                                return Err(format_err!("Unclosed class in pattern {:?}", pattern))
+                            }
+                        };
+                        match (chars.get(i + 1), chars.get(i + 2)) {
+                            (Some('-'), Some(end)) if *end != ']' => {
+                                ranges.push((start, *end));
+                                i += 3;
+                            }
+                            _ => {
+                                ranges.push((start, start));
+                                i += 1;
+                            }
+                        }
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        Ok(Pattern { tokens })
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        // classic greedy matching with backtracking to the last `*`
+        let (mut t, mut k) = (0, 0);
+        let mut backtrack = None;
+        while k < key.len() {
+            match self.tokens.get(t) {
+                Some(Token::AnySequence) => {
+                    backtrack = Some((t, k));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token_matches(token, key[k]) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((star, star_k)) => {
+                    t = star + 1;
+                    k = star_k + 1;
+                    backtrack = Some((star, star_k + 1));
+                }
+                None => return false,
+            }
+        }
+        self.tokens[t..]
+            .iter()
+            .all(|token| *token == Token::AnySequence)
+    }
+}
+
+fn token_matches(token: &Token, c: char) -> bool {
+    match token {
+        Token::Char(expected) => *expected == c,
+        Token::Any => true,
+        Token::AnySequence => false,
+        Token::Class { negated, ranges } => {
+            ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&c))
+                != *negated
+        }
+    }
+}
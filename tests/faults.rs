@@ -0,0 +1,353 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use kvs::{DiskStorage, Fault, FaultInjector, IoOp, KvStore, OpenOptions, Result, WriteOptions};
+use tempfile::TempDir;
+
+// Should recover every value after crashing in the middle of a compaction.
+#[test]
+fn crash_mid_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    faults.inject(
+        Fault::crash(IoOp::Write)
+            .on_file("kvs.compact.log")
+            .after(2),
+    );
+    let mut iter = 0;
+    while !faults.crashed() {
+        assert!(iter < 100, "compaction never ran");
+        let _ = store.set("key0".to_owned(), format!("value{}", iter));
+        store.compaction_handle().wait();
+        iter += 1;
+    }
+    assert!(store.get("key1".to_owned()).is_err());
+    drop(store);
+    assert!(temp_dir.path().join("kvs.compact.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery().is_some());
+    assert!(!temp_dir.path().join("kvs.compact.log").exists());
+    // the write that started the compaction reached the log before the crash
+    assert_eq!(
+        store.get("key0".to_owned())?,
+        Some(format!("value{}", iter - 1))
+    );
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should recover every value and no removed one after crashing at any step that swaps the
+// compacted segment in.
+#[test]
+fn crash_swapping_compacted_segment() -> Result<()> {
+    let steps = [
+        Fault::crash(IoOp::Sync).on_file("kvs.compact.log"),
+        Fault::crash(IoOp::Rename).on_file("kvs.compact.log"),
+        Fault::crash(IoOp::SyncDir),
+        Fault::crash(IoOp::Remove),
+        Fault::crash(IoOp::Remove).after(1),
+    ];
+    for step in steps {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let faults = FaultInjector::new(DiskStorage);
+        let mut options = OpenOptions::new();
+        options
+            .storage(faults.clone())
+            .segment_size(200)
+            .target_amplification(f64::INFINITY);
+        let store = options.open(temp_dir.path())?;
+        for round in 0..2 {
+            for i in 0..10 {
+                store.set(format!("key{}", i), format!("value{}", round))?;
+            }
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        // compacts on the next write
+        options.target_amplification(1.5);
+        let store = options.open(temp_dir.path())?;
+        faults.inject(step.clone());
+        let _ = store.set("key1".to_owned(), "last".to_owned());
+        store.compaction_handle().wait();
+        assert!(faults.crashed(), "{:?} never ran", step);
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None, "after {:?}", step);
+        assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+        for i in 2..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some("value1".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// Should keep deduplicated values readable after crashing between renaming the compacted
+// segment in and deleting the segments it replaces, which are then replayed together.
+#[test]
+fn crash_after_compaction_rename_with_dedup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let mut options = OpenOptions::new();
+    options.storage(faults.clone()).dedup_values(10);
+    let store = options.open(temp_dir.path())?;
+    let value = "v".repeat(100);
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), value.clone())?;
+    faults.inject(Fault::crash(IoOp::Remove));
+    assert!(store.compact_now().is_err());
+    assert!(faults.crashed());
+    drop(store);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    let store = OpenOptions::new().dedup_values(10).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Should serve reads from several threads at once, none waiting for another to reach the
+// disk.
+#[test]
+fn concurrent_reads() -> Result<()> {
+    const THREADS: usize = 4;
+    const READ_DELAY: Duration = Duration::from_millis(100);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .eviction(100, kvs::Lru::default())
+        .open(temp_dir.path())?;
+    for i in 0..THREADS {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    faults.inject(
+        Fault::delay(IoOp::Read, READ_DELAY)
+            .on_file("1.log")
+            .times(u64::MAX),
+    );
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for i in 0..THREADS {
+            let store = store.clone();
+            scope.spawn(move || {
+                assert_eq!(
+                    store.get(format!("key{}", i)).unwrap(),
+                    Some(format!("value{}", i))
+                );
+            });
+        }
+    });
+    // one after the other, the reads would take at least THREADS delays
+    let elapsed = started.elapsed();
+    assert!(elapsed < READ_DELAY * THREADS as u32, "{:?}", elapsed);
+    faults.reset();
+    Ok(())
+}
+
+// Should keep serving reads and writes while a compaction runs in the background.
+#[test]
+fn write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let mut options = OpenOptions::new();
+    options
+        .storage(faults.clone())
+        .target_amplification(f64::INFINITY);
+    let store = options.open(temp_dir.path())?;
+    for round in 0..2 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", round))?;
+        }
+    }
+    store.rpush("list".to_owned(), ["a".to_owned()])?;
+    drop(store);
+
+    options.target_amplification(1.5);
+    let store = options.open(temp_dir.path())?;
+    let handle = store.compaction_handle();
+    faults.inject(Fault::delay(IoOp::Write, Duration::from_millis(20)).on_file("kvs.compact.log"));
+    store.set("key1".to_owned(), "last".to_owned())?;
+    assert!(handle.progress().running);
+    store.set("key2".to_owned(), "new".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.rpush("list".to_owned(), ["b".to_owned()])?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value1".to_owned()));
+    handle.wait();
+    assert!(store.info().last_compaction.is_some());
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.lrange("list".to_owned(), 0, -1)?, ["a", "b"]);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Writes should wait for a compaction that lags behind, and start one outside its window.
+#[test]
+fn write_stall() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let mut options = OpenOptions::new();
+    options
+        .storage(faults.clone())
+        .target_amplification(1.5)
+        .compaction_max_write_rate(0.0)
+        .write_stall(f64::INFINITY, 50)
+        .max_write_stall(Duration::from_secs(10));
+    let store = options.open(temp_dir.path())?;
+    let handle = store.compaction_handle();
+    faults.inject(Fault::delay(IoOp::Write, Duration::from_millis(20)).on_file("kvs.compact.log"));
+    for i in 0..3 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    assert!(!handle.progress().running);
+    assert_eq!(store.write_stalls().count, 0);
+
+    // the write starting the compaction goes through, the next one waits for it
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(handle.progress().running);
+    store.set("key1".to_owned(), "last".to_owned())?;
+    assert!(!handle.progress().running);
+    let stalls = store.write_stalls();
+    assert_eq!(stalls.count, 1);
+    assert!(stalls.total >= Duration::from_millis(20));
+    assert!(store.info().last_compaction.is_some());
+    assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+    Ok(())
+}
+
+// Writes should sync the log as the store or the write options say.
+#[test]
+fn sync_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let sync = WriteOptions { sync: true };
+    let relaxed = WriteOptions { sync: false };
+    faults.inject(Fault::fail(IoOp::Sync).on_file("1.log").times(u64::MAX));
+
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store
+        .set_opts("key2".to_owned(), "value2".to_owned(), sync)
+        .is_err());
+    assert!(store.remove_opts("key1".to_owned(), sync).is_err());
+    store.remove_opts("key1".to_owned(), relaxed)?;
+    drop(store);
+
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .sync_writes(true)
+        .open(temp_dir.path())?;
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    store.set_opts("key4".to_owned(), "value4".to_owned(), relaxed)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    faults.reset();
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    Ok(())
+}
+
+// Should cut the torn record off the log after crashing mid-write.
+#[test]
+fn crash_mid_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    faults.inject(Fault::torn_write().on_file("1.log"));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(faults.crashed());
+    drop(store);
+
+    faults.reset();
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .open(temp_dir.path())?;
+    let recovery = store.recovery().expect("dirty open");
+    assert_eq!(recovery.records, 1);
+    assert!(recovery.bytes_discarded > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should keep whole segments of a bulk load after crashing in the middle of it, the first
+// keys in order, and none of the segment being written.
+#[test]
+fn crash_mid_bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let mut options = OpenOptions::new();
+    options.storage(faults.clone()).segment_size(4096);
+    let store = options.open(temp_dir.path())?;
+    faults.inject(Fault::torn_write().on_file("2.log"));
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{:04}", i)).collect();
+    let pairs = keys.iter().map(|key| (key.clone(), "value".to_owned()));
+    assert!(store.bulk_load(pairs).is_err());
+    assert!(faults.crashed());
+    drop(store);
+
+    faults.reset();
+    let store = options.open(temp_dir.path())?;
+    assert!(store.recovery().is_some());
+    let loaded = store
+        .scan(..)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(!loaded.is_empty() && loaded.len() < keys.len());
+    assert_eq!(loaded, keys[..loaded.len()]);
+    Ok(())
+}
+
+// Should surface injected failures and delays without crashing the store.
+#[test]
+fn fail_and_delay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new(DiskStorage);
+    let store = OpenOptions::new()
+        .storage(faults.clone())
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    faults.inject(Fault::fail(IoOp::Write).on_file("1.log"));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(!faults.crashed());
+
+    faults.inject(Fault::delay(IoOp::Read, Duration::from_millis(50)).times(1));
+    let start = Instant::now();
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    faults.inject(Fault::fail(IoOp::Sync));
+    assert!(store.shutdown().is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info().clean_shutdown, Some(false));
+    Ok(())
+}
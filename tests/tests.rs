@@ -1,9 +1,26 @@
+// the CLI tests pass their arguments as `&[..]`
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    check_against_model, parse_duration, parse_size, serve_repl, BackingStore, BufferPolicy,
+    CompactionWindow, Compression, Config, Divergence, Engine, ErrorCode, EventKind, Fifo,
+    FsckStatus, IndexKind, KeyEvent, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Lfu, Lru,
+    MemStorage, ModelOp, OpenOptions, Outcome, Passwords, Prefetch, ProblemKind, Progress, Result,
+    SimClock, Storage, ValueKind, WriteBatch, SORTED_EXPORT_INDEX_INTERVAL, SORTED_EXPORT_MAGIC,
+};
 use predicates::ord::eq;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::env::current_dir;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::path::Path;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -23,7 +40,8 @@ fn cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
-// `kvs get <KEY>` should print "Key not found" for a non-existent key and exit with zero.
+// `kvs get <KEY>` should report "Key not found" on stderr for a non-existent key and exit
+// with 2.
 #[test]
 fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
@@ -32,11 +50,13 @@ fn cli_get_non_existent_key() {
         .args(&["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(eq("Key not found").trim());
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
 }
 
-// `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+// `kvs rm <KEY>` should report "Key not found" on stderr for an empty database and exit
+// with 2.
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -45,8 +65,48 @@ fn cli_rm_non_existent_key() {
         .args(&["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+}
+
+// Failures of `kvs` should be reported on stderr, with an exit code telling them apart.
+#[test]
+fn cli_exit_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["set", "key1", "value1"]).assert().code(0);
+    kvs(&["get", "key2"]).assert().code(2).stdout(is_empty());
+    for args in [&["unknown"][..], &["set", "key1"], &["mset", "a", "1", "b"]] {
+        kvs(args)
+            .assert()
+            .code(4)
+            .stdout(is_empty())
+            .stderr(contains("Usage"));
+    }
+    kvs(&["--help"])
+        .assert()
+        .code(0)
+        .stdout(contains("Exit codes"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    kvs(&["get", "key1"])
+        .assert()
+        .code(5)
+        .stderr(contains("locked by another process"));
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&log)?;
+    bytes[0] ^= 0xff;
+    std::fs::write(&log, bytes)?;
+    std::fs::remove_file(temp_dir.path().join("kvs.hint"))?;
+    kvs(&["get", "key1"]).assert().code(3).stdout(is_empty());
+    Ok(())
 }
 
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
@@ -66,7 +126,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -95,7 +155,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -112,8 +172,8 @@ fn cli_rm_stored() -> Result<()> {
         .args(&["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(eq("Key not found").trim());
+        .code(2)
+        .stderr(contains("Key not found"));
 
     Ok(())
 }
@@ -169,6 +229,94 @@ fn cli_invalid_rm() {
         .failure();
 }
 
+// Keys and values shell arguments cannot hold should go through files or hex, as long as
+// they are UTF-8.
+#[test]
+fn cli_key_and_value_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    std::fs::write(temp_dir.path().join("key"), "two\nlines")?;
+    std::fs::write(temp_dir.path().join("value"), "tab\tand\nnewline")?;
+    std::fs::write(temp_dir.path().join("binary"), b"\xff\xfe")?;
+
+    kvs(&["set", "--key-file", "key", "--value-file", "value"])
+        .assert()
+        .success();
+    kvs(&["get", "--key-file", "key"])
+        .assert()
+        .success()
+        .stdout(eq("tab\tand\nnewline\n"));
+    // the key in hex
+    kvs(&["get", "--hex", "74776f0a6c696e6573"])
+        .assert()
+        .success()
+        .stdout(eq("74616209616e640a6e65776c696e65\n"));
+    kvs(&["set", "--key-file", "key", "plain"])
+        .assert()
+        .success();
+    kvs(&["get", "--key-file", "key"])
+        .assert()
+        .success()
+        .stdout(eq("plain\n"));
+    kvs(&["set", "--value-file", "-", "piped"])
+        .with_stdin()
+        .buffer("from stdin")
+        .assert()
+        .success();
+    kvs(&["get", "piped"])
+        .assert()
+        .success()
+        .stdout(eq("from stdin\n"));
+
+    kvs(&["set", "--value-file", "binary", "key"])
+        .assert()
+        .failure()
+        .stderr(contains("not valid UTF-8"));
+    // hex only carries UTF-8 text
+    kvs(&["get", "--hex", "fffe"])
+        .assert()
+        .code(4)
+        .stderr(contains("not valid UTF-8"));
+    kvs(&["get", "--hex", "zz"]).assert().failure();
+    kvs(&["set", "--key-file", "key"]).assert().failure();
+    kvs(&["rm", "--key-file", "key"]).assert().success();
+    kvs(&["get", "--key-file", "key"])
+        .assert()
+        .code(2)
+        .stderr(contains("Key not found"));
+    Ok(())
+}
+
+// Invalid arguments should be reported with the usage of the subcommand, before a store is
+// created.
+#[test]
+fn cli_invalid_arguments_leave_no_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (args, usage) in [
+        (&["get"][..], "Usage: kvs get"),
+        (&["rm"], "Usage: kvs rm"),
+        (&["set", "onlykey"], "Usage: kvs set"),
+        (&["get", "--hex", "zz"], "Usage: kvs get"),
+        (&["set", "--key-file", "missing", "value"], "No such file"),
+        (&["mset", "a", "1", "b"], "Usage: kvs mset"),
+        (&["export", "--format", "sst-like"], "Usage: kvs export"),
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(usage));
+    }
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
@@ -182,7 +330,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -192,7 +340,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,9 +350,8 @@ fn get_stored_value() -> Result<()> {
 // Should overwrite existent value.
 #[test]
 fn overwrite_value() -> Result<()> {
-    // let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let temp_dir = current_dir().unwrap();
-    let mut store = KvStore::open(&temp_dir)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -213,7 +360,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(&temp_dir)?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     let v = store.get("key1".to_owned())?;
     println!("{:?}", v);
@@ -229,14 +376,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -245,7 +392,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -253,7 +400,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -264,12 +411,11 @@ fn remove_key() -> Result<()> {
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
-    // let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let temp_dir = current_dir().unwrap();
-    let mut store = KvStore::open(temp_dir)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
-        let entries = WalkDir::new(current_dir().unwrap()).into_iter();
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
         let len: walkdir::Result<u64> = entries
             .map(|res| {
                 res.and_then(|entry| entry.metadata())
@@ -296,7 +442,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(current_dir().unwrap())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -306,3 +452,3507 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Scan should honour inclusive/exclusive bounds and support descending order.
+#[test]
+fn scan_bounds_and_reverse() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    fn keys(scan: impl Iterator<Item = Result<(String, String)>>) -> Result<Vec<String>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    }
+
+    assert_eq!(
+        keys(store.scan("key2".to_owned().."key6".to_owned())?)?,
+        vec!["key2", "key3", "key4"]
+    );
+    assert_eq!(
+        keys(store.scan((
+            Bound::Excluded("key2".to_owned()),
+            Bound::Included("key6".to_owned())
+        ))?)?,
+        vec!["key3", "key4", "key6"]
+    );
+    assert_eq!(
+        keys(store.scan(.."key3".to_owned())?.rev())?,
+        vec!["key2", "key1", "key0"]
+    );
+
+    let latest: Vec<(String, String)> = store
+        .scan("key".to_owned()..)?
+        .rev()
+        .take(2)
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        latest,
+        vec![
+            ("key9".to_owned(), "value9".to_owned()),
+            ("key8".to_owned(), "value8".to_owned())
+        ]
+    );
+
+    Ok(())
+}
+
+// Keys removed while a scan runs should be skipped, not end it.
+#[test]
+fn scan_skips_keys_removed_meanwhile() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut scan = store.scan(..)?;
+    assert_eq!(
+        scan.next().transpose()?.map(|(key, _)| key),
+        Some("key0".to_owned())
+    );
+    let other = store.clone();
+    other.remove("key1".to_owned())?;
+    other.remove("key4".to_owned())?;
+    other.set("key2".to_owned(), "changed".to_owned())?;
+    assert_eq!(
+        scan.collect::<Result<Vec<_>>>()?,
+        vec![
+            ("key2".to_owned(), "changed".to_owned()),
+            ("key3".to_owned(), "value3".to_owned())
+        ]
+    );
+
+    let mut scan = store.scan(..)?.rev();
+    other.remove("key3".to_owned())?;
+    other.remove("key0".to_owned())?;
+    assert_eq!(
+        scan.next().transpose()?.map(|(key, _)| key),
+        Some("key2".to_owned())
+    );
+    assert!(scan.next().is_none());
+
+    Ok(())
+}
+
+// Should scan exactly the keys starting with a prefix, in order.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = OpenOptions::new()
+        .index(IndexKind::Ordered)
+        .open(temp_dir.path())?;
+    for key in [
+        "user:2",
+        "user:1",
+        "user;",
+        "users",
+        "use",
+        "a\u{10FFFF}x",
+        "b",
+    ] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+    store.rpush("user:list".to_owned(), ["a".to_owned()])?;
+
+    fn keys(scan: impl Iterator<Item = Result<(String, String)>>) -> Result<Vec<String>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    }
+
+    assert_eq!(keys(store.scan_prefix("user:")?)?, ["user:1", "user:2"]);
+    assert_eq!(
+        keys(store.scan_prefix("user:")?.rev())?,
+        ["user:2", "user:1"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("use")?)?,
+        ["use", "user:1", "user:2", "user;", "users"]
+    );
+    assert_eq!(keys(store.scan_prefix("a\u{10FFFF}")?)?, ["a\u{10FFFF}x"]);
+    assert_eq!(keys(store.scan_prefix("")?)?.len(), 7);
+    assert!(keys(store.scan_prefix("x")?)?.is_empty());
+    assert_eq!(
+        KvsEngine::scan_prefix(&mut store, "user:")?.collect::<Result<Vec<_>>>()?,
+        [
+            ("user:1".to_owned(), "USER:1".to_owned()),
+            ("user:2".to_owned(), "USER:2".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Should enumerate every key, and the string pairs in order.
+#[test]
+fn keys_and_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().len(), 0);
+    assert!(store.iter().next().is_none());
+    for i in [3, 1, 2] {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key2".to_owned())?;
+    store.sadd("set".to_owned(), ["a".to_owned()])?;
+
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["key1", "key3", "set"]);
+    let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        [
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key3".to_owned(), "value3".to_owned())
+        ]
+    );
+    let mut keys = Vec::new();
+    for pair in &store {
+        keys.push(pair?.0);
+    }
+    assert_eq!(keys, ["key1", "key3"]);
+    Ok(())
+}
+
+// An ordered index should behave like the default one, including after reopening.
+#[test]
+fn ordered_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options.index(IndexKind::Ordered);
+
+    let store = options.open(temp_dir.path())?;
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    let keys: Vec<String> = store
+        .scan("key1".to_owned()..="key4".to_owned())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key1", "key2", "key4"]);
+    // Inverted bounds yield nothing instead of panicking.
+    assert_eq!(store.scan("key5".to_owned().."key1".to_owned())?.count(), 0);
+
+    Ok(())
+}
+
+// `get_set` and `get_del` should return the previous value.
+#[test]
+fn get_set_and_get_del() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.get_set("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.get_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_del("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_del("key1".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// `set_returning` and `remove_returning` should return what the backing store holds too,
+// without caching it, also through a boxed engine.
+#[test]
+fn set_and_remove_returning() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let origin = Origin::default();
+    origin
+        .values
+        .lock()
+        .unwrap()
+        .insert("remote".to_owned(), "value1".to_owned());
+    let store = OpenOptions::new()
+        .backing_store(origin.clone())
+        .open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_returning("remote".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.set_returning("local".to_owned(), "value3".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.remove_returning("remote".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.remove_returning("remote".to_owned())?, None);
+    assert!(origin.values.lock().unwrap().get("remote").is_none());
+
+    let mut engine: Box<dyn KvsEngine> = Box::new(store);
+    assert_eq!(
+        engine.set_returning("local".to_owned(), "value4".to_owned())?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(
+        engine.remove_returning("local".to_owned())?,
+        Some("value4".to_owned())
+    );
+    assert_eq!(engine.remove_returning("local".to_owned())?, None);
+    Ok(())
+}
+
+// `compare_and_swap` should only write if the value is the expected one, and keep
+// concurrent increments from getting lost.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "key".to_owned();
+    assert!(!store.compare_and_swap(key(), Some("1".to_owned()), Some("2".to_owned()))?);
+    assert_eq!(store.get(key())?, None);
+    assert!(store.compare_and_swap(key(), None, Some("1".to_owned()))?);
+    assert!(!store.compare_and_swap(key(), None, Some("2".to_owned()))?);
+    assert!(store.compare_and_swap(key(), Some("1".to_owned()), Some("2".to_owned()))?);
+    assert_eq!(store.get(key())?, Some("2".to_owned()));
+    assert!(store.compare_and_swap(key(), Some("2".to_owned()), None)?);
+    assert_eq!(store.get(key())?, None);
+    assert!(store.compare_and_swap(key(), None, None)?);
+
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned())?;
+                        let next = current.as_deref().unwrap_or("0").parse::<u64>()? + 1;
+                        let new = Some(next.to_string());
+                        if store.compare_and_swap("counter".to_owned(), current, new)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// `set_if_version` should only succeed with the version of the current value.
+#[test]
+fn set_if_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, meta) = store.get_with_metadata("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+
+    let version = store.set_if_version("key1".to_owned(), meta.version, "value2".to_owned())?;
+    assert_ne!(version, meta.version);
+    let err = store
+        .set_if_version("key1".to_owned(), meta.version, "value3".to_owned())
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::VersionConflict("key1".to_owned()))
+    );
+
+    // Tokens survive a round trip through a string and a reopen.
+    let token = version.to_string();
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_if_version("key1".to_owned(), token.parse()?, "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert!(store
+        .set_if_version("key1".to_owned(), version, "value4".to_owned())
+        .is_err());
+
+    Ok(())
+}
+
+// Versions should not be handed out again once a compaction dropped the records they
+// were handed out for, even when the store is opened without its hint.
+#[test]
+fn versions_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let (_, meta) = store.get_with_metadata("key".to_owned())?.unwrap();
+    store.remove("key".to_owned())?;
+    store.compact_now()?;
+    drop(store);
+
+    std::fs::remove_file(temp_dir.path().join("kvs.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    let err = store
+        .set_if_version("key".to_owned(), meta.version, "value3".to_owned())
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::VersionConflict("key".to_owned()))
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `remove_many` should remove the existing keys and report the missing ones.
+#[test]
+fn remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let keys = ["key1", "key3", "key9", "key1"].map(str::to_owned);
+    let summary = store.remove_many(keys)?;
+    assert_eq!(summary.removed, vec!["key1", "key3"]);
+    assert_eq!(summary.missing, vec!["key9"]);
+    assert!(store
+        .remove_many(vec!["key1".to_owned()])?
+        .removed
+        .is_empty());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Typed helpers should round-trip and reject values of another type.
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_i64("counter".to_owned(), -42)?;
+    store.set_u64("size".to_owned(), u64::MAX)?;
+    store.set_f64("ratio".to_owned(), 0.1)?;
+    store.set_bool("enabled".to_owned(), true)?;
+    store.set("name".to_owned(), "kvs".to_owned())?;
+
+    assert_eq!(store.get_i64("counter".to_owned())?, Some(-42));
+    assert_eq!(store.get("counter".to_owned())?, Some("-42".to_owned()));
+    assert_eq!(store.get_u64("size".to_owned())?, Some(u64::MAX));
+    assert_eq!(store.get_f64("ratio".to_owned())?, Some(0.1));
+    assert_eq!(store.get_bool("enabled".to_owned())?, Some(true));
+    assert_eq!(store.get_bool("missing".to_owned())?, None);
+
+    let err = store.get_i64("name".to_owned()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::TypeMismatch {
+            key: "name".to_owned(),
+            expected: "i64"
+        })
+    );
+    assert!(store.get_u64("counter".to_owned()).is_err());
+    assert!(store.get_bool("counter".to_owned()).is_err());
+
+    Ok(())
+}
+
+// `incr` and `decr` should update integer values in place and refuse other values.
+#[test]
+fn incr_and_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.decr("counter".to_owned(), 7)?, -2);
+    assert_eq!(store.get_i64("counter".to_owned())?, Some(-2));
+    store.set_i64("max".to_owned(), i64::MAX)?;
+    assert!(store.incr("max".to_owned(), 1).is_err());
+    assert_eq!(store.get_i64("max".to_owned())?, Some(i64::MAX));
+
+    store.set("text".to_owned(), "abc".to_owned())?;
+    let err = store.incr("text".to_owned(), 1).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::TypeMismatch {
+            key: "text".to_owned(),
+            expected: "i64"
+        })
+    );
+    assert_eq!(store.get("text".to_owned())?, Some("abc".to_owned()));
+    drop(store);
+
+    for (args, output) in [
+        (&["incr", "counter"][..], "-1"),
+        (&["incr", "counter", "10"], "9"),
+        (&["decr", "counter", "-3"], "12"),
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq(output).trim());
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["decr", "text"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// Existence checks and key counts should cover every kind of value and leave out expired
+// ones, also before they are purged.
+#[test]
+fn contains_key_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = SimClock::new(Duration::from_secs(1_000));
+    let store = OpenOptions::new()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    assert!(store.is_empty());
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.rpush("list".to_owned(), ["item".to_owned()])?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "lived".to_owned(),
+        Duration::from_secs(5),
+    )?;
+    assert!(store.contains_key("key") && store.contains_key("list"));
+    assert!(!store.contains_key("missing"));
+    assert_eq!(store.len(), 3);
+
+    clock.advance(Duration::from_secs(6));
+    assert!(!store.contains_key("short"));
+    assert_eq!(store.len(), 2);
+    store.remove("key".to_owned())?;
+    assert!(!store.contains_key("key"));
+    assert_eq!(store.len(), 1);
+    assert!(!store.is_empty());
+    Ok(())
+}
+
+// A view should keep reading the values as they were when it was taken, through later
+// writes and a compaction replacing the segments it points into, which stay on disk until
+// the view is dropped.
+#[test]
+fn view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .segment_size(1024)
+        .target_amplification(1.5)
+        .dedup_values(64)
+        .open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.set("shared".to_owned(), "s".repeat(100))?;
+    store.rpush("list".to_owned(), ["item".to_owned()])?;
+
+    let view = store.view()?;
+    for i in 0..200 {
+        store.set(format!("key{:02}", i % 20), "newer".to_owned())?;
+    }
+    store.remove("key00".to_owned())?;
+    store.remove("shared".to_owned())?;
+    store.set("added".to_owned(), "later".to_owned())?;
+    store.compaction_handle().wait();
+    assert!(temp_dir.path().join("1.log").exists());
+
+    assert_eq!(view.len(), 21);
+    assert_eq!(view.get("key00")?, Some("value0".to_owned()));
+    assert_eq!(view.get("shared")?, Some("s".repeat(100)));
+    assert_eq!(view.get("added")?, None);
+    assert!(!view.contains_key("list"));
+    assert_eq!(
+        view.scan_prefix("key1").collect::<Result<Vec<_>>>()?.len(),
+        10
+    );
+    assert_eq!(
+        view.scan("key18".to_owned().."key20".to_owned())
+            .collect::<Result<Vec<_>>>()?,
+        [
+            ("key18".to_owned(), "value18".to_owned()),
+            ("key19".to_owned(), "value19".to_owned())
+        ]
+    );
+    assert_eq!(view.scan("key5".to_owned().."key1".to_owned()).count(), 0);
+    assert_eq!(view.iter().count(), 21);
+    assert_eq!(store.get("key00".to_owned())?, None);
+    assert_eq!(store.get("key01".to_owned())?, Some("newer".to_owned()));
+
+    drop(view);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("key01".to_owned())?, Some("newer".to_owned()));
+    Ok(())
+}
+
+// Multi-gets should return the values in the order asked for, whatever order they were
+// written in.
+#[test]
+fn get_many_and_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_many(
+        (0..100)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect(),
+    )?;
+    store.set("key7".to_owned(), "newer".to_owned())?;
+    store.rpush("list".to_owned(), ["item".to_owned()])?;
+
+    let keys: Vec<String> = ["key50", "key7", "missing", "key0"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    assert_eq!(
+        store.get_many(&keys)?,
+        [
+            Some("value50".to_owned()),
+            Some("newer".to_owned()),
+            None,
+            Some("value0".to_owned())
+        ]
+    );
+    assert!(store.get_many(&["list".to_owned()]).is_err());
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["mset", "a", "1", "b", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["mset", "c", "3", "d"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["mget", "b", "c", "key99"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(eq("2\n\nvalue99\n"))
+        .stderr(eq("kvs: Key not found: c\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["mget", "a", "b"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1\n2\n"))
+        .stderr(is_empty());
+    Ok(())
+}
+
+// Lists should support pushes on both ends and Redis-style ranges.
+#[test]
+fn list_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.rpush("queue".to_owned(), ["b", "c"].map(str::to_owned))?;
+    store.lpush("queue".to_owned(), ["a", "z"].map(str::to_owned))?;
+    assert_eq!(store.kind("queue"), Some(ValueKind::List));
+    assert_eq!(
+        store.lrange("queue".to_owned(), 0, -1)?,
+        vec!["z", "a", "b", "c"]
+    );
+    assert_eq!(store.lrange("queue".to_owned(), 1, 2)?, vec!["a", "b"]);
+    assert_eq!(store.lrange("queue".to_owned(), -2, 10)?, vec!["b", "c"]);
+    assert!(store.lrange("queue".to_owned(), 3, 1)?.is_empty());
+    assert!(store.lrange("missing".to_owned(), 0, -1)?.is_empty());
+    assert_eq!(store.llen("queue".to_owned())?, 4);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.lrange("queue".to_owned(), 0, -1)?,
+        vec!["z", "a", "b", "c"]
+    );
+
+    Ok(())
+}
+
+// Sets should ignore duplicates and survive reopening.
+#[test]
+fn set_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.sadd("tags".to_owned(), ["x", "y", "x"].map(str::to_owned))?;
+    store.sadd("tags".to_owned(), ["z".to_owned()])?;
+    store.srem("tags".to_owned(), ["y".to_owned(), "w".to_owned()])?;
+    store.srem("missing".to_owned(), ["y".to_owned()])?;
+    assert_eq!(store.kind("missing"), None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let members: Vec<String> = store.smembers("tags".to_owned())?.into_iter().collect();
+    assert_eq!(members, vec!["x", "z"]);
+
+    Ok(())
+}
+
+// Operations on a key holding another kind of value should fail, while `set` replaces it.
+#[test]
+fn wrong_kind_of_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sadd("key2".to_owned(), ["x".to_owned()])?;
+    let err = store
+        .rpush("key1".to_owned(), ["a".to_owned()])
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::WrongType("key1".to_owned()))
+    );
+    assert!(store.get("key2".to_owned()).is_err());
+    assert!(store.lrange("key2".to_owned(), 0, -1).is_err());
+    // scans skip values that are not strings
+    assert_eq!(store.scan(..)?.count(), 1);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should fold list and set deltas into their current value.
+#[test]
+fn compaction_folds_deltas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..100 {
+        store.rpush("list".to_owned(), [i.to_string()])?;
+        store.sadd("set".to_owned(), [(i % 10).to_string()])?;
+        store.set("counter".to_owned(), i.to_string())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let list = store.lrange("list".to_owned(), 0, -1)?;
+    assert_eq!(list, (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+    assert_eq!(store.smembers("set".to_owned())?.len(), 10);
+    assert_eq!(store.get("counter".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// Hash fields should be updated individually and folded by compaction.
+#[test]
+fn hash_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.hset("user".to_owned(), "name".to_owned(), "alice".to_owned())?;
+    store.hset("user".to_owned(), "age".to_owned(), "30".to_owned())?;
+    store.hset("user".to_owned(), "name".to_owned(), "bob".to_owned())?;
+    store.hdel("user".to_owned(), ["age".to_owned(), "missing".to_owned()])?;
+    assert_eq!(store.kind("user"), Some(ValueKind::Hash));
+    assert_eq!(
+        store.hget("user".to_owned(), "name".to_owned())?,
+        Some("bob".to_owned())
+    );
+    assert_eq!(store.hget("user".to_owned(), "age".to_owned())?, None);
+    assert!(store.hget("user".to_owned(), "x".to_owned()).is_ok());
+    assert!(store.get("user".to_owned()).is_err());
+
+    for i in 0..50 {
+        store.hset("user".to_owned(), "visits".to_owned(), i.to_string())?;
+        store.set("other".to_owned(), i.to_string())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let fields: Vec<(String, String)> = store.hgetall("user".to_owned())?.into_iter().collect();
+    assert_eq!(
+        fields,
+        vec![
+            ("name".to_owned(), "bob".to_owned()),
+            ("visits".to_owned(), "49".to_owned())
+        ]
+    );
+    assert!(store.hgetall("missing".to_owned())?.is_empty());
+
+    Ok(())
+}
+
+// Subscriptions should only see keys matching their pattern, buffered per their policy.
+#[test]
+fn subscribe_patterns_and_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let users = store.subscribe("user:[0-9]*", BufferPolicy::DropOldest(2))?;
+    let all = store.subscribe("*", BufferPolicy::Coalesce)?;
+    assert!(store.subscribe("[abc", BufferPolicy::Coalesce).is_err());
+
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("user:x".to_owned(), "b".to_owned())?;
+    store.set("user:2".to_owned(), "c".to_owned())?;
+    store.sadd("user:3".to_owned(), ["d".to_owned()])?;
+    store.remove("user:1".to_owned())?;
+
+    let event = |key: &str, kind| KeyEvent {
+        key: key.to_owned(),
+        kind,
+    };
+    assert_eq!(users.dropped(), 2);
+    assert_eq!(users.try_recv(), Some(event("user:3", EventKind::Written)));
+    assert_eq!(users.try_recv(), Some(event("user:1", EventKind::Removed)));
+    assert_eq!(users.try_recv(), None);
+
+    // user:1 was coalesced into its latest event, keeping its original position
+    let events: Vec<KeyEvent> = std::iter::from_fn(|| all.try_recv()).collect();
+    assert_eq!(
+        events,
+        vec![
+            event("user:1", EventKind::Removed),
+            event("user:x", EventKind::Written),
+            event("user:2", EventKind::Written),
+            event("user:3", EventKind::Written),
+        ]
+    );
+
+    drop(store);
+    assert_eq!(all.recv(), None);
+
+    Ok(())
+}
+
+// A blocking subscription should hold up writes until its consumer catches up.
+#[test]
+fn subscribe_block_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let subscription = store.subscribe("key*", BufferPolicy::Block(1))?;
+    let consumer = std::thread::spawn(move || subscription.take(10).count());
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(consumer.join().unwrap(), 10);
+    // the subscription is gone, so writes no longer block
+    store.set("key10".to_owned(), "value".to_owned())?;
+    store.set("key11".to_owned(), "value".to_owned())?;
+
+    Ok(())
+}
+
+// Reads should not wait for a write a blocking subscription holds up.
+#[test]
+fn reads_do_not_wait_for_blocked_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let subscription = store.subscribe("key*", BufferPolicy::Block(1))?;
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || -> Result<()> {
+            store.set("key1".to_owned(), "1".to_owned())?;
+            // applied, but held up telling the subscription until it is read from
+            store.set("key2".to_owned(), "2".to_owned())
+        })
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!writer.is_finished());
+
+    let (done, reads) = std::sync::mpsc::channel();
+    {
+        let store = store.clone();
+        std::thread::spawn(move || {
+            let read = || -> Result<(Option<String>, bool, usize)> {
+                let value = store.get("key2".to_owned())?;
+                let scanned = store.scan(..)?.collect::<Result<Vec<_>>>()?;
+                Ok((value, store.contains_key("other"), scanned.len()))
+            };
+            done.send(read().unwrap()).unwrap();
+        });
+    }
+    let read = reads.recv_timeout(Duration::from_secs(10));
+    assert_eq!(read, Ok((Some("2".to_owned()), true, 3)));
+
+    assert_eq!(subscription.take(2).count(), 2);
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+// Automatic compaction should only run when the schedule allows it.
+#[test]
+fn compaction_schedule() -> Result<()> {
+    let log_size = |dir: &TempDir| -> u64 {
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let write = |options: &OpenOptions, dir: &TempDir| -> Result<()> {
+        let store = options.open(dir.path())?;
+        for i in 0..100 {
+            store.set("key".to_owned(), i.to_string())?;
+            // compact as often as the schedule allows
+            store.compaction_handle().wait();
+        }
+        Ok(())
+    };
+
+    // a window starting an hour from now never contains the current time
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let hour = (now / 3600 % 24) as u32;
+    let closed = CompactionWindow::new((hour + 1) % 24, 0, (hour + 2) % 24, 0)?;
+
+    let unscheduled = TempDir::new().expect("unable to create temporary working directory");
+    write(&OpenOptions::new(), &unscheduled)?;
+
+    let outside_window = TempDir::new().expect("unable to create temporary working directory");
+    write(
+        OpenOptions::new().compaction_window(closed),
+        &outside_window,
+    )?;
+    assert!(log_size(&outside_window) > 10 * log_size(&unscheduled));
+
+    let busy = TempDir::new().expect("unable to create temporary working directory");
+    write(OpenOptions::new().compaction_max_write_rate(0.0), &busy)?;
+    assert!(log_size(&busy) > 10 * log_size(&unscheduled));
+
+    let quiet = TempDir::new().expect("unable to create temporary working directory");
+    write(
+        OpenOptions::new()
+            .compaction_window(closed)
+            .compaction_max_write_rate(f64::MAX),
+        &quiet,
+    )?;
+    assert_eq!(log_size(&quiet), log_size(&unscheduled));
+
+    let store = KvStore::open(outside_window.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+
+    let window: CompactionWindow = "22:30-04:00".parse()?;
+    assert_eq!(window.to_string(), "22:30-04:00");
+    assert!("25:00-04:00".parse::<CompactionWindow>().is_err());
+    assert!("02:00".parse::<CompactionWindow>().is_err());
+
+    Ok(())
+}
+
+// Compaction progress should be visible through the handle, and cancelling must never
+// lose data.
+#[test]
+fn compaction_progress_and_cancel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = store.compaction_handle();
+    assert!(!handle.progress().running);
+    handle.cancel();
+
+    for i in 0..20 {
+        store.set(format!("key{}", i % 5), i.to_string())?;
+    }
+    handle.wait();
+    let progress = handle.progress();
+    assert!(!progress.running);
+    assert!(progress.bytes_total > 0);
+    assert_eq!(progress.bytes_processed, progress.bytes_total);
+
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let canceller = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                handle.cancel();
+            }
+        })
+    };
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), iter.to_string())?;
+        }
+    }
+    done.store(true, std::sync::atomic::Ordering::SeqCst);
+    canceller.join().unwrap();
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    assert!(!temp_dir.path().join("kvs.compact.log").exists());
+
+    Ok(())
+}
+
+// A compact file left behind by a crash should be finished or discarded on open.
+#[test]
+fn leftover_compact_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    let compact = temp_dir.path().join("kvs.compact.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // crash while writing the compact file: the log is still authoritative
+    std::fs::write(&compact, "{\"Set\":{\"key\":\"key1\",\"va")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!compact.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // crash between deleting the log and renaming the compact file
+    std::fs::rename(&log, &compact)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!compact.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The log should roll over into numbered segments, which a compaction replaces by a
+// single new one.
+#[test]
+fn log_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment = |gen: u64| temp_dir.path().join(format!("{}.log", gen));
+    let mut options = OpenOptions::new();
+    options
+        .segment_size(100)
+        .target_amplification(f64::INFINITY);
+
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(store);
+    assert!(segment(1).exists());
+    assert!(segment(3).exists());
+    assert!(std::fs::metadata(segment(1))?.len() < 150);
+
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value".to_owned()));
+    }
+    let gens = (1..).take_while(|&gen| segment(gen).exists()).count() as u64;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "other".to_owned())?;
+    }
+    drop(store);
+
+    // a lower target compacts on the next write
+    options.target_amplification(1.5);
+    let store = options.open(temp_dir.path())?;
+    store.set("key0".to_owned(), "last".to_owned())?;
+    store.compaction_handle().wait();
+    assert!(store.info().last_compaction.is_some());
+    drop(store);
+    assert!(!segment(1).exists());
+    assert!(!segment(gens).exists());
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("last".to_owned()));
+    for i in 1..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("other".to_owned()));
+    }
+    Ok(())
+}
+
+// The single log of an older store should become its first segment.
+#[test]
+fn legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("kvs.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}",
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("kvs.log").exists());
+    assert!(temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// Segments of JSON records written before the binary format should be converted on open.
+#[test]
+fn json_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let chunks = [r#"{"Chunk":{"data":"abc"}}"#, r#"{"Chunk":{"data":"def"}}"#];
+    let json = format!(
+        r#"{{"Set":{{"key":"key1","value":"value1","seq":0}}}}{}{}{{"Chunked":{{"key":"large","records":[{},{}],"sizes":[3,3],"seq":1}}}}"#,
+        chunks[0],
+        chunks[1],
+        chunks[0].len(),
+        chunks[1].len()
+    );
+    std::fs::write(&log_path, &json)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some("abcdef".to_owned()));
+    assert_eq!(
+        store.get_range("large".to_owned(), 2, 2)?,
+        Some(b"cd".to_vec())
+    );
+    drop(store);
+    let log = std::fs::read(&log_path)?;
+    assert!(log.starts_with(b"KVSL"));
+    assert!(log.len() < json.len());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some("abcdef".to_owned()));
+    Ok(())
+}
+
+// Opening should load the index from the hint written on close, replay only what was
+// written after it, and replay the whole log without a matching hint.
+#[test]
+fn hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let hint_path = temp_dir.path().join("kvs.hint");
+    // keeps the record of the removed key around
+    let mut options = OpenOptions::new();
+    options.target_amplification(1000.0);
+
+    let store = options.open(temp_dir.path())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("gone".to_owned())?;
+    store.rpush("list".to_owned(), ["a".to_owned(), "b".to_owned()])?;
+    drop(store);
+    assert!(hint_path.exists());
+
+    // garble the record of the removed key, which only a replay reads
+    let mut log = std::fs::read(&log_path)?;
+    let len = records(&log)[0].len();
+    log[12..12 + len].fill(b'x');
+    std::fs::write(&log_path, &log)?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, ["a", "b"]);
+    let usage = store.space_usage();
+    drop(store);
+
+    // an older hint still covers the start of the log
+    let old_hint = std::fs::read(&hint_path)?;
+    let store = options.open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    std::fs::write(&hint_path, old_hint)?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.space_usage().disk_bytes > usage.disk_bytes);
+    drop(store);
+
+    // without a hint the garbled record is replayed
+    std::fs::remove_file(&hint_path)?;
+    assert!(options.open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Keys with a TTL should read as missing once it passed, across restarts, and be left
+// behind by compactions.
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = SimClock::new(Duration::from_secs(1_000));
+    let mut options = OpenOptions::new();
+    options.clock(clock.clone());
+
+    let store = options.open(temp_dir.path())?;
+    store.set_with_ttl("a".to_owned(), "1".to_owned(), Duration::from_secs(10))?;
+    store.set_with_ttl("b".to_owned(), "2".to_owned(), Duration::from_secs(5))?;
+    store.set("c".to_owned(), "3".to_owned())?;
+    assert_eq!(store.ttl("a"), Some(Duration::from_secs(10)));
+    assert_eq!(store.ttl("c"), None);
+    assert_eq!(store.ttl("missing"), None);
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.ttl("b"), None);
+    assert!(store.remove("b".to_owned()).is_err());
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "c"]);
+    assert_eq!(store.ttl("a"), Some(Duration::from_secs(4)));
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.ttl("a"), Some(Duration::from_secs(4)));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert!(store.persist("a".to_owned())?);
+    assert!(!store.persist("a".to_owned())?);
+    assert!(!store.persist("c".to_owned())?);
+    store.set_with_ttl("c".to_owned(), "4".to_owned(), Duration::from_secs(1))?;
+    store.set("c".to_owned(), "5".to_owned())?;
+    clock.advance(Duration::from_secs(100));
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("5".to_owned()));
+
+    for i in 0..10 {
+        let key = format!("key{}", i);
+        store.set_with_ttl(key, "value".repeat(200), Duration::from_secs(1))?;
+    }
+    clock.advance(Duration::from_secs(2));
+    // a compaction started by the writes before may have copied them before they expired
+    for _ in 0..2 {
+        store.compaction_handle().wait();
+        store.set("d".to_owned(), "6".to_owned())?;
+    }
+    store.compaction_handle().wait();
+    assert!(store.space_usage().disk_bytes < 1_000);
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "c", "d"]);
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "c", "d"]);
+    Ok(())
+}
+
+// Hot keys in mostly stale segments should be copied to the current one.
+#[test]
+fn relocate_hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options
+        .segment_size(1_000)
+        .target_amplification(f64::INFINITY)
+        .relocate_hot_keys(0.5);
+    let store = options.open(temp_dir.path())?;
+    store.set("hot".to_owned(), "value".to_owned())?;
+    store.lpush("list".to_owned(), ["a".to_owned()])?;
+    store.lpush("list".to_owned(), ["b".to_owned()])?;
+    for _ in 0..10 {
+        store.get("hot".to_owned())?;
+        store.lrange("list".to_owned(), 0, -1)?;
+    }
+    assert_eq!(store.relocated_keys(), 0);
+    for i in 0..1_000 {
+        store.set("cold".to_owned(), i.to_string())?;
+    }
+    assert!(store.relocated_keys() >= 2);
+    assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, ["b", "a"]);
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, ["b", "a"]);
+    assert_eq!(store.get("cold".to_owned())?, Some("999".to_owned()));
+    Ok(())
+}
+
+// Restoring a snapshot should roll the store back to when it was taken, even after
+// compactions replaced its segments.
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = SimClock::new(Duration::from_secs(1_000));
+    let mut options = OpenOptions::new();
+    options.target_amplification(1.1).clock(clock.clone());
+
+    let store = options.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot("before")?;
+    assert_eq!(snapshot.created_at, 1_000);
+    assert!(store.snapshot("before").is_err());
+    assert!(store.snapshot("not.valid").is_err());
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.remove("key2".to_owned())?;
+    store.compaction_handle().wait();
+    assert!(store.info().last_compaction.is_some());
+    clock.advance(Duration::from_secs(1));
+    // ordered by time rather than name
+    store.snapshot("after")?;
+    let names: Vec<_> = store.snapshots()?.into_iter().map(|s| s.name).collect();
+    assert_eq!(names, ["before", "after"]);
+    assert!(KvStore::restore_snapshot(temp_dir.path(), "before").is_err());
+    drop(store);
+
+    assert!(KvStore::restore_snapshot(temp_dir.path(), "missing").is_err());
+    KvStore::restore_snapshot(temp_dir.path(), "before")?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.recovery(), None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // writing to the restored store leaves the snapshot as it was
+    store.set("key2".to_owned(), "changed".to_owned())?;
+    drop(store);
+
+    KvStore::restore_snapshot(temp_dir.path(), "before")?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    KvStore::restore_snapshot(temp_dir.path(), "after")?;
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A branch should start out with the contents of its parent, and the two should not see
+// each other's writes afterwards.
+#[test]
+fn branch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let parent_dir = temp_dir.path().join("parent");
+    let branch_dir = temp_dir.path().join("branch");
+    std::fs::create_dir(&parent_dir)?;
+    let mut options = OpenOptions::new();
+    options.target_amplification(1.1);
+
+    let parent = options.open(&parent_dir)?;
+    parent.set("key1".to_owned(), "value1".to_owned())?;
+    parent.rpush("list".to_owned(), ["a".to_owned()])?;
+    parent.branch(&branch_dir)?;
+    assert!(parent.branch(&branch_dir).is_err());
+    let branch = options.open(&branch_dir)?;
+    assert_eq!(branch.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    branch.set("key1".to_owned(), "branched".to_owned())?;
+    branch.rpush("list".to_owned(), ["b".to_owned()])?;
+    for i in 0..100 {
+        parent.set("key2".to_owned(), format!("value{}", i))?;
+    }
+    parent.compaction_handle().wait();
+    assert!(parent.info().last_compaction.is_some());
+    assert_eq!(parent.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(parent.lrange("list".to_owned(), 0, -1)?, ["a"]);
+    assert_eq!(branch.get("key2".to_owned())?, None);
+    drop(branch);
+
+    let branch = options.open(&branch_dir)?;
+    assert_eq!(branch.get("key1".to_owned())?, Some("branched".to_owned()));
+    assert_eq!(branch.lrange("list".to_owned(), 0, -1)?, ["a", "b"]);
+    Ok(())
+}
+
+// A follower should serve the records its store wrote up to the last refresh, read-only.
+#[test]
+fn follower() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(OpenOptions::new()
+        .follow(Duration::from_secs(3600))
+        .open(temp_dir.path())
+        .is_err());
+    let store = OpenOptions::new()
+        .segment_size(200)
+        .target_amplification(1.1)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let follower = OpenOptions::new()
+        .follow(Duration::from_secs(3600))
+        .open(temp_dir.path())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i + 10), "x".repeat(50))?;
+    }
+    assert_eq!(follower.get("key2".to_owned())?, None);
+    follower.refresh()?;
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(follower.get("key19".to_owned())?, Some("x".repeat(50)));
+
+    let err = follower
+        .set("key1".to_owned(), "other".to_owned())
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<KvsError>(), Some(&KvsError::ReadOnly));
+    assert!(follower.remove("key1".to_owned()).is_err());
+    assert!(follower.snapshot("snap").is_err());
+
+    // the compaction moves every record
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.compaction_handle().wait();
+    store.remove("key2".to_owned())?;
+    assert!(store.info().last_compaction.is_some());
+    follower.refresh()?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, None);
+    assert_eq!(follower.get("key19".to_owned())?, Some("x".repeat(50)));
+
+    // the store is left as the writer closes it
+    drop(store);
+    drop(follower);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery().is_none());
+    Ok(())
+}
+
+// A follower should pick up new writes and compactions by itself, shortly after.
+#[test]
+fn follower_watches_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .target_amplification(1.1)
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "0".to_owned())?;
+    let follower = OpenOptions::new()
+        .follow(Duration::from_millis(5))
+        .open(temp_dir.path())?;
+    let eventually = |value: &str| -> Result<()> {
+        for _ in 0..400 {
+            if follower.get("key".to_owned())?.as_deref() == Some(value) {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("the follower never saw {}", value);
+    };
+    store.set("key".to_owned(), "1".to_owned())?;
+    eventually("1")?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("{}", i + 2))?;
+    }
+    store.compaction_handle().wait();
+    eventually("101")?;
+    Ok(())
+}
+
+// Any number of readers should open a store next to its one writer, and keep other
+// processes from replacing its files.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "1".to_owned())?;
+    // as another process would
+    let lock = std::fs::File::open(temp_dir.path().join("kvs.lock"))?;
+    assert!(lock.try_lock().is_err());
+    drop(lock);
+
+    let mut options = OpenOptions::new();
+    options.read_only(true);
+    let reader = options.open(temp_dir.path())?;
+    let other_reader = options.open(temp_dir.path())?;
+    store.set("key".to_owned(), "2".to_owned())?;
+    assert_eq!(reader.get("key".to_owned())?, Some("1".to_owned()));
+    reader.refresh()?;
+    assert_eq!(reader.get("key".to_owned())?, Some("2".to_owned()));
+    let err = reader.set("key".to_owned(), "3".to_owned()).unwrap_err();
+    assert_eq!(err.downcast_ref::<KvsError>(), Some(&KvsError::ReadOnly));
+    store.snapshot("before")?;
+    drop(store);
+    drop(other_reader);
+
+    let err = KvStore::restore_snapshot(temp_dir.path(), "before").unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(KvsError::Locked(_))));
+    drop(reader);
+    KvStore::restore_snapshot(temp_dir.path(), "before")?;
+
+    let lock = std::fs::File::open(temp_dir.path().join("kvs.lock"))?;
+    lock.try_lock()?;
+    let err = KvStore::open(temp_dir.path()).err().expect("locked");
+    assert!(matches!(err.downcast_ref(), Some(KvsError::Locked(_))));
+    drop(lock);
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key".to_owned())?,
+        Some("2".to_owned())
+    );
+    Ok(())
+}
+
+// Should read the log and the values asked for ahead when opening.
+#[test]
+fn prefetch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new().segment_size(100).open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".repeat(5))?;
+    }
+    assert_eq!(store.warm_up(), None);
+    drop(store);
+    let log_size: u64 = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum();
+
+    let store = OpenOptions::new()
+        .prefetch(Prefetch::Recent(150))
+        .prefetch(Prefetch::Keys(vec![
+            "key3".to_owned(),
+            "missing".to_owned(),
+        ]))
+        .open(temp_dir.path())?;
+    let warm_up = store.warm_up().expect("prefetched");
+    assert_eq!(warm_up.keys, 1);
+    assert!(warm_up.bytes > 150);
+    assert_eq!(store.hot_keys(1), [("key3".to_owned(), 1)]);
+    drop(store);
+
+    let store = OpenOptions::new()
+        .prefetch(Prefetch::All)
+        .open(temp_dir.path())?;
+    assert_eq!(store.warm_up().expect("prefetched").bytes, log_size);
+    assert_eq!(store.get("key9".to_owned())?, Some("value".repeat(5)));
+    Ok(())
+}
+
+// Hot keys should be saved, and come back hot after reopening.
+#[test]
+fn persist_heat() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .persist_heat(Duration::from_millis(10))
+        .open(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    for _ in 0..5 {
+        store.get("a".to_owned())?;
+    }
+    store.get("c".to_owned())?;
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(temp_dir.path().join("kvs.heat").exists());
+    store.get("c".to_owned())?;
+    drop(store);
+
+    let store = OpenOptions::new()
+        .prefetch(Prefetch::Hot(2))
+        .open(temp_dir.path())?;
+    let hot = [("a".to_owned(), 6), ("c".to_owned(), 3)];
+    assert_eq!(store.hot_keys(2), hot);
+    assert_eq!(store.warm_up().expect("prefetched").keys, 2);
+    drop(store);
+
+    // "a" was written first, but is the last to be evicted
+    let store = OpenOptions::new()
+        .eviction(1, Lru::default())
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+    Ok(())
+}
+
+// Store metadata should be created once and track compactions and shutdowns.
+#[test]
+fn store_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let created_at = store.info().created_at;
+    assert!(created_at.is_some());
+    assert_eq!(store.info().format_version, kvs::FORMAT_VERSION);
+    assert_eq!(store.info().codec, "binary");
+    assert!(!store.info().encrypted);
+    assert_eq!(store.info().last_compaction, None);
+
+    for i in 0..20 {
+        store.set("key1".to_owned(), i.to_string())?;
+    }
+    store.compaction_handle().wait();
+    assert!(store.info().last_compaction.is_some());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info().created_at, created_at);
+    assert_eq!(store.info().clean_shutdown, Some(true));
+    // simulate a crash: the metadata still says the store is open
+    let meta_path = temp_dir.path().join("kvs.meta");
+    let meta = std::fs::read_to_string(&meta_path)?;
+    drop(store);
+    std::fs::write(&meta_path, meta)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info().clean_shutdown, Some(false));
+    assert!(store.info().last_compaction.is_some());
+
+    Ok(())
+}
+
+// Should record a clean shutdown and keep the data.
+#[test]
+fn store_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.shutdown()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.info().clean_shutdown, Some(true));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should cut a torn tail off the log, and a garbled one only when the store was not
+// closed cleanly.
+#[test]
+fn dirty_open_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let meta_path = temp_dir.path().join("kvs.meta");
+    let append = |bytes: &[u8]| -> Result<()> {
+        let mut log = std::fs::OpenOptions::new().append(true).open(&log_path)?;
+        log.write_all(bytes)?;
+        Ok(())
+    };
+    // a record of 40 bytes of which the crash only left some
+    let torn = b"\x28\0\0\0\x00\x04key3";
+    let garbled = b"\x02\0\0\0\xff\xff";
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery(), None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // a crash of the system after a clean close, before the log reached the disk
+    append(torn)?;
+    let store = KvStore::open(temp_dir.path())?;
+    let recovery = store.recovery().expect("torn write");
+    assert_eq!(recovery.bytes_discarded, torn.len() as u64);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    append(garbled)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    // simulate a crash in the middle of the last write
+    let meta = std::fs::read_to_string(&meta_path)?;
+    std::fs::write(&meta_path, meta.replace("true", "false"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    let recovery = store.recovery().expect("dirty open");
+    // the hint written on close covers both records, only the garbled one is read
+    assert_eq!(recovery.records, 0);
+    assert_eq!(recovery.bytes_discarded, garbled.len() as u64);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery(), None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should sample the keyspace and extrapolate to the whole store.
+#[test]
+fn analyze_keyspace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let report = store.analyze(10)?;
+    assert_eq!(report.keys, 0);
+    assert_eq!(report.sampled, 0);
+
+    for i in 0..60 {
+        store.set(format!("user:{:02}", i), "x".repeat(10))?;
+    }
+    for i in 0..40 {
+        store.set(format!("session:{:02}", i), "abcd".repeat(5))?;
+    }
+    store.rpush("queue".to_owned(), vec!["a".to_owned(), "bc".to_owned()])?;
+
+    let report = store.analyze(1000)?;
+    assert_eq!(report.keys, 101);
+    assert_eq!(report.sampled, 101);
+    assert_eq!(report.key_lengths.min, 5);
+    assert_eq!(report.key_lengths.max, 10);
+    assert_eq!(report.value_sizes.min, 3);
+    assert_eq!(report.value_sizes.max, 20);
+    assert_eq!(
+        report.prefixes,
+        vec![("user".to_owned(), 60), ("session".to_owned(), 40)]
+    );
+    assert!(report.compression_ratio > 1.0);
+
+    let report = store.analyze(10)?;
+    assert_eq!(report.keys, 101);
+    assert!(report.sampled <= 11);
+    Ok(())
+}
+
+// Should run entirely in memory on simulated time.
+#[test]
+fn simulated_storage_and_clock() -> Result<()> {
+    let storage = MemStorage::new();
+    let clock = SimClock::new(Duration::from_secs(86400 + 3600));
+    let mut options = OpenOptions::new();
+    options
+        .storage(storage.clone())
+        .clock(clock.clone())
+        .compaction_window("02:00-03:00".parse()?);
+
+    let store = options.open("db")?;
+    assert_eq!(store.info().created_at, Some(86400 + 3600));
+    assert!(options.open("db").is_err());
+    for i in 0..100 {
+        store.set("key1".to_owned(), i.to_string())?;
+    }
+    assert_eq!(store.info().last_compaction, None);
+
+    clock.advance(Duration::from_secs(3600));
+    store.set("key1".to_owned(), "last".to_owned())?;
+    store.compaction_handle().wait();
+    assert_eq!(store.info().last_compaction, Some(86400 + 2 * 3600));
+    drop(store);
+
+    // the compaction moved the data into the next segment
+    assert!(storage.exists(Path::new("db/2.log")));
+    let store = options.open("db")?;
+    assert_eq!(store.info().clean_shutdown, Some(true));
+    assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+    // another storage holds other files under the same path
+    let other = OpenOptions::new().storage(MemStorage::new()).open("db")?;
+    assert_eq!(other.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should behave like the model store on generated operations, across reopens.
+#[test]
+fn model_check() -> Result<()> {
+    // xorshift, so failures are reproducible
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % n
+    };
+    let mut ops = Vec::new();
+    for i in 0..2000 {
+        let key = format!("key{}", next(20));
+        ops.push(match next(100) {
+            0..=39 => ModelOp::Set(key, format!("value{}", i)),
+            40..=69 => ModelOp::Get(key),
+            70..=89 => ModelOp::Remove(key),
+            90..=97 => ModelOp::Scan(key, format!("key{}", next(20))),
+            _ => ModelOp::Reopen,
+        });
+    }
+    for kind in [IndexKind::Hash, IndexKind::Ordered] {
+        let mut options = OpenOptions::new();
+        options.index(kind).storage(MemStorage::new());
+        check_against_model(&options, "db", &ops)?;
+    }
+
+    // a store that is not empty diverges from the model
+    let mut options = OpenOptions::new();
+    options.storage(MemStorage::new());
+    options
+        .open("db")?
+        .set("key1".to_owned(), "value1".to_owned())?;
+    let ops = [ModelOp::Reopen, ModelOp::Get("key1".to_owned())];
+    let err = check_against_model(&options, "db", &ops).unwrap_err();
+    let divergence = err.downcast_ref::<Divergence>().expect("a divergence");
+    assert_eq!(divergence.step, 1);
+    assert_eq!(divergence.expected, Outcome::Value(None));
+    assert_eq!(divergence.actual, Outcome::Value(Some("value1".to_owned())));
+    Ok(())
+}
+
+// Should report, and on request repair, damage to the store.
+#[test]
+fn fsck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(KvStore::fsck(temp_dir.path(), false).is_err());
+    drop(store);
+
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert_eq!(report.status, FsckStatus::Clean);
+    assert_eq!(report.records, 2);
+    assert!(report.problems.is_empty());
+
+    let log = std::fs::read(&log_path)?;
+    let mut torn = log.clone();
+    torn.extend_from_slice(br#"{"Set":{"key":"ke"#);
+    std::fs::write(&log_path, &torn)?;
+    std::fs::write(temp_dir.path().join("kvs.compact.log"), "")?;
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert_eq!(report.status, FsckStatus::NeedsRepair);
+    let kinds: Vec<_> = report.problems.iter().map(|p| p.kind).collect();
+    assert_eq!(
+        kinds,
+        [ProblemKind::LeftoverCompaction, ProblemKind::TornTail]
+    );
+    assert_eq!(std::fs::read(&log_path)?, torn);
+
+    let report = KvStore::fsck(temp_dir.path(), true)?;
+    assert_eq!(report.status, FsckStatus::Repaired);
+    assert!(report.problems.iter().all(|p| p.repaired));
+    assert_eq!(std::fs::read(&log_path)?, log);
+    assert!(!temp_dir.path().join("kvs.compact.log").exists());
+    assert_eq!(
+        KvStore::fsck(temp_dir.path(), false)?.status,
+        FsckStatus::Clean
+    );
+
+    // garbage in the middle cannot be cut off without losing the second record
+    let mut corrupt = log.clone();
+    // the kind of the first record, after the header and its length
+    corrupt[12] = 0x7f;
+    std::fs::write(&log_path, &corrupt)?;
+    let report = KvStore::fsck(temp_dir.path(), true)?;
+    assert_eq!(report.status, FsckStatus::Unrecoverable);
+    assert_eq!(report.problems[0].kind, ProblemKind::CorruptRecord);
+    assert_eq!(std::fs::read(&log_path)?, corrupt);
+    Ok(())
+}
+
+// `kvs fsck` should exit with the documented codes and print JSON on request.
+#[test]
+fn cli_fsck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fsck = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.arg("fsck").args(args).current_dir(&temp_dir);
+        cmd
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fsck(&[]).assert().code(0).stdout(contains("status: clean"));
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(b"{")?;
+    fsck(&["--json"])
+        .assert()
+        .code(2)
+        .stdout(contains(r#""status": "needs_repair""#).and(contains(r#""kind": "torn_tail""#)));
+    fsck(&["--repair", "--dry-run"])
+        .assert()
+        .code(2)
+        .stdout(contains("status: needs_repair"))
+        .stderr(contains("Dry run"));
+    fsck(&["--dry-run"]).assert().code(4);
+    fsck(&["--repair"])
+        .assert()
+        .code(1)
+        .stdout(contains("status: repaired"));
+    fsck(&[]).assert().code(0);
+    Ok(())
+}
+
+#[test]
+fn cli_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["set", "key1", "value1"]).assert().success();
+    kvs(&["snapshot", "create", "first"])
+        .assert()
+        .success()
+        .stdout(contains("first"));
+    kvs(&["set", "key1", "value2"]).assert().success();
+    kvs(&["snapshot", "list"])
+        .assert()
+        .success()
+        .stdout(contains("first"));
+    kvs(&["snapshot", "restore", "first"]).assert().success();
+    kvs(&["get", "key1"]).assert().success().stdout("value1\n");
+    kvs(&["snapshot", "restore", "second"]).assert().failure();
+    Ok(())
+}
+
+// `compact_now` should compact whatever the amplification and return once it is done.
+#[test]
+fn compact_now() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options.target_amplification(1000.0);
+    let store = options.open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.space_usage().amplification() > 10.0);
+
+    store.compact_now()?;
+    assert!(!store.compaction_handle().progress().running);
+    assert!(store.space_usage().amplification() < 1.1);
+    assert_eq!(store.len(), 9);
+    assert_eq!(store.get("key9".to_owned())?, Some("value199".to_owned()));
+    store.set("key0".to_owned(), "again".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("space_amplification: 1.00"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("value199".to_owned()));
+    Ok(())
+}
+
+// `stats` should count the keys and split the log into live and stale bytes.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options.target_amplification(1000.0);
+    let store = options.open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    let usage = store.space_usage();
+    assert_eq!(
+        (stats.live_bytes, stats.stale_bytes),
+        (usage.live_bytes, usage.disk_bytes - usage.live_bytes)
+    );
+    assert!(stats.stale_bytes > stats.live_bytes * 10);
+    assert_eq!(stats.segments, 1);
+    assert!(stats.disk_bytes > usage.disk_bytes);
+    assert_eq!(stats.target_amplification, 1000.0);
+    assert!(!stats.compaction_running && !stats.compaction_due);
+    assert!(stats.to_string().contains("keys: 2\n"));
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(json["keys"], 2);
+    assert_eq!(json["segments"], 1);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("stale_bytes: ").and(contains("compaction_due: ")));
+    Ok(())
+}
+
+// A clone should hold the live data of every kind in one compacted segment, and nothing
+// written after it was taken.
+#[test]
+fn clone_to() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clone_dir = temp_dir.path().join("clone");
+    let mut options = OpenOptions::new();
+    options.dedup_values(100);
+    let store = options.open(temp_dir.path())?;
+    let large = "x".repeat(1000);
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.set("a".to_owned(), large.clone())?;
+    store.set("b".to_owned(), large.clone())?;
+    store.set("removed".to_owned(), "gone".to_owned())?;
+    store.remove("removed".to_owned())?;
+    store.set_with_ttl(
+        "expired".to_owned(),
+        "x".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "y".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.hset("hash".to_owned(), "f1".to_owned(), "v1".to_owned())?;
+    store.hset("hash".to_owned(), "f2".to_owned(), "v2".to_owned())?;
+    store.incr("counter".to_owned(), 5)?;
+    std::thread::sleep(Duration::from_millis(10));
+
+    store.clone_to(&clone_dir)?;
+    store.set("key".to_owned(), "later".to_owned())?;
+    assert!(store.clone_to(&clone_dir).is_err());
+    let source_usage = store.space_usage();
+    drop(store);
+
+    let clone = options.open(&clone_dir)?;
+    assert_eq!(clone.get("key".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(clone.get("a".to_owned())?, Some(large.clone()));
+    assert_eq!(clone.get("b".to_owned())?, Some(large));
+    assert_eq!(clone.get("removed".to_owned())?, None);
+    assert_eq!(clone.get("expired".to_owned())?, None);
+    assert_eq!(clone.get("expiring".to_owned())?, Some("y".to_owned()));
+    assert!(clone.ttl("expiring").is_some());
+    assert_eq!(
+        clone.hget("hash".to_owned(), "f2".to_owned())?,
+        Some("v2".to_owned())
+    );
+    assert_eq!(clone.incr("counter".to_owned(), 0)?, 5);
+    let usage = clone.space_usage();
+    assert!(usage.disk_bytes < source_usage.disk_bytes / 2);
+    assert!(usage.amplification() < 1.1, "{}", usage);
+    Ok(())
+}
+
+// A backup should hold the store as it was when it was taken, for restoring it later or
+// opening it elsewhere.
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let backup_dir = temp_dir.path().join("backup");
+    std::fs::create_dir(&store_dir)?;
+    let mut options = OpenOptions::new();
+    options.segment_size(1024).target_amplification(1.5);
+
+    let store = options.open(&store_dir)?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), "before".to_owned())?;
+    }
+    store.backup(&backup_dir)?;
+    assert!(store.backup(&backup_dir).is_err());
+    for i in 0..200 {
+        store.set(format!("key{}", i % 50), "after".to_owned())?;
+    }
+    store.compaction_handle().wait();
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+
+    // the backup is a store of its own
+    let copy = KvStore::open(&backup_dir)?;
+    assert_eq!(copy.len(), 50);
+    assert_eq!(copy.get("key0".to_owned())?, Some("before".to_owned()));
+    drop(copy);
+
+    assert!(KvStore::restore(&store_dir, &backup_dir).is_err());
+    drop(store);
+    KvStore::restore(&store_dir, &backup_dir)?;
+    let store = KvStore::open(&store_dir)?;
+    assert_eq!(store.get("key49".to_owned())?, Some("before".to_owned()));
+    store.set("key0".to_owned(), "restored".to_owned())?;
+    drop(store);
+    assert!(KvStore::restore(&store_dir, temp_dir.path()).is_err());
+
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&store_dir);
+        cmd
+    };
+    kvs(&["backup", "../cli-backup"]).assert().success();
+    kvs(&["set", "key0", "changed"]).assert().success();
+    kvs(&["restore", "../cli-backup"]).assert().success();
+    kvs(&["get", "key0"])
+        .assert()
+        .success()
+        .stdout("restored\n");
+    kvs(&["restore", "../missing"]).assert().failure();
+    Ok(())
+}
+
+// Should report the most accessed keys first.
+#[test]
+fn hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.hot_keys(10).is_empty());
+    for i in 0..200 {
+        store.set(format!("cold{}", i), "value".to_owned())?;
+    }
+    store.set("hot".to_owned(), "value".to_owned())?;
+    store.set("warm".to_owned(), "value".to_owned())?;
+    for _ in 0..50 {
+        store.get("hot".to_owned())?;
+    }
+    for _ in 0..20 {
+        store.get("warm".to_owned())?;
+    }
+    for _ in 0..10 {
+        store.get("missing".to_owned())?;
+    }
+
+    let hot = store.hot_keys(3);
+    let keys: Vec<_> = hot.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["hot", "warm", "missing"]);
+    assert!(hot[0].1 >= 51);
+    assert!(hot[1].1 >= 21);
+    assert_eq!(store.hot_keys(100).len(), 64);
+    Ok(())
+}
+
+// Reads from every thread should count towards the hot keys and the eviction policy.
+#[test]
+fn accesses_from_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .eviction(3, Lru::default())
+        .open(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            let store = store.clone();
+            scope.spawn(move || {
+                for _ in 0..10 {
+                    store.get("a".to_owned()).unwrap();
+                }
+            });
+        }
+    });
+    let hot = store.hot_keys(1);
+    assert_eq!(hot[0].0, "a");
+    assert!(hot[0].1 >= 41, "{:?}", hot);
+
+    // b is the least recently used now
+    store.set("d".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert!(store.get("a".to_owned())?.is_some());
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+struct Origin {
+    values: Arc<Mutex<HashMap<String, String>>>,
+    fetches: Arc<AtomicUsize>,
+}
+
+impl BackingStore for Origin {
+    fn fetch(&self, key: &str) -> Result<Option<String>> {
+        self.fetches.fetch_add(1, SeqCst);
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn store(&self, key: &str, value: &str) -> Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool> {
+        Ok(self.values.lock().unwrap().remove(key).is_some())
+    }
+}
+
+// Should read through to and write through to the backing store.
+#[test]
+fn backing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let origin = Origin::default();
+    origin
+        .values
+        .lock()
+        .unwrap()
+        .insert("remote".to_owned(), "value1".to_owned());
+    let store = OpenOptions::new()
+        .backing_store(origin.clone())
+        .open(temp_dir.path())?;
+    let fetches = || origin.fetches.load(SeqCst);
+
+    assert_eq!(store.get("remote".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("remote".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fetches(), 1);
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(fetches(), 2);
+
+    store.set("local".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        origin.values.lock().unwrap().get("local"),
+        Some(&"value2".to_owned())
+    );
+
+    origin
+        .values
+        .lock()
+        .unwrap()
+        .insert("uncached".to_owned(), "value3".to_owned());
+    store.remove("uncached".to_owned())?;
+    store.remove("remote".to_owned())?;
+    assert!(store.remove("missing".to_owned()).is_err());
+    assert_eq!(store.get("remote".to_owned())?, None);
+    let summary = store.remove_many(vec!["local".to_owned(), "missing".to_owned()])?;
+    assert_eq!(summary.removed, ["local"]);
+    assert_eq!(summary.missing, ["missing"]);
+    assert!(origin.values.lock().unwrap().is_empty());
+    drop(store);
+
+    // the cache survives without the origin
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("remote".to_owned())?, None);
+    Ok(())
+}
+
+// Should evict the keys chosen by the policy beyond the key limit.
+#[test]
+fn eviction_policies() -> Result<()> {
+    fn run(options: &mut OpenOptions) -> Result<Vec<String>> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = options.open(temp_dir.path())?;
+        for key in ["a", "b", "c"] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        store.get("a".to_owned())?;
+        store.get("a".to_owned())?;
+        store.get("b".to_owned())?;
+        store.set("d".to_owned(), "value".to_owned())?;
+        store.set("e".to_owned(), "value".to_owned())?;
+        let keys = store
+            .scan(..)?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect();
+        keys
+    }
+    assert_eq!(
+        run(OpenOptions::new().eviction(3, Lru::default()))?,
+        ["b", "d", "e"]
+    );
+    assert_eq!(
+        run(OpenOptions::new().eviction(3, Lfu::default()))?,
+        ["a", "b", "e"]
+    );
+    assert_eq!(
+        run(OpenOptions::new().eviction(3, Fifo::default()))?,
+        ["c", "d", "e"]
+    );
+
+    // evicted keys come back from the backing store, and a lower limit applies on open
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let origin = Origin::default();
+    let store = OpenOptions::new()
+        .backing_store(origin.clone())
+        .eviction(2, Lru::default())
+        .open(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(store.kind("a"), None);
+    assert_eq!(store.get("a".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.kind("b"), None);
+    drop(store);
+    let store = OpenOptions::new()
+        .eviction(1, Lru::default())
+        .open(temp_dir.path())?;
+    assert_eq!(store.kind("c"), None);
+    assert!(store.kind("a").is_some());
+    Ok(())
+}
+
+// Automatic compaction should keep the log within the target amplification.
+#[test]
+fn space_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new()
+        .target_amplification(3.0)
+        .open(temp_dir.path())?;
+    assert_eq!(store.space_usage().amplification(), 1.0);
+
+    // distinct keys never leave stale data behind
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let usage = store.space_usage();
+    assert_eq!(usage.live_bytes, usage.disk_bytes);
+    assert_eq!(store.info().last_compaction, None);
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i % 10), format!("{}", i))?;
+        store.compaction_handle().wait();
+        assert!(store.space_usage().amplification() <= 3.0);
+    }
+    assert!(store.info().last_compaction.is_some());
+    store.remove("key0".to_owned())?;
+    store.compaction_handle().wait();
+    let usage = store.space_usage();
+    drop(store);
+
+    // the same usage is found on open
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.space_usage(), usage);
+    Ok(())
+}
+
+// `kvs info` should print the store metadata.
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["info"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("format_version: 2")
+                .and(contains("last_shutdown: clean"))
+                .and(contains("space_amplification: 1.00")),
+        );
+}
+
+// A batch should apply all its writes in order, and none of them if the log ends inside it.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "old".to_owned())?;
+    store.set("b".to_owned(), "old".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("a".to_owned(), "new".to_owned())
+        .remove("b".to_owned())
+        .remove("missing".to_owned())
+        .set("c".to_owned(), "1".to_owned())
+        .remove("c".to_owned())
+        .set("c".to_owned(), "2".to_owned());
+    assert_eq!(batch.len(), 6);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+    let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    let expected = [
+        ("a".to_owned(), "new".to_owned()),
+        ("c".to_owned(), "2".to_owned()),
+    ];
+    assert_eq!(pairs, expected);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("d".to_owned(), "value".to_owned())
+        .remove("a".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+    // the last record of the batch is cut short
+    let last_segment = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .max_by_key(|path| {
+            let gen = path.file_stem().unwrap().to_str().unwrap();
+            gen.parse::<u64>().unwrap_or(0)
+        })
+        .expect("a segment");
+    let log = std::fs::OpenOptions::new().write(true).open(last_segment)?;
+    log.set_len(log.metadata()?.len() - 2)?;
+    drop(log);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+    assert!(store.recovery().is_some());
+    Ok(())
+}
+
+// Bulk loading should set every pair, the last one winning for repeated keys.
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    let pairs = (0..1000)
+        .rev()
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .chain(std::iter::once(("key1".to_owned(), "last".to_owned())));
+    assert_eq!(store.bulk_load(pairs)?, 1000);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?.count(), 1000);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    drop(store);
+
+    // a load larger than a segment fills new ones, none beyond the size
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options.segment_size(4096);
+    let store = options.open(temp_dir.path())?;
+    store.set("first".to_owned(), "value".to_owned())?;
+    let pairs = (0..1000).map(|i| (format!("key{:04}", i), format!("value{}", i)));
+    assert_eq!(store.bulk_load(pairs)?, 1000);
+    drop(store);
+    let mut segments = 0;
+    for gen in 1.. {
+        let Ok(meta) = std::fs::metadata(temp_dir.path().join(format!("{}.log", gen))) else {
+            break;
+        };
+        assert!(
+            meta.len() <= 4096,
+            "segment {} has {} bytes",
+            gen,
+            meta.len()
+        );
+        segments += 1;
+    }
+    assert!(segments > 5, "{} segments", segments);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?.count(), 1001);
+    assert_eq!(
+        store.get("key0999".to_owned())?,
+        Some("value999".to_owned())
+    );
+    Ok(())
+}
+
+// `kvs load` should set the tab separated pairs read from stdin, with or without --bulk.
+#[test]
+fn cli_load() -> Result<()> {
+    for args in [&["load"][..], &["load", "--bulk"]] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .with_stdin()
+            .buffer("key1\tvalue1\nkey2\tvalue\twith tab\n")
+            .assert()
+            .success();
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(
+            store.get("key2".to_owned())?,
+            Some("value\twith tab".to_owned())
+        );
+        drop(store);
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .with_stdin()
+            .buffer("no tab\n")
+            .assert()
+            .failure();
+    }
+    Ok(())
+}
+
+// A sorted export should hold the string values in key order, indexed every few keys.
+#[test]
+fn export_sorted() -> Result<()> {
+    fn read_u32(bytes: &[u8], at: &mut usize) -> usize {
+        *at += 4;
+        u32::from_le_bytes(bytes[*at - 4..*at].try_into().unwrap()) as usize
+    }
+    fn read_u64(bytes: &[u8], at: &mut usize) -> u64 {
+        *at += 8;
+        u64::from_le_bytes(bytes[*at - 8..*at].try_into().unwrap())
+    }
+    fn read_str(bytes: &[u8], at: &mut usize) -> String {
+        let len = read_u32(bytes, at);
+        *at += len;
+        String::from_utf8(bytes[*at - len..*at].to_vec()).unwrap()
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (0..40).rev() {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.rpush("list".to_owned(), vec!["item".to_owned()])?;
+    let mut out = Vec::new();
+    assert_eq!(store.export_sorted(&mut out)?, 40);
+
+    let footer = out.len() - 24;
+    assert_eq!(&out[footer + 16..], SORTED_EXPORT_MAGIC);
+    let mut at = footer;
+    let index_offset = read_u64(&out, &mut at) as usize;
+    assert_eq!(read_u64(&out, &mut at), 40);
+
+    let mut at = 0;
+    let mut offsets = Vec::new();
+    for i in 0..40 {
+        offsets.push(at as u64);
+        assert_eq!(read_str(&out, &mut at), format!("key{:02}", i));
+        assert_eq!(read_str(&out, &mut at), format!("value{}", i));
+    }
+    assert_eq!(at, index_offset);
+    let mut index = Vec::new();
+    while at < footer {
+        index.push((read_str(&out, &mut at), read_u64(&out, &mut at)));
+    }
+    let step = SORTED_EXPORT_INDEX_INTERVAL as usize;
+    let expected: Vec<(String, u64)> = (0..40)
+        .step_by(step)
+        .map(|i| (format!("key{:02}", i), offsets[i]))
+        .collect();
+    assert_eq!(index, expected);
+    Ok(())
+}
+
+// `kvs export --sorted --format sst-like` should write the export to stdout.
+#[test]
+fn cli_export() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut expected = Vec::new();
+    store.export_sorted(&mut expected)?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--sorted", "--format", "sst-like"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "sst-like"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// `kvs export` should write JSON lines and CSV in key order, quoting CSV fields as needed.
+#[test]
+fn cli_export_json_and_csv() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "say \"hi\", then\nleave".to_owned())?;
+    store.set("a".to_owned(), "plain".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "x".to_owned(), Duration::from_millis(1))?;
+    std::thread::sleep(Duration::from_millis(10));
+    let mut json = Vec::new();
+    assert_eq!(store.export_json(&mut json)?, 2);
+    drop(store);
+
+    assert_eq!(
+        String::from_utf8(json.clone())?,
+        "{\"key\":\"a\",\"value\":\"plain\"}\n\
+         {\"key\":\"b\",\"value\":\"say \\\"hi\\\", then\\nleave\"}\n"
+    );
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "json"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, json);
+
+    let csv = temp_dir.path().join("out.csv");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output"])
+        .arg(&csv)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&csv)?,
+        "key,value\na,plain\nb,\"say \"\"hi\"\", then\nleave\"\n"
+    );
+    Ok(())
+}
+
+// `kvs import` should load what `kvs export` wrote, and nothing of an invalid file.
+#[test]
+fn cli_import_round_trip() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(source_dir.path())?;
+    store.set("a".to_owned(), "plain".to_owned())?;
+    store.set("b, c".to_owned(), "say \"hi\"\r\nthen leave".to_owned())?;
+    store.set("d".to_owned(), String::new())?;
+    drop(store);
+
+    for format in ["json", "csv"] {
+        let file = source_dir.path().join(format!("pairs.{}", format));
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["export", "--format", format, "--output"])
+            .arg(&file)
+            .current_dir(&source_dir)
+            .assert()
+            .success();
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["import", "--format", format])
+            .arg(&file)
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get("a".to_owned())?, Some("plain".to_owned()));
+        assert_eq!(
+            store.get("b, c".to_owned())?,
+            Some("say \"hi\"\r\nthen leave".to_owned())
+        );
+        assert_eq!(store.get("d".to_owned())?, Some(String::new()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let err = store
+        .import_csv("key,value\nx,1\ny,\"2\n".as_bytes())
+        .expect_err("an unterminated field should fail");
+    assert!(err.to_string().contains("line 3"), "{}", err);
+    let err = store
+        .import_json("{\"key\":\"x\",\"value\":\"1\"}\n\n{\"key\":\"y\"}\n".as_bytes())
+        .expect_err("a pair without value should fail");
+    assert!(err.to_string().contains("line 3"), "{}", err);
+    assert!(store.import_csv("k,v\n".as_bytes()).is_err());
+    assert!(store.is_empty());
+    Ok(())
+}
+
+// Exports and imports should count the keys and bytes they went through.
+#[test]
+fn export_and_import_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let progress = Progress::new();
+    let mut json = Vec::new();
+    store.export_json_with_progress(&mut json, &progress)?;
+    let report = progress.report();
+    assert_eq!((report.keys, report.keys_total), (3, Some(3)));
+    assert_eq!(report.bytes, json.len() as u64);
+    assert_eq!(report.eta, Some(Duration::ZERO));
+    assert!(report.to_string().starts_with("3/3 keys, "), "{}", report);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    let progress = Progress::new();
+    progress.expect_bytes(json.len() as u64);
+    other.import_json_with_progress(&json[..], &progress)?;
+    let report = progress.report();
+    assert_eq!((report.keys, report.keys_total), (3, None));
+    assert_eq!(report.bytes_total, Some(report.bytes));
+    drop(store);
+
+    // without a terminal there is no progress to show
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output", "out.csv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+    Ok(())
+}
+
+// `kvs repl` should answer commands until `exit`, prompting only on a terminal.
+#[test]
+fn cli_repl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repl"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set a 1\nget a\nscan\nrm b\nexit\nset b 2\n")
+        .assert()
+        .success()
+        .stdout("OK\n\"1\"\n\"a\" \"1\"\nEND\nERR KeyNotFound Key not found\n");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    let mut output = Vec::new();
+    serve_repl(&mut store, "help\nget a\n".as_bytes(), &mut output, "> ")?;
+    let output = String::from_utf8(output)?;
+    assert!(output.starts_with("> SET key value"), "{}", output);
+    assert!(output.ends_with("> \"1\"\n> "), "{}", output);
+    Ok(())
+}
+
+// `--dry-run` should print what import and restore would change, changing nothing.
+#[test]
+fn cli_dry_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("same".to_owned(), "1".to_owned())?;
+    store.set("changed".to_owned(), "2".to_owned())?;
+    store.backup(&backup_dir)?;
+    store.set("after".to_owned(), "3".to_owned())?;
+    drop(store);
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    std::fs::write(
+        temp_dir.path().join("pairs.csv"),
+        "key,value\nsame,1\nchanged,two\nnew,4\nnew,5\n",
+    )?;
+    kvs(&["import", "--format", "csv", "pairs.csv", "--dry-run"])
+        .assert()
+        .success()
+        .stdout("new_keys: 1\noverwritten_keys: 1\nunchanged_keys: 1\n");
+    kvs(&["restore", "backup", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(
+            contains("remove ")
+                .and(contains("copy "))
+                .and(contains("bytes)")),
+        );
+    kvs(&["restore", "missing", "--dry-run"]).assert().failure();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("changed".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, None);
+    assert_eq!(store.get("after".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+// `kvs pipe` should answer text and JSON commands in order, going on after bad ones.
+#[test]
+fn cli_pipe() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["pipe"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(
+            "SET a 1\n\
+             set \"b c\" \"two\\nlines\"\n\
+             GET \"b c\"\n\
+             GET missing\n\
+             RM missing\n\
+             SET a\n\
+             FROB a\n\
+             \n\
+             {\"Get\":{\"key\":\"a\"}}\n\
+             {\"Set\":{\"key\":\"d\",\"value\":\"4\"}}\n\
+             {\"Get\"\n\
+             SCAN a c\n\
+             {\"Scan\":{\"start\":{\"Included\":\"c\"},\"end\":\"Unbounded\"}}\n\
+             GETRANGE \"b c\" 1 4\n\
+             GETRANGE a x 1\n",
+        )
+        .output()?;
+    assert!(output.status.success());
+    let lines: Vec<&str> = std::str::from_utf8(&output.stdout)?.lines().collect();
+    assert_eq!(
+        lines[..5],
+        [
+            "OK",
+            "OK",
+            "\"two\\nlines\"",
+            "(nil)",
+            "ERR KeyNotFound Key not found"
+        ]
+    );
+    assert!(lines[5].starts_with("ERR BadRequest Wrong number of arguments"));
+    assert!(lines[6].starts_with("ERR BadRequest Unknown command"));
+    assert_eq!(lines[7], "{\"Value\":\"1\"}");
+    assert_eq!(lines[8], "\"Done\"");
+    assert!(lines[9].starts_with("{\"Err\":{\"code\":\"BadRequest\""));
+    assert_eq!(
+        lines[10..],
+        [
+            "\"a\" \"1\"",
+            "\"b c\" \"two\\nlines\"",
+            "END",
+            "{\"Pairs\":{\"pairs\":[[\"d\",\"4\"]],\"more\":false}}",
+            "776f0a6c",
+            "ERR BadRequest Expected a number, found \"x\"",
+        ]
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("d".to_owned())?, Some("4".to_owned()));
+    Ok(())
+}
+
+// Identical large values should be stored once and reclaimed with their last key.
+#[test]
+fn dedup_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options.dedup_values(100);
+    let store = options.open(temp_dir.path())?;
+    let large = "x".repeat(1000);
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), large.clone())?;
+    }
+    assert!(store.space_usage().disk_bytes < 1500);
+    store.set("small".to_owned(), "value".to_owned())?;
+    for key in ["a", "b", "c"] {
+        assert_eq!(store.get(key.to_owned())?, Some(large.clone()));
+    }
+
+    // compactions keep the shared value while a key refers to it
+    store.set("a".to_owned(), "value".to_owned())?;
+    store.remove("b".to_owned())?;
+    for i in 0..100 {
+        store.set("small".to_owned(), format!("{}", i))?;
+    }
+    store.compaction_handle().wait();
+    assert!(store.info().last_compaction.is_some());
+    assert_eq!(store.get("c".to_owned())?, Some(large.clone()));
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("c".to_owned())?, Some(large.clone()));
+    store.set("d".to_owned(), large.clone())?;
+    assert!(store.space_usage().live_bytes < 1500);
+    store.remove("c".to_owned())?;
+    store.remove("d".to_owned())?;
+    assert!(store.space_usage().live_bytes < 100);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, None);
+    assert!(store.space_usage().live_bytes < 100);
+    Ok(())
+}
+
+// Splits a segment into its records, after the header and each behind its length.
+fn records(segment: &[u8]) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    let mut rest = &segment[8..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        records.push(&rest[4..4 + len]);
+        rest = &rest[4 + len..];
+    }
+    records
+}
+
+// Large values should be compressed on disk, and read back whatever the options.
+#[test]
+fn compress_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = "value".repeat(1000);
+    let mut options = OpenOptions::new();
+    options.compress_values(Compression::Deflate, 100);
+
+    let store = options.open(temp_dir.path())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(log_size < 200, "{} bytes", log_size);
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    let stats = store.compression_stats().get(Compression::Deflate);
+    assert_eq!((stats.compressed, stats.decompressed), (1, 1));
+    assert_eq!(stats.raw_bytes, 2 * large.len() as u64);
+    assert!(stats.ratio() > 10.0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), large.clone())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("plain".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert!(store.compression_stats().codecs().len() == 1);
+    Ok(())
+}
+
+// Large values should be split into bounded records and read back whole.
+#[test]
+fn chunk_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = OpenOptions::new();
+    options.chunk_values(100);
+    let store = options.open(temp_dir.path())?;
+    let large: String = (0..1000)
+        .map(|i| if i % 7 == 0 { 'é' } else { 'x' })
+        .collect();
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+    let records = records(&log);
+    assert!(records.len() > 10);
+    assert!(records.iter().all(|record| record.len() < 200));
+
+    // compactions keep the chunks
+    for i in 0..100 {
+        store.set("small".to_owned(), format!("{}", i))?;
+    }
+    store.compaction_handle().wait();
+    assert!(store.info().last_compaction.is_some());
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    store.remove("large".to_owned())?;
+    assert!(store.space_usage().live_bytes < 100);
+    Ok(())
+}
+
+// Byte ranges of values should be cut from the whole value, chunked or not.
+#[test]
+fn get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new().chunk_values(10).open(temp_dir.path())?;
+    let large: String = (0..100).map(|i| char::from(b'a' + i % 26)).collect();
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.rpush("list".to_owned(), vec!["item".to_owned()])?;
+
+    for (offset, len) in [
+        (0, 100),
+        (0, 5),
+        (5, 10),
+        (15, 30),
+        (95, 10),
+        (100, 1),
+        (200, 1),
+    ] {
+        let start = offset.min(100) as usize;
+        let end = (offset + len).min(100) as usize;
+        assert_eq!(
+            store.get_range("large".to_owned(), offset, len)?,
+            Some(large.as_bytes()[start..end].to_vec())
+        );
+    }
+    assert_eq!(
+        store.get_range("small".to_owned(), 1, 3)?,
+        Some(b"alu".to_vec())
+    );
+    assert_eq!(
+        store.get_range("small".to_owned(), 3, u64::MAX)?,
+        Some(b"ue".to_vec())
+    );
+    assert_eq!(store.get_range("missing".to_owned(), 0, 1)?, None);
+    assert!(store.get_range("list".to_owned(), 0, 1).is_err());
+    Ok(())
+}
+
+// `KvStore` should be usable through the `KvsEngine` trait.
+#[test]
+fn kvs_engine() -> Result<()> {
+    fn exercise(engine: &mut dyn KvsEngine) -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        engine.remove("key1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        assert!(engine.remove("key1".to_owned()).is_err());
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise(&mut KvStore::open(temp_dir.path())?)
+}
+
+// Engines picked by name should work behind a `Box<dyn KvsEngine>`, also in a server.
+#[test]
+fn engine_by_name() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = Engine::open("kvs", temp_dir.path())?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    drop(engine);
+    let mut engine = Engine::open("kvs", temp_dir.path())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    drop(engine);
+
+    let mut engine = Engine::open("mem", temp_dir.path())?;
+    assert_eq!(engine.get("key".to_owned())?, None);
+    engine.set("key".to_owned(), "memory".to_owned())?;
+    assert_eq!(
+        engine.scan_prefix("k")?.collect::<Result<Vec<_>>>()?,
+        [("key".to_owned(), "memory".to_owned())]
+    );
+    assert!(Engine::open("sled", temp_dir.path()).is_err());
+    assert!(Engine::open("other", temp_dir.path()).is_err());
+    assert_eq!("mem".parse::<Engine>()?, Engine::Mem);
+    assert_eq!(Engine::Kvs.to_string(), "kvs");
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(engine).serve(listener));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("memory".to_owned()));
+    Ok(())
+}
+
+// Clones of a store should share it across threads, and only the last one can shut it down.
+#[test]
+fn shared_store() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync>() {}
+    assert_shareable::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    assert_eq!(store.scan(..)?.count(), 200);
+    assert_eq!(store.get("key3-49".to_owned())?, Some("value49".to_owned()));
+
+    let other = store.clone();
+    assert!(store.shutdown().is_err());
+    other.shutdown()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0-0".to_owned())?, Some("value0".to_owned()));
+    assert!(store.recovery().is_none());
+    Ok(())
+}
+
+// Reads running in parallel with a writer should each see a whole, never older value.
+#[test]
+fn parallel_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_u64("counter".to_owned(), 0)?;
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut last = 0;
+                while last < 200 {
+                    let current = store
+                        .get_u64("counter".to_owned())?
+                        .expect("counter is set");
+                    assert!(current >= last);
+                    last = current;
+                    std::thread::yield_now();
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 1..=200 {
+        store.set_u64("counter".to_owned(), i)?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Clients should reach the store through a server.
+#[test]
+fn server_and_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvsError::KeyNotFound));
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Clients connected at the same time should all be answered, each on its own connection.
+#[test]
+fn server_serves_connections_at_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut first = KvsClient::connect(addr)?;
+    let mut second = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    second.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(first.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a scan left open on one connection holds up no other
+    let mut scan = first.scan(Bound::Unbounded, Bound::Unbounded)?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("key1".to_owned(), "value1".to_owned()))
+    );
+    second.remove("key1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Failed requests should be answered with a code clients can branch on.
+#[test]
+fn server_error_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    // a client in any language only has to speak JSON
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Rm":{"key":"missing"}} "ScanNext""#)?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(&stream).into_iter::<serde_json::Value>();
+    let mut error = || responses.next().unwrap().unwrap()["Err"].clone();
+    let not_found = error();
+    assert_eq!(not_found["code"], "KeyNotFound");
+    assert_eq!(not_found["message"], "Key not found");
+    assert_eq!(error()["code"], "BadRequest");
+
+    let mut client = KvsClient::connect(addr)?;
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    let err = err.downcast_ref::<KvsError>().expect("a store error");
+    assert!(err.is_not_found());
+    assert!(!err.is_retryable());
+    let busy = KvsError::Server {
+        code: ErrorCode::Busy,
+        message: "Store is busy".to_owned(),
+    };
+    assert!(busy.is_retryable());
+    assert!(!busy.is_not_found());
+    Ok(())
+}
+
+// Scans through a server should arrive in batches, and any other request should end them.
+#[test]
+fn server_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.bulk_load((0..1000).map(|i| (format!("key{:04}", i), "value".to_owned())))?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    let keys = client
+        .scan(Bound::Unbounded, Bound::Unbounded)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys.len(), 1000);
+    assert_eq!(keys.first().map(String::as_str), Some("key0000"));
+    assert_eq!(keys.last().map(String::as_str), Some("key0999"));
+
+    let pairs = client
+        .scan(
+            Bound::Included("key0100".to_owned()),
+            Bound::Excluded("key0103".to_owned()),
+        )?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key0100".to_owned(), "value".to_owned()),
+            ("key0101".to_owned(), "value".to_owned()),
+            ("key0102".to_owned(), "value".to_owned()),
+        ]
+    );
+
+    // abandon a scan after its first batch
+    assert!(client
+        .scan(Bound::Unbounded, Bound::Unbounded)?
+        .next()
+        .is_some());
+    assert_eq!(client.get("key0999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Operators should follow and cancel the compactions of a store through its server.
+#[test]
+fn server_compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i % 5), i.to_string())?;
+    }
+    store.compact_now()?;
+    let progress = store.compaction_handle().progress();
+    assert!(progress.bytes_total > 0);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.compaction_progress()?, progress);
+    // nothing to cancel, which is no error
+    client.cancel_compaction()?;
+    assert_eq!(client.get("key4".to_owned())?, Some("19".to_owned()));
+
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(br#""CompactionProgress""#)?;
+    let response = serde_json::Deserializer::from_reader(&stream)
+        .into_iter::<serde_json::Value>()
+        .next()
+        .unwrap()?;
+    let sent = &response["CompactionProgress"];
+    assert_eq!(sent["running"], false);
+    assert_eq!(sent["bytes_total"], progress.bytes_total);
+    Ok(())
+}
+
+// Operators should find the most accessed keys of a store through its server.
+#[test]
+fn server_hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.hot_keys(10)?.is_empty());
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+        for _ in 0..i * 10 {
+            client.get(format!("key{}", i))?;
+        }
+    }
+    let hot = client.hot_keys(2)?;
+    assert_eq!(
+        hot.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(),
+        ["key2", "key1"]
+    );
+    assert!(hot[0].1 >= 20);
+    Ok(())
+}
+
+// Parts of a value should be fetched through a server without the rest of it.
+#[test]
+fn server_get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = OpenOptions::new().chunk_values(10).open(temp_dir.path())?;
+    let value: String = ('a'..='z').cycle().take(100).collect();
+    store.set("large".to_owned(), value.clone())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || KvsServer::new(store).serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.get_range("large".to_owned(), 25, 10)?,
+        Some(value.as_bytes()[25..35].to_vec())
+    );
+    assert_eq!(
+        client.get_range("large".to_owned(), 95, 10)?,
+        Some(b"rstuv".to_vec())
+    );
+    assert_eq!(client.get_range("large".to_owned(), 200, 10)?, Some(vec![]));
+    assert_eq!(client.get_range("missing".to_owned(), 0, 10)?, None);
+    Ok(())
+}
+
+// Responses should arrive intact once a connection agreed on a compression, and after
+// turning it off again.
+#[test]
+fn server_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store);
+    let server_stats = server.compression_stats();
+    std::thread::spawn(move || server.serve(listener));
+
+    let large = "value".repeat(1000);
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.compress(&[Compression::Deflate])?,
+        Some(Compression::Deflate)
+    );
+    client.set("large".to_owned(), large.clone())?;
+    client.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvsError::KeyNotFound));
+
+    let sent = server_stats.get(Compression::Deflate);
+    assert_eq!(sent.compressed, 1);
+    assert!(sent.ratio() > 10.0);
+    let received = client.compression_stats().get(Compression::Deflate);
+    assert_eq!(received.decompressed, 1);
+    assert_eq!(
+        (received.raw_bytes, received.compressed_bytes),
+        (sent.raw_bytes, sent.compressed_bytes)
+    );
+
+    assert_eq!(client.compress(&[])?, None);
+    assert_eq!(client.get("large".to_owned())?, Some(large));
+    assert_eq!(client.compression_stats().codecs().len(), 1);
+    Ok(())
+}
+
+// A server with an auth provider should only serve authenticated connections.
+#[test]
+fn server_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth_file = temp_dir.path().join("passwords");
+    std::fs::write(&auth_file, "# users\nalice:secret\n\nbob:hunter2\n")?;
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store).auth(Passwords::load(&auth_file)?);
+    std::thread::spawn(move || server.serve(listener));
+
+    let unauthorized =
+        |result: Result<()>| result.unwrap_err().downcast_ref() == Some(&KvsError::Unauthorized);
+    let mut client = KvsClient::connect(addr)?;
+    assert!(unauthorized(
+        client.set("key1".to_owned(), "value1".to_owned())
+    ));
+    assert!(unauthorized(client.authenticate("alice", "hunter2")));
+    assert!(unauthorized(client.authenticate("carol", "secret")));
+    assert!(unauthorized(client.get("key1".to_owned()).map(drop)));
+    client.authenticate("alice", "secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.authenticate("bob", "hunter2")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `kvs-server --addr` should serve the store in the current directory.
+#[test]
+fn cli_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .spawn()?;
+    let mut client = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(Duration::from_millis(20));
+            KvsClient::connect(addr).ok()
+        })
+        .expect("server did not start");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    server.kill()?;
+    server.wait()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Configuration files should be checked for syntax errors, unknown fields, wrong types and
+// conflicting settings, naming the line at fault.
+#[test]
+fn config_validation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("config.toml");
+    let load = |text: &str| {
+        std::fs::write(&path, text).unwrap();
+        Config::load(&path)
+    };
+
+    let config = load(
+        r#"# the store
+engine = "mem"  # no files
+segment_size = 4_096
+
+addr = '127.0.0.1:4001'
+target_amplification = 2.5e0
+sync_writes = false
+"#,
+    )?;
+    assert_eq!(config.engine.as_deref(), Some("mem"));
+    assert_eq!(config.segment_size, Some(4096));
+    assert_eq!(config.addr.as_deref(), Some("127.0.0.1:4001"));
+    assert_eq!(config.target_amplification, Some(2.5));
+    assert_eq!(config.sync_writes, Some(false));
+
+    for (text, expected) in [
+        (
+            "# settings\nengin = \"kvs\"",
+            "at line 2: unknown field `engin`",
+        ),
+        ("segment_size = \"10Gb\"", "segment_size = \"10Gb\""),
+        ("\nengine = \"leveldb\"", "at line 2"),
+        ("engine = \"mem\"\n\nread_only = true", "at line 3"),
+        ("read_only = true\nsync_writes = true", "contradict"),
+        (
+            "engine = \"mem\"\naddr = \"127.0.0.1",
+            "at line 2: unterminated string",
+        ),
+        ("engine = \"mem\" \"kvs\"", "at line 1: unexpected"),
+        (
+            "engine = \"mem\"\nengine = \"kvs\"",
+            "set twice, first at line 1",
+        ),
+        (
+            "[server]\naddr = \"127.0.0.1:4001\"",
+            "tables are not supported",
+        ),
+        (
+            "sync_writes = yes",
+            "expected a string, a number or a boolean",
+        ),
+        ("sync_writes = 1", "at line 1: invalid type"),
+        ("segment_size = 01", "at line 1"),
+        ("engine", "expected `=`"),
+    ] {
+        let message = load(text).expect_err("invalid configuration").to_string();
+        assert!(message.contains(expected), "{}", message);
+    }
+
+    let config = Config {
+        segment_size: Some(0),
+        ..Config::default()
+    };
+    assert!(config.validate().is_err());
+    assert!(config.open(temp_dir.path()).is_err());
+
+    let config = Config {
+        engine: Some("kvs".to_owned()),
+        segment_size: Some(1024),
+        ..Config::default()
+    };
+    let mut engine = config.open(temp_dir.path())?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+
+    std::fs::write(&path, "engine = \"mem\"\nread_only = true")?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", path.to_str().unwrap(), "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read_only"));
+    Ok(())
+}
+
+// The compaction settings should be read from the configuration and checked.
+#[test]
+fn config_compaction_settings() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("config.toml");
+    let load = |text: &str| {
+        std::fs::write(&path, text).unwrap();
+        Config::load(&path)
+    };
+
+    let config = load(
+        r#"compaction_window = "22:00-04:00"
+compaction_max_write_rate = 50
+target_amplification = 1.5
+"#,
+    )?;
+    assert_eq!(
+        config.compaction_window,
+        Some(CompactionWindow::new(22, 0, 4, 0)?)
+    );
+    assert_eq!(config.compaction_max_write_rate, Some(50.0));
+    assert_eq!(config.target_amplification, Some(1.5));
+    let mut engine = config.open(temp_dir.path())?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    drop(engine);
+
+    for (text, expected) in [
+        ("compaction_window = \"22:00\"", "expected HH:MM-HH:MM"),
+        (
+            "engine = \"kvs\"\n\ncompaction_max_write_rate = 0",
+            "at line 3",
+        ),
+        (
+            "target_amplification = 0.5",
+            "target_amplification must be at least 1",
+        ),
+    ] {
+        let message = load(text).expect_err("invalid configuration").to_string();
+        assert!(message.contains(expected), "{}", message);
+    }
+    Ok(())
+}
+
+// Sizes and durations should parse from the units people write them in.
+#[test]
+fn parse_units() -> Result<()> {
+    assert_eq!(parse_size("4096")?, 4096);
+    assert_eq!(parse_size("512MiB")?, 512 << 20);
+    assert_eq!(parse_size("1.5 GB")?, 1_500_000_000);
+    assert_eq!(parse_size("2KiB")?, 2048);
+    for invalid in ["10Gb", "10 parsecs", "MiB", "0.1B", ""] {
+        assert!(parse_size(invalid).is_err(), "{}", invalid);
+    }
+    assert_eq!(parse_duration("250ms")?, Duration::from_millis(250));
+    assert_eq!(parse_duration("2h")?, Duration::from_secs(7200));
+    assert_eq!(parse_duration("1h 30m")?, Duration::from_secs(5400));
+    assert_eq!(parse_duration("1.5s")?, Duration::from_millis(1500));
+    for invalid in ["5", "5 fortnights", "ms", "", "1e400s"] {
+        assert!(parse_duration(invalid).is_err(), "{}", invalid);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("config.toml");
+    std::fs::write(
+        &path,
+        "segment_size = \"64KiB\"\nmax_write_stall = \"250ms\"\npersist_heat = \"1m\"",
+    )?;
+    let config = Config::load(&path)?;
+    assert_eq!(config.segment_size, Some(64 << 10));
+    assert_eq!(config.max_write_stall, Some(Duration::from_millis(250)));
+    assert_eq!(config.persist_heat, Some(Duration::from_secs(60)));
+    std::fs::write(&path, "persist_heat = 60")?;
+    assert!(Config::load(&path).is_err());
+    Ok(())
+}
+
+// Opening the same directory twice in one process should fail until the first store is
+// dropped.
+#[test]
+fn double_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let err = KvStore::open(temp_dir.path().join(".")).err().unwrap();
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::AlreadyOpen(_))
+    ));
+
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}